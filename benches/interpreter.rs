@@ -0,0 +1,152 @@
+// Compares the interpreter's engines. Run with `cargo bench`, adding
+// `--features jit` to include the jit.
+use std::time::{Duration, Instant};
+use watson::*;
+
+fn interpreter(program: &Program, engine: Engine) -> Interpreter<Program> {
+    Interpreter::with_config(
+        program.clone(),
+        &InterpreterConfig {
+            engine,
+            ..Default::default()
+        },
+    )
+    .unwrap()
+}
+
+fn run(interpreter: &mut Interpreter<Program>, name: &str, params: &[WasmValue]) -> Vec<WasmValue> {
+    let mut executor = interpreter.call(name, params).unwrap();
+    loop {
+        let response = match executor.next_unit().unwrap() {
+            ExecutionUnit::CallImport(x) if x.name == "input_byte" => {
+                ExecutionResponse::AddValues(vec![WasmValue::I32(0)])
+            }
+            ExecutionUnit::CallImport(_) => ExecutionResponse::DoNothing,
+            ExecutionUnit::Complete(v) => break v,
+            mut x => x.evaluate().unwrap(),
+        };
+        executor.execute(response).unwrap();
+    }
+}
+
+fn fib() -> Program {
+    let mut p = Program::new();
+    let (fib, _) = p
+        .create_export("main", &[ValueType::I32], &[ValueType::I32])
+        .unwrap();
+    fib.instructions = vec![
+        Instruction::LocalGet(0),
+        Instruction::I32Const(2),
+        Instruction::I32LtS,
+        Instruction::If(
+            ValueType::I32.into_wasm_byte(),
+            vec![Instruction::LocalGet(0)],
+            Some(vec![
+                Instruction::LocalGet(0),
+                Instruction::I32Const(1),
+                Instruction::I32Sub,
+                Instruction::Call(0),
+                Instruction::LocalGet(0),
+                Instruction::I32Const(2),
+                Instruction::I32Sub,
+                Instruction::Call(0),
+                Instruction::I32Add,
+            ]),
+        ),
+    ];
+    p
+}
+
+// counts primes below n with a sieve kept in memory
+fn sieve() -> Program {
+    let mut p = Program::new();
+    p.create_memory("memory", 1, None).unwrap();
+    let mut f = FunctionBuilder::new(&p, &[ValueType::I32], &[ValueType::I32]);
+    f.name_param(0, "n");
+    f.local("i", ValueType::I32);
+    f.local("j", ValueType::I32);
+    f.local("count", ValueType::I32);
+    f.emit(Instruction::I32Const(2)).local_set("i");
+    let done = f.begin_block(None);
+    let outer = f.begin_loop(None);
+    f.local_get("i")
+        .local_get("n")
+        .emit(Instruction::I32GeS)
+        .br_if(done);
+    f.local_get("i").emit(Instruction::I32Load8U(0, 0));
+    f.emit(Instruction::I32Eqz);
+    f.begin_if(None);
+    f.local_get("count")
+        .emit(Instruction::I32Const(1))
+        .emit(Instruction::I32Add)
+        .local_set("count");
+    f.local_get("i")
+        .local_get("i")
+        .emit(Instruction::I32Mul)
+        .local_set("j");
+    let marked = f.begin_block(None);
+    let mark = f.begin_loop(None);
+    // unsigned so i * i can't wrap below n
+    f.local_get("j")
+        .local_get("n")
+        .emit(Instruction::I32GeU)
+        .br_if(marked);
+    f.local_get("j")
+        .emit(Instruction::I32Const(1))
+        .emit(Instruction::I32Store8(0, 0));
+    f.local_get("j")
+        .local_get("i")
+        .emit(Instruction::I32Add)
+        .local_set("j");
+    f.br(mark).end().end();
+    f.end();
+    f.local_get("i")
+        .emit(Instruction::I32Const(1))
+        .emit(Instruction::I32Add)
+        .local_set("i");
+    f.br(outer).end().end();
+    f.local_get("count");
+    let sieve = f.build(&mut p).unwrap();
+    let (main, _) = p
+        .create_export("main", &[ValueType::I32], &[ValueType::I32])
+        .unwrap();
+    main.instructions = vec![Instruction::LocalGet(0), Instruction::Call(sieve as u32)];
+    p
+}
+
+fn engines() -> Vec<Engine> {
+    #[allow(unused_mut)]
+    let mut engines = vec![Engine::Stack, Engine::Registers];
+    #[cfg(feature = "jit")]
+    engines.push(Engine::Jit);
+    engines
+}
+
+// times only the calls, each on a fresh instance so they all start from the
+// same memory, leaving out lowering and compiling the module
+fn time(name: &str, program: &Program, params: &[WasmValue], iterations: u32) {
+    let mut results = vec![];
+    for engine in engines().iter() {
+        let mut elapsed = Duration::default();
+        let mut value = vec![];
+        for _ in 0..iterations {
+            let mut interpreter = interpreter(program, *engine);
+            let start = Instant::now();
+            value = run(&mut interpreter, "main", params);
+            elapsed += start.elapsed();
+        }
+        let elapsed = elapsed / iterations;
+        println!("{:<12} {:<10?} {:>12?}", name, engine, elapsed);
+        results.push(value);
+    }
+    assert!(results.iter().all(|r| *r == results[0]));
+}
+
+fn main() {
+    let bf = parse(include_bytes!("../examples/bf/helloworld.wasm"))
+        .unwrap()
+        .to_owned();
+    time("bf hello", &bf, &[], 1000);
+    time("fib(25)", &fib(), &[WasmValue::I32(25)], 5);
+    time("sieve(65000)", &sieve(), &[WasmValue::I32(65000)], 20);
+}
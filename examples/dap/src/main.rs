@@ -0,0 +1,491 @@
+mod source;
+
+use serde_json::{json, Value};
+use source::{Lines, Listing};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use watson::wasi::{HostDirectory, Wasi, EXITED};
+use watson::*;
+
+// the source reference the instruction listing is given
+const LISTING: u64 = 1;
+
+// events to send, by name and body
+type Events = Vec<(&'static str, Value)>;
+
+/// A module being debugged.
+struct Session {
+    debugger: Debugger<Program>,
+    wasi: Wasi,
+    name: String,
+    listing: Listing,
+    lines: Option<Lines>,
+    // the breakpoints set for each source, by the key it's set through
+    breakpoints: HashMap<String, Vec<usize>>,
+    stop_on_entry: bool,
+    // how much of standard output and error has been sent on
+    written: (usize, usize),
+}
+
+struct Adapter {
+    seq: u64,
+    session: Option<Session>,
+}
+
+// the next message, or why it isn't one. nothing comes back once the input
+// ends
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<Result<Value, String>>> {
+    let mut headers = 0;
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            // blank lines before the headers are skipped
+            if headers == 0 {
+                continue;
+            }
+            break;
+        }
+        headers += 1;
+        if let Some(l) = header.strip_prefix("Content-Length:") {
+            length = l.trim().parse::<usize>().ok();
+        }
+    }
+    let length = match length {
+        Some(length) => length,
+        None => return Ok(Some(Err("message has no valid Content-Length".into()))),
+    };
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    Ok(Some(
+        serde_json::from_slice(&body).map_err(|e| format!("message isn't JSON: {}", e)),
+    ))
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const DIGITS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut text = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                text.push(DIGITS[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+    text
+}
+
+fn number(value: &Value) -> Option<u64> {
+    match value {
+        Value::Number(n) => n.as_u64(),
+        Value::String(s) => match s.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16).ok(),
+            None => s.parse().ok(),
+        },
+        _ => None,
+    }
+}
+
+fn variable(name: String, value: WasmValue) -> Value {
+    let (text, kind) = match value {
+        WasmValue::I32(v) => (v.to_string(), "i32"),
+        WasmValue::I64(v) => (v.to_string(), "i64"),
+        WasmValue::F32(v) => (v.to_string(), "f32"),
+        WasmValue::F64(v) => (v.to_string(), "f64"),
+    };
+    json!({ "name": name, "value": text, "type": kind, "variablesReference": 0 })
+}
+
+impl Session {
+    fn launch(arguments: &Value) -> Result<Self, Box<dyn Error>> {
+        let path = arguments["program"]
+            .as_str()
+            .ok_or("no program to launch")?;
+        let bytes = fs::read(path)?;
+        let program = parse(&bytes)?.to_owned();
+        let mut wasi = Wasi::new();
+        wasi.arg(path).preopen(".", HostDirectory::new("."));
+        for arg in arguments["args"].as_array().into_iter().flatten() {
+            wasi.arg(arg.as_str().unwrap_or_default());
+        }
+        let mut instance = Instance::new(program.clone(), &Default::default(), wasi.imports())?;
+        let entry = match arguments["entry"].as_str() {
+            Some(entry) => entry,
+            None if instance.function("_start").is_some() => "_start",
+            None => "main",
+        };
+        let execution = instance.interpreter().call(entry, &[])?;
+        let debugger = Debugger::new(execution)?;
+        let name = Path::new(path)
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        Ok(Session {
+            listing: Listing::new(&program, &debugger),
+            lines: Lines::new(&program, debugger.bytes()),
+            debugger,
+            wasi,
+            name,
+            breakpoints: HashMap::new(),
+            stop_on_entry: arguments["stopOnEntry"].as_bool().unwrap_or(false),
+            written: (0, 0),
+        })
+    }
+
+    fn set_breakpoints(&mut self, arguments: &Value) -> Value {
+        let source = &arguments["source"];
+        let path = source["path"].as_str();
+        let key = path.unwrap_or(&self.name).to_string();
+        for id in self.breakpoints.remove(&key).unwrap_or_default() {
+            self.debugger.remove_breakpoint(id);
+        }
+        let listed = number(&source["sourceReference"]) == Some(LISTING) || path.is_none();
+        let mut ids = vec![];
+        let mut breakpoints = vec![];
+        for b in arguments["breakpoints"].as_array().into_iter().flatten() {
+            let line = number(&b["line"]).unwrap_or(0);
+            let id = if listed {
+                let (listing, debugger) = (&self.listing, &mut self.debugger);
+                listing
+                    .instruction(line)
+                    .and_then(|(f, p)| debugger.break_at(f, p).ok())
+            } else {
+                let offsets = match (&self.lines, path) {
+                    (Some(lines), Some(path)) => lines.offsets(Path::new(path), line),
+                    _ => vec![],
+                };
+                let debugger = &mut self.debugger;
+                offsets
+                    .into_iter()
+                    .find_map(|o| debugger.break_at_offset(o).ok())
+            };
+            ids.extend(id);
+            breakpoints.push(json!({ "verified": id.is_some(), "line": line }));
+        }
+        self.breakpoints.insert(key, ids);
+        json!({ "breakpoints": breakpoints })
+    }
+
+    // sets breakpoints by function name or instruction offset, which
+    // replace those set the same way before
+    fn set_other_breakpoints(&mut self, key: &str, arguments: &Value) -> Value {
+        for id in self.breakpoints.remove(key).unwrap_or_default() {
+            self.debugger.remove_breakpoint(id);
+        }
+        let mut ids = vec![];
+        let mut breakpoints = vec![];
+        for b in arguments["breakpoints"].as_array().into_iter().flatten() {
+            let id = match b["name"].as_str() {
+                Some(name) => self.debugger.break_at_name(name, &[0]).ok(),
+                None => {
+                    let offset = number(&b["instructionReference"]).unwrap_or(0) as i64
+                        + b["offset"].as_i64().unwrap_or(0);
+                    self.debugger.break_at_offset(offset as usize).ok()
+                }
+            };
+            ids.extend(id);
+            breakpoints.push(json!({ "verified": id.is_some() }));
+        }
+        self.breakpoints.insert(key.to_string(), ids);
+        json!({ "breakpoints": breakpoints })
+    }
+
+    fn stack_trace(&self) -> Value {
+        let frames = self.debugger.frames().unwrap_or_default();
+        let frames: Vec<Value> = frames
+            .iter()
+            .enumerate()
+            .map(|(i, frame)| {
+                let location = &frame.location;
+                let name = frame
+                    .name
+                    .clone()
+                    .unwrap_or_else(|| format!("func {}", location.function));
+                let mut value = json!({ "id": i, "name": name, "line": 0, "column": 0 });
+                if let Some(offset) = location.offset {
+                    value["instructionPointerReference"] = json!(format!("0x{:x}", offset));
+                }
+                let line = location.offset.and_then(|o| self.lines.as_ref()?.line(o));
+                if let Some(line) = line {
+                    let name = line.path.file_name().map(|n| n.to_string_lossy());
+                    value["source"] = json!({ "name": name, "path": line.path });
+                    value["line"] = json!(line.line);
+                    value["column"] = json!(1);
+                } else if let Some(line) = self.listing.line(location.function, &location.path) {
+                    value["source"] = json!({ "name": self.name, "sourceReference": LISTING });
+                    value["line"] = json!(line);
+                    value["column"] = json!(1);
+                }
+                value
+            })
+            .collect();
+        json!({ "stackFrames": frames, "totalFrames": frames.len() })
+    }
+
+    // variables references are 1 + 3 times the frame plus which of its
+    // locals, operands or the globals
+    fn variables(&self, reference: u64) -> Value {
+        let (frame, kind) = ((reference - 1) / 3, (reference - 1) % 3);
+        let frames = self.debugger.frames().unwrap_or_default();
+        let frame = match frames.get(frame as usize) {
+            Some(f) => f,
+            None => return json!({ "variables": [] }),
+        };
+        let variables: Vec<Value> = match kind {
+            0 => frame
+                .locals
+                .iter()
+                .enumerate()
+                .map(|(i, v)| {
+                    let name = self
+                        .debugger
+                        .local_name(frame.location.function, i)
+                        .map(|n| n.to_string())
+                        .unwrap_or_else(|| format!("local {}", i));
+                    variable(name, *v)
+                })
+                .collect(),
+            1 => frame
+                .operands
+                .iter()
+                .enumerate()
+                .map(|(i, v)| variable(format!("[{}]", i), *v))
+                .collect(),
+            _ => self
+                .debugger
+                .globals()
+                .into_iter()
+                .enumerate()
+                .map(|(i, v)| variable(format!("global {}", i), v))
+                .collect(),
+        };
+        json!({ "variables": variables })
+    }
+
+    fn read_memory(&mut self, arguments: &Value) -> Value {
+        let address = number(&arguments["memoryReference"]).unwrap_or(0) as i64
+            + arguments["offset"].as_i64().unwrap_or(0);
+        let count = number(&arguments["count"]).unwrap_or(0) as usize;
+        let size = self.debugger.execution().memory.lock().len();
+        let start = (address.max(0) as usize).min(size);
+        let end = start.saturating_add(count).min(size);
+        let bytes = self.debugger.read_memory(start..end).unwrap_or_default();
+        json!({
+            "address": format!("0x{:x}", start),
+            "data": base64(&bytes),
+            "unreadableBytes": count - bytes.len(),
+        })
+    }
+
+    // what the program has written since last time, as output events
+    fn output(&mut self) -> Events {
+        let mut events = vec![];
+        let stdout = self.wasi.stdout();
+        let stderr = self.wasi.stderr();
+        for (category, text, written) in [
+            ("stdout", stdout, &mut self.written.0),
+            ("stderr", stderr, &mut self.written.1),
+        ] {
+            if text.len() > *written {
+                let output = String::from_utf8_lossy(&text[*written..]).into_owned();
+                events.push(("output", json!({ "category": category, "output": output })));
+                *written = text.len();
+            }
+        }
+        events
+    }
+
+    // carries on with the call, giving the events for how it stopped
+    fn run(&mut self, command: &str) -> Events {
+        let stop = match command {
+            "next" => self.debugger.step_over(),
+            "stepIn" => self.debugger.step(),
+            "stepOut" => self.debugger.step_out(),
+            _ => self.debugger.resume(),
+        };
+        let mut events = self.output();
+        let stopped = |reason: &str, text: Option<&str>| {
+            let mut body = json!({ "reason": reason, "threadId": 1, "allThreadsStopped": true });
+            if let Some(text) = text {
+                body["text"] = json!(text);
+                body["description"] = json!(text);
+            }
+            ("stopped", body)
+        };
+        let exit_code = match stop {
+            Ok(StopReason::Breakpoint(_)) => {
+                events.push(stopped("breakpoint", None));
+                return events;
+            }
+            Ok(StopReason::Step) => {
+                events.push(stopped("step", None));
+                return events;
+            }
+            Ok(StopReason::Watchpoint { .. }) => {
+                events.push(stopped("data breakpoint", None));
+                return events;
+            }
+            Ok(StopReason::Trap(EXITED)) => self.wasi.exit_code().unwrap_or(0),
+            Ok(StopReason::Unreachable) => {
+                events.push(stopped("exception", Some("unreachable")));
+                return events;
+            }
+            Ok(StopReason::Trap(e)) => {
+                events.push(stopped("exception", Some(e)));
+                return events;
+            }
+            Ok(StopReason::Complete(_)) => 0,
+            Ok(StopReason::CallImport(call)) => {
+                let output = format!(
+                    "called {}.{}, which isn't provided\n",
+                    call.module_name, call.name
+                );
+                events.push(("output", json!({ "category": "stderr", "output": output })));
+                1
+            }
+            Ok(StopReason::OutOfFuel) | Err(_) => 1,
+        };
+        events.push(("exited", json!({ "exitCode": exit_code })));
+        events.push(("terminated", json!({})));
+        events
+    }
+}
+
+impl Adapter {
+    fn send(&mut self, out: &mut impl Write, mut message: Value) -> io::Result<()> {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        let body = message.to_string();
+        write!(out, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+        out.flush()
+    }
+
+    // the body of the response to a request, and the events that follow it
+    fn handle(
+        &mut self,
+        command: &str,
+        arguments: &Value,
+    ) -> Result<(Value, Events), Box<dyn Error>> {
+        if command == "initialize" {
+            let capabilities = json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsFunctionBreakpoints": true,
+                "supportsInstructionBreakpoints": true,
+                "supportsReadMemoryRequest": true,
+            });
+            return Ok((capabilities, vec![]));
+        }
+        if command == "launch" {
+            self.session = Some(Session::launch(arguments)?);
+            return Ok((json!({}), vec![("initialized", json!({}))]));
+        }
+        if command == "disconnect" {
+            return Ok((json!({}), vec![]));
+        }
+        let session = self.session.as_mut().ok_or("nothing has been launched")?;
+        Ok(match command {
+            "setBreakpoints" => (session.set_breakpoints(arguments), vec![]),
+            "setFunctionBreakpoints" => (
+                session.set_other_breakpoints("functions", arguments),
+                vec![],
+            ),
+            "setInstructionBreakpoints" => (
+                session.set_other_breakpoints("instructions", arguments),
+                vec![],
+            ),
+            "configurationDone" if session.stop_on_entry => {
+                let stopped =
+                    json!({ "reason": "entry", "threadId": 1, "allThreadsStopped": true });
+                (json!({}), vec![("stopped", stopped)])
+            }
+            "configurationDone" => (json!({}), session.run("continue")),
+            "threads" => (json!({ "threads": [{ "id": 1, "name": "main" }] }), vec![]),
+            "stackTrace" => (session.stack_trace(), vec![]),
+            "scopes" => {
+                let frame = number(&arguments["frameId"]).unwrap_or(0);
+                let scopes = json!({ "scopes": [
+                    { "name": "Locals", "variablesReference": frame * 3 + 1, "expensive": false },
+                    { "name": "Operand stack", "variablesReference": frame * 3 + 2, "expensive": false },
+                    { "name": "Globals", "variablesReference": frame * 3 + 3, "expensive": false },
+                ]});
+                (scopes, vec![])
+            }
+            "variables" => {
+                let reference = number(&arguments["variablesReference"]).unwrap_or(0).max(1);
+                (session.variables(reference), vec![])
+            }
+            "source" => (json!({ "content": session.listing.text }), vec![]),
+            "readMemory" => (session.read_memory(arguments), vec![]),
+            "continue" => (json!({ "allThreadsContinued": true }), session.run(command)),
+            "next" | "stepIn" | "stepOut" => (json!({}), session.run(command)),
+            _ => return Err(format!("{} isn't supported", command).into()),
+        })
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut adapter = Adapter {
+        seq: 0,
+        session: None,
+    };
+    while let Some(message) = read_message(&mut input)? {
+        let request = match message {
+            Ok(request) => request,
+            Err(e) => {
+                let response = json!({
+                    "type": "response",
+                    "request_seq": 0,
+                    "command": "",
+                    "success": false,
+                    "message": e,
+                });
+                adapter.send(&mut out, response)?;
+                continue;
+            }
+        };
+        let command = request["command"].as_str().unwrap_or_default().to_string();
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": command,
+        });
+        let events = match adapter.handle(&command, &request["arguments"]) {
+            Ok((body, events)) => {
+                response["success"] = json!(true);
+                response["body"] = body;
+                events
+            }
+            Err(e) => {
+                response["success"] = json!(false);
+                response["message"] = json!(e.to_string());
+                vec![]
+            }
+        };
+        adapter.send(&mut out, response)?;
+        for (event, body) in events {
+            let event = json!({ "type": "event", "event": event, "body": body });
+            adapter.send(&mut out, event)?;
+        }
+        if command == "disconnect" {
+            break;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,256 @@
+use gimli::{EndianSlice, LittleEndian};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use watson::*;
+
+/// A listing of every function's instructions, one to a line and named from
+/// the name section, which stands in for the source of modules without
+/// DWARF.
+pub struct Listing {
+    pub text: String,
+    // the instruction on each line, as a function and a path into its body
+    lines: Vec<Option<(usize, Vec<usize>)>>,
+}
+
+impl Listing {
+    pub fn new(program: &Program, debugger: &Debugger<Program>) -> Self {
+        let mut listing = Listing {
+            text: String::new(),
+            lines: vec![],
+        };
+        let imports = program
+            .sections
+            .iter()
+            .map(|s| match s {
+                Section::Import(s) => s
+                    .imports
+                    .iter()
+                    .filter(|i| matches!(i, WasmImport::Function(_)))
+                    .count(),
+                _ => 0,
+            })
+            .sum::<usize>();
+        for s in program.sections.iter() {
+            if let Section::Code(s) = s {
+                for (i, c) in s.code_blocks.iter().enumerate() {
+                    let function = imports + i;
+                    let header = match debugger.function_name(function) {
+                        Some(name) => format!("func ${} ;; {}", name, function),
+                        None => format!("func {}", function),
+                    };
+                    listing.push(0, &header, None);
+                    listing.instructions(function, &[], &[&c.instructions], 1);
+                    let end = vec![c.instructions.len()];
+                    listing.push(0, "end", Some((function, end)));
+                }
+            }
+        }
+        listing
+    }
+
+    fn push(&mut self, depth: usize, text: &str, at: Option<(usize, Vec<usize>)>) {
+        self.text.push_str(&"  ".repeat(depth));
+        self.text.push_str(text);
+        self.text.push('\n');
+        self.lines.push(at);
+    }
+
+    // lists bodies that follow on from each other, as an if's then and else
+    // do
+    fn instructions(
+        &mut self,
+        function: usize,
+        prefix: &[usize],
+        bodies: &[&[Instruction]],
+        depth: usize,
+    ) {
+        let mut index = 0;
+        for (i, body) in bodies.iter().enumerate() {
+            if i > 0 {
+                self.push(depth - 1, "else", None);
+            }
+            for instruction in body.iter() {
+                let mut path = prefix.to_vec();
+                path.push(index);
+                let at = Some((function, path.clone()));
+                match instruction {
+                    Instruction::Block(_, b) => {
+                        self.push(depth, "block", at);
+                        self.instructions(function, &path, &[b], depth + 1);
+                        self.push(depth, "end", None);
+                    }
+                    Instruction::Loop(_, b) => {
+                        self.push(depth, "loop", at);
+                        self.instructions(function, &path, &[b], depth + 1);
+                        self.push(depth, "end", None);
+                    }
+                    Instruction::If(_, then_body, else_body) => {
+                        self.push(depth, "if", at);
+                        let mut nested: Vec<&[Instruction]> = vec![then_body];
+                        if let Some(e) = else_body {
+                            nested.push(e);
+                        }
+                        self.instructions(function, &path, &nested, depth + 1);
+                        self.push(depth, "end", None);
+                    }
+                    i => self.push(depth, &format!("{:?}", i), at),
+                }
+                index += 1;
+            }
+        }
+    }
+
+    /// The line an instruction is listed on, counting from 1.
+    pub fn line(&self, function: usize, path: &[usize]) -> Option<u64> {
+        self.lines
+            .iter()
+            .position(|l| matches!(l, Some((f, p)) if *f == function && p == path))
+            .map(|i| i as u64 + 1)
+    }
+
+    /// The instruction listed on a line, or the next one listed after it.
+    pub fn instruction(&self, line: u64) -> Option<(usize, &[usize])> {
+        self.lines
+            .iter()
+            .skip(line.checked_sub(1)? as usize)
+            .find_map(|l| l.as_ref())
+            .map(|(f, p)| (*f, p.as_slice()))
+    }
+}
+
+/// A source file and line, counting from 1.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Line {
+    pub path: PathBuf,
+    pub line: u64,
+}
+
+/// The lines of source that instructions start, from the DWARF line tables
+/// in a module's custom sections.
+pub struct Lines {
+    // where each line starts, and where a sequence of them ends
+    rows: Vec<(usize, Option<Line>)>,
+}
+
+// where the code section's contents start in a module, which is what DWARF
+// addresses count from
+fn code_start(module: &[u8]) -> Option<usize> {
+    fn leb(bytes: &[u8], at: &mut usize) -> Option<usize> {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let b = *bytes.get(*at)?;
+            *at += 1;
+            value |= ((b & 0x7f) as usize) << shift;
+            if b & 0x80 == 0 {
+                return Some(value);
+            }
+            shift += 7;
+        }
+    }
+    let mut at = 8;
+    while at < module.len() {
+        let id = module[at];
+        at += 1;
+        let length = leb(module, &mut at)?;
+        if id == 10 {
+            return Some(at);
+        }
+        at += length;
+    }
+    None
+}
+
+impl Lines {
+    /// Reads the line tables, if the module has any.
+    pub fn new(program: &Program, module: &[u8]) -> Option<Self> {
+        let sections: HashMap<&str, &[u8]> = program
+            .sections
+            .iter()
+            .filter_map(|s| match s {
+                Section::Custom(c) => Some((c.name.as_str(), c.data.as_slice())),
+                _ => None,
+            })
+            .collect();
+        if !sections.contains_key(".debug_line") {
+            return None;
+        }
+        let start = code_start(module)?;
+        let dwarf = gimli::Dwarf::load(|id| -> Result<_, gimli::Error> {
+            let data = sections.get(id.name()).copied().unwrap_or(&[]);
+            Ok(EndianSlice::new(data, LittleEndian))
+        })
+        .ok()?;
+        let mut rows = vec![];
+        let mut units = dwarf.units();
+        while let Ok(Some(header)) = units.next() {
+            let unit = match dwarf.unit(header) {
+                Ok(u) => u,
+                Err(_) => continue,
+            };
+            let program = match unit.line_program.clone() {
+                Some(p) => p,
+                None => continue,
+            };
+            let mut program_rows = program.rows();
+            let mut sequence = vec![];
+            let mut kept = None;
+            while let Ok(Some((header, row))) = program_rows.next_row() {
+                // the linker leaves code it dropped at 0 or the top of the
+                // address space, over the top of code it kept
+                let address = row.address();
+                let live = *kept.get_or_insert(address != 0 && address < 0xffff_fffe);
+                if row.end_sequence() {
+                    if live {
+                        rows.append(&mut sequence);
+                        rows.push((start + address as usize, None));
+                    }
+                    sequence.clear();
+                    kept = None;
+                    continue;
+                }
+                if !live || !row.is_stmt() {
+                    continue;
+                }
+                let (line, file) = match (row.line(), row.file(header)) {
+                    (Some(l), Some(f)) => (l.get(), f),
+                    _ => continue,
+                };
+                let string = |a| {
+                    dwarf
+                        .attr_string(&unit, a)
+                        .map(|s| s.to_string_lossy().into_owned())
+                        .unwrap_or_default()
+                };
+                let mut path = PathBuf::new();
+                if let Some(dir) = &unit.comp_dir {
+                    path.push(dir.to_string_lossy().as_ref());
+                }
+                if let Some(dir) = file.directory(header) {
+                    path.push(string(dir));
+                }
+                path.push(string(file.path_name()));
+                sequence.push((start + address as usize, Some(Line { path, line })));
+            }
+        }
+        rows.sort_by_key(|r| r.0);
+        Some(Lines { rows })
+    }
+
+    /// The line the instruction at `offset` in the module is from.
+    pub fn line(&self, offset: usize) -> Option<&Line> {
+        let i = self.rows.partition_point(|r| r.0 <= offset);
+        self.rows[..i].last()?.1.as_ref()
+    }
+
+    /// Where the instructions for a line start, first to last, taking paths
+    /// that end the same way to be the same.
+    pub fn offsets(&self, path: &Path, line: u64) -> Vec<usize> {
+        self.rows
+            .iter()
+            .filter_map(|(o, l)| Some((o, l.as_ref()?)))
+            .filter(|(_, l)| l.line == line && (l.path.ends_with(path) || path.ends_with(&l.path)))
+            .map(|(o, _)| *o)
+            .collect()
+    }
+}
@@ -110,7 +110,7 @@ function showCode() {
                 <b>${f.name?"function "+(i+imports.length)+" \""+f.name+"\"":"function "+(i+imports.length)}:</b>
                 <div class="instructions">
                    ${f.instructions.map((x,j)=>{
-                       return html`<div class="instruction ${(interpreter_state.current_position[1] == i && interpreter_state.current_position[2] == j)?"selected":null}">${x.op} ${x.params != undefined?x.params.toString():""}</div>`
+                       return html`<div class="instruction ${isCurrent(i+imports.length,j)?"selected":null}">${x.op} ${x.params != undefined?x.params.toString():""}</div>`
                    })}
                 </div>
             </div>
@@ -119,6 +119,12 @@ function showCode() {
       );
 }
 
+// whether the innermost call is at top level instruction j of a function
+function isCurrent(f,j){
+    let frame = interpreter_state.call_stack[interpreter_state.call_stack.length-1];
+    return frame && frame.function == f && frame.path[0] == j;
+}
+
 function valueType(x){
     if(x.I32){
         return html`${x.I32.toString()}<span class="valuetype">i32</span> `
@@ -138,7 +144,7 @@ function showCurrentState() {
     let section = document.querySelector(".interpreter_details");
     render(
         html`
-            <div><b>Call Stack:&nbsp;</b> ${interpreter_state.current_position.join(" -> ")} </div>
+            <div><b>Call Stack:&nbsp;</b> ${interpreter_state.call_stack.map(x=>x.function+"@"+x.path.join(".")).join(" -> ")} </div>
             <div><b>Arguments:&nbsp;&nbsp;</b> None </div>
             <div><b>Value Stack:</b> ${interpreter_state.value_stack.map(x=>valueType(x))} </div>
         `,
@@ -32,10 +32,10 @@ fn load(ptr: *mut u8, len: usize) {
             s.program_string = serde_json::to_string(&prog).unwrap();
             s.program_string.push_str("\0");
             let mut interpreter = Interpreter::new(prog).unwrap();
-            match interpreter.call("main", &[]) {
-                Ok(executor) => {
+            match interpreter.call("main", &[]).and_then(Debugger::new) {
+                Ok(debugger) => {
                     log("called main function");
-                    s.execution = Some(executor);
+                    s.debugger = Some(debugger);
                 }
                 Err(_) => {
                     log("could not call main function");
@@ -49,7 +49,7 @@ fn load(ptr: *mut u8, len: usize) {
 }
 
 struct Simulator {
-    execution: Option<WasmExecution<Program>>,
+    debugger: Option<Debugger<Program>>,
     program_string: String,
     interpreter_string: String,
 }
@@ -57,7 +57,7 @@ struct Simulator {
 impl Default for Simulator {
     fn default() -> Self {
         Simulator {
-            execution: None,
+            debugger: None,
             program_string: "".to_string(),
             interpreter_string: "".to_string(),
         }
@@ -70,10 +70,30 @@ fn get_program() -> *const u8 {
     return s.program_string.as_ptr();
 }
 
+// the call stack, outermost call first, as the function and path to the
+// instruction each call is at, and the values on the stack
 #[no_mangle]
 fn get_interpreter() -> *const u8 {
     let mut s = globals::get::<Simulator>();
-    s.interpreter_string = serde_json::to_string(&s.execution).unwrap();
+    let mut frames = match s.debugger.as_ref().map(|d| d.frames()) {
+        Some(Ok(frames)) => frames,
+        _ => vec![],
+    };
+    frames.reverse();
+    let state = serde_json::json!({
+        "call_stack": frames
+            .iter()
+            .map(|f| serde_json::json!({
+                "function": f.location.function,
+                "path": f.location.path,
+            }))
+            .collect::<Vec<_>>(),
+        "value_stack": frames
+            .iter()
+            .flat_map(|f| f.operands.iter())
+            .collect::<Vec<_>>(),
+    });
+    s.interpreter_string = state.to_string();
     s.interpreter_string.push_str("\0");
     return s.interpreter_string.as_ptr();
 }
@@ -81,39 +101,19 @@ fn get_interpreter() -> *const u8 {
 #[no_mangle]
 fn next_instruction() {
     let mut s = globals::get::<Simulator>();
-    let execution_unit = match s.execution.as_mut().unwrap().next_unit() {
-        Ok(r) => r,
-        Err(e) => {
-            log(e);
-            return;
-        }
+    let debugger = match s.debugger.as_mut() {
+        Some(d) => d,
+        None => return,
     };
-    let response = match execution_unit {
+    match debugger.step() {
+        Ok(StopReason::Step) | Ok(StopReason::Breakpoint(_)) => {}
         // if an import is called, figure out what to do
-        ExecutionUnit::CallImport(x) => {
+        Ok(StopReason::CallImport(x)) => {
             let s = format!("import was called: {}", &x.name);
             log(&s);
-            ExecutionResponse::DoNothing
-        }
-        // if there's nothing left to do, break out of loop
-        ExecutionUnit::Complete(_) => {
-            log("PROGRAM COMPLETE!");
-            return;
-        }
-        // handle other execution units with default behavior
-        mut x @ _ => match x.evaluate() {
-            Ok(r) => r,
-            Err(e) => {
-                log(e);
-                panic!("");
-            }
-        },
-    };
-    match s.execution.as_mut().unwrap().execute(response) {
-        Ok(r) => r,
-        Err(e) => {
-            log(e);
-            return;
         }
+        Ok(StopReason::Complete(_)) => log("PROGRAM COMPLETE!"),
+        Ok(StopReason::Trap(e)) | Err(e) => log(e),
+        Ok(_) => log("program stopped"),
     }
 }
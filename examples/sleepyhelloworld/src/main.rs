@@ -6,46 +6,31 @@ use watson::*;
 
 async fn run(program: impl InterpretableProgram) -> Result<Vec<WasmValue>, &'static str> {
     let mut interpreter = Interpreter::new(program)?;
+    let memory = interpreter.memory.clone();
     let mut executor = interpreter.call("main", &[])?;
-    loop {
-        let execution_unit = executor.next_unit()?;
-        let response = match execution_unit {
-            // if an import is called, figure out what to do
-            ExecutionUnit::CallImport(x) => {
+    // everything but calls to imports runs inside, so only those need handling
+    executor
+        .run_async(|x| {
+            let memory = memory.clone();
+            async move {
                 if x.name == "print" {
                     let start = x.params[0].to_i32() as usize;
-                    let mem = match executor.memory() {
-                        Some(m) => m,
-                        None => return Err("there should be memory"),
-                    };
-                    let mem = mem.borrow();
-                    let mut chars = vec![];
-                    let mut i = 0;
-                    loop {
-                        if mem[start + i] == 0 {
-                            break;
-                        }
-                        chars.push(mem[start + i]);
-                        i += 1;
-                    }
-                    let text = from_utf8(&chars).unwrap();
-                    println!("{}", text);
-                    ExecutionResponse::DoNothing
+                    let mem = memory.lock();
+                    let text = mem.get(start..).ok_or("string out of bounds")?;
+                    let end = text.iter().position(|c| *c == 0);
+                    let text = &text[..end.ok_or("string is not terminated")?];
+                    println!("{}", from_utf8(text).map_err(|_| "string is not utf-8")?);
+                    Ok(vec![])
                 } else if x.name == "sleep" {
                     let millis = x.params[0].to_i32();
                     task::sleep(Duration::from_millis(millis as u64)).await;
-                    ExecutionResponse::DoNothing
+                    Ok(vec![])
                 } else {
-                    panic!("unknown import call")
+                    Err("unknown import call")
                 }
             }
-            // if there's nothing left to do, break out of loop
-            ExecutionUnit::Complete(v) => break Ok(v),
-            // handle other execution units with default behavior
-            mut x @ _ => x.evaluate()?,
-        };
-        executor.execute(response)?;
-    }
+        })
+        .await
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
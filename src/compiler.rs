@@ -1,269 +1,366 @@
 use crate::core::*;
+use alloc::collections::BTreeMap;
 use alloc::vec::Vec;
 use webassembly::*;
 
 impl WasmCompiler for Program {
     fn compile(&mut self) -> Vec<u8> {
-        self.sections.sort_by(|a, b| a.id().cmp(&b.id()));
-        let mut program_bytes = vec![];
-        program_bytes.extend(MAGIC_NUMBER);
-        program_bytes.extend(VERSION_1);
-        for s in self.sections.iter() {
-            match s {
-                Section::Type(s) => {
-                    let mut sec_data = vec![];
-                    sec_data.extend(s.types.len().to_wasm_bytes());
-                    for t in s.types.iter() {
-                        sec_data.push(FUNC);
-                        sec_data.extend(t.inputs.len().to_wasm_bytes());
-                        for i in t.inputs.iter() {
-                            sec_data.push(i.into_wasm_byte());
-                        }
-                        sec_data.extend(t.outputs.len().to_wasm_bytes());
-                        for i in t.outputs.iter() {
-                            sec_data.push(i.into_wasm_byte());
-                        }
+        compile_sections(self, &BTreeMap::new())
+    }
+}
+
+impl Program {
+    /// Compiles the program, writing every section that's unchanged since
+    /// `original` was parsed back exactly as it was written, so an unmodified
+    /// module compiles to exactly the bytes it was parsed from.
+    pub fn compile_as_parsed(&mut self, original: &ProgramView) -> Vec<u8> {
+        // the original encodings, keyed by how each section compiles now
+        let mut raw: BTreeMap<Vec<u8>, Vec<&[u8]>> = BTreeMap::new();
+        for (s, bytes) in original.sections.iter().zip(original.raw_sections()) {
+            let mut canonical = vec![];
+            s.to_owned().extend_wasm_bytes(&mut canonical);
+            raw.entry(canonical).or_default().push(bytes);
+        }
+        compile_sections(self, &raw)
+    }
+}
+
+fn compile_sections(program: &mut Program, raw: &BTreeMap<Vec<u8>, Vec<&[u8]>>) -> Vec<u8> {
+    // custom sections have no fixed place in a module, so keep each one
+    // anchored behind the section that preceded it
+    let mut anchor = 0;
+    let mut keyed: Vec<(u32, Section)> = program
+        .sections
+        .drain(..)
+        .map(|s| {
+            if s.id() != SECTION_CUSTOM as u32 {
+                anchor = section_order(&s);
+            }
+            (anchor, s)
+        })
+        .collect();
+    keyed.sort_by_key(|x| x.0);
+    program.sections = keyed.into_iter().map(|x| x.1).collect();
+
+    // how many of each canonical encoding have been written so far
+    let mut written: BTreeMap<Vec<u8>, usize> = BTreeMap::new();
+    let mut program_bytes = vec![];
+    program_bytes.extend(MAGIC_NUMBER);
+    program_bytes.extend(VERSION_1);
+    for s in program.sections.iter() {
+        let mut canonical = vec![];
+        s.extend_wasm_bytes(&mut canonical);
+        let nth = written.entry(canonical.clone()).or_insert(0);
+        match raw.get(&canonical).and_then(|r| r.get(*nth)) {
+            Some(raw) => program_bytes.extend(*raw),
+            None => program_bytes.extend(canonical),
+        }
+        *nth += 1;
+    }
+    program_bytes
+}
+
+// the data count section is numbered after data but must come before code
+fn section_order(s: &Section) -> u32 {
+    match s {
+        Section::DataCount(_) => SECTION_ELEMENT as u32 * 2 + 1,
+        s => s.id() * 2,
+    }
+}
+
+fn extend_memory_limit(
+    v: &mut Vec<u8>,
+    min: usize,
+    max: Option<usize>,
+    shared: bool,
+    memory64: bool,
+) {
+    let mut flags = if shared { LIMIT_SHARED } else { 0 };
+    if memory64 {
+        flags |= LIMIT_MEMORY64;
+    }
+    match max {
+        Some(max) => {
+            v.push(LIMIT_MIN_MAX | flags);
+            v.extend(min.to_wasm_bytes());
+            v.extend(max.to_wasm_bytes());
+        }
+        None => {
+            v.push(LIMIT_MIN | flags);
+            v.extend(min.to_wasm_bytes());
+        }
+    }
+}
+
+impl WriteWasm for Section {
+    fn extend_wasm_bytes(&self, v: &mut Vec<u8>) {
+        match self {
+            Section::Type(s) => {
+                let mut sec_data = vec![];
+                sec_data.extend(s.types.len().to_wasm_bytes());
+                for t in s.types.iter() {
+                    sec_data.push(FUNC);
+                    sec_data.extend(t.inputs.len().to_wasm_bytes());
+                    for i in t.inputs.iter() {
+                        sec_data.push(i.into_wasm_byte());
+                    }
+                    sec_data.extend(t.outputs.len().to_wasm_bytes());
+                    for i in t.outputs.iter() {
+                        sec_data.push(i.into_wasm_byte());
                     }
-                    program_bytes.push(SECTION_TYPE);
-                    program_bytes.extend(sec_data.len().to_wasm_bytes());
-                    program_bytes.extend(sec_data);
                 }
-                Section::Function(s) => {
-                    let mut sec_data = vec![];
-                    sec_data.extend(s.function_types.len().to_wasm_bytes());
-                    for f in s.function_types.iter() {
-                        sec_data.extend(f.to_wasm_bytes());
+                v.push(SECTION_TYPE);
+                v.extend(sec_data.len().to_wasm_bytes());
+                v.extend(sec_data);
+            }
+            Section::Function(s) => {
+                let mut sec_data = vec![];
+                sec_data.extend(s.function_types.len().to_wasm_bytes());
+                for f in s.function_types.iter() {
+                    sec_data.extend(f.to_wasm_bytes());
+                }
+                v.push(SECTION_FUNCTION);
+                v.extend(sec_data.len().to_wasm_bytes());
+                v.extend(sec_data);
+            }
+            Section::Code(s) => {
+                let mut sec_data = vec![];
+                sec_data.extend(s.code_blocks.len().to_wasm_bytes());
+                for c in s.code_blocks.iter() {
+                    let mut code = vec![];
+                    code.extend(c.locals.len().to_wasm_bytes());
+                    for l in c.locals.iter() {
+                        code.extend(l.count.to_wasm_bytes());
+                        code.push(l.value_type.into_wasm_byte());
+                    }
+                    for i in c.instructions.iter() {
+                        i.extend_wasm_bytes(&mut code);
                     }
-                    program_bytes.push(SECTION_FUNCTION);
-                    program_bytes.extend(sec_data.len().to_wasm_bytes());
-                    program_bytes.extend(sec_data);
+                    code.push(END);
+                    sec_data.extend(code.len().to_wasm_bytes());
+                    sec_data.extend(&code);
                 }
-                Section::Code(s) => {
-                    let mut sec_data = vec![];
-                    sec_data.extend(s.code_blocks.len().to_wasm_bytes());
-                    for c in s.code_blocks.iter() {
-                        let mut code = vec![];
-                        code.extend(c.locals.len().to_wasm_bytes());
-                        for l in c.locals.iter() {
-                            code.extend(l.count.to_wasm_bytes());
-                            code.push(l.value_type.into_wasm_byte());
+                v.push(SECTION_CODE);
+                v.extend(sec_data.len().to_wasm_bytes());
+                v.extend(sec_data);
+            }
+            Section::Export(s) => {
+                let mut sec_data = vec![];
+                sec_data.extend(s.exports.len().to_wasm_bytes());
+                for i in s.exports.iter() {
+                    match i {
+                        WasmExport::Function(f) => {
+                            sec_data.extend(f.name.len().to_wasm_bytes());
+                            sec_data.extend(f.name.as_bytes());
+                            sec_data.push(DESC_FUNCTION);
+                            sec_data.extend(f.index.to_wasm_bytes());
                         }
-                        for i in c.instructions.iter() {
-                            i.extend_wasm_bytes(&mut code);
+                        WasmExport::Global(g) => {
+                            sec_data.extend(g.name.len().to_wasm_bytes());
+                            sec_data.extend(g.name.as_bytes());
+                            sec_data.push(DESC_GLOBAL);
+                            sec_data.extend(g.index.to_wasm_bytes());
                         }
-                        code.push(END);
-                        sec_data.extend(code.len().to_wasm_bytes());
-                        sec_data.extend(&code);
-                    }
-                    program_bytes.push(SECTION_CODE);
-                    program_bytes.extend(sec_data.len().to_wasm_bytes());
-                    program_bytes.extend(sec_data);
-                }
-                Section::Export(s) => {
-                    let mut sec_data = vec![];
-                    sec_data.extend(s.exports.len().to_wasm_bytes());
-                    for i in s.exports.iter() {
-                        match i {
-                            WasmExport::Function(f) => {
-                                sec_data.extend(f.name.len().to_wasm_bytes());
-                                sec_data.extend(f.name.as_bytes());
-                                sec_data.push(DESC_FUNCTION);
-                                sec_data.extend(f.index.to_wasm_bytes());
-                            }
-                            WasmExport::Global(g) => {
-                                sec_data.extend(g.name.len().to_wasm_bytes());
-                                sec_data.extend(g.name.as_bytes());
-                                sec_data.push(DESC_GLOBAL);
-                                sec_data.extend(g.index.to_wasm_bytes());
-                            }
-                            WasmExport::Table(t) => {
-                                sec_data.extend(t.name.len().to_wasm_bytes());
-                                sec_data.extend(t.name.as_bytes());
-                                sec_data.push(DESC_TABLE);
-                                sec_data.extend(t.index.to_wasm_bytes());
-                            }
-                            WasmExport::Memory(m) => {
-                                sec_data.extend(m.name.len().to_wasm_bytes());
-                                sec_data.extend(m.name.as_bytes());
-                                sec_data.push(DESC_MEMORY);
-                                sec_data.extend(m.index.to_wasm_bytes());
-                            }
+                        WasmExport::Table(t) => {
+                            sec_data.extend(t.name.len().to_wasm_bytes());
+                            sec_data.extend(t.name.as_bytes());
+                            sec_data.push(DESC_TABLE);
+                            sec_data.extend(t.index.to_wasm_bytes());
+                        }
+                        WasmExport::Memory(m) => {
+                            sec_data.extend(m.name.len().to_wasm_bytes());
+                            sec_data.extend(m.name.as_bytes());
+                            sec_data.push(DESC_MEMORY);
+                            sec_data.extend(m.index.to_wasm_bytes());
                         }
                     }
-                    program_bytes.push(SECTION_EXPORT);
-                    program_bytes.extend(sec_data.len().to_wasm_bytes());
-                    program_bytes.extend(sec_data);
                 }
-                Section::Import(s) => {
-                    let mut sec_data = vec![];
-                    sec_data.extend(s.imports.len().to_wasm_bytes());
-                    for i in s.imports.iter() {
-                        match i {
-                            WasmImport::Function(f) => {
-                                sec_data.extend(f.module_name.len().to_wasm_bytes());
-                                sec_data.extend(f.module_name.as_bytes());
-                                sec_data.extend(f.name.len().to_wasm_bytes());
-                                sec_data.extend(f.name.as_bytes());
-                                sec_data.push(DESC_FUNCTION);
-                                sec_data.extend(f.type_index.to_wasm_bytes());
-                            }
-                            WasmImport::Global(g) => {
-                                sec_data.extend(g.module_name.len().to_wasm_bytes());
-                                sec_data.extend(g.module_name.as_bytes());
-                                sec_data.extend(g.name.len().to_wasm_bytes());
-                                sec_data.extend(g.name.as_bytes());
-                                sec_data.push(DESC_GLOBAL);
-                                sec_data.push(g.value_type.into_wasm_byte());
-                                if g.is_mutable {
-                                    sec_data.push(MUTABLE);
-                                } else {
-                                    sec_data.push(IMMUTABLE);
-                                }
-                            }
-                            WasmImport::Table(t) => {
-                                sec_data.extend(t.module_name.len().to_wasm_bytes());
-                                sec_data.extend(t.module_name.as_bytes());
-                                sec_data.extend(t.name.len().to_wasm_bytes());
-                                sec_data.extend(t.name.as_bytes());
-                                sec_data.push(DESC_TABLE);
-                                sec_data.push(t.element_type);
-                                if t.max.is_some() {
-                                    sec_data.push(LIMIT_MIN_MAX);
-                                    sec_data.extend(t.min.to_wasm_bytes());
-                                    sec_data.extend(t.max.unwrap().to_wasm_bytes());
-                                } else {
-                                    sec_data.push(LIMIT_MIN);
-                                    sec_data.extend(t.min.to_wasm_bytes());
-                                }
+                v.push(SECTION_EXPORT);
+                v.extend(sec_data.len().to_wasm_bytes());
+                v.extend(sec_data);
+            }
+            Section::Import(s) => {
+                let mut sec_data = vec![];
+                sec_data.extend(s.imports.len().to_wasm_bytes());
+                for i in s.imports.iter() {
+                    match i {
+                        WasmImport::Function(f) => {
+                            sec_data.extend(f.module_name.len().to_wasm_bytes());
+                            sec_data.extend(f.module_name.as_bytes());
+                            sec_data.extend(f.name.len().to_wasm_bytes());
+                            sec_data.extend(f.name.as_bytes());
+                            sec_data.push(DESC_FUNCTION);
+                            sec_data.extend(f.type_index.to_wasm_bytes());
+                        }
+                        WasmImport::Global(g) => {
+                            sec_data.extend(g.module_name.len().to_wasm_bytes());
+                            sec_data.extend(g.module_name.as_bytes());
+                            sec_data.extend(g.name.len().to_wasm_bytes());
+                            sec_data.extend(g.name.as_bytes());
+                            sec_data.push(DESC_GLOBAL);
+                            sec_data.push(g.value_type.into_wasm_byte());
+                            if g.is_mutable {
+                                sec_data.push(MUTABLE);
+                            } else {
+                                sec_data.push(IMMUTABLE);
                             }
-                            WasmImport::Memory(m) => {
-                                sec_data.extend(m.module_name.len().to_wasm_bytes());
-                                sec_data.extend(m.module_name.as_bytes());
-                                sec_data.extend(m.name.len().to_wasm_bytes());
-                                sec_data.extend(m.name.as_bytes());
-                                sec_data.push(DESC_MEMORY);
-                                if m.max_pages.is_some() {
-                                    sec_data.push(LIMIT_MIN_MAX);
-                                    sec_data.extend(m.min_pages.to_wasm_bytes());
-                                    sec_data.extend(m.max_pages.unwrap().to_wasm_bytes());
-                                } else {
-                                    sec_data.push(LIMIT_MIN);
-                                    sec_data.extend(m.min_pages.to_wasm_bytes());
-                                }
+                        }
+                        WasmImport::Table(t) => {
+                            sec_data.extend(t.module_name.len().to_wasm_bytes());
+                            sec_data.extend(t.module_name.as_bytes());
+                            sec_data.extend(t.name.len().to_wasm_bytes());
+                            sec_data.extend(t.name.as_bytes());
+                            sec_data.push(DESC_TABLE);
+                            sec_data.push(t.element_type);
+                            if t.max.is_some() {
+                                sec_data.push(LIMIT_MIN_MAX);
+                                sec_data.extend(t.min.to_wasm_bytes());
+                                sec_data.extend(t.max.unwrap().to_wasm_bytes());
+                            } else {
+                                sec_data.push(LIMIT_MIN);
+                                sec_data.extend(t.min.to_wasm_bytes());
                             }
                         }
-                    }
-                    program_bytes.push(SECTION_IMPORT);
-                    program_bytes.extend(sec_data.len().to_wasm_bytes());
-                    program_bytes.extend(sec_data);
-                }
-                Section::Memory(s) => {
-                    let mut sec_data = vec![];
-                    sec_data.extend(s.memories.len().to_wasm_bytes());
-                    for m in s.memories.iter() {
-                        if m.max_pages.is_some() {
-                            sec_data.push(LIMIT_MIN_MAX);
-                            sec_data.extend(m.min_pages.to_wasm_bytes());
-                            sec_data.extend(m.max_pages.unwrap().to_wasm_bytes());
-                        } else {
-                            sec_data.push(LIMIT_MIN);
-                            sec_data.extend(m.min_pages.to_wasm_bytes());
+                        WasmImport::Memory(m) => {
+                            sec_data.extend(m.module_name.len().to_wasm_bytes());
+                            sec_data.extend(m.module_name.as_bytes());
+                            sec_data.extend(m.name.len().to_wasm_bytes());
+                            sec_data.extend(m.name.as_bytes());
+                            sec_data.push(DESC_MEMORY);
+                            extend_memory_limit(
+                                &mut sec_data,
+                                m.min_pages,
+                                m.max_pages,
+                                m.shared,
+                                m.memory64,
+                            );
                         }
                     }
-                    program_bytes.push(SECTION_MEMORY);
-                    program_bytes.extend(sec_data.len().to_wasm_bytes());
-                    program_bytes.extend(sec_data);
                 }
-                Section::Start(s) => {
-                    let mut sec_data = vec![];
-                    sec_data.extend(s.start_function.to_wasm_bytes());
-                    program_bytes.push(SECTION_START);
-                    program_bytes.extend(sec_data.len().to_wasm_bytes());
-                    program_bytes.extend(sec_data);
+                v.push(SECTION_IMPORT);
+                v.extend(sec_data.len().to_wasm_bytes());
+                v.extend(sec_data);
+            }
+            Section::Memory(s) => {
+                let mut sec_data = vec![];
+                sec_data.extend(s.memories.len().to_wasm_bytes());
+                for m in s.memories.iter() {
+                    extend_memory_limit(
+                        &mut sec_data,
+                        m.min_pages,
+                        m.max_pages,
+                        m.shared,
+                        m.memory64,
+                    );
                 }
-                Section::Global(s) => {
-                    let mut sec_data = vec![];
-                    sec_data.extend(s.globals.len().to_wasm_bytes());
-                    for g in s.globals.iter() {
-                        sec_data.push(g.value_type.into_wasm_byte());
-                        if g.is_mutable {
-                            sec_data.push(MUTABLE);
-                        } else {
-                            sec_data.push(IMMUTABLE);
-                        }
-                        for i in g.value_expression.iter() {
-                            i.extend_wasm_bytes(&mut sec_data);
-                        }
-                        sec_data.push(END);
+                v.push(SECTION_MEMORY);
+                v.extend(sec_data.len().to_wasm_bytes());
+                v.extend(sec_data);
+            }
+            Section::Start(s) => {
+                let mut sec_data = vec![];
+                sec_data.extend(s.start_function.to_wasm_bytes());
+                v.push(SECTION_START);
+                v.extend(sec_data.len().to_wasm_bytes());
+                v.extend(sec_data);
+            }
+            Section::Global(s) => {
+                let mut sec_data = vec![];
+                sec_data.extend(s.globals.len().to_wasm_bytes());
+                for g in s.globals.iter() {
+                    sec_data.push(g.value_type.into_wasm_byte());
+                    if g.is_mutable {
+                        sec_data.push(MUTABLE);
+                    } else {
+                        sec_data.push(IMMUTABLE);
+                    }
+                    for i in g.value_expression.iter() {
+                        i.extend_wasm_bytes(&mut sec_data);
                     }
-                    program_bytes.push(SECTION_GLOBAL);
-                    program_bytes.extend(sec_data.len().to_wasm_bytes());
-                    program_bytes.extend(sec_data);
+                    sec_data.push(END);
                 }
-                Section::Table(s) => {
-                    let mut sec_data = vec![];
-                    sec_data.extend(s.tables.len().to_wasm_bytes());
-                    for t in s.tables.iter() {
-                        sec_data.push(ANYFUNC);
-                        if t.max.is_some() {
-                            sec_data.push(LIMIT_MIN_MAX);
-                            sec_data.extend(t.min.to_wasm_bytes());
-                            sec_data.extend(t.max.unwrap().to_wasm_bytes());
-                        } else {
-                            sec_data.push(LIMIT_MIN);
-                            sec_data.extend(t.min.to_wasm_bytes());
-                        }
+                v.push(SECTION_GLOBAL);
+                v.extend(sec_data.len().to_wasm_bytes());
+                v.extend(sec_data);
+            }
+            Section::Table(s) => {
+                let mut sec_data = vec![];
+                sec_data.extend(s.tables.len().to_wasm_bytes());
+                for t in s.tables.iter() {
+                    sec_data.push(ANYFUNC);
+                    if t.max.is_some() {
+                        sec_data.push(LIMIT_MIN_MAX);
+                        sec_data.extend(t.min.to_wasm_bytes());
+                        sec_data.extend(t.max.unwrap().to_wasm_bytes());
+                    } else {
+                        sec_data.push(LIMIT_MIN);
+                        sec_data.extend(t.min.to_wasm_bytes());
                     }
-                    program_bytes.push(SECTION_TABLE);
-                    program_bytes.extend(sec_data.len().to_wasm_bytes());
-                    program_bytes.extend(sec_data);
                 }
-                Section::Data(s) => {
-                    let mut sec_data = vec![];
-                    sec_data.extend(s.data_blocks.len().to_wasm_bytes());
-                    for d in s.data_blocks.iter() {
-                        sec_data.extend(d.memory.to_wasm_bytes());
+                v.push(SECTION_TABLE);
+                v.extend(sec_data.len().to_wasm_bytes());
+                v.extend(sec_data);
+            }
+            Section::Data(s) => {
+                let mut sec_data = vec![];
+                sec_data.extend(s.data_blocks.len().to_wasm_bytes());
+                for d in s.data_blocks.iter() {
+                    if d.passive {
+                        sec_data.push(0x01);
+                    } else {
+                        if d.memory == 0 {
+                            sec_data.push(0x00);
+                        } else {
+                            sec_data.push(0x02);
+                            sec_data.extend(d.memory.to_wasm_bytes());
+                        }
                         for i in d.offset_expression.iter() {
                             i.extend_wasm_bytes(&mut sec_data);
                         }
                         sec_data.push(END);
-                        sec_data.extend(d.data.len().to_wasm_bytes());
-                        sec_data.extend(&d.data);
                     }
-                    program_bytes.push(SECTION_DATA);
-                    program_bytes.extend(sec_data.len().to_wasm_bytes());
-                    program_bytes.extend(sec_data);
+                    sec_data.extend(d.data.len().to_wasm_bytes());
+                    sec_data.extend(&d.data);
                 }
-                Section::Custom(s) => {
-                    let mut sec_data = vec![];
-                    sec_data.extend(s.name.len().to_wasm_bytes());
-                    sec_data.extend(s.name.as_bytes());
-                    sec_data.extend(&s.data);
-                    program_bytes.push(SECTION_CUSTOM);
-                    program_bytes.extend(sec_data.len().to_wasm_bytes());
-                    program_bytes.extend(sec_data);
-                }
-                Section::Element(s) => {
-                    let mut sec_data = vec![];
-                    sec_data.extend(s.elements.len().to_wasm_bytes());
-                    for e in s.elements.iter() {
-                        sec_data.extend(e.table.to_wasm_bytes());
-                        for i in e.value_expression.iter() {
-                            i.extend_wasm_bytes(&mut sec_data);
-                        }
-                        sec_data.push(END);
-                        sec_data.extend(e.functions.len().to_wasm_bytes());
-                        for f in e.functions.iter() {
-                            sec_data.extend(f.to_wasm_bytes());
-                        }
+                v.push(SECTION_DATA);
+                v.extend(sec_data.len().to_wasm_bytes());
+                v.extend(sec_data);
+            }
+            Section::Custom(s) => {
+                let mut sec_data = vec![];
+                sec_data.extend(s.name.len().to_wasm_bytes());
+                sec_data.extend(s.name.as_bytes());
+                sec_data.extend(&s.data);
+                v.push(SECTION_CUSTOM);
+                v.extend(sec_data.len().to_wasm_bytes());
+                v.extend(sec_data);
+            }
+            Section::Element(s) => {
+                let mut sec_data = vec![];
+                sec_data.extend(s.elements.len().to_wasm_bytes());
+                for e in s.elements.iter() {
+                    sec_data.extend(e.table.to_wasm_bytes());
+                    for i in e.value_expression.iter() {
+                        i.extend_wasm_bytes(&mut sec_data);
+                    }
+                    sec_data.push(END);
+                    sec_data.extend(e.functions.len().to_wasm_bytes());
+                    for f in e.functions.iter() {
+                        sec_data.extend(f.to_wasm_bytes());
                     }
-                    program_bytes.push(SECTION_ELEMENT);
-                    program_bytes.extend(sec_data.len().to_wasm_bytes());
-                    program_bytes.extend(sec_data);
                 }
+                v.push(SECTION_ELEMENT);
+                v.extend(sec_data.len().to_wasm_bytes());
+                v.extend(sec_data);
+            }
+            Section::DataCount(s) => {
+                let mut sec_data = vec![];
+                sec_data.extend(s.count.to_wasm_bytes());
+                v.push(self.id() as u8);
+                v.extend(sec_data.len().to_wasm_bytes());
+                v.extend(sec_data);
             }
         }
-        program_bytes
     }
 }
 
@@ -273,6 +370,15 @@ impl WriteWasm for Instruction {
             Instruction::Raw(b) => {
                 v.push(*b);
             }
+            Instruction::Unknown(op, immediates) => {
+                v.push(*op);
+                v.extend(immediates);
+            }
+            Instruction::Prefixed(prefix, op, immediates) => {
+                v.push(*prefix);
+                v.extend(op.to_wasm_bytes());
+                v.extend(immediates);
+            }
             Instruction::Unreachable => {
                 v.push(webassembly::UNREACHABLE);
             }
@@ -335,6 +441,7 @@ impl WriteWasm for Instruction {
             Instruction::CallIndirect(i) => {
                 v.push(webassembly::CALL_INDIRECT);
                 v.extend(i.to_wasm_bytes());
+                v.push(0);
             }
             Instruction::Drop => {
                 v.push(webassembly::DROP);
@@ -835,7 +942,7 @@ impl WriteWasm for Instruction {
                 v.push(webassembly::F32_CONVERT_U_I32);
             }
             Instruction::F32ConvertSI64 => {
-                v.push(webassembly::F32_CONVERT_S_I32);
+                v.push(webassembly::F32_CONVERT_S_I64);
             }
             Instruction::F32ConvertUI64 => {
                 v.push(webassembly::F32_CONVERT_U_I64);
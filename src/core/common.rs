@@ -6,6 +6,11 @@ use core::convert::TryInto;
 use serde::{Deserialize, Serialize};
 use webassembly::*;
 
+// set in a memory's limits when it's shared between threads
+pub(crate) const LIMIT_SHARED: u8 = 0x02;
+// set in a memory's limits when it's addressed with 64-bit indices
+pub(crate) const LIMIT_MEMORY64: u8 = 0x04;
+
 pub trait WasmValueTypes {
     fn try_to_value_types(self) -> Result<Vec<ValueType>, &'static str>;
 }
@@ -68,7 +73,7 @@ impl TryFrom<&u8> for ValueType {
     }
 }
 
-#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[derive(Clone, PartialEq, Debug, Default, Serialize, Deserialize)]
 #[repr(C)]
 pub struct FunctionType {
     pub inputs: Vec<ValueType>,
@@ -158,6 +163,10 @@ pub struct MemoryImport {
     pub name: String,
     pub min_pages: usize,
     pub max_pages: Option<usize>,
+    #[serde(default)]
+    pub shared: bool,
+    #[serde(default)]
+    pub memory64: bool,
 }
 
 #[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
@@ -195,6 +204,10 @@ pub struct ImportSection {
 pub struct WasmMemory {
     pub min_pages: usize,
     pub max_pages: Option<usize>,
+    #[serde(default)]
+    pub shared: bool,
+    #[serde(default)]
+    pub memory64: bool,
 }
 
 #[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
@@ -243,6 +256,10 @@ pub struct DataBlock {
     pub memory: usize,
     pub offset_expression: Vec<Instruction>,
     pub data: Vec<u8>,
+    /// Passive segments are only written by `memory.init`, not when the
+    /// module is instantiated, and have no memory or offset.
+    #[serde(default)]
+    pub passive: bool,
 }
 
 #[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
@@ -272,6 +289,12 @@ pub struct ElementSection {
     pub elements: Vec<WasmElement>,
 }
 
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[repr(C)]
+pub struct DataCountSection {
+    pub count: usize,
+}
+
 #[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
 #[serde(tag = "section_type", content = "content")]
 #[repr(C)]
@@ -300,6 +323,8 @@ pub enum Section {
     Custom(CustomSection),
     //#[serde(rename = "element")]
     Element(ElementSection),
+    //#[serde(rename = "data_count")]
+    DataCount(DataCountSection),
 }
 
 impl Section {
@@ -317,6 +342,7 @@ impl Section {
             Section::Element(_) => 9,
             Section::Code(_) => 10,
             Section::Data(_) => 11,
+            Section::DataCount(_) => 12,
         }
     }
 }
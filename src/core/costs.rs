@@ -0,0 +1,48 @@
+use super::common::WriteWasm;
+use super::instructions::*;
+use webassembly::*;
+
+/// What each instruction costs to run, by opcode. Prefixed instructions cost
+/// whatever their prefix does.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct CostTable {
+    pub costs: [u32; 256],
+}
+
+impl Default for CostTable {
+    fn default() -> Self {
+        CostTable::uniform(1)
+    }
+}
+
+impl CostTable {
+    /// A table where every instruction costs the same.
+    pub fn uniform(cost: u32) -> Self {
+        CostTable { costs: [cost; 256] }
+    }
+
+    /// Sets what instructions with `opcode` cost.
+    pub fn with(mut self, opcode: u8, cost: u32) -> Self {
+        self.costs[opcode as usize] = cost;
+        self
+    }
+
+    /// What running the instruction itself costs, not counting anything
+    /// nested in it.
+    pub fn cost(&self, instruction: &Instruction) -> u32 {
+        let opcode = match instruction {
+            Instruction::Raw(op)
+            | Instruction::Unknown(op, _)
+            | Instruction::Prefixed(op, _, _) => *op,
+            Instruction::Block(_, _) => BLOCK,
+            Instruction::Loop(_, _) => LOOP,
+            Instruction::If(_, _, _) => IF,
+            i => {
+                let mut bytes = vec![];
+                i.extend_wasm_bytes(&mut bytes);
+                bytes[0]
+            }
+        };
+        self.costs[opcode as usize]
+    }
+}
@@ -0,0 +1,239 @@
+use super::common::*;
+use super::indices::*;
+use super::instructions::*;
+use super::program::*;
+use alloc::vec::Vec;
+
+const TYPE: usize = 0;
+const FUNCTION: usize = 1;
+const TABLE: usize = 2;
+const GLOBAL: usize = 3;
+const DATA: usize = 4;
+
+/// What `Program::eliminate_dead_code` removed, by the indices the removed
+/// items had before it ran.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct DeadCode {
+    pub types: Vec<usize>,
+    pub functions: Vec<usize>,
+    pub tables: Vec<usize>,
+    pub globals: Vec<usize>,
+    pub data: Vec<usize>,
+}
+
+impl DeadCode {
+    pub fn is_empty(&self) -> bool {
+        self.types.is_empty()
+            && self.functions.is_empty()
+            && self.tables.is_empty()
+            && self.globals.is_empty()
+            && self.data.is_empty()
+    }
+}
+
+fn slot(space: IndexSpace) -> Option<usize> {
+    match space {
+        IndexSpace::Type => Some(TYPE),
+        IndexSpace::Function => Some(FUNCTION),
+        IndexSpace::Table => Some(TABLE),
+        IndexSpace::Global => Some(GLOBAL),
+        IndexSpace::Data => Some(DATA),
+        _ => None,
+    }
+}
+
+fn scan(
+    instructions: &mut [Instruction],
+    pending: &mut Vec<(IndexSpace, usize)>,
+) -> Result<(), &'static str> {
+    map_instruction_indices(instructions, &mut |space, i| {
+        pending.push((space, i));
+        i
+    })
+}
+
+// removes every item not marked live, counting positions from `start`
+fn retain_live<T>(items: &mut Vec<T>, live: &[bool], start: usize) {
+    let mut i = start;
+    items.retain(|_| {
+        i += 1;
+        live[i - 1]
+    });
+}
+
+impl Program {
+    /// Removes every type, function, table, global and passive data segment
+    /// that can't be reached from the module's exports, start function,
+    /// element segments or active data segments, and renumbers whatever is
+    /// left. Memories and active data segments are always kept since
+    /// initializing them is observable, and so is any unreferenced memory
+    /// import. The "name" custom section is dropped as
+    /// its indices would no longer be right.
+    pub fn eliminate_dead_code(&mut self) -> Result<DeadCode, &'static str> {
+        let mut counts = [0; 5];
+        let mut import_counts = [0; 5];
+        let mut import_types = vec![];
+        let mut function_types = vec![];
+        let mut code_blocks = vec![];
+        let mut global_expressions = vec![];
+        let mut pending = vec![];
+        for s in self.sections.iter_mut() {
+            match s {
+                Section::Type(s) => counts[TYPE] += s.types.len(),
+                Section::Import(s) => {
+                    for i in s.imports.iter() {
+                        let kind = match i {
+                            WasmImport::Function(f) => {
+                                import_types.push(f.type_index);
+                                FUNCTION
+                            }
+                            WasmImport::Table(_) => TABLE,
+                            WasmImport::Global(_) => GLOBAL,
+                            WasmImport::Memory(_) => continue,
+                        };
+                        counts[kind] += 1;
+                        import_counts[kind] += 1;
+                    }
+                }
+                Section::Function(s) => {
+                    counts[FUNCTION] += s.function_types.len();
+                    function_types.extend(s.function_types.iter().copied());
+                }
+                Section::Table(s) => counts[TABLE] += s.tables.len(),
+                Section::Global(s) => {
+                    counts[GLOBAL] += s.globals.len();
+                    global_expressions
+                        .extend(s.globals.iter_mut().map(|g| &mut g.value_expression));
+                }
+                Section::Code(s) => code_blocks.extend(s.code_blocks.iter_mut()),
+                Section::Export(s) => {
+                    for e in s.exports.iter() {
+                        match e {
+                            WasmExport::Function(e) => {
+                                pending.push((IndexSpace::Function, e.index))
+                            }
+                            WasmExport::Table(e) => pending.push((IndexSpace::Table, e.index)),
+                            WasmExport::Global(e) => pending.push((IndexSpace::Global, e.index)),
+                            WasmExport::Memory(_) => {}
+                        }
+                    }
+                }
+                Section::Start(s) => pending.push((IndexSpace::Function, s.start_function)),
+                Section::Element(s) => {
+                    for e in s.elements.iter_mut() {
+                        pending.push((IndexSpace::Table, e.table));
+                        scan(&mut e.value_expression, &mut pending)?;
+                        for f in e.functions.iter() {
+                            pending.push((IndexSpace::Function, *f));
+                        }
+                    }
+                }
+                Section::Data(s) => {
+                    for d in s.data_blocks.iter_mut() {
+                        if !d.passive {
+                            pending.push((IndexSpace::Data, counts[DATA]));
+                        }
+                        counts[DATA] += 1;
+                        scan(&mut d.offset_expression, &mut pending)?;
+                    }
+                }
+                _ => {}
+            }
+        }
+        if code_blocks.len() != function_types.len() {
+            return Err("function and code sections have different lengths");
+        }
+
+        let mut live = [
+            vec![false; counts[TYPE]],
+            vec![false; counts[FUNCTION]],
+            vec![false; counts[TABLE]],
+            vec![false; counts[GLOBAL]],
+            vec![false; counts[DATA]],
+        ];
+        while let Some((space, i)) = pending.pop() {
+            let kind = match slot(space) {
+                Some(kind) => kind,
+                None => continue,
+            };
+            match live[kind].get(i) {
+                None => return Err("index out of range"),
+                Some(true) => continue,
+                Some(false) => live[kind][i] = true,
+            }
+            if kind == FUNCTION {
+                if i < import_counts[FUNCTION] {
+                    pending.push((IndexSpace::Type, import_types[i]));
+                } else {
+                    let d = i - import_counts[FUNCTION];
+                    pending.push((IndexSpace::Type, function_types[d]));
+                    scan(&mut code_blocks[d].instructions, &mut pending)?;
+                }
+            } else if kind == GLOBAL && i >= import_counts[GLOBAL] {
+                scan(global_expressions[i - import_counts[GLOBAL]], &mut pending)?;
+            }
+        }
+        drop(code_blocks);
+        drop(global_expressions);
+
+        let mut dead = DeadCode::default();
+        let mut maps = [vec![], vec![], vec![], vec![], vec![]];
+        for kind in 0..5 {
+            let removed = match kind {
+                TYPE => &mut dead.types,
+                FUNCTION => &mut dead.functions,
+                TABLE => &mut dead.tables,
+                GLOBAL => &mut dead.globals,
+                _ => &mut dead.data,
+            };
+            let mut next = 0;
+            for (i, is_live) in live[kind].iter().enumerate() {
+                maps[kind].push(next);
+                if *is_live {
+                    next += 1;
+                } else {
+                    removed.push(i);
+                }
+            }
+        }
+
+        let mut seen = [0; 5];
+        for s in self.sections.iter_mut() {
+            match s {
+                Section::Type(s) => retain_live(&mut s.types, &live[TYPE], 0),
+                Section::Import(s) => s.imports.retain(|i| {
+                    let kind = match i {
+                        WasmImport::Function(_) => FUNCTION,
+                        WasmImport::Table(_) => TABLE,
+                        WasmImport::Global(_) => GLOBAL,
+                        WasmImport::Memory(_) => return true,
+                    };
+                    seen[kind] += 1;
+                    live[kind][seen[kind] - 1]
+                }),
+                Section::Function(s) => retain_live(
+                    &mut s.function_types,
+                    &live[FUNCTION],
+                    import_counts[FUNCTION],
+                ),
+                Section::Code(s) => {
+                    retain_live(&mut s.code_blocks, &live[FUNCTION], import_counts[FUNCTION])
+                }
+                Section::Table(s) => retain_live(&mut s.tables, &live[TABLE], import_counts[TABLE]),
+                Section::Global(s) => {
+                    retain_live(&mut s.globals, &live[GLOBAL], import_counts[GLOBAL])
+                }
+                Section::Data(s) => retain_live(&mut s.data_blocks, &live[DATA], 0),
+                Section::DataCount(s) => s.count -= dead.data.len(),
+                _ => {}
+            }
+        }
+        self.sections
+            .retain(|s| !matches!(s, Section::Custom(c) if c.name == "name"));
+        self.map_indices(&mut |space, i| match slot(space) {
+            Some(kind) => maps[kind][i],
+            None => i,
+        })?;
+        Ok(dead)
+    }
+}
@@ -0,0 +1,345 @@
+use super::common::*;
+use super::instructions::*;
+use super::program::*;
+use super::view::*;
+use alloc::vec::Vec;
+use serde::{Deserialize, Serialize};
+use webassembly::*;
+
+/// WebAssembly proposals beyond the MVP that a module may make use of.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+#[repr(C)]
+pub struct Features {
+    pub mutable_globals: bool,
+    pub sign_extension: bool,
+    pub saturating_conversions: bool,
+    pub multi_value: bool,
+    pub bulk_memory: bool,
+    pub reference_types: bool,
+    pub simd: bool,
+    pub threads: bool,
+    pub tail_calls: bool,
+    /// 64-bit memories parse, but the interpreter can't run them.
+    pub memory64: bool,
+}
+
+impl Default for Features {
+    fn default() -> Self {
+        Self::all()
+    }
+}
+
+impl Features {
+    pub fn mvp() -> Self {
+        Features {
+            mutable_globals: false,
+            sign_extension: false,
+            saturating_conversions: false,
+            multi_value: false,
+            bulk_memory: false,
+            reference_types: false,
+            simd: false,
+            threads: false,
+            tail_calls: false,
+            memory64: false,
+        }
+    }
+
+    pub fn all() -> Self {
+        Features {
+            mutable_globals: true,
+            sign_extension: true,
+            saturating_conversions: true,
+            multi_value: true,
+            bulk_memory: true,
+            reference_types: true,
+            simd: true,
+            threads: true,
+            tail_calls: true,
+            memory64: true,
+        }
+    }
+
+    /// Fails on the first feature in `used` that isn't enabled.
+    pub fn check(&self, used: &Features) -> Result<(), &'static str> {
+        let features = [
+            (
+                self.mutable_globals,
+                used.mutable_globals,
+                "feature mutable-globals not enabled",
+            ),
+            (
+                self.sign_extension,
+                used.sign_extension,
+                "feature sign-extension not enabled",
+            ),
+            (
+                self.saturating_conversions,
+                used.saturating_conversions,
+                "feature saturating-conversions not enabled",
+            ),
+            (
+                self.multi_value,
+                used.multi_value,
+                "feature multi-value not enabled",
+            ),
+            (
+                self.bulk_memory,
+                used.bulk_memory,
+                "feature bulk-memory not enabled",
+            ),
+            (
+                self.reference_types,
+                used.reference_types,
+                "feature reference-types not enabled",
+            ),
+            (self.simd, used.simd, "feature simd not enabled"),
+            (self.threads, used.threads, "feature threads not enabled"),
+            (
+                self.tail_calls,
+                used.tail_calls,
+                "feature tail-calls not enabled",
+            ),
+            (self.memory64, used.memory64, "feature memory64 not enabled"),
+        ];
+        for (enabled, used, error) in features.iter() {
+            if *used && !*enabled {
+                return Err(error);
+            }
+        }
+        Ok(())
+    }
+
+    fn add_function_type(&mut self, t: &FunctionType) {
+        if t.outputs.len() > 1 {
+            self.multi_value = true;
+        }
+    }
+
+    fn add_block_type(&mut self, block_type: u8) {
+        match block_type {
+            EMPTY | I32 | I64 | F32 | F64 => {}
+            // v128
+            0x7B => self.simd = true,
+            // funcref, externref
+            ANYFUNC | 0x6F => self.reference_types = true,
+            // anything else is a type index
+            _ => self.multi_value = true,
+        }
+    }
+
+    fn add_instructions(&mut self, instructions: &[Instruction]) {
+        for i in instructions.iter() {
+            match i {
+                Instruction::Block(t, b) | Instruction::Loop(t, b) => {
+                    self.add_block_type(*t);
+                    self.add_instructions(b);
+                }
+                Instruction::If(t, b, e) => {
+                    self.add_block_type(*t);
+                    self.add_instructions(b);
+                    if let Some(e) = e {
+                        self.add_instructions(e);
+                    }
+                }
+                Instruction::Unknown(op, _) => match op {
+                    0x12 | 0x13 => self.tail_calls = true,
+                    0xC0..=0xC4 => self.sign_extension = true,
+                    _ => self.reference_types = true,
+                },
+                Instruction::Prefixed(prefix, op, _) => match (*prefix, op) {
+                    (PREFIX_MISC, 0..=7) => self.saturating_conversions = true,
+                    (PREFIX_MISC, 8..=14) => self.bulk_memory = true,
+                    (PREFIX_MISC, _) => self.reference_types = true,
+                    (PREFIX_SIMD, _) => self.simd = true,
+                    (PREFIX_THREADS, _) => self.threads = true,
+                    _ => {}
+                },
+                _ => {}
+            }
+        }
+    }
+}
+
+impl<'p> ProgramView<'p> {
+    /// Reports which post-MVP features this module actually makes use of.
+    pub fn used_features(&self) -> Features {
+        let mut usage = Usage::default();
+        for s in self.sections.iter() {
+            match s {
+                SectionView::Import(s) => {
+                    for i in s.imports.iter() {
+                        match i {
+                            WasmImportView::Global(g) => usage.add_global_import(g.is_mutable),
+                            WasmImportView::Table(t) => usage.add_table_import(t.element_type),
+                            WasmImportView::Memory(m) => usage.add_memory(m.shared, m.memory64),
+                            _ => {}
+                        }
+                    }
+                }
+                SectionView::Export(s) => {
+                    for e in s.exports.iter() {
+                        if let WasmExportView::Global(g) = e {
+                            usage.exported_globals.push(g.index);
+                        }
+                    }
+                }
+                SectionView::Data(s) => {
+                    for d in s.data_blocks.iter() {
+                        usage.add_data(d.passive, &d.offset_expression);
+                    }
+                }
+                SectionView::Type(s) => usage.add_types(s),
+                SectionView::Table(s) => usage.add_tables(s),
+                SectionView::Memory(s) => usage.add_memories(s),
+                SectionView::Global(s) => usage.add_globals(s),
+                SectionView::Code(s) => usage.add_code(s),
+                SectionView::Element(s) => usage.add_elements(s),
+                SectionView::DataCount(_) => usage.used.bulk_memory = true,
+                _ => {}
+            }
+        }
+        usage.finish()
+    }
+}
+
+impl Program {
+    /// Reports which post-MVP features this module actually makes use of.
+    pub fn used_features(&self) -> Features {
+        let mut usage = Usage::default();
+        for s in self.sections.iter() {
+            match s {
+                Section::Import(s) => {
+                    for i in s.imports.iter() {
+                        match i {
+                            WasmImport::Global(g) => usage.add_global_import(g.is_mutable),
+                            WasmImport::Table(t) => usage.add_table_import(t.element_type),
+                            WasmImport::Memory(m) => usage.add_memory(m.shared, m.memory64),
+                            _ => {}
+                        }
+                    }
+                }
+                Section::Export(s) => {
+                    for e in s.exports.iter() {
+                        if let WasmExport::Global(g) = e {
+                            usage.exported_globals.push(g.index);
+                        }
+                    }
+                }
+                Section::Data(s) => {
+                    for d in s.data_blocks.iter() {
+                        usage.add_data(d.passive, &d.offset_expression);
+                    }
+                }
+                Section::Type(s) => usage.add_types(s),
+                Section::Table(s) => usage.add_tables(s),
+                Section::Memory(s) => usage.add_memories(s),
+                Section::Global(s) => usage.add_globals(s),
+                Section::Code(s) => usage.add_code(s),
+                Section::Element(s) => usage.add_elements(s),
+                Section::DataCount(_) => usage.used.bulk_memory = true,
+                _ => {}
+            }
+        }
+        usage.finish()
+    }
+}
+
+// the features a module uses so far, along with what's needed to tell
+// whether its exports or tables between them use any more
+struct Usage {
+    used: Features,
+    global_mutability: Vec<bool>,
+    exported_globals: Vec<usize>,
+    table_count: usize,
+}
+
+impl Default for Usage {
+    fn default() -> Self {
+        Usage {
+            used: Features::mvp(),
+            global_mutability: vec![],
+            exported_globals: vec![],
+            table_count: 0,
+        }
+    }
+}
+
+impl Usage {
+    fn add_types(&mut self, s: &TypeSection) {
+        for t in s.types.iter() {
+            self.used.add_function_type(t);
+        }
+    }
+
+    fn add_global_import(&mut self, is_mutable: bool) {
+        if is_mutable {
+            self.used.mutable_globals = true;
+        }
+        self.global_mutability.push(is_mutable);
+    }
+
+    fn add_table_import(&mut self, element_type: u8) {
+        if element_type != ANYFUNC {
+            self.used.reference_types = true;
+        }
+        self.table_count += 1;
+    }
+
+    fn add_memory(&mut self, shared: bool, memory64: bool) {
+        if shared {
+            self.used.threads = true;
+        }
+        if memory64 {
+            self.used.memory64 = true;
+        }
+    }
+
+    fn add_tables(&mut self, s: &TableSection) {
+        self.table_count += s.tables.len();
+    }
+
+    fn add_memories(&mut self, s: &MemorySection) {
+        for m in s.memories.iter() {
+            self.add_memory(m.shared, m.memory64);
+        }
+    }
+
+    fn add_globals(&mut self, s: &GlobalSection) {
+        for g in s.globals.iter() {
+            self.global_mutability.push(g.is_mutable);
+            self.used.add_instructions(&g.value_expression);
+        }
+    }
+
+    fn add_code(&mut self, s: &CodeSection) {
+        for c in s.code_blocks.iter() {
+            self.used.add_instructions(&c.instructions);
+        }
+    }
+
+    fn add_elements(&mut self, s: &ElementSection) {
+        for e in s.elements.iter() {
+            self.used.add_instructions(&e.value_expression);
+        }
+    }
+
+    fn add_data(&mut self, passive: bool, offset_expression: &[Instruction]) {
+        if passive {
+            self.used.bulk_memory = true;
+        }
+        self.used.add_instructions(offset_expression);
+    }
+
+    fn finish(mut self) -> Features {
+        for g in self.exported_globals.iter() {
+            if self.global_mutability.get(*g) == Some(&true) {
+                self.used.mutable_globals = true;
+            }
+        }
+        if self.table_count > 1 {
+            self.used.reference_types = true;
+        }
+        self.used
+    }
+}
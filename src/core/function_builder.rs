@@ -0,0 +1,649 @@
+use super::common::*;
+use super::instructions::*;
+use super::program::*;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use webassembly::EMPTY;
+
+const I32: ValueType = ValueType::I32;
+const I64: ValueType = ValueType::I64;
+const F32: ValueType = ValueType::F32;
+const F64: ValueType = ValueType::F64;
+
+/// A block, loop or if opened by a `FunctionBuilder`, for branching to.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Label(usize);
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum FrameKind {
+    Function,
+    Block,
+    Loop,
+    If,
+    Else,
+}
+
+struct Frame {
+    kind: FrameKind,
+    label: usize,
+    results: Vec<ValueType>,
+    height: usize,
+    unreachable: bool,
+    instructions: Vec<Instruction>,
+    then_instructions: Vec<Instruction>,
+}
+
+/// Builds a function body one instruction at a time, allocating named
+/// locals, resolving branch labels to depths and checking operand types as
+/// it goes. The first mistake is remembered and reported by `build`.
+///
+/// Calls and globals are checked against the program the builder was
+/// created from. The function being built is assumed to become the next
+/// defined function, so it can call itself.
+pub struct FunctionBuilder {
+    inputs: Vec<ValueType>,
+    outputs: Vec<ValueType>,
+    locals: Vec<ValueType>,
+    names: Vec<(String, u32)>,
+    types: Vec<FunctionType>,
+    functions: Vec<FunctionType>,
+    globals: Vec<(ValueType, bool)>,
+    frames: Vec<Frame>,
+    stack: Vec<Option<ValueType>>,
+    next_label: usize,
+    error: Option<&'static str>,
+}
+
+impl FunctionBuilder {
+    pub fn new(program: &Program, inputs: &[ValueType], outputs: &[ValueType]) -> Self {
+        let mut types = vec![];
+        let mut imported_functions = vec![];
+        let mut defined_functions = vec![];
+        let mut imported_globals = vec![];
+        let mut defined_globals = vec![];
+        for s in program.sections.iter() {
+            match s {
+                Section::Type(s) => types.extend(s.types.iter().cloned()),
+                Section::Import(s) => {
+                    for i in s.imports.iter() {
+                        match i {
+                            WasmImport::Function(f) => imported_functions.push(f.type_index),
+                            WasmImport::Global(g) => {
+                                imported_globals.push((g.value_type, g.is_mutable))
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                Section::Function(s) => defined_functions.extend(s.function_types.iter()),
+                Section::Global(s) => {
+                    defined_globals.extend(s.globals.iter().map(|g| (g.value_type, g.is_mutable)))
+                }
+                _ => {}
+            }
+        }
+        let empty = FunctionType {
+            inputs: vec![],
+            outputs: vec![],
+        };
+        let mut functions: Vec<FunctionType> = imported_functions
+            .iter()
+            .chain(defined_functions.iter())
+            .map(|t| types.get(*t).unwrap_or(&empty).clone())
+            .collect();
+        functions.push(FunctionType {
+            inputs: inputs.to_vec(),
+            outputs: outputs.to_vec(),
+        });
+        imported_globals.extend(defined_globals);
+        FunctionBuilder {
+            inputs: inputs.to_vec(),
+            outputs: outputs.to_vec(),
+            locals: vec![],
+            names: vec![],
+            types,
+            functions,
+            globals: imported_globals,
+            frames: vec![Frame {
+                kind: FrameKind::Function,
+                label: 0,
+                results: outputs.to_vec(),
+                height: 0,
+                unreachable: false,
+                instructions: vec![],
+                then_instructions: vec![],
+            }],
+            stack: vec![],
+            next_label: 1,
+            error: None,
+        }
+    }
+
+    /// Gives the parameter at `index` a name usable with the `local_*`
+    /// helpers.
+    pub fn name_param(&mut self, index: u32, name: &str) -> u32 {
+        if index as usize >= self.inputs.len() {
+            self.fail("invalid parameter index");
+        } else {
+            self.add_name(name, index);
+        }
+        index
+    }
+
+    /// Allocates a new local and returns its index.
+    pub fn local(&mut self, name: &str, value_type: ValueType) -> u32 {
+        let index = (self.inputs.len() + self.locals.len()) as u32;
+        self.locals.push(value_type);
+        self.add_name(name, index);
+        index
+    }
+
+    /// Looks up the index of a named parameter or local.
+    pub fn find_local(&self, name: &str) -> Option<u32> {
+        self.names.iter().find(|x| x.0 == name).map(|x| x.1)
+    }
+
+    pub fn local_get(&mut self, name: &str) -> &mut Self {
+        match self.find_local(name) {
+            Some(i) => self.emit(Instruction::LocalGet(i)),
+            None => self.fail("unknown local name"),
+        }
+    }
+
+    pub fn local_set(&mut self, name: &str) -> &mut Self {
+        match self.find_local(name) {
+            Some(i) => self.emit(Instruction::LocalSet(i)),
+            None => self.fail("unknown local name"),
+        }
+    }
+
+    pub fn local_tee(&mut self, name: &str) -> &mut Self {
+        match self.find_local(name) {
+            Some(i) => self.emit(Instruction::LocalTee(i)),
+            None => self.fail("unknown local name"),
+        }
+    }
+
+    /// Opens a block, whose label branches to its end.
+    pub fn begin_block(&mut self, result: Option<ValueType>) -> Label {
+        self.open(FrameKind::Block, result)
+    }
+
+    /// Opens a loop, whose label branches back to its start.
+    pub fn begin_loop(&mut self, result: Option<ValueType>) -> Label {
+        self.open(FrameKind::Loop, result)
+    }
+
+    /// Pops an i32 condition and opens an if, whose label branches to its
+    /// end.
+    pub fn begin_if(&mut self, result: Option<ValueType>) -> Label {
+        self.pop(Some(I32));
+        self.open(FrameKind::If, result)
+    }
+
+    /// Switches the innermost if over to its else branch.
+    pub fn begin_else(&mut self) -> &mut Self {
+        if self.frames.last().map(|f| f.kind) != Some(FrameKind::If) {
+            return self.fail("else without if");
+        }
+        self.check_frame_results();
+        let frame = self.frames.last_mut().unwrap();
+        frame.kind = FrameKind::Else;
+        frame.unreachable = false;
+        frame.then_instructions = core::mem::take(&mut frame.instructions);
+        self
+    }
+
+    /// Closes the innermost block, loop or if.
+    pub fn end(&mut self) -> &mut Self {
+        if self.frames.len() == 1 {
+            return self.fail("end without block");
+        }
+        self.check_frame_results();
+        let frame = self.frames.pop().unwrap();
+        let block_type = match frame.results.first() {
+            Some(t) => t.into_wasm_byte(),
+            None => EMPTY,
+        };
+        let instruction = match frame.kind {
+            FrameKind::Block => Instruction::Block(block_type, frame.instructions),
+            FrameKind::Loop => Instruction::Loop(block_type, frame.instructions),
+            FrameKind::If => {
+                if !frame.results.is_empty() {
+                    self.fail("if without else can't produce a value");
+                }
+                Instruction::If(block_type, frame.instructions, None)
+            }
+            _ => Instruction::If(
+                block_type,
+                frame.then_instructions,
+                Some(frame.instructions),
+            ),
+        };
+        self.frames
+            .last_mut()
+            .unwrap()
+            .instructions
+            .push(instruction);
+        for t in frame.results.iter() {
+            self.stack.push(Some(*t));
+        }
+        self
+    }
+
+    pub fn br(&mut self, label: Label) -> &mut Self {
+        match self.depth(label) {
+            Some(depth) => self.emit(Instruction::Br(depth)),
+            None => self.fail("label is not in scope"),
+        }
+    }
+
+    pub fn br_if(&mut self, label: Label) -> &mut Self {
+        match self.depth(label) {
+            Some(depth) => self.emit(Instruction::BrIf(depth)),
+            None => self.fail("label is not in scope"),
+        }
+    }
+
+    pub fn br_table(&mut self, labels: &[Label], default: Label) -> &mut Self {
+        let depths: Option<Vec<u32>> = labels.iter().map(|l| self.depth(*l)).collect();
+        match (depths, self.depth(default)) {
+            (Some(depths), Some(default)) => self.emit(Instruction::BrTable(depths, default)),
+            _ => self.fail("label is not in scope"),
+        }
+    }
+
+    /// Appends an instruction, checking it against the operand stack.
+    /// Control instructions with bodies must go through `begin_block`,
+    /// `begin_loop` and `begin_if` instead.
+    pub fn emit(&mut self, instruction: Instruction) -> &mut Self {
+        match &instruction {
+            Instruction::Block(_, _)
+            | Instruction::Loop(_, _)
+            | Instruction::If(_, _, _)
+            | Instruction::Raw(_)
+            | Instruction::Unknown(_, _)
+            | Instruction::Prefixed(_, _, _) => {
+                return self.fail("instruction can't be checked by the function builder")
+            }
+            Instruction::Unreachable => self.set_unreachable(),
+            Instruction::Nop => {}
+            Instruction::Br(depth) => {
+                let types = self.label_types(*depth);
+                self.pop_all(&types);
+                self.set_unreachable();
+            }
+            Instruction::BrIf(depth) => {
+                self.pop(Some(I32));
+                let types = self.label_types(*depth);
+                self.pop_all(&types);
+                self.push_all(&types);
+            }
+            Instruction::BrTable(depths, default) => {
+                self.pop(Some(I32));
+                let types = self.label_types(*default);
+                for depth in depths.iter() {
+                    if self.label_types(*depth) != types {
+                        self.fail("br_table targets have different types");
+                    }
+                }
+                self.pop_all(&types);
+                self.set_unreachable();
+            }
+            Instruction::Return => {
+                let types = self.outputs.clone();
+                self.pop_all(&types);
+                self.set_unreachable();
+            }
+            Instruction::Call(f) => match self.functions.get(*f as usize).cloned() {
+                Some(t) => {
+                    self.pop_all(&t.inputs);
+                    self.push_all(&t.outputs);
+                }
+                None => return self.fail("invalid function index"),
+            },
+            Instruction::CallIndirect(t) => match self.types.get(*t as usize).cloned() {
+                Some(t) => {
+                    self.pop(Some(I32));
+                    self.pop_all(&t.inputs);
+                    self.push_all(&t.outputs);
+                }
+                None => return self.fail("invalid type index"),
+            },
+            Instruction::Drop => {
+                self.pop(None);
+            }
+            Instruction::Select => {
+                self.pop(Some(I32));
+                let a = self.pop(None);
+                let b = self.pop(a);
+                self.stack.push(b.or(a));
+            }
+            Instruction::LocalGet(i) => match self.local_type(*i) {
+                Some(t) => self.stack.push(Some(t)),
+                None => return self.fail("invalid local index"),
+            },
+            Instruction::LocalSet(i) => match self.local_type(*i) {
+                Some(t) => {
+                    self.pop(Some(t));
+                }
+                None => return self.fail("invalid local index"),
+            },
+            Instruction::LocalTee(i) => match self.local_type(*i) {
+                Some(t) => {
+                    self.pop(Some(t));
+                    self.stack.push(Some(t));
+                }
+                None => return self.fail("invalid local index"),
+            },
+            Instruction::GlobalGet(i) => match self.globals.get(*i as usize) {
+                Some((t, _)) => self.stack.push(Some(*t)),
+                None => return self.fail("invalid global index"),
+            },
+            Instruction::GlobalSet(i) => match self.globals.get(*i as usize).copied() {
+                Some((t, true)) => {
+                    self.pop(Some(t));
+                }
+                Some((_, false)) => return self.fail("global is immutable"),
+                None => return self.fail("invalid global index"),
+            },
+            i => {
+                let (inputs, outputs) = stack_effect(i);
+                self.pop_all(inputs);
+                self.push_all(outputs);
+            }
+        }
+        self.frames
+            .last_mut()
+            .unwrap()
+            .instructions
+            .push(instruction);
+        self
+    }
+
+    /// Finishes the function and adds it to `program`, returning its
+    /// function index.
+    pub fn build(mut self, program: &mut Program) -> Result<usize, &'static str> {
+        if self.frames.len() > 1 {
+            self.fail("block was never ended");
+        }
+        self.check_frame_results();
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        let mut locals: Vec<LocalCount> = vec![];
+        for t in self.locals.iter() {
+            match locals.last_mut() {
+                Some(l) if l.value_type == *t => l.count += 1,
+                _ => locals.push(LocalCount {
+                    count: 1,
+                    value_type: *t,
+                }),
+            }
+        }
+        let instructions = self.frames.pop().unwrap().instructions;
+        let (code, index) = program.create_function(&self.inputs, &self.outputs)?;
+        code.locals = locals;
+        code.instructions = instructions;
+        Ok(index)
+    }
+
+    fn add_name(&mut self, name: &str, index: u32) {
+        if self.find_local(name).is_some() {
+            self.fail("local name already in use");
+        } else {
+            self.names.push((name.to_string(), index));
+        }
+    }
+
+    fn fail(&mut self, error: &'static str) -> &mut Self {
+        if self.error.is_none() {
+            self.error = Some(error);
+        }
+        self
+    }
+
+    fn open(&mut self, kind: FrameKind, result: Option<ValueType>) -> Label {
+        let label = self.next_label;
+        self.next_label += 1;
+        self.frames.push(Frame {
+            kind,
+            label,
+            results: result.into_iter().collect(),
+            height: self.stack.len(),
+            unreachable: false,
+            instructions: vec![],
+            then_instructions: vec![],
+        });
+        Label(label)
+    }
+
+    fn depth(&self, label: Label) -> Option<u32> {
+        self.frames
+            .iter()
+            .rev()
+            .position(|f| f.label == label.0)
+            .map(|d| d as u32)
+    }
+
+    fn label_types(&mut self, depth: u32) -> Vec<ValueType> {
+        let frames = self.frames.len();
+        if depth as usize >= frames {
+            self.fail("branch depth out of range");
+            return vec![];
+        }
+        let frame = &self.frames[frames - 1 - depth as usize];
+        if frame.kind == FrameKind::Loop {
+            vec![]
+        } else {
+            frame.results.clone()
+        }
+    }
+
+    fn local_type(&self, index: u32) -> Option<ValueType> {
+        self.inputs
+            .iter()
+            .chain(self.locals.iter())
+            .nth(index as usize)
+            .copied()
+    }
+
+    fn set_unreachable(&mut self) {
+        let frame = self.frames.last_mut().unwrap();
+        frame.unreachable = true;
+        self.stack.truncate(frame.height);
+    }
+
+    // pops the current frame's results and checks nothing else is left
+    fn check_frame_results(&mut self) {
+        let results = self.frames.last().unwrap().results.clone();
+        self.pop_all(&results);
+        if self.stack.len() != self.frames.last().unwrap().height {
+            self.fail("values left on the operand stack");
+        }
+        let height = self.frames.last().unwrap().height;
+        self.stack.truncate(height);
+    }
+
+    fn pop(&mut self, expected: Option<ValueType>) -> Option<ValueType> {
+        let frame = self.frames.last().unwrap();
+        if self.stack.len() == frame.height {
+            if !frame.unreachable {
+                self.fail("operand stack underflow");
+            }
+            return expected;
+        }
+        let actual = self.stack.pop().unwrap();
+        if let (Some(a), Some(e)) = (actual, expected) {
+            if a != e {
+                self.fail("operand has the wrong type");
+            }
+        }
+        actual.or(expected)
+    }
+
+    fn pop_all(&mut self, types: &[ValueType]) {
+        for t in types.iter().rev() {
+            self.pop(Some(*t));
+        }
+    }
+
+    fn push_all(&mut self, types: &[ValueType]) {
+        self.stack.extend(types.iter().map(|t| Some(*t)));
+    }
+}
+
+// the operands popped and pushed by instructions without index immediates
+pub(crate) fn stack_effect(
+    instruction: &Instruction,
+) -> (&'static [ValueType], &'static [ValueType]) {
+    match instruction {
+        Instruction::I32Load(_, _)
+        | Instruction::I32Load8S(_, _)
+        | Instruction::I32Load8U(_, _)
+        | Instruction::I32Load16S(_, _)
+        | Instruction::I32Load16U(_, _) => (&[I32], &[I32]),
+        Instruction::I64Load(_, _)
+        | Instruction::I64Load8S(_, _)
+        | Instruction::I64Load8U(_, _)
+        | Instruction::I64Load16S(_, _)
+        | Instruction::I64Load16U(_, _)
+        | Instruction::I64Load32S(_, _)
+        | Instruction::I64Load32U(_, _) => (&[I32], &[I64]),
+        Instruction::F32Load(_, _) => (&[I32], &[F32]),
+        Instruction::F64Load(_, _) => (&[I32], &[F64]),
+        Instruction::I32Store(_, _)
+        | Instruction::I32Store8(_, _)
+        | Instruction::I32Store16(_, _) => (&[I32, I32], &[]),
+        Instruction::I64Store(_, _)
+        | Instruction::I64Store8(_, _)
+        | Instruction::I64Store16(_, _)
+        | Instruction::I64Store32(_, _) => (&[I32, I64], &[]),
+        Instruction::F32Store(_, _) => (&[I32, F32], &[]),
+        Instruction::F64Store(_, _) => (&[I32, F64], &[]),
+        Instruction::MemorySize => (&[], &[I32]),
+        Instruction::MemoryGrow => (&[I32], &[I32]),
+        Instruction::I32Const(_) => (&[], &[I32]),
+        Instruction::I64Const(_) => (&[], &[I64]),
+        Instruction::F32Const(_) => (&[], &[F32]),
+        Instruction::F64Const(_) => (&[], &[F64]),
+        Instruction::I32Eqz
+        | Instruction::I32Clz
+        | Instruction::I32Ctz
+        | Instruction::I32Popcnt => (&[I32], &[I32]),
+        Instruction::I32Eq
+        | Instruction::I32Ne
+        | Instruction::I32LtS
+        | Instruction::I32LtU
+        | Instruction::I32GtS
+        | Instruction::I32GtU
+        | Instruction::I32LeS
+        | Instruction::I32LeU
+        | Instruction::I32GeS
+        | Instruction::I32GeU
+        | Instruction::I32Add
+        | Instruction::I32Sub
+        | Instruction::I32Mul
+        | Instruction::I32DivS
+        | Instruction::I32DivU
+        | Instruction::I32RemS
+        | Instruction::I32RemU
+        | Instruction::I32And
+        | Instruction::I32Or
+        | Instruction::I32Xor
+        | Instruction::I32Shl
+        | Instruction::I32ShrS
+        | Instruction::I32ShrU
+        | Instruction::I32Rotl
+        | Instruction::I32Rotr => (&[I32, I32], &[I32]),
+        Instruction::I64Eqz => (&[I64], &[I32]),
+        Instruction::I64Eq
+        | Instruction::I64Ne
+        | Instruction::I64LtS
+        | Instruction::I64LtU
+        | Instruction::I64GtS
+        | Instruction::I64GtU
+        | Instruction::I64LeS
+        | Instruction::I64LeU
+        | Instruction::I64GeS
+        | Instruction::I64GeU => (&[I64, I64], &[I32]),
+        Instruction::I64Clz | Instruction::I64Ctz | Instruction::I64Popcnt => (&[I64], &[I64]),
+        Instruction::I64Add
+        | Instruction::I64Sub
+        | Instruction::I64Mul
+        | Instruction::I64DivS
+        | Instruction::I64DivU
+        | Instruction::I64RemS
+        | Instruction::I64RemU
+        | Instruction::I64And
+        | Instruction::I64Or
+        | Instruction::I64Xor
+        | Instruction::I64Shl
+        | Instruction::I64ShrS
+        | Instruction::I64ShrU
+        | Instruction::I64Rotl
+        | Instruction::I64Rotr => (&[I64, I64], &[I64]),
+        Instruction::F32Eq
+        | Instruction::F32Ne
+        | Instruction::F32Lt
+        | Instruction::F32Gt
+        | Instruction::F32Le
+        | Instruction::F32Ge => (&[F32, F32], &[I32]),
+        Instruction::F64Eq
+        | Instruction::F64Ne
+        | Instruction::F64Lt
+        | Instruction::F64Gt
+        | Instruction::F64Le
+        | Instruction::F64Ge => (&[F64, F64], &[I32]),
+        Instruction::F32Abs
+        | Instruction::F32Neg
+        | Instruction::F32Ceil
+        | Instruction::F32Floor
+        | Instruction::F32Trunc
+        | Instruction::F32Nearest
+        | Instruction::F32Sqrt => (&[F32], &[F32]),
+        Instruction::F32Add
+        | Instruction::F32Sub
+        | Instruction::F32Mul
+        | Instruction::F32Div
+        | Instruction::F32Min
+        | Instruction::F32Max
+        | Instruction::F32Copysign => (&[F32, F32], &[F32]),
+        Instruction::F64Abs
+        | Instruction::F64Neg
+        | Instruction::F64Ceil
+        | Instruction::F64Floor
+        | Instruction::F64Trunc
+        | Instruction::F64Nearest
+        | Instruction::F64Sqrt => (&[F64], &[F64]),
+        Instruction::F64Add
+        | Instruction::F64Sub
+        | Instruction::F64Mul
+        | Instruction::F64Div
+        | Instruction::F64Min
+        | Instruction::F64Max
+        | Instruction::F64Copysign => (&[F64, F64], &[F64]),
+        // despite its name this is i32.wrap_i64
+        Instruction::I32wrapF64 => (&[I64], &[I32]),
+        Instruction::I32TruncSF32 | Instruction::I32TruncUF32 | Instruction::I32ReinterpretF32 => {
+            (&[F32], &[I32])
+        }
+        Instruction::I32TruncSF64 | Instruction::I32TruncUF64 => (&[F64], &[I32]),
+        Instruction::I64ExtendSI32 | Instruction::I64ExtendUI32 => (&[I32], &[I64]),
+        Instruction::I64TruncSF32 | Instruction::I64TruncUF32 => (&[F32], &[I64]),
+        Instruction::I64TruncSF64 | Instruction::I64TruncUF64 | Instruction::I64ReinterpretF64 => {
+            (&[F64], &[I64])
+        }
+        Instruction::F32ConvertSI32
+        | Instruction::F32ConvertUI32
+        | Instruction::F32ReinterpretI32 => (&[I32], &[F32]),
+        Instruction::F32ConvertSI64 | Instruction::F32ConvertUI64 => (&[I64], &[F32]),
+        Instruction::F32DemoteF64 => (&[F64], &[F32]),
+        Instruction::F64ConvertSI32 | Instruction::F64ConvertUI32 => (&[I32], &[F64]),
+        Instruction::F64ConvertSI64
+        | Instruction::F64ConvertUI64
+        | Instruction::F64ReinterpretI64 => (&[I64], &[F64]),
+        Instruction::F64PromoteF32 => (&[F32], &[F64]),
+        _ => (&[], &[]),
+    }
+}
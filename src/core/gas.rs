@@ -0,0 +1,133 @@
+use super::common::*;
+use super::costs::*;
+use super::instructions::*;
+use super::program::*;
+use alloc::string::String;
+use alloc::vec::Vec;
+use webassembly::*;
+
+/// How code instrumented by `Program::inject_gas` pays for what it runs.
+#[derive(Clone, PartialEq, Debug)]
+pub enum GasMeter {
+    /// Calls a function imported as `gas` from the named module, passing
+    /// what's about to run costs as an `i64`.
+    Import(String),
+    /// Takes what's about to run costs off a mutable `i64` global exported
+    /// under the name, trapping once it goes negative. The global starts
+    /// out holding the given amount.
+    Global(String, i64),
+}
+
+impl Program {
+    /// Charges for every straight-line run of instructions in every
+    /// function before it runs, at the costs in `costs`. Runs start at the
+    /// top of functions and of the bodies of blocks, loops and ifs, and
+    /// after blocks and branches, so a loop pays for its body on every
+    /// iteration. Returns the index of the gas function or global added.
+    pub fn inject_gas(
+        &mut self,
+        meter: &GasMeter,
+        costs: &CostTable,
+    ) -> Result<usize, &'static str> {
+        let index = match meter {
+            GasMeter::Import(module_name) => {
+                self.import_function(module_name, "gas", &[ValueType::I64], &[])?
+            }
+            GasMeter::Global(name, initial) => {
+                let (_, index) =
+                    self.create_global(ValueType::I64, true, &[Instruction::I64Const(*initial)])?;
+                self.export_global(name, index)?;
+                index
+            }
+        };
+        let charge = |cost: u64| charge(meter, index as u32, cost.min(i64::MAX as u64) as i64);
+        for s in self.sections.iter_mut() {
+            if let Section::Code(s) = s {
+                for c in s.code_blocks.iter_mut() {
+                    let body = core::mem::take(&mut c.instructions);
+                    c.instructions = meter_body(body, costs, &charge);
+                }
+            }
+        }
+        Ok(index)
+    }
+}
+
+// what code paying `cost` into the function or global `gas` looks like
+fn charge(meter: &GasMeter, gas: u32, cost: i64) -> Vec<Instruction> {
+    match meter {
+        GasMeter::Import(_) => vec![Instruction::I64Const(cost), Instruction::Call(gas)],
+        GasMeter::Global(_, _) => vec![
+            Instruction::GlobalGet(gas),
+            Instruction::I64Const(cost),
+            Instruction::I64Sub,
+            Instruction::GlobalSet(gas),
+            Instruction::GlobalGet(gas),
+            Instruction::I64Const(0),
+            Instruction::I64LtS,
+            Instruction::If(EMPTY, vec![Instruction::Unreachable], None),
+        ],
+    }
+}
+
+// puts a charge in front of each run of instructions in a body
+fn meter_body(
+    body: Vec<Instruction>,
+    costs: &CostTable,
+    charge: &dyn Fn(u64) -> Vec<Instruction>,
+) -> Vec<Instruction> {
+    let mut metered = vec![];
+    let mut run = vec![];
+    let mut cost = 0;
+    for instruction in body {
+        cost += costs.cost(&instruction) as u64;
+        let ends_run = match instruction {
+            Instruction::Block(t, body) => {
+                run.push(Instruction::Block(t, meter_body(body, costs, charge)));
+                true
+            }
+            Instruction::Loop(t, body) => {
+                run.push(Instruction::Loop(t, meter_body(body, costs, charge)));
+                true
+            }
+            Instruction::If(t, then_body, else_body) => {
+                run.push(Instruction::If(
+                    t,
+                    meter_body(then_body, costs, charge),
+                    else_body.map(|b| meter_body(b, costs, charge)),
+                ));
+                true
+            }
+            i => {
+                let ends_run = matches!(
+                    i,
+                    Instruction::Br(_)
+                        | Instruction::BrIf(_)
+                        | Instruction::BrTable(_, _)
+                        | Instruction::Return
+                        | Instruction::Unreachable
+                );
+                run.push(i);
+                ends_run
+            }
+        };
+        if ends_run {
+            pay(&mut metered, &mut run, &mut cost, charge);
+        }
+    }
+    pay(&mut metered, &mut run, &mut cost, charge);
+    metered
+}
+
+fn pay(
+    metered: &mut Vec<Instruction>,
+    run: &mut Vec<Instruction>,
+    cost: &mut u64,
+    charge: &dyn Fn(u64) -> Vec<Instruction>,
+) {
+    if *cost > 0 {
+        metered.extend(charge(*cost));
+    }
+    metered.append(run);
+    *cost = 0;
+}
@@ -0,0 +1,196 @@
+use super::common::*;
+use super::instructions::*;
+use super::program::*;
+use crate::parser::wasm::map_name_indices;
+use alloc::vec::Vec;
+use webassembly::*;
+
+/// The index spaces a module's instructions and sections refer into.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum IndexSpace {
+    Type,
+    Function,
+    Table,
+    Memory,
+    Global,
+    Element,
+    Data,
+}
+
+impl Program {
+    /// Rewrites every reference into one of the module's index spaces with
+    /// whatever `f` returns for it. The definitions themselves are left where
+    /// they are, so callers reordering imports or functions use this to bring
+    /// all the references along. Fails if a block type would need a type
+    /// index too large to be written in one, or if table 0 would move while
+    /// `call_indirect`, which uses it without naming it, is around. A "name"
+    /// section is renumbered along with everything else, or dropped if it
+    /// can't be read.
+    pub fn map_indices(
+        &mut self,
+        f: &mut impl FnMut(IndexSpace, usize) -> usize,
+    ) -> Result<(), &'static str> {
+        let mut unreadable_names = false;
+        for s in self.sections.iter_mut() {
+            match s {
+                Section::Custom(s) if s.name == "name" => match map_name_indices(&s.data, f) {
+                    Ok(data) => s.data = data,
+                    Err(_) => unreadable_names = true,
+                },
+                Section::Import(s) => {
+                    for i in s.imports.iter_mut() {
+                        if let WasmImport::Function(i) = i {
+                            i.type_index = f(IndexSpace::Type, i.type_index);
+                        }
+                    }
+                }
+                Section::Function(s) => {
+                    for t in s.function_types.iter_mut() {
+                        *t = f(IndexSpace::Type, *t);
+                    }
+                }
+                Section::Export(s) => {
+                    for e in s.exports.iter_mut() {
+                        match e {
+                            WasmExport::Function(e) => e.index = f(IndexSpace::Function, e.index),
+                            WasmExport::Table(e) => e.index = f(IndexSpace::Table, e.index),
+                            WasmExport::Memory(e) => e.index = f(IndexSpace::Memory, e.index),
+                            WasmExport::Global(e) => e.index = f(IndexSpace::Global, e.index),
+                        }
+                    }
+                }
+                Section::Start(s) => {
+                    s.start_function = f(IndexSpace::Function, s.start_function);
+                }
+                Section::Element(s) => {
+                    for e in s.elements.iter_mut() {
+                        e.table = f(IndexSpace::Table, e.table);
+                        map_instruction_indices(&mut e.value_expression, f)?;
+                        for i in e.functions.iter_mut() {
+                            *i = f(IndexSpace::Function, *i);
+                        }
+                    }
+                }
+                Section::Data(s) => {
+                    for d in s.data_blocks.iter_mut() {
+                        d.memory = f(IndexSpace::Memory, d.memory);
+                        map_instruction_indices(&mut d.offset_expression, f)?;
+                    }
+                }
+                Section::Global(s) => {
+                    for g in s.globals.iter_mut() {
+                        map_instruction_indices(&mut g.value_expression, f)?;
+                    }
+                }
+                Section::Code(s) => {
+                    for c in s.code_blocks.iter_mut() {
+                        map_instruction_indices(&mut c.instructions, f)?;
+                    }
+                }
+                _ => {}
+            }
+        }
+        if unreadable_names {
+            self.sections
+                .retain(|s| !matches!(s, Section::Custom(c) if c.name == "name"));
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn map_instruction_indices(
+    instructions: &mut [Instruction],
+    f: &mut impl FnMut(IndexSpace, usize) -> usize,
+) -> Result<(), &'static str> {
+    for i in instructions.iter_mut() {
+        match i {
+            Instruction::Block(t, b) | Instruction::Loop(t, b) => {
+                map_block_type(t, f)?;
+                map_instruction_indices(b, f)?;
+            }
+            Instruction::If(t, b, e) => {
+                map_block_type(t, f)?;
+                map_instruction_indices(b, f)?;
+                if let Some(e) = e {
+                    map_instruction_indices(e, f)?;
+                }
+            }
+            Instruction::Call(i) => *i = f(IndexSpace::Function, *i as usize) as u32,
+            Instruction::CallIndirect(i) => {
+                *i = f(IndexSpace::Type, *i as usize) as u32;
+                // the table it calls through isn't encoded, it's always 0
+                if f(IndexSpace::Table, 0) != 0 {
+                    return Err("call_indirect can only use table 0");
+                }
+            }
+            Instruction::GlobalGet(i) | Instruction::GlobalSet(i) => {
+                *i = f(IndexSpace::Global, *i as usize) as u32
+            }
+            Instruction::Unknown(op, immediates) => {
+                let spaces: &[IndexSpace] = match op {
+                    // return_call, ref.func
+                    0x12 | 0xD2 => &[IndexSpace::Function],
+                    // return_call_indirect
+                    0x13 => &[IndexSpace::Type, IndexSpace::Table],
+                    // table.get, table.set
+                    0x25 | 0x26 => &[IndexSpace::Table],
+                    _ => &[],
+                };
+                map_immediate_indices(immediates, spaces, f);
+            }
+            Instruction::Prefixed(PREFIX_MISC, op, immediates) => {
+                let spaces: &[IndexSpace] = match op {
+                    // memory.init, data.drop
+                    8 | 9 => &[IndexSpace::Data],
+                    // table.init
+                    12 => &[IndexSpace::Element, IndexSpace::Table],
+                    // elem.drop
+                    13 => &[IndexSpace::Element],
+                    // table.copy
+                    14 => &[IndexSpace::Table, IndexSpace::Table],
+                    // table.grow, table.size, table.fill
+                    15..=17 => &[IndexSpace::Table],
+                    _ => &[],
+                };
+                map_immediate_indices(immediates, spaces, f);
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+// block types below EMPTY's byte range are (single byte) type indices
+fn map_block_type(
+    t: &mut u8,
+    f: &mut impl FnMut(IndexSpace, usize) -> usize,
+) -> Result<(), &'static str> {
+    if *t < 0x40 {
+        let mapped = f(IndexSpace::Type, *t as usize);
+        if mapped >= 0x40 {
+            return Err("type index too large for a block type");
+        }
+        *t = mapped as u8;
+    }
+    Ok(())
+}
+
+// rewrites the leading LEB128 immediates of an unmodeled instruction
+fn map_immediate_indices(
+    immediates: &mut Vec<u8>,
+    spaces: &[IndexSpace],
+    f: &mut impl FnMut(IndexSpace, usize) -> usize,
+) {
+    let mut mapped = vec![];
+    let mut position = 0;
+    for space in spaces.iter() {
+        let (value, byte_count) = match immediates[position..].try_extract_u32(0) {
+            Ok(r) => r,
+            Err(_) => return,
+        };
+        mapped.extend((f(*space, value as usize) as u32).to_wasm_bytes());
+        position += byte_count;
+    }
+    mapped.extend(&immediates[position..]);
+    *immediates = mapped;
+}
@@ -1,11 +1,21 @@
 use alloc::vec::Vec;
 use serde::{Deserialize, Serialize};
 
+pub(crate) const PREFIX_MISC: u8 = 0xFC;
+pub(crate) const PREFIX_SIMD: u8 = 0xFD;
+pub(crate) const PREFIX_THREADS: u8 = 0xFE;
+
 #[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
 #[serde(tag = "op", content = "params")]
 #[repr(C)]
 pub enum Instruction {
     Raw(u8),
+    /// An opcode watson doesn't model yet, along with the encoded bytes of
+    /// its immediates.
+    Unknown(u8, Vec<u8>),
+    /// A `0xFC`/`0xFD`/`0xFE` prefixed opcode with its sub-opcode and the
+    /// encoded bytes of its immediates.
+    Prefixed(u8, u32, Vec<u8>),
     Unreachable,
     Nop,
     Block(u8, Vec<Instruction>),
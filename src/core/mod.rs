@@ -1,6 +1,24 @@
 pub mod common;
 pub use common::*;
 
+mod costs;
+pub use costs::*;
+
+mod dead_code;
+pub use dead_code::*;
+
+mod features;
+pub use features::*;
+
+mod gas;
+pub use gas::*;
+
+mod function_builder;
+pub use function_builder::*;
+
+mod indices;
+pub use indices::*;
+
 mod instructions;
 pub use instructions::*;
 
@@ -1,17 +1,22 @@
 use super::common::*;
+use super::indices::*;
+use super::instructions::*;
 use super::view::*;
 use crate::alloc::string::ToString;
 use alloc::vec::Vec;
 use serde::{Deserialize, Serialize};
+use webassembly::*;
 
-#[derive(PartialEq, Debug, Serialize, Deserialize)]
+#[derive(Default, PartialEq, Debug, Serialize, Deserialize)]
 #[repr(C)]
 pub struct ProgramView<'a> {
     #[serde(borrow)]
     pub sections: Vec<SectionView<'a>>,
+    #[serde(skip)]
+    pub(crate) raw_sections: Vec<&'a [u8]>,
 }
 
-#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[derive(Clone, Default, PartialEq, Debug, Serialize, Deserialize)]
 #[repr(C)]
 pub struct Program {
     pub sections: Vec<Section>,
@@ -80,6 +85,11 @@ impl<'p> ProgramView<'p> {
         }
     }
 
+    /// The exact bytes of each parsed section, header included.
+    pub fn raw_sections(&self) -> &[&'p [u8]] {
+        &self.raw_sections
+    }
+
     pub fn to_owned(&self) -> Program {
         Program {
             sections: self
@@ -91,14 +101,6 @@ impl<'p> ProgramView<'p> {
     }
 }
 
-impl Default for Program {
-    fn default() -> Program {
-        Program {
-            sections: Vec::new(),
-        }
-    }
-}
-
 impl Program {
     #[inline]
     pub fn new() -> Self {
@@ -169,6 +171,17 @@ impl Program {
         name: &str,
         inputs: &[ValueType],
         outputs: &[ValueType],
+    ) -> Result<usize, &'static str> {
+        self.import_function("env", name, inputs, outputs)
+    }
+
+    /// Imports a function from `module_name`, returning its function index.
+    pub fn import_function(
+        &mut self,
+        module_name: &str,
+        name: &str,
+        inputs: &[ValueType],
+        outputs: &[ValueType],
     ) -> Result<usize, &'static str> {
         let type_section = match self
             .sections
@@ -204,31 +217,115 @@ impl Program {
             unreachable!()
         };
 
-        let imports_section = match self
-            .sections
-            .iter_mut()
-            .find(|x| matches!(x, Section::Import(_)))
-        {
-            Some(x) => x,
-            None => {
-                self.sections.push(Section::Import(ImportSection {
-                    imports: Vec::new(),
-                }));
-                let len = self.sections.len() - 1;
-                &mut self.sections[len]
-            }
-        };
+        self.add_import(WasmImport::Function(FunctionImport {
+            module_name: module_name.to_string(),
+            name: name.to_string(),
+            type_index,
+        }))
+    }
 
-        if let Section::Import(s) = imports_section {
-            s.imports.push(WasmImport::Function(FunctionImport {
-                module_name: "env".to_string(),
-                name: name.to_string(),
-                type_index,
-            }));
-            Ok(s.imports.len() - 1)
+    /// Adds an import after any others of its kind, renumbering every
+    /// reference to the functions, globals, tables or memories defined in
+    /// this module that it shifts along. Returns the import's index in its
+    /// index space. Fails if it would move table 0 from under
+    /// `call_indirect`.
+    pub fn add_import(&mut self, import: WasmImport) -> Result<usize, &'static str> {
+        let space = import_space(&import);
+        let index = self.import_count(space);
+        self.map_indices(&mut |s, i| if s == space && i >= index { i + 1 } else { i })?;
+        let (import_section, _) = self.ensure_imports();
+        import_section.imports.push(import);
+        Ok(index)
+    }
+
+    /// Removes a function, imported or defined, renumbering every reference
+    /// to the functions after it. Fails if the function is still referenced.
+    pub fn remove_function(&mut self, index: usize) -> Result<(), &'static str> {
+        self.remove(IndexSpace::Function, index)
+    }
+
+    /// Removes a global, imported or defined, renumbering every reference
+    /// to the globals after it. Fails if the global is still referenced.
+    pub fn remove_global(&mut self, index: usize) -> Result<(), &'static str> {
+        self.remove(IndexSpace::Global, index)
+    }
+
+    /// Removes a table, imported or defined, renumbering every reference
+    /// to the tables after it. Fails if the table is still referenced.
+    pub fn remove_table(&mut self, index: usize) -> Result<(), &'static str> {
+        self.remove(IndexSpace::Table, index)
+    }
+
+    fn import_count(&self, space: IndexSpace) -> usize {
+        self.sections
+            .iter()
+            .map(|s| match s {
+                Section::Import(s) => s
+                    .imports
+                    .iter()
+                    .filter(|i| import_space(i) == space)
+                    .count(),
+                _ => 0,
+            })
+            .sum()
+    }
+
+    fn remove(&mut self, space: IndexSpace, index: usize) -> Result<(), &'static str> {
+        let mut referenced = false;
+        self.map_indices(&mut |s, i| {
+            if s == space && i == index {
+                referenced = true;
+            }
+            i
+        })?;
+        if referenced {
+            return Err("cannot remove something that is still referenced");
+        }
+        let import_count = self.import_count(space);
+        let mut removed = false;
+        if index < import_count {
+            let (import_section, _) = self.ensure_imports();
+            let position = import_section
+                .imports
+                .iter()
+                .enumerate()
+                .filter(|(_, i)| import_space(i) == space)
+                .nth(index)
+                .map(|(p, _)| p)
+                .unwrap();
+            import_section.imports.remove(position);
+            removed = true;
         } else {
-            unreachable!()
+            let d = index - import_count;
+            for s in self.sections.iter_mut() {
+                match s {
+                    Section::Function(s)
+                        if space == IndexSpace::Function && d < s.function_types.len() =>
+                    {
+                        s.function_types.remove(d);
+                        removed = true;
+                    }
+                    Section::Code(s)
+                        if space == IndexSpace::Function && d < s.code_blocks.len() =>
+                    {
+                        s.code_blocks.remove(d);
+                    }
+                    Section::Global(s) if space == IndexSpace::Global && d < s.globals.len() => {
+                        s.globals.remove(d);
+                        removed = true;
+                    }
+                    Section::Table(s) if space == IndexSpace::Table && d < s.tables.len() => {
+                        s.tables.remove(d);
+                        removed = true;
+                    }
+                    _ => {}
+                }
+            }
+        }
+        if !removed {
+            return Err("index out of range");
         }
+        self.map_indices(&mut |s, i| if s == space && i > index { i - 1 } else { i })
     }
 
     pub fn create_export<'a>(
@@ -237,10 +334,7 @@ impl Program {
         inputs: &[ValueType],
         outputs: &[ValueType],
     ) -> Result<(&'a mut CodeBlock, usize), &'static str> {
-        let import_count = {
-            let (import_section, _) = self.ensure_imports();
-            import_section.imports.len()
-        };
+        let import_count = self.import_count(IndexSpace::Function);
         let type_section = match self
             .sections
             .iter_mut()
@@ -353,10 +447,7 @@ impl Program {
         inputs: &[ValueType],
         outputs: &[ValueType],
     ) -> Result<(&'a mut CodeBlock, usize), &'static str> {
-        let import_count = {
-            let (import_section, _) = self.ensure_imports();
-            import_section.imports.len()
-        };
+        let import_count = self.import_count(IndexSpace::Function);
         let type_section = match self
             .sections
             .iter_mut()
@@ -503,12 +594,15 @@ impl Program {
         }
     }
 
+    /// Defines a memory exported as `name`, returning it and its memory
+    /// index.
     pub fn create_memory<'a>(
         &'a mut self,
         name: &str,
         min: usize,
         max: Option<usize>,
     ) -> Result<(&'a mut WasmMemory, usize), &'static str> {
+        let import_count = self.import_count(IndexSpace::Memory);
         let mem_idx;
         let mem_sec_idx;
         {
@@ -517,6 +611,8 @@ impl Program {
             memory_section.memories.push(WasmMemory {
                 min_pages: min,
                 max_pages: max,
+                shared: false,
+                memory64: false,
             });
             mem_idx = memory_section.memories.len() - 1;
         }
@@ -525,14 +621,307 @@ impl Program {
 
             export_section.exports.push(WasmExport::Memory(Export {
                 name: name.to_string(),
-                index: mem_idx,
+                index: import_count + mem_idx,
             }));
         }
 
         if let Section::Memory(s) = &mut self.sections[mem_sec_idx] {
-            Ok((&mut s.memories[mem_idx], mem_idx))
+            Ok((&mut s.memories[mem_idx], import_count + mem_idx))
         } else {
             unreachable!();
         }
     }
+
+    /// Imports a memory from `module_name`, returning its memory index.
+    pub fn import_memory(
+        &mut self,
+        module_name: &str,
+        name: &str,
+        min: usize,
+        max: Option<usize>,
+    ) -> Result<usize, &'static str> {
+        self.add_import(WasmImport::Memory(MemoryImport {
+            module_name: module_name.to_string(),
+            name: name.to_string(),
+            min_pages: min,
+            max_pages: max,
+            shared: false,
+            memory64: false,
+        }))
+    }
+
+    /// Imports a table of functions from `module_name`, returning its table
+    /// index. Fails if `call_indirect` is using a table defined here.
+    pub fn import_table(
+        &mut self,
+        module_name: &str,
+        name: &str,
+        min: usize,
+        max: Option<usize>,
+    ) -> Result<usize, &'static str> {
+        self.add_import(WasmImport::Table(TableImport {
+            module_name: module_name.to_string(),
+            name: name.to_string(),
+            element_type: ANYFUNC,
+            min,
+            max,
+        }))
+    }
+
+    /// Imports a global from `module_name`, returning its global index.
+    pub fn import_global(
+        &mut self,
+        module_name: &str,
+        name: &str,
+        value_type: ValueType,
+        is_mutable: bool,
+    ) -> Result<usize, &'static str> {
+        self.add_import(WasmImport::Global(GlobalImport {
+            module_name: module_name.to_string(),
+            name: name.to_string(),
+            value_type,
+            is_mutable,
+        }))
+    }
+
+    /// Defines a global initialized by `value_expression`, returning it and
+    /// its global index.
+    pub fn create_global(
+        &mut self,
+        value_type: ValueType,
+        is_mutable: bool,
+        value_expression: &[Instruction],
+    ) -> Result<(&mut Global, usize), &'static str> {
+        let import_count = self.import_count(IndexSpace::Global);
+        let (global_section, _) = self.ensure_globals();
+        global_section.globals.push(Global {
+            value_type,
+            is_mutable,
+            value_expression: value_expression.to_vec(),
+        });
+        let idx = global_section.globals.len() - 1;
+        Ok((&mut global_section.globals[idx], import_count + idx))
+    }
+
+    /// Defines a table of functions, returning it and its table index.
+    pub fn create_table(
+        &mut self,
+        min: usize,
+        max: Option<usize>,
+    ) -> Result<(&mut Table, usize), &'static str> {
+        let import_count = self.import_count(IndexSpace::Table);
+        let (table_section, _) = self.ensure_tables();
+        table_section.tables.push(Table {
+            element_type: ANYFUNC,
+            min,
+            max,
+        });
+        let idx = table_section.tables.len() - 1;
+        Ok((&mut table_section.tables[idx], import_count + idx))
+    }
+
+    /// Adds an element segment placing `functions` into `table` at `offset`,
+    /// returning the segment's index.
+    pub fn add_element_segment(
+        &mut self,
+        table: usize,
+        offset: &[Instruction],
+        functions: &[usize],
+    ) -> Result<usize, &'static str> {
+        if table >= self.index_space_len(IndexSpace::Table) {
+            return Err("invalid table index");
+        }
+        let function_count = self.index_space_len(IndexSpace::Function);
+        if functions.iter().any(|f| *f >= function_count) {
+            return Err("invalid function index");
+        }
+        let (element_section, _) = self.ensure_elements();
+        element_section.elements.push(WasmElement {
+            table,
+            value_expression: offset.to_vec(),
+            functions: functions.to_vec(),
+        });
+        Ok(element_section.elements.len() - 1)
+    }
+
+    /// Adds a data segment writing `data` into `memory` at `offset`,
+    /// returning the segment's index.
+    pub fn add_data_segment(
+        &mut self,
+        memory: usize,
+        offset: &[Instruction],
+        data: &[u8],
+    ) -> Result<usize, &'static str> {
+        if memory >= self.index_space_len(IndexSpace::Memory) {
+            return Err("invalid memory index");
+        }
+        let (data_section, _) = self.ensure_data();
+        data_section.data_blocks.push(DataBlock {
+            memory,
+            offset_expression: offset.to_vec(),
+            data: data.to_vec(),
+            passive: false,
+        });
+        let count = data_section.data_blocks.len();
+        for s in self.sections.iter_mut() {
+            if let Section::DataCount(s) = s {
+                s.count = count;
+            }
+        }
+        Ok(count - 1)
+    }
+
+    /// Makes `function` the module's start function, replacing any other.
+    pub fn set_start(&mut self, function: usize) -> Result<(), &'static str> {
+        if function >= self.index_space_len(IndexSpace::Function) {
+            return Err("invalid function index");
+        }
+        match self
+            .sections
+            .iter_mut()
+            .find(|x| matches!(x, Section::Start(_)))
+        {
+            Some(Section::Start(s)) => s.start_function = function,
+            _ => self.sections.push(Section::Start(StartSection {
+                start_function: function,
+            })),
+        }
+        Ok(())
+    }
+
+    /// Exports the global at `index` as `name`.
+    pub fn export_global(&mut self, name: &str, index: usize) -> Result<(), &'static str> {
+        if index >= self.index_space_len(IndexSpace::Global) {
+            return Err("invalid global index");
+        }
+        let (export_section, _) = self.ensure_exports();
+        export_section.exports.push(WasmExport::Global(Export {
+            name: name.to_string(),
+            index,
+        }));
+        Ok(())
+    }
+
+    /// Exports the table at `index` as `name`.
+    pub fn export_table(&mut self, name: &str, index: usize) -> Result<(), &'static str> {
+        if index >= self.index_space_len(IndexSpace::Table) {
+            return Err("invalid table index");
+        }
+        let (export_section, _) = self.ensure_exports();
+        export_section.exports.push(WasmExport::Table(Export {
+            name: name.to_string(),
+            index,
+        }));
+        Ok(())
+    }
+
+    // the number of imported and defined items in an index space
+    fn index_space_len(&self, space: IndexSpace) -> usize {
+        let defined: usize = self
+            .sections
+            .iter()
+            .map(|s| match (s, space) {
+                (Section::Function(s), IndexSpace::Function) => s.function_types.len(),
+                (Section::Table(s), IndexSpace::Table) => s.tables.len(),
+                (Section::Memory(s), IndexSpace::Memory) => s.memories.len(),
+                (Section::Global(s), IndexSpace::Global) => s.globals.len(),
+                _ => 0,
+            })
+            .sum();
+        self.import_count(space) + defined
+    }
+
+    fn ensure_globals(&mut self) -> (&mut GlobalSection, usize) {
+        let idx = match self
+            .sections
+            .iter()
+            .enumerate()
+            .find(|x| matches!(x, (_, Section::Global(_))))
+        {
+            Some(x) => x.0,
+            None => {
+                self.sections
+                    .push(Section::Global(GlobalSection { globals: vec![] }));
+                self.sections.len() - 1
+            }
+        };
+        if let Section::Global(s) = &mut self.sections[idx] {
+            (s, idx)
+        } else {
+            unreachable!();
+        }
+    }
+
+    fn ensure_tables(&mut self) -> (&mut TableSection, usize) {
+        let idx = match self
+            .sections
+            .iter()
+            .enumerate()
+            .find(|x| matches!(x, (_, Section::Table(_))))
+        {
+            Some(x) => x.0,
+            None => {
+                self.sections
+                    .push(Section::Table(TableSection { tables: vec![] }));
+                self.sections.len() - 1
+            }
+        };
+        if let Section::Table(s) = &mut self.sections[idx] {
+            (s, idx)
+        } else {
+            unreachable!();
+        }
+    }
+
+    fn ensure_elements(&mut self) -> (&mut ElementSection, usize) {
+        let idx = match self
+            .sections
+            .iter()
+            .enumerate()
+            .find(|x| matches!(x, (_, Section::Element(_))))
+        {
+            Some(x) => x.0,
+            None => {
+                self.sections
+                    .push(Section::Element(ElementSection { elements: vec![] }));
+                self.sections.len() - 1
+            }
+        };
+        if let Section::Element(s) = &mut self.sections[idx] {
+            (s, idx)
+        } else {
+            unreachable!();
+        }
+    }
+
+    fn ensure_data(&mut self) -> (&mut DataSection, usize) {
+        let idx = match self
+            .sections
+            .iter()
+            .enumerate()
+            .find(|x| matches!(x, (_, Section::Data(_))))
+        {
+            Some(x) => x.0,
+            None => {
+                self.sections.push(Section::Data(DataSection {
+                    data_blocks: vec![],
+                }));
+                self.sections.len() - 1
+            }
+        };
+        if let Section::Data(s) = &mut self.sections[idx] {
+            (s, idx)
+        } else {
+            unreachable!();
+        }
+    }
+}
+
+fn import_space(import: &WasmImport) -> IndexSpace {
+    match import {
+        WasmImport::Function(_) => IndexSpace::Function,
+        WasmImport::Global(_) => IndexSpace::Global,
+        WasmImport::Memory(_) => IndexSpace::Memory,
+        WasmImport::Table(_) => IndexSpace::Table,
+    }
 }
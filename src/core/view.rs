@@ -96,6 +96,10 @@ pub struct MemoryImportView<'a> {
     pub name: &'a str,
     pub min_pages: usize,
     pub max_pages: Option<usize>,
+    #[serde(default)]
+    pub shared: bool,
+    #[serde(default)]
+    pub memory64: bool,
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
@@ -158,6 +162,8 @@ impl<'a> ImportSectionView<'a> {
                         name: x.name.to_string(),
                         min_pages: x.min_pages,
                         max_pages: x.max_pages,
+                        shared: x.shared,
+                        memory64: x.memory64,
                     }),
                     WasmImportView::Table(x) => WasmImport::Table(TableImport {
                         module_name: x.module_name.to_string(),
@@ -179,6 +185,8 @@ pub struct DataBlockView<'a> {
     pub offset_expression: Vec<Instruction>,
     #[serde(borrow)]
     pub data: &'a [u8],
+    #[serde(default)]
+    pub passive: bool,
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
@@ -198,6 +206,7 @@ impl<'a> DataSectionView<'a> {
                     memory: x.memory,
                     offset_expression: x.offset_expression.clone(),
                     data: x.data.to_vec(),
+                    passive: x.passive,
                 })
                 .collect::<Vec<DataBlock>>(),
         }
@@ -236,6 +245,8 @@ pub enum SectionView<'a> {
     Custom(CustomSectionView<'a>),
     //#[serde(rename = "element")]
     Element(ElementSection),
+    //#[serde(rename = "data_count")]
+    DataCount(DataCountSection),
 }
 
 impl<'a> SectionView<'a> {
@@ -253,6 +264,7 @@ impl<'a> SectionView<'a> {
             SectionView::Data(s) => Section::Data(s.to_owned()),
             SectionView::Custom(s) => Section::Custom(s.to_owned()),
             SectionView::Element(s) => Section::Element(s.clone()),
+            SectionView::DataCount(s) => Section::DataCount(s.clone()),
         }
     }
 }
@@ -0,0 +1,458 @@
+//! A stub for the GDB remote serial protocol, so that `lldb` can debug a call
+//! running in the interpreter with `process connect --plugin wasm
+//! connect://localhost:<port>`. It speaks the protocol's WebAssembly
+//! flavour: code addresses are offsets into the module with [`CODE`] set,
+//! data addresses are offsets into linear memory, and the module's bytes are
+//! listed as a library so that the DWARF in them can be read.
+
+use crate::interpreter::{Debugger, InterpretableProgram, StopReason, WasmValue};
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use std::io::{Read, Write};
+use std::net::{TcpListener, ToSocketAddrs};
+
+/// Set in addresses that are into the module's bytes rather than memory.
+pub const CODE: u64 = 0x4000_0000_0000_0000;
+
+const TRIPLE: &str = "wasm32-unknown-unknown-wasm";
+const DISCONNECTED: &str = "connection to the debugger failed";
+
+/// Serves a debugger connected over the protocol, which sees the call as a
+/// process with a single thread and a 64-bit program counter.
+pub struct GdbStub<T>
+where
+    T: InterpretableProgram,
+{
+    debugger: Debugger<T>,
+    name: String,
+    no_ack: bool,
+    // the reply to give when asked why the call stopped
+    stop: String,
+    breakpoints: Vec<(u64, usize)>,
+    watchpoints: Vec<(u64, u64, usize)>,
+    // how the call ended, once it can't be carried on with
+    ended: Option<StopReason>,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn value_bytes(value: WasmValue) -> Vec<u8> {
+    match value {
+        WasmValue::I32(v) => v.to_le_bytes().to_vec(),
+        WasmValue::I64(v) => v.to_le_bytes().to_vec(),
+        WasmValue::F32(v) => v.to_bits().to_le_bytes().to_vec(),
+        WasmValue::F64(v) => v.to_bits().to_le_bytes().to_vec(),
+    }
+}
+
+// whether a packet carries on with the call, and if so whether by a step,
+// ignoring any signal it would be given
+fn resumption(packet: &str) -> Option<bool> {
+    let action = packet.strip_prefix("vCont;").unwrap_or(packet);
+    match action.chars().next() {
+        _ if packet == "vCont?" => None,
+        Some('c') | Some('C') => Some(false),
+        Some('s') | Some('S') => Some(true),
+        _ => None,
+    }
+}
+
+// the numbers in a packet's arguments, which are hex however they're
+// separated
+fn numbers(args: &str) -> Option<Vec<u64>> {
+    args.split([',', ';', ':'])
+        .map(|n| u64::from_str_radix(n, 16).ok())
+        .collect()
+}
+
+impl<T> GdbStub<T>
+where
+    T: InterpretableProgram,
+{
+    pub fn new(debugger: Debugger<T>) -> Self {
+        GdbStub {
+            debugger,
+            name: "module.wasm".to_string(),
+            no_ack: false,
+            stop: "T05thread:1;".to_string(),
+            breakpoints: vec![],
+            watchpoints: vec![],
+            ended: None,
+        }
+    }
+
+    /// Sets the name the module is listed under.
+    pub fn name(&mut self, name: &str) -> &mut Self {
+        self.name = name.to_string();
+        self
+    }
+
+    pub fn debugger(&mut self) -> &mut Debugger<T> {
+        &mut self.debugger
+    }
+
+    pub fn into_debugger(self) -> Debugger<T> {
+        self.debugger
+    }
+
+    /// Waits for a debugger to connect to `address`, then serves it.
+    pub fn listen(
+        &mut self,
+        address: impl ToSocketAddrs,
+    ) -> Result<Option<StopReason>, &'static str> {
+        let listener = TcpListener::bind(address).map_err(|_| "could not listen on address")?;
+        let (stream, _) = listener.accept().map_err(|_| DISCONNECTED)?;
+        // packets are small and each waits on the last
+        stream.set_nodelay(true).map_err(|_| DISCONNECTED)?;
+        self.serve(stream)
+    }
+
+    /// Serves a connected debugger until it detaches or kills the call, or
+    /// the call returns or stops in a way it can't be carried on from here,
+    /// like calling an import the interpreter wasn't given. How the call
+    /// ended is given back if it did. Nothing runs between packets, so
+    /// interrupts are ignored.
+    pub fn serve(
+        &mut self,
+        mut stream: impl Read + Write,
+    ) -> Result<Option<StopReason>, &'static str> {
+        loop {
+            let packet = match self.receive(&mut stream)? {
+                Some(p) => p,
+                None => return Ok(self.ended.take()),
+            };
+            let reply = match packet.as_str() {
+                "k" => return Ok(self.ended.take()),
+                "D" | "D;1" => {
+                    self.send(&mut stream, "OK")?;
+                    return Ok(self.ended.take());
+                }
+                "QStartNoAckMode" => {
+                    self.send(&mut stream, "OK")?;
+                    self.no_ack = true;
+                    continue;
+                }
+                _ if resumption(&packet).is_some() => {
+                    match self.run(resumption(&packet) == Some(true)) {
+                        Ok(reply) => reply,
+                        Err(exit) => {
+                            self.send(&mut stream, &exit)?;
+                            return Ok(self.ended.take());
+                        }
+                    }
+                }
+                _ => self.reply(&packet),
+            };
+            self.send(&mut stream, &reply)?;
+        }
+    }
+
+    // carries on with the call, giving the stop reply or, if it's over, the
+    // reply that says so
+    fn run(&mut self, step: bool) -> Result<String, String> {
+        if self.ended.is_some() {
+            return Err("X04".to_string());
+        }
+        let stop = if step {
+            self.debugger.step()
+        } else {
+            self.debugger.resume()
+        };
+        let stop = match stop {
+            Ok(s) => s,
+            Err(e) => StopReason::Trap(e),
+        };
+        self.stop = match &stop {
+            StopReason::Breakpoint(_) => "T05thread:1;reason:breakpoint;".to_string(),
+            StopReason::Step => "T05thread:1;reason:trace;".to_string(),
+            StopReason::Watchpoint { id, .. } => {
+                let address = self
+                    .watchpoints
+                    .iter()
+                    .find(|w| w.2 == *id)
+                    .map_or(0, |w| w.0);
+                format!("T05watch:{:x};thread:1;reason:watchpoint;", address)
+            }
+            StopReason::Unreachable => {
+                format!(
+                    "T04thread:1;reason:exception;description:{};",
+                    hex(b"unreachable")
+                )
+            }
+            StopReason::Trap(e) => format!(
+                "T04thread:1;reason:exception;description:{};",
+                hex(e.as_bytes())
+            ),
+            StopReason::Complete(results) => {
+                let code = match results.first() {
+                    Some(WasmValue::I32(c)) => *c as u8,
+                    _ => 0,
+                };
+                self.ended = Some(stop);
+                return Err(format!("W{:02x}", code));
+            }
+            StopReason::CallImport(_) => {
+                self.ended = Some(stop);
+                return Err("X06".to_string());
+            }
+            StopReason::OutOfFuel => {
+                self.ended = Some(stop);
+                return Err("X18".to_string());
+            }
+        };
+        // traps stop where they happened so they can be looked into, but
+        // there's no carrying on from them
+        if let StopReason::Unreachable | StopReason::Trap(_) = stop {
+            self.ended = Some(stop);
+        }
+        Ok(self.stop.clone())
+    }
+
+    fn pc(&self) -> u64 {
+        self.debugger
+            .location()
+            .and_then(|l| l.offset)
+            .map_or(0, |o| CODE | o as u64)
+    }
+
+    // the reply to anything that doesn't run the call
+    fn reply(&mut self, packet: &str) -> String {
+        if let Some(args) = packet.strip_prefix("qXfer:libraries:read::") {
+            return self.libraries(args);
+        }
+        let (name, args) = match packet.find(':') {
+            Some(i) if packet.starts_with('q') => (&packet[..i], &packet[i + 1..]),
+            _ => (packet, ""),
+        };
+        match name {
+            "?" => self.stop.clone(),
+            "qSupported" => "PacketSize=4000;qXfer:libraries:read+;QStartNoAckMode+".to_string(),
+            "qHostInfo" => format!("triple:{};endian:little;ptrsize:4;", hex(TRIPLE.as_bytes())),
+            "qProcessInfo" => format!(
+                "pid:1;parent-pid:1;endian:little;ptrsize:4;triple:{};",
+                hex(TRIPLE.as_bytes())
+            ),
+            "qC" => "QC1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            "qAttached" => "1".to_string(),
+            "vCont?" => "vCont;c;C;s;S".to_string(),
+            "qRegisterInfo0" => "name:pc;alt-name:pc;bitsize:64;offset:0;encoding:uint;\
+                format:hex;set:General Purpose Registers;gcc:16;dwarf:16;generic:pc;"
+                .to_string(),
+            "g" | "p0" => hex(&self.pc().to_le_bytes()),
+            "qWasmCallStack" => match self.debugger.frames() {
+                Ok(frames) => frames
+                    .iter()
+                    .map(|f| {
+                        let pc = f.location.offset.map_or(0, |o| CODE | o as u64);
+                        hex(&pc.to_le_bytes())
+                    })
+                    .collect(),
+                Err(_) => "E01".to_string(),
+            },
+            "qWasmLocal" | "qWasmGlobal" | "qWasmStackValue" => self.value(name, args),
+            "qWasmMem" => match numbers(args).as_deref() {
+                Some([_, address, length]) => self.memory(*address, *length),
+                _ => "E01".to_string(),
+            },
+            _ => self.command(packet),
+        }
+    }
+
+    // the replies to packets named by their first letter
+    fn command(&mut self, packet: &str) -> String {
+        let (kind, args) = packet.split_at(packet.len().min(1));
+        match kind {
+            "H" | "T" => "OK".to_string(),
+            "m" => match numbers(args).as_deref() {
+                Some([address, length]) => self.memory(*address, *length),
+                _ => "E01".to_string(),
+            },
+            "Z" | "z" => match numbers(args).as_deref() {
+                Some([kind, address, length]) => {
+                    let insert = packet.starts_with('Z');
+                    self.breakpoint(insert, *kind, *address, *length)
+                }
+                _ => "E01".to_string(),
+            },
+            // registers can't be written and anything else isn't supported
+            "P" | "G" => "E01".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    fn memory(&self, address: u64, length: u64) -> String {
+        if address & CODE != 0 {
+            let bytes = self.debugger.bytes();
+            let start = ((address & !CODE) as usize).min(bytes.len());
+            let end = start.saturating_add(length as usize).min(bytes.len());
+            return hex(&bytes[start..end]);
+        }
+        let range = address as usize..address.saturating_add(length) as usize;
+        match self.debugger.read_memory(range) {
+            Ok(bytes) => hex(&bytes),
+            Err(_) => "E01".to_string(),
+        }
+    }
+
+    // a local, global or operand stack value of a frame
+    fn value(&self, name: &str, args: &str) -> String {
+        let (frame, index) = match numbers(args).as_deref() {
+            Some([frame, index]) => (*frame as usize, *index as usize),
+            _ => return "E01".to_string(),
+        };
+        let value = if name == "qWasmGlobal" {
+            self.debugger.globals().get(index).copied()
+        } else {
+            let frames = self.debugger.frames().unwrap_or_default();
+            frames.get(frame).and_then(|f| {
+                if name == "qWasmLocal" {
+                    f.locals.get(index).copied()
+                } else {
+                    f.operands.get(index).copied()
+                }
+            })
+        };
+        match value {
+            Some(v) => hex(&value_bytes(v)),
+            None => "E03".to_string(),
+        }
+    }
+
+    fn breakpoint(&mut self, insert: bool, kind: u64, address: u64, length: u64) -> String {
+        match (kind, insert) {
+            // software and hardware breakpoints are the same thing here
+            (0, true) | (1, true) => {
+                if address & CODE == 0 {
+                    return "E01".to_string();
+                }
+                match self.debugger.break_at_offset((address & !CODE) as usize) {
+                    Ok(id) => {
+                        self.breakpoints.push((address, id));
+                        "OK".to_string()
+                    }
+                    Err(_) => "E01".to_string(),
+                }
+            }
+            (0, false) | (1, false) => {
+                let debugger = &mut self.debugger;
+                self.breakpoints.retain(|(a, id)| {
+                    if *a == address {
+                        debugger.remove_breakpoint(*id);
+                    }
+                    *a != address
+                });
+                "OK".to_string()
+            }
+            // only writes are watched for
+            (2, true) => {
+                let range = address as usize..address.saturating_add(length) as usize;
+                match self.debugger.watch_memory(range) {
+                    Ok(id) => {
+                        self.watchpoints.push((address, length, id));
+                        "OK".to_string()
+                    }
+                    Err(_) => "E01".to_string(),
+                }
+            }
+            (2, false) => {
+                let debugger = &mut self.debugger;
+                self.watchpoints.retain(|(a, l, id)| {
+                    if (*a, *l) == (address, length) {
+                        debugger.unwatch(*id);
+                    }
+                    (*a, *l) != (address, length)
+                });
+                "OK".to_string()
+            }
+            _ => String::new(),
+        }
+    }
+
+    // the part of the library list asked for by `::offset,length`
+    fn libraries(&self, args: &str) -> String {
+        let list = format!(
+            "<library-list><library name=\"{}\"><section address=\"0x{:x}\"/></library>\
+             </library-list>",
+            self.name, CODE
+        );
+        let (offset, length) = match numbers(args).as_deref() {
+            Some([offset, length]) => (*offset as usize, *length as usize),
+            _ => return "E01".to_string(),
+        };
+        let start = offset.min(list.len());
+        let end = start.saturating_add(length).min(list.len());
+        let more = if end < list.len() { "m" } else { "l" };
+        format!("{}{}", more, &list[start..end])
+    }
+
+    // the next packet's contents, acknowledging it unless that's been
+    // turned off, or nothing once the connection closes
+    fn receive<S: Read + Write>(&mut self, stream: &mut S) -> Result<Option<String>, &'static str> {
+        let mut byte = [0];
+        loop {
+            match stream.read(&mut byte) {
+                Ok(0) => return Ok(None),
+                Ok(_) => {}
+                Err(_) => return Err(DISCONNECTED),
+            }
+            // acknowledgements and interrupts are all that's outside packets
+            if byte[0] != b'$' {
+                continue;
+            }
+            let mut data = vec![];
+            loop {
+                stream.read_exact(&mut byte).map_err(|_| DISCONNECTED)?;
+                if byte[0] == b'#' {
+                    break;
+                }
+                data.push(byte[0]);
+            }
+            let mut checksum = [0; 2];
+            stream.read_exact(&mut checksum).map_err(|_| DISCONNECTED)?;
+            let expected = core::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|c| u8::from_str_radix(c, 16).ok());
+            let sum = data.iter().fold(0u8, |s, b| s.wrapping_add(*b));
+            if !self.no_ack {
+                let ack: &[u8] = if expected == Some(sum) { b"+" } else { b"-" };
+                stream.write_all(ack).map_err(|_| DISCONNECTED)?;
+                if expected != Some(sum) {
+                    continue;
+                }
+            }
+            return Ok(Some(String::from_utf8_lossy(&data).to_string()));
+        }
+    }
+
+    // sends a reply, again until it's acknowledged unless that's been
+    // turned off
+    fn send<S: Read + Write>(&mut self, stream: &mut S, reply: &str) -> Result<(), &'static str> {
+        let mut data = vec![b'$'];
+        for b in reply.bytes() {
+            if let b'#' | b'$' | b'}' | b'*' = b {
+                data.push(b'}');
+                data.push(b ^ 0x20);
+            } else {
+                data.push(b);
+            }
+        }
+        let sum = data[1..].iter().fold(0u8, |s, b| s.wrapping_add(*b));
+        data.extend(format!("#{:02x}", sum).bytes());
+        loop {
+            stream.write_all(&data).map_err(|_| DISCONNECTED)?;
+            stream.flush().map_err(|_| DISCONNECTED)?;
+            if self.no_ack {
+                return Ok(());
+            }
+            let mut ack = [0];
+            stream.read_exact(&mut ack).map_err(|_| DISCONNECTED)?;
+            if ack[0] != b'-' {
+                return Ok(());
+            }
+        }
+    }
+}
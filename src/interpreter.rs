@@ -3,18 +3,51 @@ use alloc::string::{String, ToString};
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::convert::TryInto;
+use core::fmt;
 use serde::{Deserialize, Serialize};
 use spin::Mutex;
 
+mod bytecode;
+mod config;
+mod debugger;
+mod driver;
+mod imports;
+mod instance;
+#[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+mod jit;
+mod numeric;
+mod registers;
+mod run;
+mod snapshot;
+mod store;
+mod trace;
+mod typed;
+
+use bytecode::*;
+pub use config::*;
+pub use debugger::*;
+pub use imports::*;
+pub use instance::Instance;
+use run::{Fuel, Limits, EXHAUSTED, PAGE_SIZE};
+pub use snapshot::{MemoryImage, Snapshot};
+pub use store::Store;
+use store::{Context, Link, Suspended};
+pub use trace::*;
+pub use typed::*;
+
 pub struct Interpreter<T>
 where
     T: InterpretableProgram,
 {
     pub memory: Arc<Mutex<Vec<u8>>>,
+    pub globals: Arc<Mutex<Vec<WasmValue>>>,
     pub program: Arc<Mutex<T>>,
+    module: Arc<Module>,
+    fuel: Option<u64>,
+    imports: Option<Arc<Imports>>,
 }
 
-#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[derive(Clone, Copy, PartialEq, Debug, Deserialize, Serialize)]
 pub enum WasmValue {
     I32(i32),
     I64(i64),
@@ -90,6 +123,15 @@ impl WasmValue {
         }
     }
 
+    pub fn value_type(&self) -> ValueType {
+        match self {
+            WasmValue::I32(_) => ValueType::I32,
+            WasmValue::I64(_) => ValueType::I64,
+            WasmValue::F32(_) => ValueType::F32,
+            WasmValue::F64(_) => ValueType::F64,
+        }
+    }
+
     pub fn to_f64(&self) -> f64 {
         match self {
             WasmValue::I32(i) => *i as f64,
@@ -100,11 +142,48 @@ impl WasmValue {
     }
 }
 
-#[derive(Debug)]
+/// A call the interpreter hands back for the host to make. It can't be
+/// built field by field, since a call through a table can carry where it
+/// goes without a name, so use `ImportCall::new`.
+#[derive(Clone)]
 pub struct ImportCall {
     pub module_name: String,
     pub name: String,
     pub params: Vec<WasmValue>,
+    // set for a call_indirect to a function another instance put in a
+    // shared table, which has no name
+    link: Option<Link>,
+}
+
+impl ImportCall {
+    /// A call to the function imported as `name` from `module_name`.
+    pub fn new(module_name: &str, name: &str, params: Vec<WasmValue>) -> Self {
+        ImportCall {
+            module_name: module_name.to_string(),
+            name: name.to_string(),
+            params,
+            link: None,
+        }
+    }
+
+    pub(crate) fn linked(link: Link, params: Vec<WasmValue>) -> Self {
+        ImportCall {
+            module_name: String::new(),
+            name: String::new(),
+            params,
+            link: Some(link),
+        }
+    }
+}
+
+impl fmt::Debug for ImportCall {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ImportCall")
+            .field("module_name", &self.module_name)
+            .field("name", &self.name)
+            .field("params", &self.params)
+            .finish()
+    }
 }
 
 pub enum ExecutionResponse {
@@ -125,8 +204,14 @@ pub enum ExecutionUnit {
     BasicInstruction(Instruction),
     Unreachable,
     Complete(Vec<WasmValue>),
+    /// A metered call ran out of fuel. It carries on where it stopped once
+    /// more is added with `WasmExecution::add_fuel`.
+    OutOfFuel,
 }
 
+/// A module the interpreter can run. It's lowered as a whole up front, from
+/// what `to_program` gives back, which implementations from before it was
+/// added have to provide.
 pub trait InterpretableProgram {
     fn load_data_into_memory(&self, mem: &mut Vec<u8>) -> Result<(), &'static str>;
     fn initial_memory_size(&self) -> usize;
@@ -138,6 +223,7 @@ pub trait InterpretableProgram {
         position: &[usize],
     ) -> Result<Option<&'a Instruction>, &'static str>;
     fn create_locals(&self, position: &[usize]) -> Result<Vec<WasmValue>, &'static str>;
+    fn to_program(&self) -> Program;
 }
 
 impl InterpretableProgram for Program {
@@ -164,7 +250,7 @@ impl InterpretableProgram for Program {
     fn load_data_into_memory(&self, mem: &mut Vec<u8>) -> Result<(), &'static str> {
         for s in self.sections.iter() {
             if let Section::Data(d) = s {
-                for db in d.data_blocks.iter() {
+                for db in d.data_blocks.iter().filter(|db| !db.passive) {
                     match db.offset_expression[0] {
                         Instruction::I32Const(x) => {
                             let offset = x as usize;
@@ -184,7 +270,7 @@ impl InterpretableProgram for Program {
         for s in self.sections.iter() {
             if let Section::Memory(m) = s {
                 if !m.memories.is_empty() {
-                    return m.memories[0].min_pages * 1024;
+                    return m.memories[0].min_pages * PAGE_SIZE;
                 }
             }
         }
@@ -274,6 +360,10 @@ impl InterpretableProgram for Program {
         }
         Ok(locals)
     }
+
+    fn to_program(&self) -> Program {
+        self.clone()
+    }
 }
 
 impl InterpretableProgram for ProgramView<'_> {
@@ -300,7 +390,7 @@ impl InterpretableProgram for ProgramView<'_> {
     fn load_data_into_memory(&self, mem: &mut Vec<u8>) -> Result<(), &'static str> {
         for s in self.sections.iter() {
             if let SectionView::Data(d) = s {
-                for db in d.data_blocks.iter() {
+                for db in d.data_blocks.iter().filter(|db| !db.passive) {
                     match db.offset_expression[0] {
                         Instruction::I32Const(x) => {
                             let offset = x as usize;
@@ -320,7 +410,7 @@ impl InterpretableProgram for ProgramView<'_> {
         for s in self.sections.iter() {
             if let SectionView::Memory(m) = s {
                 if !m.memories.is_empty() {
-                    return m.memories[0].min_pages * 1024;
+                    return m.memories[0].min_pages * PAGE_SIZE;
                 }
             }
         }
@@ -410,6 +500,10 @@ impl InterpretableProgram for ProgramView<'_> {
         }
         Ok(locals)
     }
+
+    fn to_program(&self) -> Program {
+        self.to_owned()
+    }
 }
 
 impl<T> Interpreter<T>
@@ -417,21 +511,160 @@ where
     T: InterpretableProgram,
 {
     pub fn new(p: T) -> Result<Self, &'static str> {
-        let mem_size = p.initial_memory_size();
-        let mut mem = vec![0; mem_size];
-        p.load_data_into_memory(&mut mem)?;
-        Ok(Interpreter {
-            memory: Arc::new(Mutex::new(mem)),
+        Self::with_config(p, &InterpreterConfig::default())
+    }
+
+    /// Sets up an interpreter for `p`, leaving calls to imported functions
+    /// to the host and the start function unrun. Use `Instance` to have it
+    /// run. A memory, table or global `p` imports starts out zeroed or empty,
+    /// just as if `p` defined it.
+    ///
+    /// `p` must be valid WebAssembly. Setting up checks branches and stack
+    /// heights but not the types of values, so an ill-typed module isn't
+    /// refused, it traps or computes nonsense when run.
+    pub fn with_config(p: T, config: &InterpreterConfig) -> Result<Self, &'static str> {
+        Self::instantiate(p, config, None)
+    }
+
+    fn instantiate(
+        p: T,
+        config: &InterpreterConfig,
+        imports: Option<Imports>,
+    ) -> Result<Self, &'static str> {
+        let costs = config.fuel.map(|_| &config.costs);
+        let mut program = p.to_program();
+        let mut module = lower(&program, costs)?;
+        let instantiated = instance::instantiate(&program, &mut module, imports.as_ref())?;
+        module.hash = snapshot::module_hash(&program.compile(), config);
+        module.limits = Limits {
+            calls: config.max_call_depth,
+            values: config.max_stack_values,
+        };
+        if config.engine != Engine::Stack {
+            module.registers = registers::translate(&module);
+        }
+        #[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+        if config.engine == Engine::Jit {
+            module.native = Some(Arc::new(jit::compile(&module)?));
+        }
+        #[cfg(all(feature = "jit", not(all(target_arch = "x86_64", unix))))]
+        if config.engine == Engine::Jit {
+            return Err("the jit only compiles for x86-64 on unix");
+        }
+        let globals = module.globals.iter().map(|g| g.value).collect();
+        let interpreter = Interpreter {
+            memory: instantiated.memory,
+            globals: Arc::new(Mutex::new(globals)),
             program: Arc::new(Mutex::new(p)),
-        })
+            module: Arc::new(module),
+            fuel: config.fuel,
+            imports: imports.map(Arc::new),
+        };
+        instance::write_elements(&interpreter.context(), instantiated.elements);
+        Ok(interpreter)
+    }
+
+    // what calls into the instance run against
+    fn context(&self) -> Context {
+        Context {
+            module: self.module.clone(),
+            memory: self.memory.clone(),
+            globals: self.globals.clone(),
+            imports: self.imports.clone(),
+        }
+    }
+
+    /// Like `with_config`, except calls to the functions in `imports` are
+    /// made by the interpreter itself. Everything the module imports has to
+    /// be among them and match what the module expects.
+    pub fn with_imports(
+        p: T,
+        config: &InterpreterConfig,
+        imports: Imports,
+    ) -> Result<Self, &'static str> {
+        if !imports.unresolved(&p.to_program()).is_empty() {
+            return Err("module has imports that aren't provided");
+        }
+        Self::instantiate(p, config, Some(imports))
     }
+
     pub fn call(
         &mut self,
         name: &str,
         params: &[WasmValue],
     ) -> Result<WasmExecution<T>, &'static str> {
-        WasmExecution::new(name, params, self.program.clone(), self.memory.clone())
+        let function = self.module.find_export(name)?;
+        self.call_function(function, params)
+    }
+
+    fn call_function(
+        &mut self,
+        function: usize,
+        params: &[WasmValue],
+    ) -> Result<WasmExecution<T>, &'static str> {
+        let mut execution = WasmExecution::new(
+            function,
+            params,
+            self.program.clone(),
+            self.memory.clone(),
+            self.globals.clone(),
+            self.module.clone(),
+            self.fuel.unwrap_or(0),
+        )?;
+        execution.imports = self.imports.clone();
+        Ok(execution)
+    }
+}
+
+/// A function call in progress: which function, where it's at, its locals
+/// and where its values start on the value stack.
+#[derive(Clone, PartialEq, Debug, Deserialize, Serialize)]
+pub struct CallFrame {
+    pub function: usize,
+    pub pc: usize,
+    pub locals: Vec<WasmValue>,
+    pub stack_base: usize,
+}
+
+/// The frame a call to `function` starts with, once its parameters are
+/// checked.
+fn entry(
+    module: &Module,
+    function: usize,
+    params: &[WasmValue],
+) -> Result<CallFrame, &'static str> {
+    let f = module
+        .functions
+        .get(function)
+        .ok_or("invalid function index")?;
+    if params.len() != f.signature.inputs.len() {
+        return Err("wrong number of parameters");
+    }
+    if params
+        .iter()
+        .zip(f.signature.inputs.iter())
+        .any(|(p, t)| p.value_type() != *t)
+    {
+        return Err("parameter has the wrong type");
+    }
+    let mut locals = params.to_vec();
+    locals.extend(f.locals[params.len()..].iter().map(|t| zero(*t)));
+    let values = match module.registers.get(function) {
+        Some(r) => {
+            locals.resize(r.registers as usize, WasmValue::I32(0));
+            locals.len()
+        }
+        None => locals.len() + f.max_height as usize,
+    };
+    if module.limits.calls == 0 || values > module.limits.values {
+        return Err(EXHAUSTED);
     }
+    Ok(CallFrame {
+        function,
+        pc: 0,
+        locals,
+        stack_base: 0,
+    })
 }
 
 #[derive(Deserialize, Serialize)]
@@ -439,112 +672,232 @@ pub struct WasmExecution<T>
 where
     T: InterpretableProgram,
 {
-    #[serde(skip)]
-    import_fn_count: usize,
-    pub call_stack: (usize, Vec<WasmValue>),
+    pub call_stack: Vec<CallFrame>,
     pub value_stack: Vec<WasmValue>,
-    pub current_position: Vec<usize>,
     #[serde(skip)]
     pub memory: Arc<Mutex<Vec<u8>>>,
     #[serde(skip)]
+    pub globals: Arc<Mutex<Vec<WasmValue>>>,
+    #[serde(skip)]
     pub program: Arc<Mutex<T>>,
+    #[serde(skip)]
+    module: Arc<Module>,
+    fuel: Fuel,
+    #[serde(skip)]
+    imports: Option<Arc<Imports>>,
+    /// Calls waiting on one into another instance to return.
+    #[serde(skip)]
+    outer: Vec<Suspended>,
+    #[serde(skip)]
+    tracing: Option<Tracing>,
 }
 
 impl<T> WasmExecution<T>
 where
     T: InterpretableProgram,
 {
-    pub fn new(
-        name: &str,
+    fn new(
+        function: usize,
         params: &[WasmValue],
         program: Arc<Mutex<T>>,
         memory: Arc<Mutex<Vec<u8>>>,
+        globals: Arc<Mutex<Vec<WasmValue>>>,
+        module: Arc<Module>,
+        fuel: u64,
     ) -> Result<Self, &'static str> {
-        let p = program.lock();
-        let (section_index, function_index) = p.fetch_export_fn_index(name)?;
-        let position = vec![section_index, function_index];
-        let locals = p.create_locals(&position)?;
-        let import_fn_count = p.import_fn_count();
         Ok(WasmExecution {
-            call_stack: (function_index, locals),
-            import_fn_count,
-            value_stack: params.to_vec(),
-            current_position: position,
+            call_stack: vec![entry(&module, function, params)?],
+            value_stack: vec![],
             memory,
-            program: program.clone(),
+            globals,
+            program,
+            module,
+            fuel: Fuel {
+                remaining: fuel,
+                consumed: 0,
+            },
+            imports: None,
+            outer: vec![],
+            tracing: None,
         })
     }
 
+    /// Runs until an import is called, `unreachable` is hit or the call
+    /// completes. Any other trap is returned as an error. Imports the
+    /// interpreter was given functions for are called along the way, and
+    /// calls to another instance's exports switch over to that instance
+    /// until they return.
     pub fn next_unit(&mut self) -> Result<ExecutionUnit, &'static str> {
-        let p = self.program.lock();
-        if self.current_position.len() == 2 {
-            self.current_position.push(0);
+        loop {
+            if let Some(unit) = self.advance(false)? {
+                return Ok(unit);
+            }
+        }
+    }
+
+    /// Has the call go on with the stack engine if it's been running on the
+    /// register engine, which stepping through it needs.
+    pub(crate) fn use_stack_engine(&mut self) -> Result<(), &'static str> {
+        registers::to_stack_frames(&self.module, &mut self.call_stack, &mut self.value_stack)
+    }
+
+    // runs the engine the module was set up for, or a single op of the stack
+    // engine when stepping or tracing, which the call then stays on
+    fn run(
+        &mut self,
+        step: bool,
+        memory: &mut Vec<u8>,
+        globals: &mut [WasmValue],
+    ) -> Result<Option<ExecutionUnit>, &'static str> {
+        if step || self.tracing.is_some() {
+            self.use_stack_engine()?;
+            if self.tracing.is_some() {
+                self.traced_step(memory, globals)
+            } else {
+                run::step(
+                    &self.module,
+                    memory,
+                    globals,
+                    &mut self.call_stack,
+                    &mut self.value_stack,
+                    &mut self.fuel,
+                )
+            }
         } else {
-            let len = self.current_position.len() - 1;
-            self.current_position[len] += 1;
+            let run = if registers::in_registers(&self.module, &self.call_stack) {
+                registers::run
+            } else {
+                run::run
+            };
+            Ok(Some(run(
+                &self.module,
+                memory,
+                globals,
+                &mut self.call_stack,
+                &mut self.value_stack,
+                &mut self.fuel,
+            )?))
         }
-        let first_function_instruction = p.fetch_instruction(&self.current_position)?;
-        if let Some(instruction) = first_function_instruction {
-            let unit = match instruction {
-                Instruction::Call(fn_index) => {
-                    if ((*fn_index) as usize) < self.import_fn_count {
-                        let (module_name, name, param_ct) =
-                            p.import_fn_details((*fn_index) as usize)?;
-                        let mut params = vec![];
-                        for _ in 0..param_ct {
-                            let p = match self.value_stack.pop() {
-                                Some(p) => p,
-                                None => return Err("ran out of values on value stack"),
-                            };
-                            params.push(p);
-                        }
-                        ExecutionUnit::CallImport(ImportCall {
-                            module_name: module_name.to_string(),
-                            name: name.to_string(),
-                            params,
-                        })
-                    } else {
-                        return Err("cannot call non-imports yet");
-                    }
-                }
-                Instruction::Unreachable => ExecutionUnit::Unreachable,
-                x => ExecutionUnit::BasicInstruction(x.clone()),
+    }
+
+    // runs until there's something for the caller to do, or a single op when
+    // stepping, giving nothing back if there's more to run before that.
+    // imports the execution has are called along the way
+    fn advance(&mut self, step: bool) -> Result<Option<ExecutionUnit>, &'static str> {
+        let memory = self.memory.clone();
+        let globals = self.globals.clone();
+        let unit = {
+            let mut memory = memory.lock();
+            let mut globals = globals.lock();
+            store::load_globals(&self.module, &mut globals);
+            let unit = self.run(step, &mut memory, &mut globals);
+            store::store_globals(&self.module, &globals);
+            unit
+        };
+        let call = match unit? {
+            Some(ExecutionUnit::Complete(results)) if !self.outer.is_empty() => {
+                self.traced(|| TraceEvent::Leave {
+                    results: results.clone(),
+                })?;
+                self.leave(results);
+                return Ok(None);
+            }
+            Some(ExecutionUnit::CallImport(call)) => call,
+            unit => return Ok(unit),
+        };
+        let imports = self.imports.clone();
+        let imports = imports.as_ref();
+        let link = match &call.link {
+            Some(link) => Some(link),
+            None => imports.and_then(|i| i.get_link(&call.module_name, &call.name)),
+        };
+        if let Some(link) = link {
+            self.traced(|| TraceEvent::Enter {
+                module_name: call.module_name.clone(),
+                name: call.name.clone(),
+                params: call.params.clone(),
+            })?;
+            self.enter(link, &call.params)?;
+            return Ok(None);
+        }
+        let host = match imports.and_then(|i| i.get(&call.module_name, &call.name)) {
+            Some(host) => host,
+            None => {
+                self.await_import(&call, &memory.lock());
+                return Ok(Some(ExecutionUnit::CallImport(call)));
+            }
+        };
+        // nothing's locked while the host runs, in case it locks the memory
+        // or a global itself through something shared
+        let before = self.tracing.as_ref().map(|_| memory.lock().clone());
+        let module = self.module.clone();
+        let results = host.call(&mut Caller::new(&memory, &globals, &module), &call.params)?;
+        if let Some(before) = before {
+            self.import_traced(call, results.clone(), &before, &memory.lock())?;
+        }
+        self.value_stack.extend(results);
+        Ok(None)
+    }
+
+    /// Runs the call to completion, failing if it needs an import the
+    /// interpreter wasn't given or runs out of fuel.
+    pub fn finish(&mut self) -> Result<Vec<WasmValue>, &'static str> {
+        loop {
+            let response = match self.next_unit()? {
+                ExecutionUnit::Complete(v) => return Ok(v),
+                ExecutionUnit::CallImport(_) => {
+                    return Err("call needs an import the interpreter wasn't given")
+                }
+                ExecutionUnit::OutOfFuel => return Err("ran out of fuel"),
+                mut unit => unit.evaluate()?,
             };
-            Ok(unit)
-        } else {
-            // TODO: handle nested function call
-            Ok(ExecutionUnit::Complete(vec![]))
+            self.execute(response)?;
         }
     }
 
+    /// Tops up the fuel a metered call has left.
+    pub fn add_fuel(&mut self, fuel: u64) {
+        self.fuel.remaining = self.fuel.remaining.saturating_add(fuel);
+    }
+
+    /// How much fuel the call has left, which is always 0 if it isn't
+    /// metered.
+    pub fn fuel(&self) -> u64 {
+        self.fuel.remaining
+    }
+
+    /// How much fuel the call has burned so far.
+    pub fn fuel_consumed(&self) -> u64 {
+        self.fuel.consumed
+    }
+
     pub fn execute(&mut self, r: ExecutionResponse) -> Result<(), &'static str> {
         match r {
-            ExecutionResponse::GetMemorySize => self.value_stack.push(
-                (self.memory.lock().len().to_wasm_value().to_i32() / 1024i32).to_wasm_value(),
-            ),
+            ExecutionResponse::GetMemorySize => self
+                .value_stack
+                .push(((self.memory.lock().len() / PAGE_SIZE) as i32).to_wasm_value()),
             ExecutionResponse::GetMemoryGrow => {
                 let _page_delta = self.value_stack.pop().unwrap().to_i32();
                 return Err("do not know how to extend memory");
             }
             ExecutionResponse::ValueStackModification(f) => f(&mut self.value_stack)?,
-            ExecutionResponse::AddValues(mut v) => {
-                while let Some(wv) = v.pop() {
-                    self.value_stack.push(wv);
-                }
-            }
+            ExecutionResponse::AddValues(v) => self.value_stack.extend(v),
             ExecutionResponse::GetRegister(v) => {
-                self.value_stack.push(self.call_stack.1[v as usize]);
+                let frame = self.call_stack.last().ok_or("no function is running")?;
+                self.value_stack.push(frame.locals[v as usize]);
             }
             ExecutionResponse::SetRegister(v) => {
+                let frame = self.call_stack.last_mut().ok_or("no function is running")?;
                 if let Some(p) = self.value_stack.pop() {
-                    self.call_stack.1[v as usize] = p;
+                    frame.locals[v as usize] = p;
                 } else {
                     return Err("can't set register because value stack is empty");
                 }
             }
             ExecutionResponse::TeeRegister(v) => {
+                let frame = self.call_stack.last_mut().ok_or("no function is running")?;
                 if let Some(p) = self.value_stack.pop() {
-                    self.call_stack.1[v as usize] = p;
+                    frame.locals[v as usize] = p;
                     self.value_stack.push(p);
                 } else {
                     return Err("can't tee register because value stack is empty");
@@ -569,6 +922,9 @@ impl ExecutionUnit {
                 Instruction::Raw(b) => {
                     return Err("Cannot handle raw instruction.");
                 }
+                Instruction::Unknown(_, _) | Instruction::Prefixed(_, _, _) => {
+                    return Err("Cannot handle unknown instruction.");
+                }
                 Instruction::Unreachable => {
                     return Err("Cannot handle unreachable.");
                 }
@@ -0,0 +1,704 @@
+use super::imports::SharedGlobal;
+#[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+use super::jit::Code;
+use super::registers::RegisterFunction;
+use super::run::Limits;
+use super::store::Table;
+use super::WasmValue;
+use crate::core::*;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use serde::{Deserialize, Serialize};
+use spin::Mutex;
+use webassembly::*;
+
+// the most locals, parameters included, a function can have
+const MAX_LOCALS: usize = 50_000;
+
+/// Where a branch goes and what it leaves on the value stack: the stack is
+/// cut back to `height` values above the frame's base, keeping the top
+/// `arity` values.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub(crate) struct Branch {
+    pub target: u32,
+    pub height: u32,
+    pub arity: u32,
+}
+
+/// A function body lowered to a flat list of operations with every branch
+/// target and stack height worked out ahead of time.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub(crate) enum Op {
+    Unreachable,
+    /// An instruction the interpreter can't run, which traps if reached.
+    Unsupported,
+    Jump(u32),
+    JumpIfZero(u32),
+    Br(Branch),
+    BrIf(Branch),
+    /// Pops an index into the function's branch table at this position.
+    BrTable(u32),
+    Return,
+    Call(u32),
+    CallIndirect(u32),
+    Drop,
+    Select,
+    LocalGet(u32),
+    LocalSet(u32),
+    LocalTee(u32),
+    GlobalGet(u32),
+    GlobalSet(u32),
+    /// A load or store opcode along with its offset immediate.
+    Load(u8, u32),
+    Store(u8, u32),
+    MemorySize,
+    MemoryGrow,
+    Const(WasmValue),
+    /// Instructions without immediates taking one or two operands, by
+    /// their opcode.
+    Unary(u8),
+    Binary(u8),
+    /// Pays for the instructions up to the next branch or branch target.
+    Fuel(u32),
+}
+
+#[derive(Clone, PartialEq, Debug, Default)]
+pub(crate) struct Function {
+    pub signature: FunctionType,
+    /// The module and name of an imported function.
+    pub import: Option<(String, String)>,
+    /// Types of every local, parameters first.
+    pub locals: Vec<ValueType>,
+    pub code: Vec<Op>,
+    /// Which instruction each op was lowered from, numbering a body's
+    /// instructions in the order they're written, nested ones included. Ops
+    /// that end the function get the number after the last instruction.
+    pub instructions: Vec<u32>,
+    /// How many values are on the stack before each op runs.
+    pub heights: Vec<u32>,
+    /// The most values the function ever has on the stack.
+    pub max_height: u32,
+    pub branch_tables: Vec<Vec<Branch>>,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub(crate) struct Global {
+    pub value_type: ValueType,
+    pub is_mutable: bool,
+    /// The module and name of an imported global.
+    pub import: Option<(String, String)>,
+    /// The constant expression a defined global starts out as.
+    pub init: Vec<Instruction>,
+    pub value: WasmValue,
+}
+
+/// A module ready for the interpreter.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Module {
+    pub types: Vec<FunctionType>,
+    pub functions: Vec<Function>,
+    pub function_exports: Vec<(String, usize)>,
+    pub globals: Vec<Global>,
+    /// Where each imported global is kept, by its index.
+    pub global_imports: Vec<(usize, SharedGlobal)>,
+    /// Until the module is instantiated, an empty table of its minimum size
+    /// and maximum, even if it's imported.
+    pub table: Arc<Mutex<Table>>,
+    /// The module and name of an imported table.
+    pub table_import: Option<(String, String)>,
+    /// The module and name of an imported memory.
+    pub memory_import: Option<(String, String)>,
+    pub min_memory_pages: usize,
+    pub max_memory_pages: Option<usize>,
+    pub start: Option<usize>,
+    /// How far calls into the module can go before they trap.
+    pub limits: Limits,
+    /// Identifies the module and how it's run, for checking snapshots.
+    pub hash: u64,
+    /// Each function translated for the register engine, if it's in use.
+    pub registers: Vec<RegisterFunction>,
+    /// Machine code for whatever functions the jit could compile.
+    #[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+    pub native: Option<Arc<Code>>,
+}
+
+impl Module {
+    pub fn find_export(&self, name: &str) -> Result<usize, &'static str> {
+        self.function_exports
+            .iter()
+            .find(|x| x.0 == name)
+            .map(|x| x.1)
+            .ok_or("could not find exported function")
+    }
+}
+
+/// Lowers every function, metering them with `costs` if given. Branch
+/// depths and stack heights are checked, but not the types of values, so the
+/// program is expected to have been validated.
+pub(crate) fn lower(program: &Program, costs: Option<&CostTable>) -> Result<Module, &'static str> {
+    let mut module = Module::default();
+    let mut function_types = vec![];
+    let mut code_blocks: &[CodeBlock] = &[];
+    for s in program.sections.iter() {
+        match s {
+            Section::Type(s) => module.types = s.types.clone(),
+            Section::Import(s) => {
+                for i in s.imports.iter() {
+                    match i {
+                        WasmImport::Function(f) => {
+                            // imports get a body that calls themselves so
+                            // they can be started like any other function
+                            let signature = function_type(&module.types, f.type_index)?;
+                            let mut code: Vec<Op> = (0..signature.inputs.len() as u32)
+                                .map(Op::LocalGet)
+                                .collect();
+                            code.push(Op::Call(module.functions.len() as u32));
+                            code.push(Op::Return);
+                            let inputs = signature.inputs.len() as u32;
+                            let mut heights: Vec<u32> = (0..=inputs).collect();
+                            heights.push(signature.outputs.len() as u32);
+                            let instructions = vec![0; code.len()];
+                            module.functions.push(Function {
+                                locals: signature.inputs.clone(),
+                                max_height: inputs.max(signature.outputs.len() as u32),
+                                signature,
+                                import: Some((f.module_name.clone(), f.name.clone())),
+                                code,
+                                instructions,
+                                heights,
+                                ..Function::default()
+                            })
+                        }
+                        WasmImport::Global(g) => module.globals.push(Global {
+                            value_type: g.value_type,
+                            is_mutable: g.is_mutable,
+                            import: Some((g.module_name.clone(), g.name.clone())),
+                            init: vec![],
+                            value: zero(g.value_type),
+                        }),
+                        WasmImport::Memory(m) if m.memory64 => {
+                            return Err("64-bit memories are not supported")
+                        }
+                        WasmImport::Memory(m) => {
+                            module.memory_import = Some((m.module_name.clone(), m.name.clone()));
+                            module.min_memory_pages = m.min_pages;
+                            module.max_memory_pages = m.max_pages;
+                        }
+                        WasmImport::Table(t) => {
+                            module.table_import = Some((t.module_name.clone(), t.name.clone()));
+                            module.table = Arc::new(Mutex::new(Table::empty(t.min, t.max)));
+                        }
+                    }
+                }
+            }
+            Section::Function(s) => function_types = s.function_types.clone(),
+            Section::Code(s) => code_blocks = &s.code_blocks,
+            Section::Memory(s) => {
+                if s.memories.iter().any(|m| m.memory64) {
+                    return Err("64-bit memories are not supported");
+                }
+                if let Some(m) = s.memories.first() {
+                    module.min_memory_pages = m.min_pages;
+                    module.max_memory_pages = m.max_pages;
+                }
+            }
+            Section::Start(s) => module.start = Some(s.start_function),
+            Section::Export(s) => {
+                for e in s.exports.iter() {
+                    if let WasmExport::Function(f) = e {
+                        module.function_exports.push((f.name.clone(), f.index));
+                    }
+                }
+            }
+            Section::Global(s) => {
+                for g in s.globals.iter() {
+                    module.globals.push(Global {
+                        value_type: g.value_type,
+                        is_mutable: g.is_mutable,
+                        import: None,
+                        init: g.value_expression.clone(),
+                        value: zero(g.value_type),
+                    });
+                }
+            }
+            Section::Table(s) => {
+                if let Some(t) = s.tables.first() {
+                    module.table = Arc::new(Mutex::new(Table::empty(t.min, t.max)));
+                }
+            }
+            _ => {}
+        }
+    }
+    if function_types.len() != code_blocks.len() {
+        return Err("function and code sections have different lengths");
+    }
+    for (t, c) in function_types.iter().zip(code_blocks.iter()) {
+        let signature = function_type(&module.types, *t)?;
+        let mut locals = signature.inputs.clone();
+        for l in c.locals.iter() {
+            if locals.len().saturating_add(l.count as usize) > MAX_LOCALS {
+                return Err("a function can't have more than 50,000 locals");
+            }
+            for _ in 0..l.count {
+                locals.push(l.value_type);
+            }
+        }
+        module.functions.push(Function {
+            signature,
+            locals,
+            ..Function::default()
+        });
+    }
+    let signatures: Vec<FunctionType> = module
+        .functions
+        .iter()
+        .map(|f| f.signature.clone())
+        .collect();
+    let import_count = module.functions.len() - code_blocks.len();
+    for (i, c) in code_blocks.iter().enumerate() {
+        let f = &mut module.functions[import_count + i];
+        let mut lowering = Lowering {
+            types: &module.types,
+            functions: &signatures,
+            globals: &module.globals,
+            code: vec![],
+            instructions: vec![],
+            heights: vec![],
+            branch_tables: vec![],
+            labels: vec![],
+            height: 0,
+            costs,
+            fuel: 0,
+            next: 0,
+            current: 0,
+        };
+        lowering.function(&f.signature, &c.instructions)?;
+        f.code = lowering.code;
+        f.instructions = lowering.instructions;
+        f.max_height = lowering.heights.iter().copied().max().unwrap_or(0);
+        f.heights = lowering.heights;
+        f.branch_tables = lowering.branch_tables;
+    }
+    Ok(module)
+}
+
+/// How many instructions there are in `instructions`, nested ones included.
+pub(crate) fn count(instructions: &[Instruction]) -> usize {
+    instructions
+        .iter()
+        .map(|i| {
+            1 + match i {
+                Instruction::Block(_, body) | Instruction::Loop(_, body) => count(body),
+                Instruction::If(_, then_body, else_body) => {
+                    count(then_body) + else_body.as_deref().map_or(0, count)
+                }
+                _ => 0,
+            }
+        })
+        .sum()
+}
+
+pub(crate) fn zero(value_type: ValueType) -> WasmValue {
+    match value_type {
+        ValueType::I32 => WasmValue::I32(0),
+        ValueType::I64 => WasmValue::I64(0),
+        ValueType::F32 => WasmValue::F32(0.0),
+        ValueType::F64 => WasmValue::F64(0.0),
+    }
+}
+
+fn function_type(types: &[FunctionType], index: usize) -> Result<FunctionType, &'static str> {
+    types.get(index).cloned().ok_or("invalid type index")
+}
+
+struct Label {
+    is_loop: bool,
+    start: u32,
+    height: usize,
+    arity: usize,
+    // ops branching to the end of the label, to patch once it's known,
+    // along with which entry of a branch table is meant
+    pending: Vec<(usize, usize)>,
+}
+
+struct Lowering<'a> {
+    types: &'a [FunctionType],
+    functions: &'a [FunctionType],
+    globals: &'a [Global],
+    code: Vec<Op>,
+    instructions: Vec<u32>,
+    heights: Vec<u32>,
+    branch_tables: Vec<Vec<Branch>>,
+    labels: Vec<Label>,
+    height: usize,
+    costs: Option<&'a CostTable>,
+    // the fuel op paying for the instructions being lowered
+    fuel: usize,
+    // the number of the next instruction and of the one being lowered
+    next: u32,
+    current: u32,
+}
+
+impl<'a> Lowering<'a> {
+    fn function(
+        &mut self,
+        signature: &FunctionType,
+        body: &[Instruction],
+    ) -> Result<(), &'static str> {
+        self.labels.push(Label {
+            is_loop: false,
+            start: 0,
+            height: 0,
+            arity: signature.outputs.len(),
+            pending: vec![],
+        });
+        self.meter();
+        if self.body(body)? && self.height != signature.outputs.len() {
+            return Err("type mismatch");
+        }
+        let label = self.labels.pop().unwrap();
+        self.current = self.next;
+        self.patch(&label.pending);
+        self.emit(Op::Return, signature.outputs.len());
+        Ok(())
+    }
+
+    fn emit(&mut self, op: Op, height: usize) {
+        self.code.push(op);
+        self.instructions.push(self.current);
+        self.heights.push(height as u32);
+    }
+
+    // starts paying for instructions from here on separately, since
+    // something can branch here or everything up to here might not run
+    fn meter(&mut self) {
+        if self.costs.is_some() {
+            self.fuel = self.code.len();
+            self.emit(Op::Fuel(0), self.height);
+        }
+    }
+
+    // the number of values a block takes and leaves
+    fn block_arity(&self, block_type: u8) -> Result<(usize, usize), &'static str> {
+        match block_type {
+            EMPTY => Ok((0, 0)),
+            I32 | I64 | F32 | F64 => Ok((0, 1)),
+            t => {
+                let t = function_type(self.types, t as usize)?;
+                Ok((t.inputs.len(), t.outputs.len()))
+            }
+        }
+    }
+
+    fn patch(&mut self, pending: &[(usize, usize)]) {
+        let end = self.code.len() as u32;
+        for (op, entry) in pending.iter() {
+            match &mut self.code[*op] {
+                Op::Jump(t) | Op::JumpIfZero(t) => *t = end,
+                Op::Br(b) | Op::BrIf(b) => b.target = end,
+                Op::BrTable(table) => self.branch_tables[*table as usize][*entry].target = end,
+                _ => {}
+            }
+        }
+        if !pending.is_empty() {
+            self.meter();
+        }
+    }
+
+    // where a branch to `depth` from entry `entry` of the op about to be
+    // pushed goes, leaving the target to be patched for forward branches
+    fn branch(&mut self, depth: u32, entry: usize) -> Result<Branch, &'static str> {
+        let index = self
+            .labels
+            .len()
+            .checked_sub(depth as usize + 1)
+            .ok_or("branch depth out of range")?;
+        self.need(self.labels[index].arity)?;
+        let pc = self.code.len();
+        let label = &mut self.labels[index];
+        let target = if label.is_loop {
+            label.start
+        } else {
+            label.pending.push((pc, entry));
+            u32::MAX
+        };
+        Ok(Branch {
+            target,
+            height: label.height as u32,
+            arity: label.arity as u32,
+        })
+    }
+
+    // fails unless the innermost block has `n` values of its own on the
+    // stack, since those below it aren't its to take
+    fn need(&self, n: usize) -> Result<(), &'static str> {
+        let floor = self.labels.last().map_or(0, |l| l.height);
+        if self.height < floor + n {
+            return Err("value stack underflow");
+        }
+        Ok(())
+    }
+
+    fn pop(&mut self, n: usize) -> Result<(), &'static str> {
+        self.need(n)?;
+        self.height -= n;
+        Ok(())
+    }
+
+    // lowers a sequence of instructions, stopping after anything that never
+    // falls through since the rest can't run, and returns whether the end
+    // is reached
+    fn body(&mut self, instructions: &[Instruction]) -> Result<bool, &'static str> {
+        for (i, instruction) in instructions.iter().enumerate() {
+            if !self.instruction(instruction)? {
+                // what's skipped still has its numbers
+                self.next += count(&instructions[i + 1..]) as u32;
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn block(
+        &mut self,
+        block_type: u8,
+        is_loop: bool,
+        body: &[Instruction],
+    ) -> Result<Label, &'static str> {
+        let (params, results) = self.block_arity(block_type)?;
+        self.pop(params)?;
+        self.labels.push(Label {
+            is_loop,
+            start: self.code.len() as u32,
+            height: self.height,
+            arity: if is_loop { params } else { results },
+            pending: vec![],
+        });
+        self.height += params;
+        if is_loop {
+            self.meter();
+        }
+        let falls_through = self.body(body)?;
+        let label = self.labels.pop().unwrap();
+        if falls_through && self.height != label.height + results {
+            return Err("type mismatch");
+        }
+        self.height = label.height + results;
+        Ok(label)
+    }
+
+    // returns whether execution can continue past the instruction
+    fn instruction(&mut self, instruction: &Instruction) -> Result<bool, &'static str> {
+        if let Some(costs) = self.costs {
+            if let Op::Fuel(total) = &mut self.code[self.fuel] {
+                *total = total.saturating_add(costs.cost(instruction));
+            }
+        }
+        let number = self.next;
+        self.next += 1;
+        self.current = number;
+        let before = self.height;
+        let op = match instruction {
+            Instruction::Block(t, body) => {
+                let label = self.block(*t, false, body)?;
+                self.current = number;
+                self.patch(&label.pending);
+                return Ok(true);
+            }
+            Instruction::Loop(t, body) => {
+                self.block(*t, true, body)?;
+                return Ok(true);
+            }
+            Instruction::If(t, then_body, else_body) => {
+                self.pop(1)?;
+                let start_height = self.height;
+                let jump_if_zero = self.code.len();
+                self.emit(Op::JumpIfZero(u32::MAX), before);
+                self.meter();
+                let mut label = self.block(*t, false, then_body)?;
+                self.current = number;
+                if let Some(else_body) = else_body {
+                    label.pending.push((self.code.len(), 0));
+                    let height = self.height;
+                    self.emit(Op::Jump(u32::MAX), height);
+                    self.patch(&[(jump_if_zero, 0)]);
+                    self.height = start_height;
+                    let mut else_label = self.block(*t, false, else_body)?;
+                    self.current = number;
+                    label.pending.append(&mut else_label.pending);
+                } else {
+                    // without an else, what the block takes is what it leaves
+                    let (params, results) = self.block_arity(*t)?;
+                    if params != results {
+                        return Err("type mismatch");
+                    }
+                    label.pending.push((jump_if_zero, 0));
+                }
+                self.patch(&label.pending);
+                return Ok(true);
+            }
+            Instruction::Unreachable => {
+                self.emit(Op::Unreachable, before);
+                return Ok(false);
+            }
+            Instruction::Nop => return Ok(true),
+            Instruction::Br(depth) => {
+                let branch = self.branch(*depth, 0)?;
+                self.emit(Op::Br(branch), before);
+                return Ok(false);
+            }
+            Instruction::BrIf(depth) => {
+                self.pop(1)?;
+                Op::BrIf(self.branch(*depth, 0)?)
+            }
+            Instruction::BrTable(depths, default) => {
+                self.pop(1)?;
+                let table = self.branch_tables.len();
+                let mut branches = vec![];
+                for (entry, d) in depths.iter().chain(core::iter::once(default)).enumerate() {
+                    branches.push(self.branch(*d, entry)?);
+                }
+                self.branch_tables.push(branches);
+                self.emit(Op::BrTable(table as u32), before);
+                return Ok(false);
+            }
+            Instruction::Return => {
+                self.need(self.labels[0].arity)?;
+                self.emit(Op::Return, before);
+                return Ok(false);
+            }
+            Instruction::Call(f) => {
+                let t = self
+                    .functions
+                    .get(*f as usize)
+                    .ok_or("invalid function index")?;
+                let (inputs, outputs) = (t.inputs.len(), t.outputs.len());
+                self.pop(inputs)?;
+                self.height += outputs;
+                Op::Call(*f)
+            }
+            Instruction::CallIndirect(t) => {
+                let t2 = function_type(self.types, *t as usize)?;
+                self.pop(1 + t2.inputs.len())?;
+                self.height += t2.outputs.len();
+                Op::CallIndirect(*t)
+            }
+            Instruction::Drop => {
+                self.pop(1)?;
+                Op::Drop
+            }
+            Instruction::Select => {
+                self.pop(3)?;
+                self.height += 1;
+                Op::Select
+            }
+            Instruction::LocalGet(i) => {
+                self.height += 1;
+                Op::LocalGet(*i)
+            }
+            Instruction::LocalSet(i) => {
+                self.pop(1)?;
+                Op::LocalSet(*i)
+            }
+            Instruction::LocalTee(i) => {
+                self.need(1)?;
+                Op::LocalTee(*i)
+            }
+            Instruction::GlobalGet(i) => {
+                if *i as usize >= self.globals.len() {
+                    return Err("invalid global index");
+                }
+                self.height += 1;
+                Op::GlobalGet(*i)
+            }
+            Instruction::GlobalSet(i) => {
+                if *i as usize >= self.globals.len() {
+                    return Err("invalid global index");
+                }
+                self.pop(1)?;
+                Op::GlobalSet(*i)
+            }
+            Instruction::I32Load(_, o)
+            | Instruction::I64Load(_, o)
+            | Instruction::F32Load(_, o)
+            | Instruction::F64Load(_, o)
+            | Instruction::I32Load8S(_, o)
+            | Instruction::I32Load8U(_, o)
+            | Instruction::I32Load16S(_, o)
+            | Instruction::I32Load16U(_, o)
+            | Instruction::I64Load8S(_, o)
+            | Instruction::I64Load8U(_, o)
+            | Instruction::I64Load16S(_, o)
+            | Instruction::I64Load16U(_, o)
+            | Instruction::I64Load32S(_, o)
+            | Instruction::I64Load32U(_, o) => {
+                self.need(1)?;
+                Op::Load(opcode(instruction), *o)
+            }
+            Instruction::I32Store(_, o)
+            | Instruction::I64Store(_, o)
+            | Instruction::F32Store(_, o)
+            | Instruction::F64Store(_, o)
+            | Instruction::I32Store8(_, o)
+            | Instruction::I32Store16(_, o)
+            | Instruction::I64Store8(_, o)
+            | Instruction::I64Store16(_, o)
+            | Instruction::I64Store32(_, o) => {
+                self.pop(2)?;
+                Op::Store(opcode(instruction), *o)
+            }
+            Instruction::MemorySize => {
+                self.height += 1;
+                Op::MemorySize
+            }
+            Instruction::MemoryGrow => {
+                self.need(1)?;
+                Op::MemoryGrow
+            }
+            Instruction::I32Const(v) => {
+                self.height += 1;
+                Op::Const(WasmValue::I32(*v))
+            }
+            Instruction::I64Const(v) => {
+                self.height += 1;
+                Op::Const(WasmValue::I64(*v))
+            }
+            Instruction::F32Const(v) => {
+                self.height += 1;
+                Op::Const(WasmValue::F32(*v))
+            }
+            Instruction::F64Const(v) => {
+                self.height += 1;
+                Op::Const(WasmValue::F64(*v))
+            }
+            Instruction::Raw(_) | Instruction::Unknown(_, _) | Instruction::Prefixed(_, _, _) => {
+                self.emit(Op::Unsupported, before);
+                return Ok(false);
+            }
+            i => {
+                let (inputs, outputs) = stack_effect(i);
+                self.pop(inputs.len())?;
+                self.height += outputs.len();
+                if inputs.len() == 1 {
+                    Op::Unary(opcode(i))
+                } else {
+                    Op::Binary(opcode(i))
+                }
+            }
+        };
+        let branches = matches!(op, Op::BrIf(_));
+        self.emit(op, before);
+        if branches {
+            self.meter();
+        }
+        Ok(true)
+    }
+}
+
+// every instruction lowered by opcode starts with a lone opcode byte
+fn opcode(instruction: &Instruction) -> u8 {
+    let mut bytes = vec![];
+    instruction.extend_wasm_bytes(&mut bytes);
+    bytes[0]
+}
@@ -0,0 +1,50 @@
+use crate::core::CostTable;
+
+/// How an `Interpreter` runs function bodies.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum Engine {
+    /// Runs the stack bytecode each function is lowered to.
+    #[default]
+    Stack,
+    /// Translates the stack bytecode further so locals and stack slots live
+    /// in a frame's registers, which avoids most pushing and popping.
+    Registers,
+    /// Compiles what it can to x86-64 machine code when the module is
+    /// loaded and runs the rest on the register engine. Functions that use
+    /// `call_indirect`, `memory.grow` or floating point arithmetic stay
+    /// interpreted, as does metered code. Calling an import unwinds the
+    /// compiled frames, which the register engine picks up again once the
+    /// import returns. Instantiating fails on targets other than x86-64
+    /// unix.
+    #[cfg(feature = "jit")]
+    Jit,
+}
+
+/// Options controlling how `Interpreter::with_config` runs a module.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct InterpreterConfig {
+    pub engine: Engine,
+    /// How much fuel each call starts with, or `None` to leave calls
+    /// unmetered. A call that runs out yields `ExecutionUnit::OutOfFuel`.
+    pub fuel: Option<u64>,
+    /// What instructions cost in fuel. Each straight-line run of
+    /// instructions is paid for when it's entered.
+    pub costs: CostTable,
+    /// How deeply calls can nest, counting the one that was started.
+    /// The jit refuses to be set up with more than 10,000.
+    pub max_call_depth: usize,
+    /// How many values calls can hold between them, counting their locals.
+    pub max_stack_values: usize,
+}
+
+impl Default for InterpreterConfig {
+    fn default() -> Self {
+        InterpreterConfig {
+            engine: Engine::default(),
+            fuel: None,
+            costs: CostTable::default(),
+            max_call_depth: 10_000,
+            max_stack_values: 1 << 20,
+        }
+    }
+}
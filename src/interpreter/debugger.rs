@@ -0,0 +1,570 @@
+use super::bytecode::{count, Module};
+use super::{ExecutionUnit, ImportCall, InterpretableProgram, WasmExecution, WasmValue};
+use crate::core::*;
+use crate::parser::wasm::{instruction_offsets, name_section};
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::Range;
+use spin::Mutex;
+
+/// Where an instruction is in a function.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Location {
+    pub function: usize,
+    /// Indices into the function's body and then into the bodies of the
+    /// blocks the instruction is nested in, where an `if`'s else branch
+    /// carries on from the end of its then branch. One past the last
+    /// instruction of the body is where the function returns from.
+    pub path: Vec<usize>,
+    /// Where the instruction starts in the module's bytes, or for the end of
+    /// the function where its final `end` is.
+    pub offset: Option<usize>,
+}
+
+/// A call on the call stack, as it stands when a debugged call stops.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Frame {
+    /// The instruction about to run, or for calls waiting on another the
+    /// call instruction they're waiting on.
+    pub location: Location,
+    pub name: Option<String>,
+    pub locals: Vec<WasmValue>,
+    /// The values the call has on the operand stack, the top last.
+    pub operands: Vec<WasmValue>,
+}
+
+/// What a watchpoint looks at.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Watched {
+    Memory(Vec<u8>),
+    Global(WasmValue),
+}
+
+/// Why a debugged call stopped.
+#[derive(Debug)]
+pub enum StopReason {
+    /// An instruction with a breakpoint on it is about to run.
+    Breakpoint(usize),
+    /// A step is done.
+    Step,
+    /// What a watchpoint looks at has just changed.
+    Watchpoint {
+        id: usize,
+        old: Watched,
+        new: Watched,
+    },
+    /// An import the interpreter has no function for was called. Its results
+    /// are pushed through `execution` before carrying on.
+    CallImport(ImportCall),
+    /// A metered call ran out of fuel, which can be added through
+    /// `execution` before carrying on.
+    OutOfFuel,
+    Unreachable,
+    Trap(&'static str),
+    Complete(Vec<WasmValue>),
+}
+
+enum Watch {
+    Memory(Range<usize>, Vec<u8>),
+    Global(usize, WasmValue),
+}
+
+/// Runs a call an instruction at a time, stopping at breakpoints, at
+/// watchpoints and after steps so that its frames and memory can be looked
+/// at. A call on the register engine goes on with the stack engine. Calls
+/// into other instances are run without stopping until they return.
+pub struct Debugger<T>
+where
+    T: InterpretableProgram,
+{
+    execution: WasmExecution<T>,
+    module: Arc<Module>,
+    memory: Arc<Mutex<Vec<u8>>>,
+    globals: Arc<Mutex<Vec<WasmValue>>>,
+    bytes: Vec<u8>,
+    bodies: Vec<Vec<Instruction>>,
+    offsets: Vec<Vec<usize>>,
+    // which ops are the first one of their instruction
+    starts: Vec<Vec<bool>>,
+    names: BTreeMap<usize, String>,
+    local_names: BTreeMap<usize, BTreeMap<usize, String>>,
+    breakpoints: Vec<Option<(usize, usize)>>,
+    watchpoints: Vec<Option<Watch>>,
+    started: bool,
+    // set once the call stopped on the op it was running rather than before
+    // the next one
+    stopped_after: bool,
+    finished: bool,
+}
+
+impl<T> Debugger<T>
+where
+    T: InterpretableProgram,
+{
+    pub fn new(mut execution: WasmExecution<T>) -> Result<Self, &'static str> {
+        execution.use_stack_engine()?;
+        let mut program = execution.program.lock().to_program();
+        let bytes = program.compile();
+        let offsets = instruction_offsets(&bytes)?;
+        let mut bodies = vec![];
+        let mut names = BTreeMap::new();
+        let mut local_names = BTreeMap::new();
+        for s in program.sections.iter() {
+            match s {
+                Section::Code(s) => {
+                    bodies = s
+                        .code_blocks
+                        .iter()
+                        .map(|c| c.instructions.clone())
+                        .collect()
+                }
+                Section::Custom(s) if s.name == "name" => {
+                    let (functions, locals) = name_section(&s.data)?;
+                    for (i, name) in functions {
+                        names.insert(i as usize, name.to_string());
+                    }
+                    for (i, locals) in locals {
+                        let locals = locals
+                            .into_iter()
+                            .map(|(l, name)| (l as usize, name.to_string()))
+                            .collect();
+                        local_names.insert(i as usize, locals);
+                    }
+                }
+                _ => {}
+            }
+        }
+        // exports name what the name section doesn't
+        for (name, i) in execution.module.function_exports.iter() {
+            names.entry(*i).or_insert_with(|| name.clone());
+        }
+        let starts = execution
+            .module
+            .functions
+            .iter()
+            .map(|f| {
+                // an instruction's ops all come before those of whatever
+                // comes after it, apart from the ones blocks end with
+                let mut last = None;
+                f.instructions
+                    .iter()
+                    .map(|i| {
+                        let start = match last {
+                            Some(l) => *i > l,
+                            None => true,
+                        };
+                        if start {
+                            last = Some(*i);
+                        }
+                        start
+                    })
+                    .collect()
+            })
+            .collect();
+        Ok(Debugger {
+            module: execution.module.clone(),
+            memory: execution.memory.clone(),
+            globals: execution.globals.clone(),
+            execution,
+            bytes,
+            bodies,
+            offsets,
+            starts,
+            names,
+            local_names,
+            breakpoints: vec![],
+            watchpoints: vec![],
+            started: false,
+            stopped_after: false,
+            finished: false,
+        })
+    }
+
+    /// The call being debugged, to answer imports or add fuel through.
+    pub fn execution(&mut self) -> &mut WasmExecution<T> {
+        &mut self.execution
+    }
+
+    pub fn into_execution(self) -> WasmExecution<T> {
+        self.execution
+    }
+
+    /// The bytes of the module being debugged, which are those of
+    /// `Program::compile`. For a parsed module that hasn't been changed
+    /// they're the bytes it was parsed from.
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// The name the name section gives a function, or else one it's
+    /// exported as.
+    pub fn function_name(&self, function: usize) -> Option<&str> {
+        self.names.get(&function).map(|n| n.as_str())
+    }
+
+    /// The name the name section gives a local of a function.
+    pub fn local_name(&self, function: usize, local: usize) -> Option<&str> {
+        self.local_names
+            .get(&function)
+            .and_then(|l| l.get(&local))
+            .map(|n| n.as_str())
+    }
+
+    // the body of a function that isn't imported
+    fn body(&self, function: usize) -> Option<&[Instruction]> {
+        let imports = self.module.functions.len() - self.bodies.len();
+        self.bodies
+            .get(function.checked_sub(imports)?)
+            .map(|b| b.as_slice())
+    }
+
+    /// Sets a breakpoint on an instruction, returning its id.
+    pub fn break_at(&mut self, function: usize, path: &[usize]) -> Result<usize, &'static str> {
+        let body = self.body(function).ok_or("function has no body")?;
+        let number = if path == [body.len()] {
+            count(body)
+        } else {
+            number(&[body], path).ok_or("no instruction at that path")?
+        };
+        // an instruction that doesn't lower to anything stops at the next
+        // one that does
+        let code = &self.module.functions[function].instructions;
+        let pc = code
+            .iter()
+            .position(|i| *i as usize >= number)
+            .ok_or("no instruction at that path")?;
+        self.breakpoints.push(Some((function, pc)));
+        Ok(self.breakpoints.len() - 1)
+    }
+
+    /// Sets a breakpoint on an instruction of a function found by name.
+    pub fn break_at_name(&mut self, name: &str, path: &[usize]) -> Result<usize, &'static str> {
+        let function = self
+            .names
+            .iter()
+            .find(|(_, n)| *n == name)
+            .map(|(i, _)| *i)
+            .ok_or("no function has that name")?;
+        self.break_at(function, path)
+    }
+
+    /// Sets a breakpoint on the instruction starting at `offset` in the
+    /// module's bytes.
+    pub fn break_at_offset(&mut self, offset: usize) -> Result<usize, &'static str> {
+        let imports = self.module.functions.len() - self.bodies.len();
+        let (function, number) = self
+            .offsets
+            .iter()
+            .enumerate()
+            .find_map(|(f, o)| o.iter().position(|o| *o == offset).map(|n| (f, n)))
+            .ok_or("no instruction starts at that offset")?;
+        let body = &self.bodies[function];
+        let path = path_in(body, number).ok_or("no instruction starts at that offset")?;
+        self.break_at(imports + function, &path)
+    }
+
+    pub fn remove_breakpoint(&mut self, id: usize) {
+        if let Some(b) = self.breakpoints.get_mut(id) {
+            *b = None;
+        }
+    }
+
+    /// Stops the call whenever the bytes in `range` of memory change,
+    /// returning the watchpoint's id.
+    pub fn watch_memory(&mut self, range: Range<usize>) -> Result<usize, &'static str> {
+        let bytes = self
+            .memory
+            .lock()
+            .get(range.clone())
+            .ok_or("range is out of memory bounds")?
+            .to_vec();
+        self.watchpoints.push(Some(Watch::Memory(range, bytes)));
+        Ok(self.watchpoints.len() - 1)
+    }
+
+    /// Stops the call whenever a global changes, returning the watchpoint's
+    /// id.
+    pub fn watch_global(&mut self, index: usize) -> Result<usize, &'static str> {
+        let value = *self
+            .globals
+            .lock()
+            .get(index)
+            .ok_or("invalid global index")?;
+        self.watchpoints.push(Some(Watch::Global(index, value)));
+        Ok(self.watchpoints.len() - 1)
+    }
+
+    pub fn unwatch(&mut self, id: usize) {
+        if let Some(w) = self.watchpoints.get_mut(id) {
+            *w = None;
+        }
+    }
+
+    /// Runs to the next instruction, going into calls.
+    pub fn step(&mut self) -> Result<StopReason, &'static str> {
+        self.run(|_| true)
+    }
+
+    /// Runs to the next instruction of the same call, or of its caller once
+    /// it returns.
+    pub fn step_over(&mut self) -> Result<StopReason, &'static str> {
+        let depth = self.execution.call_stack.len();
+        self.run(|d| d.execution.call_stack.len() <= depth)
+    }
+
+    /// Runs until the current call returns.
+    pub fn step_out(&mut self) -> Result<StopReason, &'static str> {
+        let depth = self.execution.call_stack.len();
+        self.run(|d| d.execution.call_stack.len() < depth)
+    }
+
+    /// Runs until something other than a step stops the call.
+    pub fn resume(&mut self) -> Result<StopReason, &'static str> {
+        self.run(|_| false)
+    }
+
+    // runs ops until `done` says so at the start of an instruction, or
+    // something else stops the call first
+    fn run(&mut self, done: impl Fn(&Self) -> bool) -> Result<StopReason, &'static str> {
+        if self.finished {
+            return Err("call has finished");
+        }
+        self.stopped_after = false;
+        if !self.started {
+            self.started = true;
+            if let Some(id) = self.breakpoint() {
+                return Ok(StopReason::Breakpoint(id));
+            }
+        }
+        loop {
+            // only this instance's calls are run an op at a time
+            let step = self.execution.outer.is_empty();
+            let unit = match self.execution.advance(step) {
+                Ok(unit) => unit,
+                Err(e) => {
+                    self.stopped_after = true;
+                    self.finished = true;
+                    return Ok(StopReason::Trap(e));
+                }
+            };
+            match unit {
+                None => {}
+                Some(ExecutionUnit::CallImport(call)) => {
+                    self.stopped_after = true;
+                    return Ok(StopReason::CallImport(call));
+                }
+                Some(ExecutionUnit::OutOfFuel) => return Ok(StopReason::OutOfFuel),
+                Some(ExecutionUnit::Complete(results)) => {
+                    self.finished = true;
+                    return Ok(StopReason::Complete(results));
+                }
+                Some(_) => {
+                    self.stopped_after = true;
+                    self.finished = true;
+                    return Ok(StopReason::Unreachable);
+                }
+            }
+            if let Some(stop) = self.watchpoint() {
+                return Ok(stop);
+            }
+            if self.at_instruction() {
+                if let Some(id) = self.breakpoint() {
+                    return Ok(StopReason::Breakpoint(id));
+                }
+                if done(self) {
+                    return Ok(StopReason::Step);
+                }
+            }
+        }
+    }
+
+    // whether the call is at the start of one of this instance's
+    // instructions
+    fn at_instruction(&self) -> bool {
+        if !self.execution.outer.is_empty() {
+            return false;
+        }
+        match self.execution.call_stack.last() {
+            Some(frame) => {
+                self.module.functions[frame.function].import.is_none()
+                    && self.starts[frame.function]
+                        .get(frame.pc)
+                        .copied()
+                        .unwrap_or(false)
+            }
+            None => false,
+        }
+    }
+
+    fn breakpoint(&self) -> Option<usize> {
+        if !self.at_instruction() {
+            return None;
+        }
+        let frame = self.execution.call_stack.last()?;
+        self.breakpoints
+            .iter()
+            .position(|b| *b == Some((frame.function, frame.pc)))
+    }
+
+    fn watchpoint(&mut self) -> Option<StopReason> {
+        let memory = self.memory.lock();
+        let globals = self.globals.lock();
+        for (id, w) in self.watchpoints.iter_mut().enumerate() {
+            match w {
+                Some(Watch::Memory(range, bytes)) => {
+                    let now = &memory[range.clone()];
+                    if now != bytes.as_slice() {
+                        let old = core::mem::replace(bytes, now.to_vec());
+                        return Some(StopReason::Watchpoint {
+                            id,
+                            old: Watched::Memory(old),
+                            new: Watched::Memory(now.to_vec()),
+                        });
+                    }
+                }
+                Some(Watch::Global(index, value)) => {
+                    let now = globals[*index];
+                    if now != *value {
+                        let old = core::mem::replace(value, now);
+                        return Some(StopReason::Watchpoint {
+                            id,
+                            old: Watched::Global(old),
+                            new: Watched::Global(now),
+                        });
+                    }
+                }
+                None => {}
+            }
+        }
+        None
+    }
+
+    // where op `pc` of a function was lowered from
+    fn locate(&self, function: usize, pc: usize) -> Location {
+        let mut location = Location {
+            function,
+            path: vec![],
+            offset: None,
+        };
+        let body = match self.body(function) {
+            Some(b) => b,
+            None => return location,
+        };
+        let instructions = &self.module.functions[function].instructions;
+        let number = match instructions.get(pc).or_else(|| instructions.last()) {
+            Some(n) => *n as usize,
+            None => return location,
+        };
+        location.path = path_in(body, number).unwrap_or_default();
+        let imports = self.module.functions.len() - self.bodies.len();
+        location.offset = self.offsets[function - imports].get(number).copied();
+        location
+    }
+
+    /// The calls on the call stack, innermost first. They can only be looked
+    /// at while the call is stopped in this instance.
+    pub fn frames(&self) -> Result<Vec<Frame>, &'static str> {
+        if !self.execution.outer.is_empty() {
+            return Err("call is in another instance");
+        }
+        let stack = &self.execution.call_stack;
+        let values = &self.execution.value_stack;
+        let mut frames = vec![];
+        for (i, frame) in stack.iter().enumerate().rev() {
+            let innermost = i + 1 == stack.len();
+            let pc = if innermost && !self.stopped_after {
+                frame.pc
+            } else {
+                frame.pc.saturating_sub(1)
+            };
+            let end = match stack.get(i + 1) {
+                Some(callee) => callee.stack_base,
+                None => values.len(),
+            };
+            frames.push(Frame {
+                location: self.locate(frame.function, pc),
+                name: self.function_name(frame.function).map(|n| n.to_string()),
+                locals: frame.locals.clone(),
+                operands: values[frame.stack_base.min(end)..end].to_vec(),
+            });
+        }
+        Ok(frames)
+    }
+
+    /// Where the call is stopped, if it hasn't returned.
+    pub fn location(&self) -> Option<Location> {
+        self.frames().ok()?.into_iter().next().map(|f| f.location)
+    }
+
+    pub fn read_memory(&self, range: Range<usize>) -> Result<Vec<u8>, &'static str> {
+        self.memory
+            .lock()
+            .get(range)
+            .map(|b| b.to_vec())
+            .ok_or("range is out of memory bounds")
+    }
+
+    pub fn globals(&self) -> Vec<WasmValue> {
+        self.globals.lock().clone()
+    }
+}
+
+// the bodies nested in an instruction, an if's else after its then
+fn nested(instruction: &Instruction) -> Vec<&[Instruction]> {
+    match instruction {
+        Instruction::Block(_, body) | Instruction::Loop(_, body) => vec![body],
+        Instruction::If(_, then_body, Some(else_body)) => vec![then_body, else_body],
+        Instruction::If(_, then_body, None) => vec![then_body],
+        _ => vec![],
+    }
+}
+
+// the number of the instruction at `path` in bodies that follow on from
+// each other
+fn number(bodies: &[&[Instruction]], path: &[usize]) -> Option<usize> {
+    let (&index, rest) = path.split_first()?;
+    let mut index = index;
+    let mut before = 0;
+    for body in bodies.iter() {
+        if index < body.len() {
+            before += count(&body[..index]);
+            if rest.is_empty() {
+                return Some(before);
+            }
+            return Some(before + 1 + number(&nested(&body[index]), rest)?);
+        }
+        index -= body.len();
+        before += count(body);
+    }
+    None
+}
+
+// the path to an instruction of a function's body by its number, which is
+// one past the last for the body's end
+fn path_in(body: &[Instruction], number: usize) -> Option<Vec<usize>> {
+    if number == count(body) {
+        Some(vec![body.len()])
+    } else {
+        path(&[body], number)
+    }
+}
+
+// the path to an instruction by its number
+fn path(bodies: &[&[Instruction]], number: usize) -> Option<Vec<usize>> {
+    let mut number = number;
+    for (index, instruction) in bodies.iter().flat_map(|b| b.iter()).enumerate() {
+        if number == 0 {
+            return Some(vec![index]);
+        }
+        let size = count(core::slice::from_ref(instruction));
+        if number < size {
+            let mut path = vec![index];
+            path.extend(self::path(&nested(instruction), number - 1)?);
+            return Some(path);
+        }
+        number -= size;
+    }
+    None
+}
@@ -0,0 +1,31 @@
+use super::{ExecutionResponse, ExecutionUnit, ImportCall, InterpretableProgram};
+use super::{WasmExecution, WasmValue};
+use alloc::vec::Vec;
+use core::future::Future;
+
+impl<T> WasmExecution<T>
+where
+    T: InterpretableProgram,
+{
+    /// Runs the call to completion, handing each import it calls to
+    /// `resolve` and awaiting the values it returns with. Everything else
+    /// runs inside, so this works the same on any executor. A metered call
+    /// that runs out of fuel fails.
+    pub async fn run_async<F, R>(&mut self, mut resolve: F) -> Result<Vec<WasmValue>, &'static str>
+    where
+        F: FnMut(ImportCall) -> R,
+        R: Future<Output = Result<Vec<WasmValue>, &'static str>>,
+    {
+        loop {
+            let response = match self.next_unit()? {
+                ExecutionUnit::CallImport(call) => {
+                    ExecutionResponse::AddValues(resolve(call).await?)
+                }
+                ExecutionUnit::Complete(values) => return Ok(values),
+                ExecutionUnit::OutOfFuel => return Err("ran out of fuel"),
+                mut unit => unit.evaluate()?,
+            };
+            self.execute(response)?;
+        }
+    }
+}
@@ -0,0 +1,443 @@
+use super::bytecode::Module;
+use super::run::PAGE_SIZE;
+use super::store::{self, Link, Table};
+use super::{WasmType, WasmValue, WasmValues};
+use crate::core::*;
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+use spin::{Mutex, MutexGuard};
+
+/// What a host function gets to reach the instance calling it through.
+/// Nothing of the instance is locked while a host function runs, only while
+/// what `memory` or `globals` give back is held, so a host function can
+/// lock a `SharedMemory` or `SharedGlobal` of the same instance as long as it
+/// doesn't hold both at once.
+pub struct Caller<'a> {
+    memory: &'a Mutex<Vec<u8>>,
+    globals: &'a Mutex<Vec<WasmValue>>,
+    module: &'a Module,
+}
+
+impl<'a> Caller<'a> {
+    pub(crate) fn new(
+        memory: &'a Mutex<Vec<u8>>,
+        globals: &'a Mutex<Vec<WasmValue>>,
+        module: &'a Module,
+    ) -> Self {
+        Caller {
+            memory,
+            globals,
+            module,
+        }
+    }
+
+    pub fn memory(&mut self) -> MutexGuard<'_, Vec<u8>> {
+        self.memory.lock()
+    }
+
+    pub fn globals(&mut self) -> CallerGlobals<'_> {
+        let mut globals = self.globals.lock();
+        store::load_globals(self.module, &mut globals);
+        CallerGlobals {
+            globals,
+            module: self.module,
+        }
+    }
+}
+
+/// The globals of the instance calling a host function, including any it
+/// imports, which are handed back to where they're kept once it's dropped.
+pub struct CallerGlobals<'a> {
+    globals: MutexGuard<'a, Vec<WasmValue>>,
+    module: &'a Module,
+}
+
+impl<'a> Deref for CallerGlobals<'a> {
+    type Target = [WasmValue];
+
+    fn deref(&self) -> &[WasmValue] {
+        &self.globals
+    }
+}
+
+impl<'a> DerefMut for CallerGlobals<'a> {
+    fn deref_mut(&mut self) -> &mut [WasmValue] {
+        &mut self.globals
+    }
+}
+
+impl<'a> Drop for CallerGlobals<'a> {
+    fn drop(&mut self) {
+        store::store_globals(self.module, &self.globals);
+    }
+}
+
+/// Why a host function stopped the call it was made from, which the call
+/// then fails with.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Trap(pub &'static str);
+
+impl From<&'static str> for Trap {
+    fn from(reason: &'static str) -> Self {
+        Trap(reason)
+    }
+}
+
+impl From<Trap> for &'static str {
+    fn from(trap: Trap) -> Self {
+        trap.0
+    }
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+type Call = dyn Fn(&mut Caller, &[WasmValue]) -> Result<Vec<WasmValue>, Trap> + Send + Sync;
+
+/// A host function along with the signature it was registered with.
+#[derive(Clone)]
+pub struct HostFunction {
+    pub signature: FunctionType,
+    call: Arc<Call>,
+}
+
+impl HostFunction {
+    pub fn call(&self, caller: &mut Caller, params: &[WasmValue]) -> Result<Vec<WasmValue>, Trap> {
+        (self.call)(caller, params)
+    }
+}
+
+impl fmt::Debug for HostFunction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("HostFunction")
+            .field("signature", &self.signature)
+            .finish()
+    }
+}
+
+/// A closure that can be registered as a host function, taking a `Caller`
+/// and then its parameters as `i32`, `i64`, `f32` or `f64`.
+pub trait IntoHostFunction<Params, Results> {
+    fn into_host_function(self) -> HostFunction;
+}
+
+macro_rules! into_host_function {
+    ($($p:ident),*) => {
+        impl<F, R, $($p),*> IntoHostFunction<($($p,)*), R> for F
+        where
+            F: Fn(&mut Caller, $($p),*) -> Result<R, Trap> + Send + Sync + 'static,
+            R: WasmValues,
+            $($p: WasmType,)*
+        {
+            #[allow(non_snake_case, unused_mut, unused_variables)]
+            fn into_host_function(self) -> HostFunction {
+                HostFunction {
+                    signature: FunctionType {
+                        inputs: vec![$($p::VALUE_TYPE),*],
+                        outputs: R::value_types(),
+                    },
+                    call: Arc::new(move |caller: &mut Caller, params: &[WasmValue]| {
+                        let mut params = params.iter();
+                        $(
+                            let $p = params
+                                .next()
+                                .and_then(|v| $p::from_value(*v))
+                                .ok_or("host function was passed the wrong parameters")?;
+                        )*
+                        Ok(self(caller, $($p),*)?.into_values())
+                    }),
+                }
+            }
+        }
+    };
+}
+
+into_host_function!();
+into_host_function!(A);
+into_host_function!(A, B);
+into_host_function!(A, B, C);
+into_host_function!(A, B, C, D);
+into_host_function!(A, B, C, D, E);
+into_host_function!(A, B, C, D, E, G);
+into_host_function!(A, B, C, D, E, G, H);
+into_host_function!(A, B, C, D, E, G, H, I);
+into_host_function!(A, B, C, D, E, G, H, I, J);
+
+/// A memory to import, along with how many pages it can grow to.
+#[derive(Clone, Debug)]
+pub struct SharedMemory {
+    pub memory: Arc<Mutex<Vec<u8>>>,
+    pub max_pages: Option<usize>,
+}
+
+impl SharedMemory {
+    /// Whether it can stand in for a memory of between `min` and `max`
+    /// pages.
+    pub(crate) fn fits(&self, min: usize, max: Option<usize>) -> bool {
+        let size = self.memory.lock().len();
+        let pages = size / PAGE_SIZE;
+        let max_fits = match (max, self.max_pages) {
+            (Some(max), Some(limit)) => pages <= limit && limit <= max,
+            (Some(_), None) => false,
+            (None, limit) => !matches!(limit, Some(limit) if pages > limit),
+        };
+        pages * PAGE_SIZE == size && pages >= min && max_fits
+    }
+}
+
+/// A table to import. Every instance that imports it calls through the
+/// same elements, and the functions one instance puts there can be called
+/// from the others.
+#[derive(Clone, Debug)]
+pub struct SharedTable {
+    pub(crate) table: Arc<Mutex<Table>>,
+}
+
+impl SharedTable {
+    /// An empty table of `size` elements, which can't grow past `max`.
+    pub fn new(size: usize, max: Option<usize>) -> Self {
+        SharedTable {
+            table: Arc::new(Mutex::new(Table::empty(size, max))),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.table.lock().elements.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The function each element refers to, by its index in the instance
+    /// that put it there.
+    pub fn functions(&self) -> Vec<Option<u32>> {
+        self.table
+            .lock()
+            .elements
+            .iter()
+            .map(|e| e.as_ref().map(|e| e.function as u32))
+            .collect()
+    }
+
+    /// Whether it can stand in for a table of between `min` and `max`
+    /// elements.
+    pub(crate) fn fits(&self, min: usize, max: Option<usize>) -> bool {
+        let table = self.table.lock();
+        let max_fits = match (max, table.max) {
+            (Some(max), Some(limit)) => limit <= max,
+            (Some(_), None) => false,
+            (None, _) => true,
+        };
+        table.elements.len() >= min && max_fits
+    }
+}
+
+/// A global to import. Every instance importing a mutable global sees what
+/// any of them, or the host, sets it to.
+#[derive(Clone, Debug)]
+pub struct SharedGlobal {
+    pub(crate) globals: Arc<Mutex<Vec<WasmValue>>>,
+    pub(crate) index: usize,
+    pub(crate) is_mutable: bool,
+}
+
+impl SharedGlobal {
+    /// A global of its own, starting out as `value`.
+    pub fn new(value: WasmValue, is_mutable: bool) -> Self {
+        SharedGlobal {
+            globals: Arc::new(Mutex::new(vec![value])),
+            index: 0,
+            is_mutable,
+        }
+    }
+
+    pub fn get(&self) -> WasmValue {
+        self.globals.lock()[self.index]
+    }
+
+    /// Sets the global, which has to be mutable and keep its type.
+    pub fn set(&self, value: WasmValue) -> Result<(), &'static str> {
+        if !self.is_mutable {
+            return Err("global is immutable");
+        }
+        let mut globals = self.globals.lock();
+        if value.value_type() != globals[self.index].value_type() {
+            return Err("global has a different type");
+        }
+        globals[self.index] = value;
+        Ok(())
+    }
+
+    pub fn is_mutable(&self) -> bool {
+        self.is_mutable
+    }
+}
+
+/// Host functions, memories, tables and globals by the module and name
+/// they're imported under. An interpreter calls the functions itself
+/// instead of handing the calls back.
+#[derive(Clone, Debug, Default)]
+pub struct Imports {
+    functions: BTreeMap<(String, String), HostFunction>,
+    links: BTreeMap<(String, String), Link>,
+    memories: BTreeMap<(String, String), SharedMemory>,
+    tables: BTreeMap<(String, String), SharedTable>,
+    globals: BTreeMap<(String, String), SharedGlobal>,
+}
+
+impl Imports {
+    pub fn new() -> Self {
+        Imports::default()
+    }
+
+    /// Registers a closure as the function imported as `name` from
+    /// `module_name`, replacing whatever was there.
+    pub fn func<Params, Results>(
+        &mut self,
+        module_name: &str,
+        name: &str,
+        f: impl IntoHostFunction<Params, Results>,
+    ) -> &mut Self {
+        let key = (module_name.to_string(), name.to_string());
+        self.links.remove(&key);
+        self.functions.insert(key, f.into_host_function());
+        self
+    }
+
+    // makes calls to the import go to another instance's export instead
+    pub(crate) fn link(&mut self, module_name: &str, name: &str, link: Link) -> &mut Self {
+        let key = (module_name.to_string(), name.to_string());
+        self.functions.remove(&key);
+        self.links.insert(key, link);
+        self
+    }
+
+    /// Registers a memory to import as `name` from `module_name`, which
+    /// can't grow past `max_pages`. Whatever the module does to it is seen
+    /// through `memory`, which has to be a whole number of pages.
+    pub fn memory(
+        &mut self,
+        module_name: &str,
+        name: &str,
+        memory: Arc<Mutex<Vec<u8>>>,
+        max_pages: Option<usize>,
+    ) -> &mut Self {
+        self.memories.insert(
+            (module_name.to_string(), name.to_string()),
+            SharedMemory { memory, max_pages },
+        );
+        self
+    }
+
+    /// Registers a table to import as `name` from `module_name`.
+    pub fn table(&mut self, module_name: &str, name: &str, table: SharedTable) -> &mut Self {
+        self.tables
+            .insert((module_name.to_string(), name.to_string()), table);
+        self
+    }
+
+    /// Registers the value of an immutable global imported as `name` from
+    /// `module_name`.
+    pub fn global(&mut self, module_name: &str, name: &str, value: WasmValue) -> &mut Self {
+        self.shared_global(module_name, name, SharedGlobal::new(value, false))
+    }
+
+    /// Registers a global to import as `name` from `module_name`, which
+    /// can be mutable.
+    pub fn shared_global(
+        &mut self,
+        module_name: &str,
+        name: &str,
+        global: SharedGlobal,
+    ) -> &mut Self {
+        self.globals
+            .insert((module_name.to_string(), name.to_string()), global);
+        self
+    }
+
+    pub fn get(&self, module_name: &str, name: &str) -> Option<&HostFunction> {
+        self.functions
+            .get(&(module_name.to_string(), name.to_string()))
+    }
+
+    pub(crate) fn get_link(&self, module_name: &str, name: &str) -> Option<&Link> {
+        self.links.get(&(module_name.to_string(), name.to_string()))
+    }
+
+    pub fn get_memory(&self, module_name: &str, name: &str) -> Option<&SharedMemory> {
+        self.memories
+            .get(&(module_name.to_string(), name.to_string()))
+    }
+
+    pub fn get_table(&self, module_name: &str, name: &str) -> Option<&SharedTable> {
+        self.tables
+            .get(&(module_name.to_string(), name.to_string()))
+    }
+
+    pub fn get_global(&self, module_name: &str, name: &str) -> Option<&SharedGlobal> {
+        self.globals
+            .get(&(module_name.to_string(), name.to_string()))
+    }
+
+    /// The module and name of everything `program` imports that isn't
+    /// registered, or doesn't match what was: a function with a different
+    /// signature, a memory or table of the wrong size or maximum or a global
+    /// of another type or mutability.
+    pub fn unresolved<'p>(&self, program: &'p Program) -> Vec<(&'p str, &'p str)> {
+        let types: Vec<&FunctionType> = program
+            .sections
+            .iter()
+            .filter_map(|s| match s {
+                Section::Type(s) => Some(s.types.iter()),
+                _ => None,
+            })
+            .flatten()
+            .collect();
+        program
+            .sections
+            .iter()
+            .filter_map(|s| match s {
+                Section::Import(s) => Some(s.imports.iter()),
+                _ => None,
+            })
+            .flatten()
+            .filter_map(|i| match i {
+                WasmImport::Function(f) => {
+                    let signature = match self.get_link(&f.module_name, &f.name) {
+                        Some(link) => Some(link.signature()),
+                        None => self.get(&f.module_name, &f.name).map(|h| &h.signature),
+                    };
+                    match signature {
+                        Some(s) if types.get(f.type_index) == Some(&s) => None,
+                        _ => Some((f.module_name.as_str(), f.name.as_str())),
+                    }
+                }
+                WasmImport::Memory(m) => match self.get_memory(&m.module_name, &m.name) {
+                    Some(memory) if memory.fits(m.min_pages, m.max_pages) => None,
+                    _ => Some((m.module_name.as_str(), m.name.as_str())),
+                },
+                WasmImport::Global(g) => match self.get_global(&g.module_name, &g.name) {
+                    Some(global)
+                        if global.get().value_type() == g.value_type
+                            && global.is_mutable == g.is_mutable =>
+                    {
+                        None
+                    }
+                    _ => Some((g.module_name.as_str(), g.name.as_str())),
+                },
+                WasmImport::Table(t) => match self.get_table(&t.module_name, &t.name) {
+                    Some(table) if table.fits(t.min, t.max) => None,
+                    _ => Some((t.module_name.as_str(), t.name.as_str())),
+                },
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,322 @@
+use super::bytecode::{Global, Module};
+use super::imports::{SharedGlobal, SharedMemory, SharedTable};
+use super::run::PAGE_SIZE;
+use super::store::{Context, Element, Link};
+use super::{Imports, InterpretableProgram, Interpreter, InterpreterConfig, WasmValue, WasmValues};
+use crate::core::*;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+/// A module's memory once it's set up, along with where the functions of
+/// each element segment go in its table.
+pub(crate) struct Instantiated {
+    pub memory: Arc<Mutex<Vec<u8>>>,
+    pub elements: Vec<(usize, Vec<usize>)>,
+}
+
+/// Sets up what a module starts out with in the spec's order: its imported
+/// memory, table and globals are resolved, the rest of its memory and
+/// globals allocated and evaluated, then its data segments written once
+/// they and its element segments are all known to fit. The element
+/// segments are handed back for `write_elements`, since the functions in
+/// them belong to an instance that doesn't exist yet. Running the start
+/// function is left to the caller.
+///
+/// Without any `imports`, an imported memory, table or global is set up as
+/// though the module defined it, zeroed or empty, rather than refused.
+pub(crate) fn instantiate(
+    program: &Program,
+    module: &mut Module,
+    imports: Option<&Imports>,
+) -> Result<Instantiated, &'static str> {
+    let memory = match (&module.memory_import, imports) {
+        (Some((module_name, name)), Some(imports)) => {
+            let shared = imports
+                .get_memory(module_name, name)
+                .ok_or("module imports a memory that isn't provided")?;
+            if !shared.fits(module.min_memory_pages, module.max_memory_pages) {
+                return Err("imported memory is the wrong size");
+            }
+            module.max_memory_pages = shared.max_pages;
+            shared.memory.clone()
+        }
+        _ => Arc::new(Mutex::new(vec![0; module.min_memory_pages * PAGE_SIZE])),
+    };
+    if let (Some((module_name, name)), Some(imports)) = (&module.table_import, imports) {
+        let shared = imports
+            .get_table(module_name, name)
+            .ok_or("module imports a table that isn't provided")?;
+        let (min, max) = {
+            let table = module.table.lock();
+            (table.elements.len(), table.max)
+        };
+        if !shared.fits(min, max) {
+            return Err("imported table is the wrong size");
+        }
+        module.table = shared.table.clone();
+    }
+    for i in 0..module.globals.len() {
+        let (earlier, rest) = module.globals.split_at_mut(i);
+        let global = &mut rest[0];
+        global.value = match (&global.import, imports) {
+            (Some((module_name, name)), Some(imports)) => {
+                let shared = imports
+                    .get_global(module_name, name)
+                    .ok_or("module imports a global that isn't provided")?;
+                if shared.is_mutable != global.is_mutable {
+                    return Err("global has the wrong mutability");
+                }
+                module.global_imports.push((i, shared.clone()));
+                shared.get()
+            }
+            // left as the zero it was lowered with
+            (Some(_), None) => global.value,
+            (None, _) => constant(&global.init, earlier)?,
+        };
+        if global.value.value_type() != global.value_type {
+            return Err("global has the wrong type");
+        }
+    }
+
+    let mut elements = vec![];
+    let mut data = vec![];
+    let table_size = module.table.lock().elements.len();
+    let mut bytes = memory.lock();
+    for s in program.sections.iter() {
+        match s {
+            Section::Element(s) => {
+                for e in s.elements.iter() {
+                    let offset = offset(&e.value_expression, &module.globals)?;
+                    if !within(offset, e.functions.len(), table_size) {
+                        return Err("elements segment does not fit");
+                    }
+                    elements.push((offset, e.functions.clone()));
+                }
+            }
+            Section::Data(s) => {
+                for d in s.data_blocks.iter().filter(|d| !d.passive) {
+                    let offset = offset(&d.offset_expression, &module.globals)?;
+                    if !within(offset, d.data.len(), bytes.len()) {
+                        return Err("data segment does not fit");
+                    }
+                    data.push((offset, &d.data));
+                }
+            }
+            _ => {}
+        }
+    }
+    for (offset, d) in data {
+        bytes[offset..offset + d.len()].copy_from_slice(d);
+    }
+    drop(bytes);
+    Ok(Instantiated { memory, elements })
+}
+
+/// Puts the functions of element segments in the table of the instance
+/// `context` is for, as belonging to it.
+pub(crate) fn write_elements(context: &Context, elements: Vec<(usize, Vec<usize>)>) {
+    let instance = context.downgrade();
+    let mut table = context.module.table.lock();
+    for (offset, functions) in elements {
+        for (j, function) in functions.into_iter().enumerate() {
+            table.elements[offset + j] = Some(Element {
+                function,
+                instance: instance.clone(),
+            });
+        }
+    }
+}
+
+fn constant(expression: &[Instruction], globals: &[Global]) -> Result<WasmValue, &'static str> {
+    match expression {
+        [Instruction::I32Const(v)] => Ok(WasmValue::I32(*v)),
+        [Instruction::I64Const(v)] => Ok(WasmValue::I64(*v)),
+        [Instruction::F32Const(v)] => Ok(WasmValue::F32(*v)),
+        [Instruction::F64Const(v)] => Ok(WasmValue::F64(*v)),
+        [Instruction::GlobalGet(i)] => globals
+            .get(*i as usize)
+            .map(|g| g.value)
+            .ok_or("invalid global index"),
+        _ => Err("unsupported constant expression"),
+    }
+}
+
+fn offset(expression: &[Instruction], globals: &[Global]) -> Result<usize, &'static str> {
+    match constant(expression, globals)? {
+        WasmValue::I32(o) => Ok(o as u32 as usize),
+        _ => Err("segment offset must be an i32"),
+    }
+}
+
+fn within(offset: usize, len: usize, size: usize) -> bool {
+    matches!(offset.checked_add(len), Some(end) if end <= size)
+}
+
+/// A module that's been instantiated: its imports resolved, its segments
+/// written and its start function run. Its exports can be reached by name.
+pub struct Instance<T>
+where
+    T: InterpretableProgram,
+{
+    interpreter: Interpreter<T>,
+    exports: Vec<WasmExport>,
+}
+
+impl<T> Instance<T>
+where
+    T: InterpretableProgram,
+{
+    /// Instantiates `p` with everything it imports taken from `imports`,
+    /// running its start function to completion if it has one. Functions it
+    /// puts in an imported table can only be called from there for as long
+    /// as the instance is around.
+    pub fn new(p: T, config: &InterpreterConfig, imports: Imports) -> Result<Self, &'static str> {
+        let mut instance = Instance::unstarted(p, config, imports)?;
+        instance.start()?;
+        Ok(instance)
+    }
+
+    // instantiates `p` without running its start function
+    pub(crate) fn unstarted(
+        p: T,
+        config: &InterpreterConfig,
+        imports: Imports,
+    ) -> Result<Self, &'static str> {
+        let exports = p
+            .to_program()
+            .sections
+            .into_iter()
+            .filter_map(|s| match s {
+                Section::Export(s) => Some(s.exports),
+                _ => None,
+            })
+            .flatten()
+            .collect();
+        Ok(Instance {
+            interpreter: Interpreter::with_imports(p, config, imports)?,
+            exports,
+        })
+    }
+
+    // runs the start function to completion, if there is one
+    pub(crate) fn start(&mut self) -> Result<(), &'static str> {
+        if let Some(start) = self.interpreter.module.start {
+            self.interpreter.call_function(start, &[])?.finish()?;
+        }
+        Ok(())
+    }
+
+    /// Everything the module exports, in the order it exports them.
+    pub fn exports(&self) -> &[WasmExport] {
+        &self.exports
+    }
+
+    /// Calls an exported function and runs it to completion.
+    pub fn call(
+        &mut self,
+        name: &str,
+        params: &[WasmValue],
+    ) -> Result<Vec<WasmValue>, &'static str> {
+        self.interpreter.call(name, params)?.finish()
+    }
+
+    pub fn call_typed<P, R>(&mut self, name: &str, params: P) -> Result<R, &'static str>
+    where
+        P: WasmValues,
+        R: WasmValues,
+    {
+        self.interpreter.call_typed(name, params)
+    }
+
+    /// The signature of an exported function.
+    pub fn function(&self, name: &str) -> Option<&FunctionType> {
+        match self.export(name)? {
+            WasmExport::Function(f) => {
+                let function = self.interpreter.module.functions.get(f.index)?;
+                Some(&function.signature)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn memory(&self, name: &str) -> Option<Arc<Mutex<Vec<u8>>>> {
+        match self.export(name)? {
+            WasmExport::Memory(_) => Some(self.interpreter.memory.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn global(&self, name: &str) -> Option<WasmValue> {
+        Some(self.shared_global(name)?.get())
+    }
+
+    /// Sets an exported global, which has to be mutable and keep its type.
+    pub fn set_global(&mut self, name: &str, value: WasmValue) -> Result<(), &'static str> {
+        self.shared_global(name)
+            .ok_or("could not find exported global")?
+            .set(value)
+    }
+
+    pub fn table(&self, name: &str) -> Option<SharedTable> {
+        match self.export(name)? {
+            WasmExport::Table(_) => Some(SharedTable {
+                table: self.interpreter.module.table.clone(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// The interpreter underneath, for stepping through calls or taking
+    /// snapshots.
+    pub fn interpreter(&mut self) -> &mut Interpreter<T> {
+        &mut self.interpreter
+    }
+
+    // an exported global for another instance to import, which is kept
+    // wherever it was imported from if it was
+    pub(crate) fn shared_global(&self, name: &str) -> Option<SharedGlobal> {
+        let index = match self.export(name)? {
+            WasmExport::Global(g) => g.index,
+            _ => return None,
+        };
+        let module = &self.interpreter.module;
+        match module.global_imports.iter().find(|(i, _)| *i == index) {
+            Some((_, shared)) => Some(shared.clone()),
+            None => Some(SharedGlobal {
+                globals: self.interpreter.globals.clone(),
+                index,
+                is_mutable: module.globals.get(index)?.is_mutable,
+            }),
+        }
+    }
+
+    // an exported memory for another instance to import
+    pub(crate) fn shared_memory(&self, name: &str) -> Option<SharedMemory> {
+        Some(SharedMemory {
+            memory: self.memory(name)?,
+            max_pages: self.interpreter.module.max_memory_pages,
+        })
+    }
+
+    // an exported function for another instance to import
+    pub(crate) fn link(&self, name: &str) -> Option<Link> {
+        let function = match self.export(name)? {
+            WasmExport::Function(f) => f.index,
+            _ => return None,
+        };
+        Some(Link {
+            function,
+            context: self.interpreter.context(),
+        })
+    }
+
+    fn export(&self, name: &str) -> Option<&WasmExport> {
+        self.exports.iter().find(|e| match e {
+            WasmExport::Function(e)
+            | WasmExport::Table(e)
+            | WasmExport::Memory(e)
+            | WasmExport::Global(e) => e.name == name,
+        })
+    }
+}
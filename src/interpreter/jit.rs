@@ -0,0 +1,1066 @@
+use super::bytecode::*;
+use super::registers::{Move, RegisterOp};
+use super::run::{self, Limits};
+use super::{CallFrame, ExecutionUnit, ImportCall, WasmValue};
+use crate::core::ValueType;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Mutex;
+use webassembly::*;
+
+// general purpose registers by their encoding
+const RAX: u8 = 0;
+const RCX: u8 = 1;
+const RDX: u8 = 2;
+const RBX: u8 = 3;
+const RSI: u8 = 6;
+const RDI: u8 = 7;
+const R12: u8 = 12;
+const R13: u8 = 13;
+const R14: u8 = 14;
+const R15: u8 = 15;
+
+// condition codes
+const B: u8 = 0x2;
+const AE: u8 = 0x3;
+const E: u8 = 0x4;
+const NE: u8 = 0x5;
+const BE: u8 = 0x6;
+const A: u8 = 0x7;
+const L: u8 = 0xc;
+const GE: u8 = 0xd;
+const LE: u8 = 0xe;
+const G: u8 = 0xf;
+
+// what compiled code returns
+const OK: u32 = 0;
+const UNREACHABLE: u32 = 1;
+const DIVIDE_BY_ZERO: u32 = 2;
+const OVERFLOW: u32 = 3;
+const OUT_OF_BOUNDS: u32 = 4;
+const EXHAUSTED: u32 = 5;
+const TRAPS: u32 = 5;
+// a call to an import, with the frames it was made from left in `frames`
+const IMPORT: u32 = 6;
+
+/// How deep the limits can let compiled code call, which keeps it well
+/// inside the native stack.
+const MAX_DEPTH: usize = 10_000;
+
+/// What compiled code reaches the instance through. It keeps the frame's
+/// registers in `rbx`, this in `r12` and memory's base and length in `r13`
+/// and `r14`. Calling an import unwinds every frame, each of which writes
+/// its function, where it picks up again and its registers to `frames`.
+#[repr(C)]
+struct Context {
+    memory: *mut u8,
+    memory_len: u64,
+    globals: *mut u64,
+    registers_end: *const u64,
+    depth: u64,
+    frames: *mut [u64; 3],
+    unwound: u64,
+}
+
+type Entry = unsafe extern "sysv64" fn(*mut u64, *mut Context) -> u32;
+
+/// Machine code for the functions of a module that could be compiled.
+/// Values are kept as raw bits, with 32-bit ones zero extended.
+pub(crate) struct Code {
+    memory: *mut u8,
+    size: usize,
+    /// Where each function starts in the code, if it was compiled.
+    entries: Vec<Option<usize>>,
+    /// The types of a frame's registers once the call it unwound from
+    /// returns, by function and the position after the call.
+    frame_types: BTreeMap<(usize, usize), Vec<ValueType>>,
+    scratch: Mutex<Scratch>,
+}
+
+// what calls into compiled code share, which is only allocated once it's
+// first needed
+struct Scratch {
+    registers: Vec<u64>,
+    frames: Vec<[u64; 3]>,
+    // how big each has to be
+    size: (usize, usize),
+}
+
+// the code is never written to once it's mapped
+unsafe impl Send for Code {}
+unsafe impl Sync for Code {}
+
+impl fmt::Debug for Code {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Code")
+            .field("size", &self.size)
+            .field("entries", &self.entries)
+            .finish()
+    }
+}
+
+impl Drop for Code {
+    fn drop(&mut self) {
+        if !self.memory.is_null() {
+            unsafe { libc::munmap(self.memory as *mut libc::c_void, self.size) };
+        }
+    }
+}
+
+impl Code {
+    pub fn is_compiled(&self, function: usize) -> bool {
+        matches!(self.entries.get(function), Some(Some(_)))
+    }
+
+    /// Runs a compiled function until it completes or calls an import,
+    /// turning traps back into the interpreter's errors. `room` is how many
+    /// calls, the function's own included, and registers it can still use.
+    /// A call to an import leaves the frames it was made from in `unwound`,
+    /// outermost first, for the register engine to carry on with.
+    #[allow(clippy::too_many_arguments)]
+    pub fn call(
+        &self,
+        module: &Module,
+        function: usize,
+        params: &[WasmValue],
+        memory: &mut [u8],
+        globals: &mut [WasmValue],
+        room: Limits,
+        unwound: &mut Vec<CallFrame>,
+    ) -> Result<ExecutionUnit, &'static str> {
+        let entry = self.entries[function].ok_or("function was not compiled")?;
+        let mut scratch = self.scratch.lock();
+        let Scratch {
+            registers,
+            frames,
+            size,
+        } = &mut *scratch;
+        if registers.is_empty() {
+            registers.resize(size.0, 0);
+            frames.resize(size.1, [0; 3]);
+        }
+        let room_registers = room.values.min(registers.len());
+        if room.calls == 0 || module.registers[function].registers as usize > room_registers {
+            return Err(run::EXHAUSTED);
+        }
+        for (r, p) in registers.iter_mut().zip(params.iter()) {
+            *r = to_bits(*p);
+        }
+        let mut global_bits: Vec<u64> = globals.iter().map(|g| to_bits(*g)).collect();
+        let mut context = Context {
+            memory: memory.as_mut_ptr(),
+            memory_len: memory.len() as u64,
+            globals: global_bits.as_mut_ptr(),
+            registers_end: registers[..room_registers].as_ptr_range().end,
+            depth: (room.calls - 1) as u64,
+            frames: frames.as_mut_ptr(),
+            unwound: 0,
+        };
+        let result = unsafe {
+            let entry: Entry = core::mem::transmute(self.memory.add(entry));
+            entry(registers.as_mut_ptr(), &mut context)
+        };
+        // globals set before a trap stay set
+        for (g, (bits, global)) in globals
+            .iter_mut()
+            .zip(global_bits.iter().zip(module.globals.iter()))
+        {
+            *g = from_bits(global.value_type, *bits);
+        }
+        match result {
+            OK => Ok(ExecutionUnit::Complete(
+                module.functions[function]
+                    .signature
+                    .outputs
+                    .iter()
+                    .zip(registers.iter())
+                    .map(|(t, bits)| from_bits(*t, *bits))
+                    .collect(),
+            )),
+            UNREACHABLE => Ok(ExecutionUnit::Unreachable),
+            DIVIDE_BY_ZERO => Err("integer divide by zero"),
+            OVERFLOW => Err("integer overflow"),
+            OUT_OF_BOUNDS => Err("out of bounds memory access"),
+            IMPORT => {
+                let start = registers.as_ptr() as u64;
+                for [function, pc, rbx] in frames[..context.unwound as usize].iter().rev() {
+                    let (function, pc) = (*function as usize, *pc as usize);
+                    let base = ((*rbx - start) / 8) as usize;
+                    let types = &self.frame_types[&(function, pc)];
+                    unwound.push(CallFrame {
+                        function,
+                        pc,
+                        locals: types
+                            .iter()
+                            .zip(registers[base..].iter())
+                            .map(|(t, bits)| from_bits(*t, *bits))
+                            .collect(),
+                        stack_base: 0,
+                    });
+                }
+                // the innermost frame stopped at the call to the import
+                let innermost = &frames[0];
+                let base = ((innermost[2] - start) / 8) as usize;
+                match module.registers[innermost[0] as usize].code[innermost[1] as usize - 1] {
+                    RegisterOp::Call { function, base: b } => {
+                        let import = &module.functions[function as usize];
+                        let (module_name, name) = import.import.as_ref().ok_or(UNWOUND)?;
+                        let params = import
+                            .signature
+                            .inputs
+                            .iter()
+                            .zip(registers[base + b as usize..].iter())
+                            .map(|(t, bits)| from_bits(*t, *bits))
+                            .collect();
+                        Ok(ExecutionUnit::CallImport(ImportCall::new(
+                            module_name,
+                            name,
+                            params,
+                        )))
+                    }
+                    _ => Err(UNWOUND),
+                }
+            }
+            _ => Err(run::EXHAUSTED),
+        }
+    }
+}
+
+const UNWOUND: &str = "compiled code unwound from something other than an import call";
+
+fn to_bits(v: WasmValue) -> u64 {
+    match v {
+        WasmValue::I32(v) => v as u32 as u64,
+        WasmValue::I64(v) => v as u64,
+        WasmValue::F32(v) => v.to_bits() as u64,
+        WasmValue::F64(v) => v.to_bits(),
+    }
+}
+
+fn from_bits(t: ValueType, bits: u64) -> WasmValue {
+    match t {
+        ValueType::I32 => WasmValue::I32(bits as i32),
+        ValueType::I64 => WasmValue::I64(bits as i64),
+        ValueType::F32 => WasmValue::F32(f32::from_bits(bits as u32)),
+        ValueType::F64 => WasmValue::F64(f64::from_bits(bits)),
+    }
+}
+
+/// Compiles every function of a module translated for the register engine
+/// that only uses what the compiler supports and only calls imports or
+/// functions that are compiled too. The rest are left to the interpreter.
+/// Compiled code shares as many registers between all the frames it calls
+/// into as the module's limits allow, or as it can use if it can't recurse.
+pub(crate) fn compile(module: &Module) -> Result<Code, &'static str> {
+    let limits = &module.limits;
+    if limits.calls > MAX_DEPTH {
+        return Err("the jit can't nest calls deeper than 10,000");
+    }
+    let registers = limits.values;
+    let count = module.registers.len();
+    // try each function on its own first
+    let mut compiled: Vec<bool> = (0..count)
+        .map(|i| {
+            let mut trial = Assembler::new(count);
+            module.registers[i].registers as usize <= registers
+                && trial.function(module, i).is_some()
+        })
+        .collect();
+    // then drop whatever calls something that didn't compile
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for i in 0..count {
+            if compiled[i]
+                && module.registers[i].code.iter().any(|op| match op {
+                    RegisterOp::Call { function, .. } => {
+                        let f = *function as usize;
+                        !compiled[f] && module.functions[f].import.is_none()
+                    }
+                    _ => false,
+                })
+            {
+                compiled[i] = false;
+                changed = true;
+            }
+        }
+    }
+    // and work out which of what's left can end up calling an import
+    let calls_out = |i: usize, exits: &[bool]| {
+        module.registers[i].code.iter().any(|op| match op {
+            RegisterOp::Call { function, .. } => {
+                let f = *function as usize;
+                module.functions[f].import.is_some() || exits[f]
+            }
+            _ => false,
+        })
+    };
+    let mut exits = vec![false; count];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for i in 0..count {
+            if compiled[i] && !exits[i] && calls_out(i, &exits) {
+                exits[i] = true;
+                changed = true;
+            }
+        }
+    }
+    let mut frame_types = BTreeMap::new();
+    for (i, _) in exits.iter().enumerate().filter(|(_, e)| **e) {
+        frame_types.extend(
+            call_types(module, i)
+                .into_iter()
+                .map(|(pc, types)| ((i, pc), types)),
+        );
+    }
+    let mut assembler = Assembler::new(count);
+    for (i, _) in compiled.iter().enumerate().filter(|(_, c)| **c) {
+        assembler.function(module, i);
+    }
+    let entries = (0..count)
+        .map(|i| {
+            if compiled[i] {
+                assembler.labels[i]
+            } else {
+                None
+            }
+        })
+        .collect();
+    let code = assembler.finish();
+    let (memory, size) = if code.is_empty() {
+        (core::ptr::null_mut(), 0)
+    } else {
+        map(&code)?
+    };
+    let (calls, registers) = extent(module, &compiled).unwrap_or((limits.calls, registers));
+    let frames = if frame_types.is_empty() { 0 } else { calls };
+    Ok(Code {
+        memory,
+        size,
+        entries,
+        frame_types,
+        scratch: Mutex::new(Scratch {
+            registers: vec![],
+            frames: vec![],
+            size: (registers.min(limits.values), frames.min(limits.calls)),
+        }),
+    })
+}
+
+// how deep calls into compiled code can go and how many registers they can
+// use between them, unless compiled functions can end up calling themselves
+fn extent(module: &Module, compiled: &[bool]) -> Option<(usize, usize)> {
+    let count = compiled.len();
+    let mut callers = vec![vec![]; count];
+    let mut waiting = vec![0; count];
+    for (i, _) in compiled.iter().enumerate().filter(|(_, c)| **c) {
+        for op in module.registers[i].code.iter() {
+            if let RegisterOp::Call { function, .. } = op {
+                if compiled[*function as usize] {
+                    callers[*function as usize].push(i);
+                    waiting[i] += 1;
+                }
+            }
+        }
+    }
+    // callees are worked out before their callers
+    let mut ready: Vec<usize> = (0..count)
+        .filter(|i| compiled[*i] && waiting[*i] == 0)
+        .collect();
+    let mut extents: Vec<Option<(usize, usize)>> = vec![None; count];
+    while let Some(i) = ready.pop() {
+        let (calls, registers) = module.registers[i]
+            .code
+            .iter()
+            .filter_map(|op| match op {
+                RegisterOp::Call { function, .. } => extents[*function as usize],
+                _ => None,
+            })
+            .fold((0, 0), |(c, r), (calls, registers)| {
+                (c.max(calls), r.max(registers))
+            });
+        extents[i] = Some((
+            calls + 1,
+            registers + module.registers[i].registers as usize,
+        ));
+        for caller in callers[i].iter() {
+            waiting[*caller] -= 1;
+            if waiting[*caller] == 0 {
+                ready.push(*caller);
+            }
+        }
+    }
+    let mut deepest = (0, 0);
+    for (i, _) in compiled.iter().enumerate().filter(|(_, c)| **c) {
+        let (calls, registers) = extents[i]?;
+        deepest = (deepest.0.max(calls), deepest.1.max(registers));
+    }
+    Some(deepest)
+}
+
+// the types of a function's registers just after each of its calls, by
+// the position after the call, from following every path to it
+fn call_types(module: &Module, index: usize) -> BTreeMap<usize, Vec<ValueType>> {
+    let function = &module.registers[index];
+    let mut start: Vec<Option<ValueType>> = module.functions[index]
+        .locals
+        .iter()
+        .map(|t| Some(*t))
+        .collect();
+    start.resize(function.registers as usize, Some(ValueType::I32));
+    let mut states: Vec<Option<Vec<Option<ValueType>>>> = vec![None; function.code.len()];
+    let mut calls = BTreeMap::new();
+    let mut pending = vec![(0, start)];
+    while let Some((pc, state)) = pending.pop() {
+        // registers that differ between paths aren't read again before
+        // they're written, so it doesn't matter what they're taken to be
+        let mut state = match &states[pc] {
+            Some(old) => {
+                let merged: Vec<_> = old
+                    .iter()
+                    .zip(state.iter())
+                    .map(|(a, b)| if a == b { *a } else { None })
+                    .collect();
+                if &merged == old {
+                    continue;
+                }
+                merged
+            }
+            None => state,
+        };
+        states[pc] = Some(state.clone());
+        let moved = |state: &Vec<Option<ValueType>>, m: Move| {
+            let mut state = state.clone();
+            let src = state[m.src as usize..(m.src + m.count) as usize].to_vec();
+            state[m.dst as usize..(m.dst + m.count) as usize].copy_from_slice(&src);
+            state
+        };
+        let mut next = Some(pc + 1);
+        let (dst, t) = match function.code[pc] {
+            RegisterOp::Unreachable | RegisterOp::Unsupported | RegisterOp::Return { .. } => {
+                next = None;
+                (None, None)
+            }
+            RegisterOp::Jump(target) => {
+                next = Some(target as usize);
+                (None, None)
+            }
+            RegisterOp::JumpIfZero { target, .. } | RegisterOp::JumpIfNotZero { target, .. } => {
+                pending.push((target as usize, state.clone()));
+                (None, None)
+            }
+            RegisterOp::Move(m) => {
+                state = moved(&state, m);
+                (None, None)
+            }
+            RegisterOp::BrTable { table, .. } => {
+                for (target, m) in function.branch_tables[table as usize].iter() {
+                    pending.push((*target as usize, moved(&state, *m)));
+                }
+                next = None;
+                (None, None)
+            }
+            RegisterOp::Call { function: f, base } => {
+                let outputs = &module.functions[f as usize].signature.outputs;
+                for (i, t) in outputs.iter().enumerate() {
+                    state[base as usize + i] = Some(*t);
+                }
+                calls.insert(pc + 1, state.clone());
+                (None, None)
+            }
+            RegisterOp::CallIndirect {
+                type_index, base, ..
+            } => {
+                for (i, t) in module.types[type_index as usize].outputs.iter().enumerate() {
+                    state[base as usize + i] = Some(*t);
+                }
+                (None, None)
+            }
+            RegisterOp::Select { dst, a, .. } => (Some(dst), state[a as usize]),
+            RegisterOp::Copy { dst, src } => (Some(dst), state[src as usize]),
+            RegisterOp::Const { dst, value } => (Some(dst), Some(value.value_type())),
+            RegisterOp::GlobalGet { dst, global } => {
+                (Some(dst), Some(module.globals[global as usize].value_type))
+            }
+            RegisterOp::Load { opcode, dst, .. } => {
+                let t = match opcode {
+                    I64_LOAD | I64_LOAD8_S | I64_LOAD8_U | I64_LOAD16_S | I64_LOAD16_U
+                    | I64_LOAD32_S | I64_LOAD32_U => ValueType::I64,
+                    F32_LOAD => ValueType::F32,
+                    F64_LOAD => ValueType::F64,
+                    _ => ValueType::I32,
+                };
+                (Some(dst), Some(t))
+            }
+            RegisterOp::MemorySize { dst } | RegisterOp::MemoryGrow { dst, .. } => {
+                (Some(dst), Some(ValueType::I32))
+            }
+            RegisterOp::Unary { opcode, dst, .. } | RegisterOp::Binary { opcode, dst, .. } => {
+                (Some(dst), Some(result_type(opcode)))
+            }
+            RegisterOp::GlobalSet { .. } | RegisterOp::Store { .. } | RegisterOp::Fuel(_) => {
+                (None, None)
+            }
+        };
+        if let Some(dst) = dst {
+            state[dst as usize] = t;
+        }
+        if let Some(next) = next.filter(|n| *n < function.code.len()) {
+            pending.push((next, state));
+        }
+    }
+    // merging paths only ever loses the types of registers nobody reads
+    calls
+        .into_iter()
+        .map(|(pc, state): (usize, Vec<Option<ValueType>>)| {
+            let types = state.into_iter().map(|t| t.unwrap_or(ValueType::I32));
+            (pc, types.collect())
+        })
+        .collect()
+}
+
+// the type of what a numeric instruction without immediates leaves
+fn result_type(opcode: u8) -> ValueType {
+    match opcode {
+        0x45..=0x78 | 0xa7..=0xab | 0xbc | 0xc0 | 0xc1 => ValueType::I32,
+        0x79..=0x8a | 0xac..=0xb1 | 0xbd | 0xc2..=0xc4 => ValueType::I64,
+        0x8b..=0x98 | 0xb2..=0xb6 | 0xbe => ValueType::F32,
+        _ => ValueType::F64,
+    }
+}
+
+// copies code into memory of its own and makes it executable
+fn map(code: &[u8]) -> Result<(*mut u8, usize), &'static str> {
+    let size = (code.len() + 4095) & !4095;
+    unsafe {
+        let memory = libc::mmap(
+            core::ptr::null_mut(),
+            size,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        );
+        if memory == libc::MAP_FAILED {
+            return Err("could not map memory for compiled code");
+        }
+        core::ptr::copy_nonoverlapping(code.as_ptr(), memory as *mut u8, code.len());
+        if libc::mprotect(memory, size, libc::PROT_READ | libc::PROT_EXEC) != 0 {
+            libc::munmap(memory, size);
+            return Err("could not make compiled code executable");
+        }
+        Ok((memory as *mut u8, size))
+    }
+}
+
+// where a register lives relative to `rbx`
+fn slot(register: u32) -> i32 {
+    register as i32 * 8
+}
+
+/// Emits machine code for functions, with labels standing in for the
+/// addresses jumps and calls go to. The first label of each function is its
+/// entry, by the function's index.
+struct Assembler {
+    code: Vec<u8>,
+    labels: Vec<Option<usize>>,
+    // the positions of 32-bit displacements and the labels they refer to
+    fixups: Vec<(usize, usize)>,
+}
+
+impl Assembler {
+    fn new(functions: usize) -> Self {
+        Assembler {
+            code: vec![],
+            labels: vec![None; functions],
+            fixups: vec![],
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        for (at, label) in self.fixups.iter() {
+            let target = self.labels[*label].expect("label was never placed");
+            let displacement = (target as i64 - (*at as i64 + 4)) as i32;
+            self.code[*at..*at + 4].copy_from_slice(&displacement.to_le_bytes());
+        }
+        self.code
+    }
+
+    fn label(&mut self) -> usize {
+        self.labels.push(None);
+        self.labels.len() - 1
+    }
+
+    fn place(&mut self, label: usize) {
+        self.labels[label] = Some(self.code.len());
+    }
+
+    fn emit(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    fn rel32(&mut self, label: usize) {
+        self.fixups.push((self.code.len(), label));
+        self.emit(&[0; 4]);
+    }
+
+    fn rex(&mut self, wide: bool, reg: u8, index: u8, base: u8) {
+        let rex = 0x40 | (wide as u8) << 3 | (reg >> 3) << 2 | (index >> 3) << 1 | base >> 3;
+        if rex != 0x40 {
+            self.code.push(rex);
+        }
+    }
+
+    // an instruction with a register and a `[base + index + displacement]`
+    // operand, which always goes through a SIB byte with a 32-bit
+    // displacement to keep the encoding uniform
+    fn mem(&mut self, wide: bool, opcode: &[u8], reg: u8, base: u8, index: Option<u8>, disp: i32) {
+        self.rex(wide, reg, index.unwrap_or(0), base);
+        self.emit(opcode);
+        self.code.push(0x84 | (reg & 7) << 3);
+        self.code.push((index.unwrap_or(4) & 7) << 3 | (base & 7));
+        self.emit(&disp.to_le_bytes());
+    }
+
+    // an instruction with two register operands
+    fn rr(&mut self, wide: bool, opcode: &[u8], reg: u8, rm: u8) {
+        self.rex(wide, reg, 0, rm);
+        self.emit(opcode);
+        self.code.push(0xc0 | (reg & 7) << 3 | (rm & 7));
+    }
+
+    fn load(&mut self, wide: bool, reg: u8, register: u32) {
+        self.mem(wide, &[0x8b], reg, RBX, None, slot(register));
+    }
+
+    fn store(&mut self, register: u32, reg: u8) {
+        self.mem(true, &[0x89], reg, RBX, None, slot(register));
+    }
+
+    fn mov_imm(&mut self, reg: u8, value: u64) {
+        if value <= u32::MAX as u64 {
+            self.rex(false, 0, 0, reg);
+            self.code.push(0xb8 | (reg & 7));
+            self.emit(&(value as u32).to_le_bytes());
+        } else {
+            self.rex(true, 0, 0, reg);
+            self.code.push(0xb8 | (reg & 7));
+            self.emit(&value.to_le_bytes());
+        }
+    }
+
+    fn push(&mut self, reg: u8) {
+        self.rex(false, 0, 0, reg);
+        self.code.push(0x50 | (reg & 7));
+    }
+
+    fn pop(&mut self, reg: u8) {
+        self.rex(false, 0, 0, reg);
+        self.code.push(0x58 | (reg & 7));
+    }
+
+    fn jump(&mut self, label: usize) {
+        self.code.push(0xe9);
+        self.rel32(label);
+    }
+
+    fn jump_if(&mut self, condition: u8, label: usize) {
+        self.emit(&[0x0f, 0x80 | condition]);
+        self.rel32(label);
+    }
+
+    // sets eax to 1 if the condition holds, 0 otherwise
+    fn set(&mut self, condition: u8) {
+        self.rr(false, &[0x0f, 0x90 | condition], 0, RAX);
+        self.rr(false, &[0x0f, 0xb6], RAX, RAX);
+    }
+
+    fn moves(&mut self, m: Move) {
+        let copy = |a: &mut Self, i: u32| {
+            a.load(true, RAX, m.src + i);
+            a.store(m.dst + i, RAX);
+        };
+        if m.dst <= m.src {
+            (0..m.count).for_each(|i| copy(self, i));
+        } else {
+            (0..m.count).rev().for_each(|i| copy(self, i));
+        }
+    }
+
+    /// Emits a function, or returns `None` if it uses something that can't
+    /// be compiled.
+    fn function(&mut self, module: &Module, index: usize) -> Option<()> {
+        let function = &module.registers[index];
+        let params = module.functions[index].signature.inputs.len() as u32;
+        let results = module.functions[index].signature.outputs.len() as u32;
+        if module.functions[index].import.is_some() {
+            return None;
+        }
+        self.place(index);
+        for reg in [RBX, R12, R13, R14, R15].iter() {
+            self.push(*reg);
+        }
+        self.rr(true, &[0x8b], RBX, RDI);
+        self.rr(true, &[0x8b], R12, RSI);
+        self.mem(true, &[0x8b], R13, R12, None, 0);
+        self.mem(true, &[0x8b], R14, R12, None, 8);
+        // everything past the parameters starts out zeroed
+        if function.registers > params {
+            self.mem(true, &[0x8d], RDI, RBX, None, slot(params));
+            self.mov_imm(RCX, (function.registers - params) as u64);
+            self.rr(false, &[0x33], RAX, RAX);
+            self.emit(&[0xf3, 0x48, 0xab]);
+        }
+        let epilogue = self.label();
+        let unwind = self.label();
+        let traps: Vec<usize> = (0..TRAPS).map(|_| self.label()).collect();
+        let trap = |code: u32| traps[code as usize - 1];
+        let starts: Vec<usize> = function.code.iter().map(|_| self.label()).collect();
+        for (i, op) in function.code.iter().enumerate() {
+            self.place(starts[i]);
+            match *op {
+                RegisterOp::Unreachable => self.jump(trap(UNREACHABLE)),
+                RegisterOp::Jump(target) => self.jump(starts[target as usize]),
+                RegisterOp::JumpIfZero { condition, target }
+                | RegisterOp::JumpIfNotZero { condition, target } => {
+                    self.load(false, RAX, condition);
+                    self.rr(false, &[0x85], RAX, RAX);
+                    let jump = if matches!(op, RegisterOp::JumpIfZero { .. }) {
+                        E
+                    } else {
+                        NE
+                    };
+                    self.jump_if(jump, starts[target as usize]);
+                }
+                RegisterOp::Move(m) => self.moves(m),
+                RegisterOp::BrTable { index, table } => {
+                    let table = &function.branch_tables[table as usize];
+                    self.load(false, RCX, index);
+                    let (default, cases) = table.split_last()?;
+                    for (case, (target, m)) in cases.iter().enumerate() {
+                        let next = self.label();
+                        self.rr(false, &[0x81], 7, RCX);
+                        self.emit(&(case as u32).to_le_bytes());
+                        self.jump_if(NE, next);
+                        self.moves(*m);
+                        self.jump(starts[*target as usize]);
+                        self.place(next);
+                    }
+                    self.moves(default.1);
+                    self.jump(starts[default.0 as usize]);
+                }
+                RegisterOp::Return { results: src } => {
+                    self.moves(Move {
+                        dst: 0,
+                        src,
+                        count: results,
+                    });
+                    self.rr(false, &[0x33], RAX, RAX);
+                    self.jump(epilogue);
+                }
+                // imports are called from outside, once every frame has
+                // unwound, and `edx` is where this one picks up again
+                RegisterOp::Call { function, .. }
+                    if module.functions.get(function as usize)?.import.is_some() =>
+                {
+                    self.mov_imm(RDX, i as u64 + 1);
+                    self.jump(unwind);
+                }
+                RegisterOp::Call { function, base } => {
+                    let callee = module.registers.get(function as usize)?;
+                    // sub qword [r12 + depth], 1
+                    self.mem(true, &[0x83], 5, R12, None, 32);
+                    self.code.push(1);
+                    self.jump_if(B, trap(EXHAUSTED));
+                    self.mem(true, &[0x8d], RAX, RBX, None, slot(base + callee.registers));
+                    self.mem(true, &[0x3b], RAX, R12, None, 24);
+                    self.jump_if(A, trap(EXHAUSTED));
+                    self.mem(true, &[0x8d], RDI, RBX, None, slot(base));
+                    self.rr(true, &[0x8b], RSI, R12);
+                    self.code.push(0xe8);
+                    self.rel32(function as usize);
+                    // add qword [r12 + depth], 1
+                    self.mem(true, &[0x83], 0, R12, None, 32);
+                    self.code.push(1);
+                    let next = self.label();
+                    self.rr(false, &[0x85], RAX, RAX);
+                    self.jump_if(E, next);
+                    self.mov_imm(RDX, i as u64 + 1);
+                    // cmp eax, IMPORT
+                    self.rr(false, &[0x81], 7, RAX);
+                    self.emit(&IMPORT.to_le_bytes());
+                    self.jump_if(E, unwind);
+                    self.jump(epilogue);
+                    self.place(next);
+                }
+                RegisterOp::Select {
+                    dst,
+                    a,
+                    b,
+                    condition,
+                } => {
+                    self.load(false, RCX, condition);
+                    self.load(true, RAX, a);
+                    self.load(true, RDX, b);
+                    self.rr(false, &[0x85], RCX, RCX);
+                    self.rr(true, &[0x0f, 0x44], RAX, RDX);
+                    self.store(dst, RAX);
+                }
+                RegisterOp::Copy { dst, src } => {
+                    self.load(true, RAX, src);
+                    self.store(dst, RAX);
+                }
+                RegisterOp::Const { dst, value } => {
+                    self.mov_imm(RAX, to_bits(value));
+                    self.store(dst, RAX);
+                }
+                RegisterOp::GlobalGet { dst, global } => {
+                    module.globals.get(global as usize)?;
+                    self.mem(true, &[0x8b], RCX, R12, None, 16);
+                    self.mem(true, &[0x8b], RAX, RCX, None, slot(global));
+                    self.store(dst, RAX);
+                }
+                RegisterOp::GlobalSet { global, src } => {
+                    module.globals.get(global as usize)?;
+                    self.load(true, RAX, src);
+                    self.mem(true, &[0x8b], RCX, R12, None, 16);
+                    self.mem(true, &[0x89], RAX, RCX, None, slot(global));
+                }
+                RegisterOp::Load {
+                    opcode,
+                    offset,
+                    dst,
+                    address,
+                } => {
+                    let (wide, instruction, size): (bool, &[u8], i32) = match opcode {
+                        I32_LOAD | F32_LOAD | I64_LOAD32_U => (false, &[0x8b], 4),
+                        I64_LOAD | F64_LOAD => (true, &[0x8b], 8),
+                        I32_LOAD8_U | I64_LOAD8_U => (false, &[0x0f, 0xb6], 1),
+                        I32_LOAD8_S => (false, &[0x0f, 0xbe], 1),
+                        I64_LOAD8_S => (true, &[0x0f, 0xbe], 1),
+                        I32_LOAD16_U | I64_LOAD16_U => (false, &[0x0f, 0xb7], 2),
+                        I32_LOAD16_S => (false, &[0x0f, 0xbf], 2),
+                        I64_LOAD16_S => (true, &[0x0f, 0xbf], 2),
+                        I64_LOAD32_S => (true, &[0x63], 4),
+                        _ => return None,
+                    };
+                    self.address(address, offset, size, trap(OUT_OF_BOUNDS));
+                    self.mem(wide, instruction, RAX, R13, Some(RAX), 0);
+                    self.store(dst, RAX);
+                }
+                RegisterOp::Store {
+                    opcode,
+                    offset,
+                    address,
+                    value,
+                } => {
+                    let size = match opcode {
+                        I32_STORE8 | I64_STORE8 => 1,
+                        I32_STORE16 | I64_STORE16 => 2,
+                        I32_STORE | F32_STORE | I64_STORE32 => 4,
+                        I64_STORE | F64_STORE => 8,
+                        _ => return None,
+                    };
+                    self.load(true, RDX, value);
+                    self.address(address, offset, size, trap(OUT_OF_BOUNDS));
+                    match size {
+                        1 => self.mem(false, &[0x88], RDX, R13, Some(RAX), 0),
+                        2 => {
+                            self.code.push(0x66);
+                            self.mem(false, &[0x89], RDX, R13, Some(RAX), 0)
+                        }
+                        _ => self.mem(size == 8, &[0x89], RDX, R13, Some(RAX), 0),
+                    }
+                }
+                RegisterOp::MemorySize { dst } => {
+                    self.rr(true, &[0x8b], RAX, R14);
+                    // shr rax, 16
+                    self.rr(true, &[0xc1], 5, RAX);
+                    self.code.push(16);
+                    self.store(dst, RAX);
+                }
+                RegisterOp::Unary { opcode, dst, a } => {
+                    match opcode {
+                        I32_EQZ | I64_EQZ => {
+                            let wide = opcode == I64_EQZ;
+                            self.load(wide, RAX, a);
+                            self.rr(wide, &[0x85], RAX, RAX);
+                            self.set(E);
+                        }
+                        I32_WRAP_F64 | I64_EXTEND_U_I32 => self.load(false, RAX, a),
+                        I64_EXTEND_S_I32 => self.mem(true, &[0x63], RAX, RBX, None, slot(a)),
+                        I32_REINTERPRET_F32 | I64_REINTERPRET_F64 | F32_REINTERPRET_I32
+                        | F64_REINTERPRET_I64 => self.load(true, RAX, a),
+                        _ => return None,
+                    }
+                    self.store(dst, RAX);
+                }
+                RegisterOp::Binary { opcode, dst, a, b } => {
+                    self.binary(opcode, dst, a, b, &trap)?;
+                }
+                // metered code has to be able to stop and pick up again
+                RegisterOp::Unsupported
+                | RegisterOp::CallIndirect { .. }
+                | RegisterOp::MemoryGrow { .. }
+                | RegisterOp::Fuel(_) => return None,
+            }
+        }
+        // running off the end can't happen, but shouldn't run on into
+        // whatever comes next either
+        self.jump(trap(UNREACHABLE));
+        for (i, label) in traps.iter().enumerate() {
+            self.place(*label);
+            self.mov_imm(RAX, i as u64 + 1);
+            self.jump(epilogue);
+        }
+        // writes this frame out as the next one in the context's `frames`
+        self.place(unwind);
+        self.mem(true, &[0x8b], RAX, R12, None, 48);
+        // imul rax, rax, 24
+        self.rr(true, &[0x6b], RAX, RAX);
+        self.code.push(24);
+        self.mem(true, &[0x03], RAX, R12, None, 40);
+        self.mov_imm(RCX, index as u64);
+        self.mem(true, &[0x89], RCX, RAX, None, 0);
+        self.mem(true, &[0x89], RDX, RAX, None, 8);
+        self.mem(true, &[0x89], RBX, RAX, None, 16);
+        // add qword [r12 + unwound], 1
+        self.mem(true, &[0x83], 0, R12, None, 48);
+        self.code.push(1);
+        self.mov_imm(RAX, IMPORT as u64);
+        self.jump(epilogue);
+        self.place(epilogue);
+        for reg in [R15, R14, R13, R12, RBX].iter() {
+            self.pop(*reg);
+        }
+        self.code.push(0xc3);
+        Some(())
+    }
+
+    // leaves `address + offset` in rax, trapping if `size` bytes from there
+    // aren't all in memory
+    fn address(&mut self, address: u32, offset: u32, size: i32, out_of_bounds: usize) {
+        self.load(false, RAX, address);
+        self.mov_imm(RCX, offset as u64);
+        self.rr(true, &[0x03], RAX, RCX);
+        self.mem(true, &[0x8d], RCX, RAX, None, size);
+        self.rr(true, &[0x3b], RCX, R14);
+        self.jump_if(A, out_of_bounds);
+    }
+
+    fn binary(
+        &mut self,
+        opcode: u8,
+        dst: u32,
+        a: u32,
+        b: u32,
+        trap: &dyn Fn(u32) -> usize,
+    ) -> Option<()> {
+        let wide = (I64_EQ..=I64_GE_U).contains(&opcode) || (I64_ADD..=I64_ROTR).contains(&opcode);
+        let arithmetic: Option<&[u8]> = match opcode {
+            I32_ADD | I64_ADD => Some(&[0x03]),
+            I32_SUB | I64_SUB => Some(&[0x2b]),
+            I32_MUL | I64_MUL => Some(&[0x0f, 0xaf]),
+            I32_AND | I64_AND => Some(&[0x23]),
+            I32_OR | I64_OR => Some(&[0x0b]),
+            I32_XOR | I64_XOR => Some(&[0x33]),
+            _ => None,
+        };
+        let comparison = match opcode {
+            I32_EQ | I64_EQ => Some(E),
+            I32_NE | I64_NE => Some(NE),
+            I32_LT_S | I64_LT_S => Some(L),
+            I32_LT_U | I64_LT_U => Some(B),
+            I32_GT_S | I64_GT_S => Some(G),
+            I32_GT_U | I64_GT_U => Some(A),
+            I32_LE_S | I64_LE_S => Some(LE),
+            I32_LE_U | I64_LE_U => Some(BE),
+            I32_GE_S | I64_GE_S => Some(GE),
+            I32_GE_U | I64_GE_U => Some(AE),
+            _ => None,
+        };
+        let shift = match opcode {
+            I32_SHL | I64_SHL => Some(4),
+            I32_SHR_U | I64_SHR_U => Some(5),
+            I32_SHR_S | I64_SHR_S => Some(7),
+            I32_ROTL | I64_ROTL => Some(0),
+            I32_ROTR | I64_ROTR => Some(1),
+            _ => None,
+        };
+        self.load(wide, RAX, a);
+        if let Some(instruction) = arithmetic {
+            self.mem(wide, instruction, RAX, RBX, None, slot(b));
+        } else if let Some(condition) = comparison {
+            self.mem(wide, &[0x3b], RAX, RBX, None, slot(b));
+            self.set(condition);
+        } else if let Some(extension) = shift {
+            // the count is masked to the operand's width like wasm does
+            self.load(false, RCX, b);
+            self.rr(wide, &[0xd3], extension, RAX);
+        } else {
+            return self.divide(opcode, wide, dst, b, trap);
+        }
+        self.store(dst, RAX);
+        Some(())
+    }
+
+    // division and remainder with a in rax, trapping where wasm does rather
+    // than faulting
+    fn divide(
+        &mut self,
+        opcode: u8,
+        wide: bool,
+        dst: u32,
+        b: u32,
+        trap: &dyn Fn(u32) -> usize,
+    ) -> Option<()> {
+        let (signed, remainder) = match opcode {
+            I32_DIV_S | I64_DIV_S => (true, false),
+            I32_DIV_U | I64_DIV_U => (false, false),
+            I32_REM_S | I64_REM_S => (true, true),
+            I32_REM_U | I64_REM_U => (false, true),
+            _ => return None,
+        };
+        self.load(wide, RCX, b);
+        self.rr(wide, &[0x85], RCX, RCX);
+        self.jump_if(E, trap(DIVIDE_BY_ZERO));
+        let done = self.label();
+        if signed {
+            let divide = self.label();
+            // cmp rcx, -1
+            self.rr(wide, &[0x83], 7, RCX);
+            self.code.push(0xff);
+            self.jump_if(NE, divide);
+            if remainder {
+                // the remainder of dividing by -1 is always 0, even where
+                // the quotient overflows
+                self.rr(false, &[0x33], RAX, RAX);
+                self.store(dst, RAX);
+                self.jump(done);
+            } else if wide {
+                self.mov_imm(RDX, i64::MIN as u64);
+                self.rr(true, &[0x3b], RAX, RDX);
+                self.jump_if(E, trap(OVERFLOW));
+            } else {
+                self.rr(false, &[0x81], 7, RAX);
+                self.emit(&i32::MIN.to_le_bytes());
+                self.jump_if(E, trap(OVERFLOW));
+            }
+            self.place(divide);
+            // cdq or cqo, then idiv rcx
+            if wide {
+                self.code.push(0x48);
+            }
+            self.code.push(0x99);
+            self.rr(wide, &[0xf7], 7, RCX);
+        } else {
+            self.rr(false, &[0x33], RDX, RDX);
+            self.rr(wide, &[0xf7], 6, RCX);
+        }
+        self.store(dst, if remainder { RDX } else { RAX });
+        self.place(done);
+        Some(())
+    }
+}
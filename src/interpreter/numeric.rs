@@ -0,0 +1,300 @@
+use super::WasmValue;
+use webassembly::*;
+
+const DIVIDE_BY_ZERO: &str = "integer divide by zero";
+const OVERFLOW: &str = "integer overflow";
+const INVALID_CONVERSION: &str = "invalid conversion to integer";
+const WRONG_TYPE: &str = "value on the stack has the wrong type";
+const UNSUPPORTED: &str = "instruction is not supported by the interpreter";
+
+/// Runs an instruction taking one operand, by its opcode.
+#[inline(always)]
+pub(crate) fn unary(opcode: u8, a: WasmValue) -> Result<WasmValue, &'static str> {
+    use WasmValue::*;
+    macro_rules! op {
+        ($t:ident => $r:ident, |$a:ident| $e:expr) => {
+            match a {
+                $t($a) => $r($e),
+                _ => return Err(WRONG_TYPE),
+            }
+        };
+    }
+    Ok(match opcode {
+        I32_EQZ => op!(I32 => I32, |a| (a == 0) as i32),
+        I64_EQZ => op!(I64 => I32, |a| (a == 0) as i32),
+        I32_CLZ => op!(I32 => I32, |a| a.leading_zeros() as i32),
+        I32_CTZ => op!(I32 => I32, |a| a.trailing_zeros() as i32),
+        I32_POPCNT => op!(I32 => I32, |a| a.count_ones() as i32),
+        I64_CLZ => op!(I64 => I64, |a| a.leading_zeros() as i64),
+        I64_CTZ => op!(I64 => I64, |a| a.trailing_zeros() as i64),
+        I64_POPCNT => op!(I64 => I64, |a| a.count_ones() as i64),
+        F32_ABS => op!(F32 => F32, |a| f32::from_bits(a.to_bits() & !(1 << 31))),
+        F32_NEG => op!(F32 => F32, |a| f32::from_bits(a.to_bits() ^ (1 << 31))),
+        F32_CEIL => op!(F32 => F32, |a| libm::ceilf(a)),
+        F32_FLOOR => op!(F32 => F32, |a| libm::floorf(a)),
+        F32_TRUNC => op!(F32 => F32, |a| libm::truncf(a)),
+        F32_NEAREST => op!(F32 => F32, |a| nearest_f32(a)),
+        F32_SQRT => op!(F32 => F32, |a| libm::sqrtf(a)),
+        F64_ABS => op!(F64 => F64, |a| f64::from_bits(a.to_bits() & !(1 << 63))),
+        F64_NEG => op!(F64 => F64, |a| f64::from_bits(a.to_bits() ^ (1 << 63))),
+        F64_CEIL => op!(F64 => F64, |a| libm::ceil(a)),
+        F64_FLOOR => op!(F64 => F64, |a| libm::floor(a)),
+        F64_TRUNC => op!(F64 => F64, |a| libm::trunc(a)),
+        F64_NEAREST => op!(F64 => F64, |a| nearest_f64(a)),
+        F64_SQRT => op!(F64 => F64, |a| libm::sqrt(a)),
+        I32_WRAP_F64 => op!(I64 => I32, |a| a as i32),
+        I32_TRUNC_S_F32 => {
+            op!(F32 => I32, |a| truncate(a as f64, -2147483649.0, 2147483648.0)? as i32)
+        }
+        I32_TRUNC_U_F32 => {
+            op!(F32 => I32, |a| truncate(a as f64, -1.0, 4294967296.0)? as u32 as i32)
+        }
+        I32_TRUNC_S_F64 => op!(F64 => I32, |a| truncate(a, -2147483649.0, 2147483648.0)? as i32),
+        I32_TRUNC_U_F64 => op!(F64 => I32, |a| truncate(a, -1.0, 4294967296.0)? as u32 as i32),
+        I64_EXTEND_S_I32 => op!(I32 => I64, |a| a as i64),
+        I64_EXTEND_U_I32 => op!(I32 => I64, |a| a as u32 as i64),
+        I64_TRUNC_S_F32 => op!(F32 => I64, |a| {
+            truncate(a as f64, -9223372036854777856.0, 9223372036854775808.0)? as i64
+        }),
+        I64_TRUNC_U_F32 => op!(F32 => I64, |a| {
+            truncate(a as f64, -1.0, 18446744073709551616.0)? as u64 as i64
+        }),
+        I64_TRUNC_S_F64 => op!(F64 => I64, |a| {
+            truncate(a, -9223372036854777856.0, 9223372036854775808.0)? as i64
+        }),
+        I64_TRUNC_U_F64 => op!(F64 => I64, |a| {
+            truncate(a, -1.0, 18446744073709551616.0)? as u64 as i64
+        }),
+        F32_CONVERT_S_I32 => op!(I32 => F32, |a| a as f32),
+        F32_CONVERT_U_I32 => op!(I32 => F32, |a| a as u32 as f32),
+        F32_CONVERT_S_I64 => op!(I64 => F32, |a| a as f32),
+        F32_CONVERT_U_I64 => op!(I64 => F32, |a| a as u64 as f32),
+        F32_DEMOTE_F64 => op!(F64 => F32, |a| a as f32),
+        F64_CONVERT_S_I32 => op!(I32 => F64, |a| a as f64),
+        F64_CONVERT_U_I32 => op!(I32 => F64, |a| a as u32 as f64),
+        F64_CONVERT_S_I64 => op!(I64 => F64, |a| a as f64),
+        F64_CONVERT_U_I64 => op!(I64 => F64, |a| a as u64 as f64),
+        F64_PROMOTE_F32 => op!(F32 => F64, |a| a as f64),
+        I32_REINTERPRET_F32 => op!(F32 => I32, |a| a.to_bits() as i32),
+        I64_REINTERPRET_F64 => op!(F64 => I64, |a| a.to_bits() as i64),
+        F32_REINTERPRET_I32 => op!(I32 => F32, |a| f32::from_bits(a as u32)),
+        F64_REINTERPRET_I64 => op!(I64 => F64, |a| f64::from_bits(a as u64)),
+        _ => return Err(UNSUPPORTED),
+    })
+}
+
+/// Runs an instruction taking two operands, by its opcode.
+#[inline(always)]
+pub(crate) fn binary(opcode: u8, a: WasmValue, b: WasmValue) -> Result<WasmValue, &'static str> {
+    use WasmValue::*;
+    macro_rules! op {
+        ($t:ident => $r:ident, |$a:ident, $b:ident| $e:expr) => {
+            match (a, b) {
+                ($t($a), $t($b)) => $r($e),
+                _ => return Err(WRONG_TYPE),
+            }
+        };
+    }
+    Ok(match opcode {
+        I32_EQ => op!(I32 => I32, |a, b| (a == b) as i32),
+        I32_NE => op!(I32 => I32, |a, b| (a != b) as i32),
+        I32_LT_S => op!(I32 => I32, |a, b| (a < b) as i32),
+        I32_LT_U => op!(I32 => I32, |a, b| ((a as u32) < (b as u32)) as i32),
+        I32_GT_S => op!(I32 => I32, |a, b| (a > b) as i32),
+        I32_GT_U => op!(I32 => I32, |a, b| (a as u32 > b as u32) as i32),
+        I32_LE_S => op!(I32 => I32, |a, b| (a <= b) as i32),
+        I32_LE_U => op!(I32 => I32, |a, b| (a as u32 <= b as u32) as i32),
+        I32_GE_S => op!(I32 => I32, |a, b| (a >= b) as i32),
+        I32_GE_U => op!(I32 => I32, |a, b| (a as u32 >= b as u32) as i32),
+        I64_EQ => op!(I64 => I32, |a, b| (a == b) as i32),
+        I64_NE => op!(I64 => I32, |a, b| (a != b) as i32),
+        I64_LT_S => op!(I64 => I32, |a, b| (a < b) as i32),
+        I64_LT_U => op!(I64 => I32, |a, b| ((a as u64) < (b as u64)) as i32),
+        I64_GT_S => op!(I64 => I32, |a, b| (a > b) as i32),
+        I64_GT_U => op!(I64 => I32, |a, b| (a as u64 > b as u64) as i32),
+        I64_LE_S => op!(I64 => I32, |a, b| (a <= b) as i32),
+        I64_LE_U => op!(I64 => I32, |a, b| (a as u64 <= b as u64) as i32),
+        I64_GE_S => op!(I64 => I32, |a, b| (a >= b) as i32),
+        I64_GE_U => op!(I64 => I32, |a, b| (a as u64 >= b as u64) as i32),
+        F32_EQ => op!(F32 => I32, |a, b| (a == b) as i32),
+        F32_NE => op!(F32 => I32, |a, b| (a != b) as i32),
+        F32_LT => op!(F32 => I32, |a, b| (a < b) as i32),
+        F32_GT => op!(F32 => I32, |a, b| (a > b) as i32),
+        F32_LE => op!(F32 => I32, |a, b| (a <= b) as i32),
+        F32_GE => op!(F32 => I32, |a, b| (a >= b) as i32),
+        F64_EQ => op!(F64 => I32, |a, b| (a == b) as i32),
+        F64_NE => op!(F64 => I32, |a, b| (a != b) as i32),
+        F64_LT => op!(F64 => I32, |a, b| (a < b) as i32),
+        F64_GT => op!(F64 => I32, |a, b| (a > b) as i32),
+        F64_LE => op!(F64 => I32, |a, b| (a <= b) as i32),
+        F64_GE => op!(F64 => I32, |a, b| (a >= b) as i32),
+        I32_ADD => op!(I32 => I32, |a, b| a.wrapping_add(b)),
+        I32_SUB => op!(I32 => I32, |a, b| a.wrapping_sub(b)),
+        I32_MUL => op!(I32 => I32, |a, b| a.wrapping_mul(b)),
+        I32_DIV_S => op!(I32 => I32, |a, b| {
+            if b == 0 {
+                return Err(DIVIDE_BY_ZERO);
+            }
+            if a == i32::MIN && b == -1 {
+                return Err(OVERFLOW);
+            }
+            a / b
+        }),
+        I32_DIV_U => op!(I32 => I32, |a, b| {
+            if b == 0 {
+                return Err(DIVIDE_BY_ZERO);
+            }
+            (a as u32 / b as u32) as i32
+        }),
+        I32_REM_S => op!(I32 => I32, |a, b| {
+            if b == 0 {
+                return Err(DIVIDE_BY_ZERO);
+            }
+            a.wrapping_rem(b)
+        }),
+        I32_REM_U => op!(I32 => I32, |a, b| {
+            if b == 0 {
+                return Err(DIVIDE_BY_ZERO);
+            }
+            (a as u32 % b as u32) as i32
+        }),
+        I32_AND => op!(I32 => I32, |a, b| a & b),
+        I32_OR => op!(I32 => I32, |a, b| a | b),
+        I32_XOR => op!(I32 => I32, |a, b| a ^ b),
+        I32_SHL => op!(I32 => I32, |a, b| a.wrapping_shl(b as u32)),
+        I32_SHR_S => op!(I32 => I32, |a, b| a.wrapping_shr(b as u32)),
+        I32_SHR_U => op!(I32 => I32, |a, b| (a as u32).wrapping_shr(b as u32) as i32),
+        I32_ROTL => op!(I32 => I32, |a, b| a.rotate_left(b as u32 % 32)),
+        I32_ROTR => op!(I32 => I32, |a, b| a.rotate_right(b as u32 % 32)),
+        I64_ADD => op!(I64 => I64, |a, b| a.wrapping_add(b)),
+        I64_SUB => op!(I64 => I64, |a, b| a.wrapping_sub(b)),
+        I64_MUL => op!(I64 => I64, |a, b| a.wrapping_mul(b)),
+        I64_DIV_S => op!(I64 => I64, |a, b| {
+            if b == 0 {
+                return Err(DIVIDE_BY_ZERO);
+            }
+            if a == i64::MIN && b == -1 {
+                return Err(OVERFLOW);
+            }
+            a / b
+        }),
+        I64_DIV_U => op!(I64 => I64, |a, b| {
+            if b == 0 {
+                return Err(DIVIDE_BY_ZERO);
+            }
+            (a as u64 / b as u64) as i64
+        }),
+        I64_REM_S => op!(I64 => I64, |a, b| {
+            if b == 0 {
+                return Err(DIVIDE_BY_ZERO);
+            }
+            a.wrapping_rem(b)
+        }),
+        I64_REM_U => op!(I64 => I64, |a, b| {
+            if b == 0 {
+                return Err(DIVIDE_BY_ZERO);
+            }
+            (a as u64 % b as u64) as i64
+        }),
+        I64_AND => op!(I64 => I64, |a, b| a & b),
+        I64_OR => op!(I64 => I64, |a, b| a | b),
+        I64_XOR => op!(I64 => I64, |a, b| a ^ b),
+        I64_SHL => op!(I64 => I64, |a, b| a.wrapping_shl(b as u32)),
+        I64_SHR_S => op!(I64 => I64, |a, b| a.wrapping_shr(b as u32)),
+        I64_SHR_U => op!(I64 => I64, |a, b| (a as u64).wrapping_shr(b as u32) as i64),
+        I64_ROTL => op!(I64 => I64, |a, b| a.rotate_left((b as u64 % 64) as u32)),
+        I64_ROTR => op!(I64 => I64, |a, b| a.rotate_right((b as u64 % 64) as u32)),
+        F32_ADD => op!(F32 => F32, |a, b| a + b),
+        F32_SUB => op!(F32 => F32, |a, b| a - b),
+        F32_MUL => op!(F32 => F32, |a, b| a * b),
+        F32_DIV => op!(F32 => F32, |a, b| a / b),
+        F32_MIN => op!(F32 => F32, |a, b| {
+            if a.is_nan() || b.is_nan() {
+                f32::NAN
+            } else if a == b {
+                f32::from_bits(a.to_bits() | b.to_bits())
+            } else if a < b {
+                a
+            } else {
+                b
+            }
+        }),
+        F32_MAX => op!(F32 => F32, |a, b| {
+            if a.is_nan() || b.is_nan() {
+                f32::NAN
+            } else if a == b {
+                f32::from_bits(a.to_bits() & b.to_bits())
+            } else if a > b {
+                a
+            } else {
+                b
+            }
+        }),
+        F32_COPYSIGN => op!(F32 => F32, |a, b| {
+            f32::from_bits((a.to_bits() & !(1 << 31)) | (b.to_bits() & (1 << 31)))
+        }),
+        F64_ADD => op!(F64 => F64, |a, b| a + b),
+        F64_SUB => op!(F64 => F64, |a, b| a - b),
+        F64_MUL => op!(F64 => F64, |a, b| a * b),
+        F64_DIV => op!(F64 => F64, |a, b| a / b),
+        F64_MIN => op!(F64 => F64, |a, b| {
+            if a.is_nan() || b.is_nan() {
+                f64::NAN
+            } else if a == b {
+                f64::from_bits(a.to_bits() | b.to_bits())
+            } else if a < b {
+                a
+            } else {
+                b
+            }
+        }),
+        F64_MAX => op!(F64 => F64, |a, b| {
+            if a.is_nan() || b.is_nan() {
+                f64::NAN
+            } else if a == b {
+                f64::from_bits(a.to_bits() & b.to_bits())
+            } else if a > b {
+                a
+            } else {
+                b
+            }
+        }),
+        F64_COPYSIGN => op!(F64 => F64, |a, b| {
+            f64::from_bits((a.to_bits() & !(1 << 63)) | (b.to_bits() & (1 << 63)))
+        }),
+        _ => return Err(UNSUPPORTED),
+    })
+}
+
+// truncates toward zero, trapping unless the result lies strictly between
+// `low` and `high`
+fn truncate(value: f64, low: f64, high: f64) -> Result<f64, &'static str> {
+    if value.is_nan() {
+        return Err(INVALID_CONVERSION);
+    }
+    let value = libm::trunc(value);
+    if value <= low || value >= high {
+        return Err(OVERFLOW);
+    }
+    Ok(value)
+}
+
+// rounds to the nearest integer with ties to even, by adding and removing
+// 2^23 so the hardware rounding does the work
+fn nearest_f32(value: f32) -> f32 {
+    let magnitude = f32::from_bits(value.to_bits() & !(1 << 31));
+    if value.is_nan() || magnitude >= 8388608.0 {
+        return value;
+    }
+    let rounded = (magnitude + 8388608.0) - 8388608.0;
+    f32::from_bits(rounded.to_bits() | (value.to_bits() & (1 << 31)))
+}
+
+fn nearest_f64(value: f64) -> f64 {
+    let magnitude = f64::from_bits(value.to_bits() & !(1 << 63));
+    if value.is_nan() || magnitude >= 4503599627370496.0 {
+        return value;
+    }
+    let rounded = (magnitude + 4503599627370496.0) - 4503599627370496.0;
+    f64::from_bits(rounded.to_bits() | (value.to_bits() & (1 << 63)))
+}
@@ -0,0 +1,852 @@
+use super::bytecode::*;
+use super::numeric::{binary, unary};
+use super::run::{grow, indirect, load, store, Callee, Fuel, Limits, EXHAUSTED, PAGE_SIZE};
+use super::{CallFrame, ExecutionUnit, ImportCall, WasmValue};
+use alloc::vec::Vec;
+
+/// Registers copied when a branch is taken: `count` of them from `src`
+/// onwards to `dst` onwards.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub(crate) struct Move {
+    pub dst: u32,
+    pub src: u32,
+    pub count: u32,
+}
+
+/// An operation on a frame's registers. A frame has one register for each
+/// local followed by one for each slot of the function's value stack.
+#[derive(Clone, PartialEq, Debug)]
+pub(crate) enum RegisterOp {
+    Unreachable,
+    Unsupported,
+    Jump(u32),
+    JumpIfZero {
+        condition: u32,
+        target: u32,
+    },
+    JumpIfNotZero {
+        condition: u32,
+        target: u32,
+    },
+    Move(Move),
+    BrTable {
+        index: u32,
+        table: u32,
+    },
+    /// Returns the registers starting at `results`.
+    Return {
+        results: u32,
+    },
+    /// Calls with the arguments starting at `base`, which is also where the
+    /// results are put.
+    Call {
+        function: u32,
+        base: u32,
+    },
+    CallIndirect {
+        type_index: u32,
+        base: u32,
+        element: u32,
+    },
+    Select {
+        dst: u32,
+        a: u32,
+        b: u32,
+        condition: u32,
+    },
+    Copy {
+        dst: u32,
+        src: u32,
+    },
+    Const {
+        dst: u32,
+        value: WasmValue,
+    },
+    GlobalGet {
+        dst: u32,
+        global: u32,
+    },
+    GlobalSet {
+        global: u32,
+        src: u32,
+    },
+    Load {
+        opcode: u8,
+        offset: u32,
+        dst: u32,
+        address: u32,
+    },
+    Store {
+        opcode: u8,
+        offset: u32,
+        address: u32,
+        value: u32,
+    },
+    MemorySize {
+        dst: u32,
+    },
+    MemoryGrow {
+        dst: u32,
+        delta: u32,
+    },
+    Unary {
+        opcode: u8,
+        dst: u32,
+        a: u32,
+    },
+    Binary {
+        opcode: u8,
+        dst: u32,
+        a: u32,
+        b: u32,
+    },
+    Fuel(u32),
+}
+
+#[derive(Clone, PartialEq, Debug, Default)]
+pub(crate) struct RegisterFunction {
+    pub code: Vec<RegisterOp>,
+    pub branch_tables: Vec<Vec<(u32, Move)>>,
+    /// How many registers a frame of the function needs.
+    pub registers: u32,
+    /// Which op of the stack bytecode each op was translated from.
+    pub origins: Vec<u32>,
+}
+
+/// Translates every function's stack bytecode into register operations.
+pub(crate) fn translate(module: &Module) -> Vec<RegisterFunction> {
+    module
+        .functions
+        .iter()
+        .map(|f| {
+            let mut t = Translation {
+                module,
+                function: f,
+                base: f.locals.len() as u32,
+                code: vec![],
+                branch_tables: vec![],
+                aliases: vec![],
+                patches: vec![],
+            };
+            t.function()
+        })
+        .collect()
+}
+
+struct Translation<'a> {
+    module: &'a Module,
+    function: &'a Function,
+    // the first register after the locals
+    base: u32,
+    code: Vec<RegisterOp>,
+    branch_tables: Vec<Vec<(u32, Move)>>,
+    // the local each stack slot is still just a copy of, which is only
+    // copied into the slot's own register once it has to be
+    aliases: Vec<Option<u32>>,
+    // ops whose targets are still positions in the stack bytecode
+    patches: Vec<usize>,
+}
+
+impl<'a> Translation<'a> {
+    fn function(&mut self) -> RegisterFunction {
+        let f = self.function;
+        let mut targets = vec![false; f.code.len() + 1];
+        for op in f.code.iter() {
+            match op {
+                Op::Jump(t) | Op::JumpIfZero(t) => targets[*t as usize] = true,
+                Op::Br(b) | Op::BrIf(b) => targets[b.target as usize] = true,
+                Op::BrTable(t) => {
+                    for b in f.branch_tables[*t as usize].iter() {
+                        targets[b.target as usize] = true;
+                    }
+                }
+                _ => {}
+            }
+        }
+        let mut starts = Vec::with_capacity(f.code.len() + 1);
+        let mut i = 0;
+        while i < f.code.len() {
+            // values arriving from a branch are always in their own registers
+            if targets[i] {
+                self.flush();
+            }
+            starts.push(self.code.len() as u32);
+            self.aliases.resize(f.heights[i] as usize, None);
+            let fused = self.op(i, &targets);
+            if fused {
+                starts.push(self.code.len() as u32);
+                i += 1;
+            }
+            i += 1;
+        }
+        starts.push(self.code.len() as u32);
+
+        for p in self.patches.iter() {
+            match &mut self.code[*p] {
+                RegisterOp::Jump(target)
+                | RegisterOp::JumpIfZero { target, .. }
+                | RegisterOp::JumpIfNotZero { target, .. } => *target = starts[*target as usize],
+                _ => {}
+            }
+        }
+        for table in self.branch_tables.iter_mut() {
+            for (target, _) in table.iter_mut() {
+                *target = starts[*target as usize];
+            }
+        }
+        let mut origins = vec![0; self.code.len()];
+        for (i, range) in starts.windows(2).enumerate() {
+            for origin in origins[range[0] as usize..range[1] as usize].iter_mut() {
+                *origin = i as u32;
+            }
+        }
+        let height = f.heights.iter().copied().max().unwrap_or(0);
+        RegisterFunction {
+            code: core::mem::take(&mut self.code),
+            branch_tables: core::mem::take(&mut self.branch_tables),
+            registers: self.base + height + 1,
+            origins,
+        }
+    }
+
+    fn slot(&self, slot: u32) -> u32 {
+        self.base + slot
+    }
+
+    fn operand(&self, slot: u32) -> u32 {
+        match self.aliases[slot as usize] {
+            Some(local) => local,
+            None => self.slot(slot),
+        }
+    }
+
+    fn set(&mut self, slot: u32, alias: Option<u32>) {
+        self.aliases.truncate(slot as usize);
+        self.aliases.push(alias);
+    }
+
+    // copies every aliased slot into its own register
+    fn flush(&mut self) {
+        for slot in 0..self.aliases.len() {
+            if let Some(local) = self.aliases[slot] {
+                self.code.push(RegisterOp::Copy {
+                    dst: self.slot(slot as u32),
+                    src: local,
+                });
+                self.aliases[slot] = None;
+            }
+        }
+    }
+
+    // copies slots below `below` aliasing a local about to be overwritten
+    fn materialize(&mut self, local: u32, below: u32) {
+        for slot in 0..(below as usize).min(self.aliases.len()) {
+            if self.aliases[slot] == Some(local) {
+                self.code.push(RegisterOp::Copy {
+                    dst: self.slot(slot as u32),
+                    src: local,
+                });
+                self.aliases[slot] = None;
+            }
+        }
+    }
+
+    // where the result of op `i` going into `slot` should be written, which
+    // is straight into a local when the next op just stores it there
+    fn result(&mut self, slot: u32, i: usize, targets: &[bool]) -> (u32, bool) {
+        if let Some(Op::LocalSet(local)) = self.function.code.get(i + 1) {
+            if !targets[i + 1] {
+                self.materialize(*local, slot);
+                self.aliases.truncate(slot as usize);
+                return (*local, true);
+            }
+        }
+        self.set(slot, None);
+        (self.slot(slot), false)
+    }
+
+    fn jump(&mut self, op: RegisterOp) {
+        self.patches.push(self.code.len());
+        self.code.push(op);
+    }
+
+    // the values a branch carries, once the operand stack is `height` high
+    fn carry(&self, b: &Branch, height: u32) -> Move {
+        let src = self.slot(height - b.arity);
+        let dst = self.slot(b.height);
+        if src == dst {
+            Move::default()
+        } else {
+            Move {
+                dst,
+                src,
+                count: b.arity,
+            }
+        }
+    }
+
+    // translates op `i`, returning whether it also took care of the op
+    // after it
+    fn op(&mut self, i: usize, targets: &[bool]) -> bool {
+        let h = self.function.heights[i];
+        let op = match &self.function.code[i] {
+            Op::Unreachable => RegisterOp::Unreachable,
+            Op::Unsupported => RegisterOp::Unsupported,
+            Op::Fuel(cost) => {
+                // a frame can stop here, so every slot has to be in its own
+                // register, and it mustn't look like one waiting on a call
+                self.flush();
+                if self.code.last().and_then(call_base).is_some() {
+                    self.code.push(RegisterOp::Move(Move::default()));
+                }
+                RegisterOp::Fuel(*cost)
+            }
+            Op::Jump(target) => {
+                self.flush();
+                self.jump(RegisterOp::Jump(*target));
+                return false;
+            }
+            Op::JumpIfZero(target) => {
+                self.flush();
+                self.jump(RegisterOp::JumpIfZero {
+                    condition: self.slot(h - 1),
+                    target: *target,
+                });
+                return false;
+            }
+            Op::Br(b) => {
+                self.flush();
+                let carry = self.carry(b, h);
+                if carry.count > 0 {
+                    self.code.push(RegisterOp::Move(carry));
+                }
+                self.jump(RegisterOp::Jump(b.target));
+                return false;
+            }
+            Op::BrIf(b) => {
+                self.flush();
+                let condition = self.slot(h - 1);
+                let carry = self.carry(b, h - 1);
+                if carry.count > 0 {
+                    // skip over the move and jump when not branching
+                    let skip = self.code.len() as u32 + 3;
+                    self.code.push(RegisterOp::JumpIfZero {
+                        condition,
+                        target: skip,
+                    });
+                    self.code.push(RegisterOp::Move(carry));
+                    self.jump(RegisterOp::Jump(b.target));
+                } else {
+                    self.jump(RegisterOp::JumpIfNotZero {
+                        condition,
+                        target: b.target,
+                    });
+                }
+                return false;
+            }
+            Op::BrTable(table) => {
+                self.flush();
+                let entries = self.function.branch_tables[*table as usize]
+                    .iter()
+                    .map(|b| (b.target, self.carry(b, h - 1)))
+                    .collect();
+                self.branch_tables.push(entries);
+                RegisterOp::BrTable {
+                    index: self.slot(h - 1),
+                    table: self.branch_tables.len() as u32 - 1,
+                }
+            }
+            Op::Return => {
+                self.flush();
+                let outputs = self.function.signature.outputs.len() as u32;
+                RegisterOp::Return {
+                    results: self.slot(h - outputs),
+                }
+            }
+            Op::Call(function) => {
+                self.flush();
+                let inputs = self.module.functions[*function as usize]
+                    .signature
+                    .inputs
+                    .len() as u32;
+                RegisterOp::Call {
+                    function: *function,
+                    base: self.slot(h - inputs),
+                }
+            }
+            Op::CallIndirect(type_index) => {
+                self.flush();
+                let inputs = self.module.types[*type_index as usize].inputs.len() as u32;
+                RegisterOp::CallIndirect {
+                    type_index: *type_index,
+                    base: self.slot(h - 1 - inputs),
+                    element: self.slot(h - 1),
+                }
+            }
+            Op::Drop => return false,
+            Op::Select => {
+                let condition = self.operand(h - 1);
+                let b = self.operand(h - 2);
+                let a = self.operand(h - 3);
+                let (dst, fused) = self.result(h - 3, i, targets);
+                self.code.push(RegisterOp::Select {
+                    dst,
+                    a,
+                    b,
+                    condition,
+                });
+                return fused;
+            }
+            Op::LocalGet(local) => {
+                self.set(h, Some(*local));
+                return false;
+            }
+            Op::LocalSet(local) => {
+                let src = self.operand(h - 1);
+                self.materialize(*local, h - 1);
+                if src == *local {
+                    return false;
+                }
+                RegisterOp::Copy { dst: *local, src }
+            }
+            Op::LocalTee(local) => {
+                let src = self.operand(h - 1);
+                self.materialize(*local, h - 1);
+                if src != *local {
+                    self.code.push(RegisterOp::Copy { dst: *local, src });
+                }
+                self.set(h - 1, Some(*local));
+                return false;
+            }
+            Op::GlobalGet(global) => {
+                let (dst, fused) = self.result(h, i, targets);
+                self.code.push(RegisterOp::GlobalGet {
+                    dst,
+                    global: *global,
+                });
+                return fused;
+            }
+            Op::GlobalSet(global) => RegisterOp::GlobalSet {
+                global: *global,
+                src: self.operand(h - 1),
+            },
+            Op::Load(opcode, offset) => {
+                let address = self.operand(h - 1);
+                let (dst, fused) = self.result(h - 1, i, targets);
+                self.code.push(RegisterOp::Load {
+                    opcode: *opcode,
+                    offset: *offset,
+                    dst,
+                    address,
+                });
+                return fused;
+            }
+            Op::Store(opcode, offset) => RegisterOp::Store {
+                opcode: *opcode,
+                offset: *offset,
+                address: self.operand(h - 2),
+                value: self.operand(h - 1),
+            },
+            Op::MemorySize => {
+                let (dst, fused) = self.result(h, i, targets);
+                self.code.push(RegisterOp::MemorySize { dst });
+                return fused;
+            }
+            Op::MemoryGrow => {
+                let delta = self.operand(h - 1);
+                let (dst, fused) = self.result(h - 1, i, targets);
+                self.code.push(RegisterOp::MemoryGrow { dst, delta });
+                return fused;
+            }
+            Op::Const(value) => {
+                let (dst, fused) = self.result(h, i, targets);
+                self.code.push(RegisterOp::Const { dst, value: *value });
+                return fused;
+            }
+            Op::Unary(opcode) => {
+                let a = self.operand(h - 1);
+                let (dst, fused) = self.result(h - 1, i, targets);
+                self.code.push(RegisterOp::Unary {
+                    opcode: *opcode,
+                    dst,
+                    a,
+                });
+                return fused;
+            }
+            Op::Binary(opcode) => {
+                let b = self.operand(h - 1);
+                let a = self.operand(h - 2);
+                let (dst, fused) = self.result(h - 2, i, targets);
+                self.code.push(RegisterOp::Binary {
+                    opcode: *opcode,
+                    dst,
+                    a,
+                    b,
+                });
+                return fused;
+            }
+        };
+        self.code.push(op);
+        false
+    }
+}
+
+fn condition(v: WasmValue) -> Result<bool, &'static str> {
+    match v {
+        WasmValue::I32(v) => Ok(v != 0),
+        _ => Err("value on the stack has the wrong type"),
+    }
+}
+
+fn address(v: WasmValue) -> Result<i32, &'static str> {
+    match v {
+        WasmValue::I32(v) => Ok(v),
+        _ => Err("value on the stack has the wrong type"),
+    }
+}
+
+// where a call op puts its results
+fn call_base(op: &RegisterOp) -> Option<usize> {
+    match op {
+        RegisterOp::Call { base, .. } | RegisterOp::CallIndirect { base, .. } => {
+            Some(*base as usize)
+        }
+        _ => None,
+    }
+}
+
+// runs a function as machine code if the jit compiled it, within what's
+// left of the limits, leaving the frames it unwound from in `unwound` if
+// it calls an import
+#[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+fn native(
+    module: &Module,
+    function: usize,
+    params: &[WasmValue],
+    memory: &mut [u8],
+    globals: &mut [WasmValue],
+    room: Limits,
+    unwound: &mut Vec<CallFrame>,
+) -> Option<Result<ExecutionUnit, &'static str>> {
+    let code = module.native.as_ref().filter(|c| c.is_compiled(function))?;
+    Some(code.call(module, function, params, memory, globals, room, unwound))
+}
+
+#[cfg(not(all(feature = "jit", target_arch = "x86_64", unix)))]
+fn native(
+    _: &Module,
+    _: usize,
+    _: &[WasmValue],
+    _: &mut [u8],
+    _: &mut [WasmValue],
+    _: Limits,
+    _: &mut Vec<CallFrame>,
+) -> Option<Result<ExecutionUnit, &'static str>> {
+    None
+}
+
+/// Whether the frames on the call stack hold registers rather than being
+/// the stack engine's, which a frame's number of locals tells apart.
+pub(crate) fn in_registers(module: &Module, call_stack: &[CallFrame]) -> bool {
+    let frame = match call_stack.last() {
+        Some(frame) => frame,
+        None => return false,
+    };
+    let registers = module.registers.get(frame.function);
+    matches!(registers, Some(r) if frame.locals.len() == r.registers as usize)
+}
+
+/// Turns frames holding registers into the stack engine's, so a call that
+/// ran on the register engine can go on an op at a time. Frames only stop
+/// before they start, on a call, or on a fuel check, where none of their
+/// stack slots alias a local, and the value stack only has the results of
+/// the import the top frame called.
+pub(crate) fn to_stack_frames(
+    module: &Module,
+    call_stack: &mut [CallFrame],
+    stack: &mut Vec<WasmValue>,
+) -> Result<(), &'static str> {
+    if !in_registers(module, call_stack) {
+        return Ok(());
+    }
+    let results = core::mem::take(stack);
+    let top = call_stack.len() - 1;
+    for (i, frame) in call_stack.iter_mut().enumerate() {
+        let function = &module.functions[frame.function];
+        let registers = &module.registers[frame.function];
+        let base = function.locals.len();
+        let previous = frame.pc.checked_sub(1).map(|pc| &registers.code[pc]);
+        let (pc, height) = match previous {
+            None => (0, 0),
+            Some(RegisterOp::Call { .. }) | Some(RegisterOp::CallIndirect { .. }) => {
+                let op = registers.origins[frame.pc - 1] as usize;
+                let taken = match function.code[op] {
+                    Op::Call(f) => module.functions[f as usize].signature.inputs.len(),
+                    Op::CallIndirect(t) => module.types[t as usize].inputs.len() + 1,
+                    _ => return Err("frame stopped somewhere it can't have"),
+                };
+                (op + 1, function.heights[op] as usize - taken)
+            }
+            Some(_) if i == top => match registers.code.get(frame.pc) {
+                Some(RegisterOp::Fuel(_)) => {
+                    let op = registers.origins[frame.pc] as usize;
+                    (op, function.heights[op] as usize)
+                }
+                _ => return Err("frame stopped somewhere it can't have"),
+            },
+            Some(_) => return Err("frame stopped somewhere it can't have"),
+        };
+        frame.stack_base = stack.len();
+        stack.extend_from_slice(&frame.locals[base..base + height]);
+        frame.locals.truncate(base);
+        frame.pc = pc;
+    }
+    stack.extend(results);
+    Ok(())
+}
+
+/// Runs the frame on top of the call stack like `run::run`, except frames
+/// hold registers and the value stack only carries an import's results.
+pub(crate) fn run(
+    module: &Module,
+    memory: &mut Vec<u8>,
+    globals: &mut [WasmValue],
+    call_stack: &mut Vec<CallFrame>,
+    stack: &mut Vec<WasmValue>,
+    fuel: &mut Fuel,
+) -> Result<ExecutionUnit, &'static str> {
+    let mut frame = match call_stack.pop() {
+        Some(f) => f,
+        None => return Ok(ExecutionUnit::Complete(vec![])),
+    };
+    if frame.pc == 0 {
+        let params = module.functions[frame.function].signature.inputs.len();
+        let held: usize = call_stack.iter().map(|f| f.locals.len()).sum();
+        let room = Limits {
+            calls: module.limits.calls.saturating_sub(call_stack.len()),
+            values: module.limits.values.saturating_sub(held),
+        };
+        let mut unwound = vec![];
+        if let Some(result) = native(
+            module,
+            frame.function,
+            &frame.locals[..params],
+            memory,
+            globals,
+            room,
+            &mut unwound,
+        ) {
+            // the compiled code's frames take over from this one
+            if !unwound.is_empty() {
+                call_stack.extend(unwound);
+            } else if !matches!(result, Ok(ExecutionUnit::Complete(_))) {
+                call_stack.push(frame);
+            }
+            return result;
+        }
+    }
+    // picking up after an import call, whose results are on the stack
+    if frame.pc > 0 {
+        if let Some(base) = call_base(&module.registers[frame.function].code[frame.pc - 1]) {
+            for (i, v) in stack.drain(..).enumerate() {
+                frame.locals[base + i] = v;
+            }
+        }
+    }
+    let result = run_frame(module, memory, globals, call_stack, fuel, &mut frame);
+    if !matches!(result, Ok(ExecutionUnit::Complete(_))) {
+        call_stack.push(frame);
+    }
+    result
+}
+
+fn run_frame(
+    module: &Module,
+    memory: &mut Vec<u8>,
+    globals: &mut [WasmValue],
+    call_stack: &mut Vec<CallFrame>,
+    fuel: &mut Fuel,
+    frame: &mut CallFrame,
+) -> Result<ExecutionUnit, &'static str> {
+    let limits = &module.limits;
+    // how many registers the frames hold between them
+    let mut held: usize =
+        call_stack.iter().map(|f| f.locals.len()).sum::<usize>() + frame.locals.len();
+    let mut function = &module.registers[frame.function];
+    loop {
+        let op = function
+            .code
+            .get(frame.pc)
+            .ok_or("ran past the end of a function")?;
+        frame.pc += 1;
+        let r = &mut frame.locals;
+        match *op {
+            RegisterOp::Unreachable => return Ok(ExecutionUnit::Unreachable),
+            RegisterOp::Unsupported => {
+                return Err("instruction is not supported by the interpreter")
+            }
+            RegisterOp::Jump(target) => frame.pc = target as usize,
+            RegisterOp::JumpIfZero {
+                condition: c,
+                target,
+            } => {
+                if !condition(r[c as usize])? {
+                    frame.pc = target as usize;
+                }
+            }
+            RegisterOp::JumpIfNotZero {
+                condition: c,
+                target,
+            } => {
+                if condition(r[c as usize])? {
+                    frame.pc = target as usize;
+                }
+            }
+            RegisterOp::Move(m) => {
+                let src = m.src as usize;
+                r.copy_within(src..src + m.count as usize, m.dst as usize);
+            }
+            RegisterOp::BrTable { index, table } => {
+                let table = &function.branch_tables[table as usize];
+                let i = address(r[index as usize])? as u32 as usize;
+                let (target, m) = table.get(i).unwrap_or(&table[table.len() - 1]);
+                let src = m.src as usize;
+                r.copy_within(src..src + m.count as usize, m.dst as usize);
+                frame.pc = *target as usize;
+            }
+            RegisterOp::Return { results } => {
+                let count = module.functions[frame.function].signature.outputs.len();
+                let results = results as usize..results as usize + count;
+                held -= r.len();
+                match call_stack.pop() {
+                    Some(caller) => {
+                        let callee = core::mem::replace(frame, caller);
+                        function = &module.registers[frame.function];
+                        let base = call_base(&function.code[frame.pc - 1])
+                            .ok_or("returned to something other than a call")?;
+                        frame.locals[base..base + count].copy_from_slice(&callee.locals[results]);
+                    }
+                    None => return Ok(ExecutionUnit::Complete(r[results].to_vec())),
+                }
+            }
+            RegisterOp::Call { .. } | RegisterOp::CallIndirect { .. } => {
+                let (index, base) = match *op {
+                    RegisterOp::CallIndirect {
+                        type_index,
+                        base,
+                        element,
+                    } => match indirect(module, type_index, address(r[element as usize])?)? {
+                        Callee::Local(index) => (index, base as usize),
+                        Callee::Linked(link) => {
+                            let base = base as usize;
+                            let params = r[base..base + link.signature().inputs.len()].to_vec();
+                            return Ok(ExecutionUnit::CallImport(ImportCall::linked(link, params)));
+                        }
+                    },
+                    RegisterOp::Call { function, base } => (function as usize, base as usize),
+                    _ => unreachable!(),
+                };
+                let callee = &module.functions[index];
+                let params = &r[base..base + callee.signature.inputs.len()];
+                if let Some((module_name, name)) = &callee.import {
+                    return Ok(ExecutionUnit::CallImport(ImportCall::new(
+                        module_name,
+                        name,
+                        params.to_vec(),
+                    )));
+                }
+                let registers = &module.registers[index];
+                if call_stack.len() + 2 > limits.calls
+                    || held + registers.registers as usize > limits.values
+                {
+                    return Err(EXHAUSTED);
+                }
+                let room = Limits {
+                    calls: limits.calls.saturating_sub(call_stack.len() + 1),
+                    values: limits.values.saturating_sub(held),
+                };
+                let mut unwound = vec![];
+                match native(module, index, params, memory, globals, room, &mut unwound) {
+                    Some(Ok(ExecutionUnit::Complete(results))) => {
+                        r[base..base + results.len()].copy_from_slice(&results);
+                        continue;
+                    }
+                    Some(result) => {
+                        if let Some(innermost) = unwound.pop() {
+                            call_stack.push(core::mem::replace(frame, innermost));
+                            call_stack.extend(unwound);
+                        }
+                        return result;
+                    }
+                    None => {}
+                }
+                held += registers.registers as usize;
+                let mut locals = Vec::with_capacity(registers.registers as usize);
+                locals.extend_from_slice(params);
+                locals.extend(callee.locals[params.len()..].iter().map(|t| zero(*t)));
+                locals.resize(registers.registers as usize, WasmValue::I32(0));
+                let caller = core::mem::replace(
+                    frame,
+                    CallFrame {
+                        function: index,
+                        pc: 0,
+                        locals,
+                        stack_base: 0,
+                    },
+                );
+                call_stack.push(caller);
+                function = registers;
+            }
+            RegisterOp::Select {
+                dst,
+                a,
+                b,
+                condition: c,
+            } => {
+                r[dst as usize] = if condition(r[c as usize])? {
+                    r[a as usize]
+                } else {
+                    r[b as usize]
+                }
+            }
+            RegisterOp::Copy { dst, src } => r[dst as usize] = r[src as usize],
+            RegisterOp::Const { dst, value } => r[dst as usize] = value,
+            RegisterOp::GlobalGet { dst, global } => r[dst as usize] = globals[global as usize],
+            RegisterOp::GlobalSet { global, src } => globals[global as usize] = r[src as usize],
+            RegisterOp::Load {
+                opcode,
+                offset,
+                dst,
+                address: a,
+            } => r[dst as usize] = load(memory, opcode, address(r[a as usize])?, offset)?,
+            RegisterOp::Store {
+                opcode,
+                offset,
+                address: a,
+                value,
+            } => store(
+                memory,
+                opcode,
+                address(r[a as usize])?,
+                offset,
+                r[value as usize],
+            )?,
+            RegisterOp::MemorySize { dst } => {
+                r[dst as usize] = WasmValue::I32((memory.len() / PAGE_SIZE) as i32)
+            }
+            RegisterOp::MemoryGrow { dst, delta } => {
+                r[dst as usize] = WasmValue::I32(grow(module, memory, address(r[delta as usize])?))
+            }
+            RegisterOp::Unary { opcode, dst, a } => r[dst as usize] = unary(opcode, r[a as usize])?,
+            RegisterOp::Binary { opcode, dst, a, b } => {
+                r[dst as usize] = binary(opcode, r[a as usize], r[b as usize])?
+            }
+            RegisterOp::Fuel(cost) => {
+                if !fuel.burn(cost) {
+                    frame.pc -= 1;
+                    return Ok(ExecutionUnit::OutOfFuel);
+                }
+            }
+        }
+    }
+}
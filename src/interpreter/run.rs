@@ -0,0 +1,398 @@
+use super::bytecode::*;
+use super::numeric::{binary, unary};
+use super::store::Link;
+use super::{CallFrame, ExecutionUnit, ImportCall, WasmValue};
+use alloc::vec::Vec;
+use core::ops::Range;
+use serde::{Deserialize, Serialize};
+use webassembly::*;
+
+pub(crate) const PAGE_SIZE: usize = 65536;
+pub(crate) const MAX_PAGES: usize = 65536;
+
+/// What a metered execution has left to burn and has burned so far.
+#[derive(Clone, Copy, PartialEq, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct Fuel {
+    pub remaining: u64,
+    pub consumed: u64,
+}
+
+impl Fuel {
+    // pays for what's about to run, if there's enough left
+    #[inline(always)]
+    pub fn burn(&mut self, cost: u32) -> bool {
+        let cost = cost as u64;
+        if self.remaining < cost {
+            return false;
+        }
+        self.remaining -= cost;
+        self.consumed += cost;
+        true
+    }
+}
+
+/// How deeply calls can nest and how many values they can hold between
+/// them, counting locals, before an execution traps.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub(crate) struct Limits {
+    pub calls: usize,
+    pub values: usize,
+}
+
+pub(crate) const EXHAUSTED: &str = "call stack exhausted";
+
+fn pop(stack: &mut Vec<WasmValue>) -> Result<WasmValue, &'static str> {
+    stack.pop().ok_or("value stack underflow")
+}
+
+fn pop_i32(stack: &mut Vec<WasmValue>) -> Result<i32, &'static str> {
+    match stack.pop() {
+        Some(WasmValue::I32(v)) => Ok(v),
+        Some(_) => Err("value on the stack has the wrong type"),
+        None => Err("value stack underflow"),
+    }
+}
+
+// cuts the stack back to the branch's height, keeping the values it carries
+fn branch(stack: &mut Vec<WasmValue>, base: usize, b: &Branch) {
+    let height = base + b.height as usize;
+    let keep = stack.len() - b.arity as usize;
+    if keep > height {
+        stack.drain(height..keep);
+    }
+}
+
+/// Runs the frame on top of the call stack until an import is called, the
+/// outermost function returns, or something traps.
+pub(crate) fn run(
+    module: &Module,
+    memory: &mut Vec<u8>,
+    globals: &mut [WasmValue],
+    call_stack: &mut Vec<CallFrame>,
+    stack: &mut Vec<WasmValue>,
+    fuel: &mut Fuel,
+) -> Result<ExecutionUnit, &'static str> {
+    let mut frame = match call_stack.pop() {
+        Some(f) => f,
+        None => return Ok(ExecutionUnit::Complete(vec![])),
+    };
+    let result = run_frame::<false>(module, memory, globals, call_stack, stack, fuel, &mut frame);
+    if !matches!(result, Ok(Some(ExecutionUnit::Complete(_)))) {
+        call_stack.push(frame);
+    }
+    result.map(|unit| unit.expect("only stepping stops between ops"))
+}
+
+/// Runs a single op of the frame on top of the call stack, giving nothing
+/// back unless that op ends the execution's current unit of work.
+pub(crate) fn step(
+    module: &Module,
+    memory: &mut Vec<u8>,
+    globals: &mut [WasmValue],
+    call_stack: &mut Vec<CallFrame>,
+    stack: &mut Vec<WasmValue>,
+    fuel: &mut Fuel,
+) -> Result<Option<ExecutionUnit>, &'static str> {
+    let mut frame = match call_stack.pop() {
+        Some(f) => f,
+        None => return Ok(Some(ExecutionUnit::Complete(vec![]))),
+    };
+    let result = run_frame::<true>(module, memory, globals, call_stack, stack, fuel, &mut frame);
+    if !matches!(result, Ok(Some(ExecutionUnit::Complete(_)))) {
+        call_stack.push(frame);
+    }
+    result
+}
+
+fn run_frame<const STEP: bool>(
+    module: &Module,
+    memory: &mut Vec<u8>,
+    globals: &mut [WasmValue],
+    call_stack: &mut Vec<CallFrame>,
+    stack: &mut Vec<WasmValue>,
+    fuel: &mut Fuel,
+    frame: &mut CallFrame,
+) -> Result<Option<ExecutionUnit>, &'static str> {
+    let limits = &module.limits;
+    let mut function = &module.functions[frame.function];
+    // how many locals the frames hold between them
+    let mut held: usize =
+        call_stack.iter().map(|f| f.locals.len()).sum::<usize>() + frame.locals.len();
+    loop {
+        let op = function
+            .code
+            .get(frame.pc)
+            .ok_or("ran past the end of a function")?;
+        frame.pc += 1;
+        match op {
+            Op::Unreachable => return Ok(Some(ExecutionUnit::Unreachable)),
+            Op::Unsupported => return Err("instruction is not supported by the interpreter"),
+            Op::Jump(target) => frame.pc = *target as usize,
+            Op::JumpIfZero(target) => {
+                if pop_i32(stack)? == 0 {
+                    frame.pc = *target as usize;
+                }
+            }
+            Op::Br(b) => {
+                branch(stack, frame.stack_base, b);
+                frame.pc = b.target as usize;
+            }
+            Op::BrIf(b) => {
+                if pop_i32(stack)? != 0 {
+                    branch(stack, frame.stack_base, b);
+                    frame.pc = b.target as usize;
+                }
+            }
+            Op::BrTable(table) => {
+                let table = &function.branch_tables[*table as usize];
+                let i = pop_i32(stack)? as u32 as usize;
+                let b = table.get(i).unwrap_or(&table[table.len() - 1]);
+                branch(stack, frame.stack_base, b);
+                frame.pc = b.target as usize;
+            }
+            Op::Return => {
+                let results = function.signature.outputs.len();
+                let keep = stack.len() - results;
+                stack.drain(frame.stack_base..keep);
+                held -= frame.locals.len();
+                match call_stack.pop() {
+                    Some(caller) => {
+                        *frame = caller;
+                        function = &module.functions[frame.function];
+                    }
+                    None => {
+                        return Ok(Some(ExecutionUnit::Complete(
+                            stack.split_off(frame.stack_base),
+                        )))
+                    }
+                }
+            }
+            Op::Call(_) | Op::CallIndirect(_) => {
+                let index = match op {
+                    Op::CallIndirect(t) => match indirect(module, *t, pop_i32(stack)?)? {
+                        Callee::Local(index) => index,
+                        Callee::Linked(link) => {
+                            let params =
+                                stack.split_off(stack.len() - link.signature().inputs.len());
+                            let call = ImportCall::linked(link, params);
+                            return Ok(Some(ExecutionUnit::CallImport(call)));
+                        }
+                    },
+                    Op::Call(f) => *f as usize,
+                    _ => unreachable!(),
+                };
+                let callee = &module.functions[index];
+                let params = stack.split_off(stack.len() - callee.signature.inputs.len());
+                if let Some((module_name, name)) = &callee.import {
+                    let call = ImportCall::new(module_name, name, params);
+                    return Ok(Some(ExecutionUnit::CallImport(call)));
+                }
+                held += callee.locals.len();
+                if call_stack.len() + 2 > limits.calls
+                    || held + stack.len() + callee.max_height as usize > limits.values
+                {
+                    return Err(EXHAUSTED);
+                }
+                let mut locals = params;
+                locals.extend(callee.locals[locals.len()..].iter().map(|t| zero(*t)));
+                let caller = core::mem::replace(
+                    frame,
+                    CallFrame {
+                        function: index,
+                        pc: 0,
+                        locals,
+                        stack_base: stack.len(),
+                    },
+                );
+                call_stack.push(caller);
+                function = callee;
+            }
+            Op::Drop => {
+                pop(stack)?;
+            }
+            Op::Select => {
+                let condition = pop_i32(stack)?;
+                let b = pop(stack)?;
+                let a = pop(stack)?;
+                stack.push(if condition != 0 { a } else { b });
+            }
+            Op::LocalGet(i) => {
+                let v = *frame.locals.get(*i as usize).ok_or("invalid local index")?;
+                stack.push(v);
+            }
+            Op::LocalSet(i) => {
+                let v = pop(stack)?;
+                *frame
+                    .locals
+                    .get_mut(*i as usize)
+                    .ok_or("invalid local index")? = v;
+            }
+            Op::LocalTee(i) => {
+                let v = *stack.last().ok_or("value stack underflow")?;
+                *frame
+                    .locals
+                    .get_mut(*i as usize)
+                    .ok_or("invalid local index")? = v;
+            }
+            Op::GlobalGet(i) => stack.push(globals[*i as usize]),
+            Op::GlobalSet(i) => globals[*i as usize] = pop(stack)?,
+            Op::Load(opcode, offset) => {
+                let address = pop_i32(stack)?;
+                stack.push(load(memory, *opcode, address, *offset)?);
+            }
+            Op::Store(opcode, offset) => {
+                let value = pop(stack)?;
+                let address = pop_i32(stack)?;
+                store(memory, *opcode, address, *offset, value)?;
+            }
+            Op::MemorySize => stack.push(WasmValue::I32((memory.len() / PAGE_SIZE) as i32)),
+            Op::MemoryGrow => {
+                let delta = pop_i32(stack)?;
+                stack.push(WasmValue::I32(grow(module, memory, delta)));
+            }
+            Op::Const(v) => stack.push(*v),
+            Op::Unary(opcode) => {
+                let a = pop(stack)?;
+                stack.push(unary(*opcode, a)?);
+            }
+            Op::Binary(opcode) => {
+                let b = pop(stack)?;
+                let a = pop(stack)?;
+                stack.push(binary(*opcode, a, b)?);
+            }
+            Op::Fuel(cost) => {
+                if !fuel.burn(*cost) {
+                    frame.pc -= 1;
+                    return Ok(Some(ExecutionUnit::OutOfFuel));
+                }
+            }
+        }
+        if STEP {
+            return Ok(None);
+        }
+    }
+}
+
+// grows memory by `delta` pages, returning the old page count or -1 if it
+// can't grow that far
+pub(crate) fn grow(module: &Module, memory: &mut Vec<u8>, delta: i32) -> i32 {
+    let pages = memory.len() / PAGE_SIZE;
+    let max = module.max_memory_pages.unwrap_or(MAX_PAGES).min(MAX_PAGES);
+    if pages + delta as u32 as usize > max {
+        return -1;
+    }
+    memory.resize((pages + delta as u32 as usize) * PAGE_SIZE, 0);
+    pages as i32
+}
+
+/// Where a call_indirect goes.
+pub(crate) enum Callee {
+    /// One of the module's own functions.
+    Local(usize),
+    /// A function another instance put in a table the two share.
+    Linked(Link),
+}
+
+// finds the function a call_indirect goes to, checking its signature
+pub(crate) fn indirect(
+    module: &Module,
+    type_index: u32,
+    element: i32,
+) -> Result<Callee, &'static str> {
+    let table = module.table.lock();
+    let element = match table.elements.get(element as u32 as usize) {
+        Some(Some(e)) => e,
+        Some(None) => return Err("uninitialized element"),
+        None => return Err("undefined element"),
+    };
+    let owner = match &element.instance {
+        i if i.is(module) => None,
+        i => Some(
+            i.upgrade()
+                .ok_or("element belongs to an instance that's gone")?,
+        ),
+    };
+    let functions = match &owner {
+        Some(context) => &context.module.functions,
+        None => &module.functions,
+    };
+    let callee = functions
+        .get(element.function)
+        .ok_or("invalid function index")?;
+    if module.types.get(type_index as usize) != Some(&callee.signature) {
+        return Err("indirect call type mismatch");
+    }
+    Ok(match owner {
+        Some(context) => Callee::Linked(Link {
+            function: element.function,
+            context,
+        }),
+        None => Callee::Local(element.function),
+    })
+}
+
+fn range(
+    memory: &[u8],
+    address: i32,
+    offset: u32,
+    size: usize,
+) -> Result<Range<usize>, &'static str> {
+    let start = address as u32 as u64 + offset as u64;
+    let end = start + size as u64;
+    if end > memory.len() as u64 {
+        return Err("out of bounds memory access");
+    }
+    Ok(start as usize..end as usize)
+}
+
+pub(crate) fn load(
+    memory: &[u8],
+    opcode: u8,
+    address: i32,
+    offset: u32,
+) -> Result<WasmValue, &'static str> {
+    let size = match opcode {
+        I32_LOAD8_S | I32_LOAD8_U | I64_LOAD8_S | I64_LOAD8_U => 1,
+        I32_LOAD16_S | I32_LOAD16_U | I64_LOAD16_S | I64_LOAD16_U => 2,
+        I32_LOAD | F32_LOAD | I64_LOAD32_S | I64_LOAD32_U => 4,
+        _ => 8,
+    };
+    let mut bytes = [0; 8];
+    bytes[..size].copy_from_slice(&memory[range(memory, address, offset, size)?]);
+    let raw = u64::from_le_bytes(bytes);
+    Ok(match opcode {
+        I32_LOAD | I32_LOAD8_U | I32_LOAD16_U => WasmValue::I32(raw as i32),
+        I32_LOAD8_S => WasmValue::I32(raw as i8 as i32),
+        I32_LOAD16_S => WasmValue::I32(raw as i16 as i32),
+        I64_LOAD8_S => WasmValue::I64(raw as i8 as i64),
+        I64_LOAD16_S => WasmValue::I64(raw as i16 as i64),
+        I64_LOAD32_S => WasmValue::I64(raw as i32 as i64),
+        F32_LOAD => WasmValue::F32(f32::from_bits(raw as u32)),
+        F64_LOAD => WasmValue::F64(f64::from_bits(raw)),
+        _ => WasmValue::I64(raw as i64),
+    })
+}
+
+pub(crate) fn store(
+    memory: &mut [u8],
+    opcode: u8,
+    address: i32,
+    offset: u32,
+    value: WasmValue,
+) -> Result<(), &'static str> {
+    let (raw, size) = match (opcode, value) {
+        (I32_STORE8, WasmValue::I32(v)) => (v as u64, 1),
+        (I32_STORE16, WasmValue::I32(v)) => (v as u64, 2),
+        (I32_STORE, WasmValue::I32(v)) => (v as u64, 4),
+        (I64_STORE8, WasmValue::I64(v)) => (v as u64, 1),
+        (I64_STORE16, WasmValue::I64(v)) => (v as u64, 2),
+        (I64_STORE32, WasmValue::I64(v)) => (v as u64, 4),
+        (I64_STORE, WasmValue::I64(v)) => (v as u64, 8),
+        (F32_STORE, WasmValue::F32(v)) => (v.to_bits() as u64, 4),
+        (F64_STORE, WasmValue::F64(v)) => (v.to_bits(), 8),
+        _ => return Err("value on the stack has the wrong type"),
+    };
+    let range = range(memory, address, offset, size)?;
+    memory[range].copy_from_slice(&raw.to_le_bytes()[..size]);
+    Ok(())
+}
@@ -0,0 +1,315 @@
+use super::bytecode::{Module, Op};
+use super::registers::{self, RegisterOp};
+use super::run::{Fuel, MAX_PAGES, PAGE_SIZE};
+use super::store::{store_globals, Element};
+use super::{CallFrame, Engine, InterpretableProgram, Interpreter, InterpreterConfig};
+use super::{WasmExecution, WasmValue};
+use alloc::vec::Vec;
+use serde::{Deserialize, Serialize};
+use webassembly::*;
+
+const CORRUPT: &str = "snapshot does not fit the module";
+
+/// Everything a paused call needs to carry on later, possibly in another
+/// process, against the same module run the same way.
+#[derive(Clone, PartialEq, Debug, Deserialize, Serialize)]
+pub struct Snapshot {
+    /// What `Interpreter::module_hash` was for the interpreter the call ran
+    /// on.
+    pub module_hash: u64,
+    pub call_stack: Vec<CallFrame>,
+    pub value_stack: Vec<WasmValue>,
+    pub fuel: u64,
+    pub fuel_consumed: u64,
+    pub memory: MemoryImage,
+    pub globals: Vec<WasmValue>,
+    pub table: Vec<Option<u32>>,
+}
+
+/// The contents of linear memory in a snapshot.
+#[derive(Clone, PartialEq, Debug, Deserialize, Serialize)]
+pub enum MemoryImage {
+    Raw(Vec<u8>),
+    /// Memory of the given length with its runs of zeros squeezed out.
+    Compressed(usize, Vec<u8>),
+}
+
+impl MemoryImage {
+    pub fn new(memory: &[u8], compress: bool) -> Self {
+        if compress {
+            MemoryImage::Compressed(memory.len(), compress_zeros(memory))
+        } else {
+            MemoryImage::Raw(memory.to_vec())
+        }
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, &'static str> {
+        match self {
+            MemoryImage::Raw(bytes) => Ok(bytes.clone()),
+            MemoryImage::Compressed(len, bytes) => expand_zeros(*len, bytes),
+        }
+    }
+}
+
+// how long a stretch of zeros has to be before it's worth its own run
+const ZEROS_WORTH_SKIPPING: usize = 8;
+
+// writes memory as runs of zeros each followed by a run of literal bytes,
+// both counts as LEB128
+fn compress_zeros(memory: &[u8]) -> Vec<u8> {
+    let max_run = u32::MAX as usize;
+    let mut compressed = vec![];
+    let mut i = 0;
+    while i < memory.len() {
+        let zeros = memory[i..]
+            .iter()
+            .take(max_run)
+            .take_while(|b| **b == 0)
+            .count();
+        i += zeros;
+        let start = i;
+        while i < memory.len()
+            && i - start < max_run
+            && !memory[i..].starts_with(&[0; ZEROS_WORTH_SKIPPING])
+        {
+            i += 1;
+        }
+        compressed.extend(zeros.to_wasm_bytes());
+        compressed.extend((i - start).to_wasm_bytes());
+        compressed.extend_from_slice(&memory[start..i]);
+    }
+    compressed
+}
+
+fn expand_zeros(len: usize, mut compressed: &[u8]) -> Result<Vec<u8>, &'static str> {
+    let mut memory = Vec::with_capacity(len);
+    while !compressed.is_empty() {
+        let zeros = count(&mut compressed)?;
+        let literals = count(&mut compressed)?;
+        if memory.len() + zeros + literals > len || literals > compressed.len() {
+            return Err("compressed memory is corrupt");
+        }
+        memory.resize(memory.len() + zeros, 0);
+        memory.extend_from_slice(&compressed[..literals]);
+        compressed = &compressed[literals..];
+    }
+    if memory.len() != len {
+        return Err("compressed memory is corrupt");
+    }
+    Ok(memory)
+}
+
+fn count(compressed: &mut &[u8]) -> Result<usize, &'static str> {
+    let (n, size) = compressed.try_extract_u32(0)?;
+    *compressed = &compressed[size..];
+    Ok(n as usize)
+}
+
+/// Hashes a module's bytes together with what decides how calls into it
+/// are laid out, so snapshots only restore where their frames make sense.
+pub(crate) fn module_hash(wasm: &[u8], config: &InterpreterConfig) -> u64 {
+    let layout = [
+        (config.engine != Engine::Stack) as u8,
+        config.fuel.is_some() as u8,
+    ];
+    // FNV-1a
+    wasm.iter()
+        .chain(layout.iter())
+        .fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+            (hash ^ *b as u64).wrapping_mul(0x0100_0000_01b3)
+        })
+}
+
+impl<T> WasmExecution<T>
+where
+    T: InterpretableProgram,
+{
+    /// Captures the call as it stands, along with the memory, globals and
+    /// table of the instance it runs on. Memory is compressed if asked.
+    /// Calls into another instance of a store can't be captured, since the
+    /// calls waiting on them belong to other instances, and neither can
+    /// tables holding functions of other instances.
+    pub fn snapshot(&self, compress: bool) -> Result<Snapshot, &'static str> {
+        if !self.outer.is_empty() {
+            return Err("can't snapshot a call into another instance");
+        }
+        let table = self
+            .module
+            .table
+            .lock()
+            .elements
+            .iter()
+            .map(|e| match e {
+                Some(e) if e.instance.is(&self.module) => Ok(Some(e.function as u32)),
+                Some(_) => Err("can't snapshot a table holding another instance's functions"),
+                None => Ok(None),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Snapshot {
+            module_hash: self.module.hash,
+            call_stack: self.call_stack.clone(),
+            value_stack: self.value_stack.clone(),
+            fuel: self.fuel.remaining,
+            fuel_consumed: self.fuel.consumed,
+            memory: MemoryImage::new(&self.memory.lock(), compress),
+            globals: self.globals.lock().clone(),
+            table,
+        })
+    }
+}
+
+impl<T> Interpreter<T>
+where
+    T: InterpretableProgram,
+{
+    /// Identifies the module and how the interpreter runs it. Snapshots
+    /// only restore on interpreters with the same hash.
+    pub fn module_hash(&self) -> u64 {
+        self.module.hash
+    }
+
+    /// Puts the memory, globals and table back the way a snapshot has them
+    /// and returns its call, ready to carry on from where it paused.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<WasmExecution<T>, &'static str> {
+        if snapshot.module_hash != self.module.hash {
+            return Err("snapshot was taken of a different module");
+        }
+        check_frames(&self.module, snapshot)?;
+        let globals_fit = snapshot.globals.len() == self.module.globals.len()
+            && snapshot
+                .globals
+                .iter()
+                .zip(self.module.globals.iter())
+                .all(|(v, g)| v.value_type() == g.value_type);
+        let mut table = self.module.table.lock();
+        let table_fits = snapshot.table.len() == table.elements.len()
+            && snapshot
+                .table
+                .iter()
+                .all(|f| !matches!(f, Some(f) if *f as usize >= self.module.functions.len()));
+        if !globals_fit || !table_fits {
+            return Err(CORRUPT);
+        }
+        let memory = snapshot.memory.to_bytes()?;
+        let pages = self.module.max_memory_pages.unwrap_or(MAX_PAGES);
+        if memory.len() % PAGE_SIZE != 0 || memory.len() / PAGE_SIZE > pages {
+            return Err(CORRUPT);
+        }
+        let instance = self.context().downgrade();
+        for (e, f) in table.elements.iter_mut().zip(snapshot.table.iter()) {
+            *e = f.map(|function| Element {
+                function: function as usize,
+                instance: instance.clone(),
+            });
+        }
+        drop(table);
+        *self.memory.lock() = memory;
+        let mut globals = self.globals.lock();
+        *globals = snapshot.globals.clone();
+        store_globals(&self.module, &globals);
+        drop(globals);
+        Ok(WasmExecution {
+            call_stack: snapshot.call_stack.clone(),
+            value_stack: snapshot.value_stack.clone(),
+            memory: self.memory.clone(),
+            globals: self.globals.clone(),
+            program: self.program.clone(),
+            module: self.module.clone(),
+            fuel: Fuel {
+                remaining: snapshot.fuel,
+                consumed: snapshot.fuel_consumed,
+            },
+            imports: self.imports.clone(),
+            outer: vec![],
+            tracing: None,
+        })
+    }
+}
+
+// makes sure the frames are ones the module could have, with locals of
+// the right types and each frame's share of the value stack exactly as high
+// as it is at that point in the function, which is all running them relies
+// on not to panic. values that merely have the wrong type make the call
+// trap the way it would on a module that doesn't validate
+fn check_frames(module: &Module, snapshot: &Snapshot) -> Result<(), &'static str> {
+    let frames = &snapshot.call_stack;
+    let stack = &snapshot.value_stack;
+    if frames.len() > module.limits.calls {
+        return Err(CORRUPT);
+    }
+    // calls that were stepped through are left on the stack engine
+    let in_registers = registers::in_registers(module, frames);
+    for (i, frame) in frames.iter().enumerate() {
+        let function = module.functions.get(frame.function).ok_or(CORRUPT)?;
+        let registers = module
+            .registers
+            .get(frame.function)
+            .filter(|_| in_registers);
+        let locals = registers.map_or(function.locals.len(), |r| r.registers as usize);
+        let typed = frame
+            .locals
+            .iter()
+            .zip(function.locals.iter())
+            .all(|(v, t)| v.value_type() == *t);
+        if frame.locals.len() != locals || !typed {
+            return Err(CORRUPT);
+        }
+        let top = i + 1 == frames.len();
+        // the signature of the call just before the frame's position, if
+        // that's what it is
+        let previous = frame.pc.checked_sub(1);
+        let callee = |f: &u32| module.functions.get(*f as usize).map(|f| &f.signature);
+        let signature = match registers {
+            Some(r) => match previous.and_then(|pc| r.code.get(pc)) {
+                Some(RegisterOp::Call { function, .. }) => callee(function),
+                Some(RegisterOp::CallIndirect { type_index, .. }) => {
+                    module.types.get(*type_index as usize)
+                }
+                _ => None,
+            },
+            None => match previous.and_then(|pc| function.code.get(pc)) {
+                Some(Op::Call(function)) => callee(function),
+                Some(Op::CallIndirect(t)) => module.types.get(*t as usize),
+                _ => None,
+            },
+        };
+        let results = signature.map(|s| s.outputs.as_slice());
+        // frames below the top one are always waiting on a call
+        if !top && results.is_none() {
+            return Err(CORRUPT);
+        }
+        if registers.is_some() {
+            // the value stack only ever holds an import's results
+            let waiting = results.filter(|_| top).unwrap_or(&[]);
+            let fits = frame.stack_base == 0
+                && frame.pc <= registers.map_or(0, |r| r.code.len())
+                && (!top
+                    || stack.is_empty()
+                    || stack
+                        .iter()
+                        .map(|v| v.value_type())
+                        .eq(waiting.iter().copied()));
+            if !fits {
+                return Err(CORRUPT);
+            }
+            continue;
+        }
+        let end = frames.get(i + 1).map_or(stack.len(), |f| f.stack_base);
+        let expected = *function.heights.get(frame.pc).ok_or(CORRUPT)? as usize;
+        let outputs = results.map_or(0, |r| r.len());
+        let height = end.checked_sub(frame.stack_base).ok_or(CORRUPT)?;
+        // a frame waiting on a call has yet to get its results, although the
+        // top one has them once the host answers an import
+        let fits = (i > 0 || frame.stack_base == 0)
+            && end <= stack.len()
+            && if top {
+                height == expected || (results.is_some() && height + outputs == expected)
+            } else {
+                height + outputs == expected
+            };
+        if !fits {
+            return Err(CORRUPT);
+        }
+    }
+    Ok(())
+}
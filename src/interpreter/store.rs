@@ -0,0 +1,286 @@
+use super::bytecode::Module;
+use super::run::EXHAUSTED;
+use super::{
+    entry, CallFrame, Imports, Instance, InterpretableProgram, InterpreterConfig, WasmExecution,
+    WasmValue,
+};
+use crate::core::*;
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::mem;
+use spin::Mutex;
+
+/// Everything a call runs against that belongs to the instance it's in.
+#[derive(Clone, Debug)]
+pub(crate) struct Context {
+    pub module: Arc<Module>,
+    pub memory: Arc<Mutex<Vec<u8>>>,
+    pub globals: Arc<Mutex<Vec<WasmValue>>>,
+    pub imports: Option<Arc<Imports>>,
+}
+
+impl Context {
+    pub fn downgrade(&self) -> WeakContext {
+        WeakContext {
+            module: Arc::downgrade(&self.module),
+            memory: Arc::downgrade(&self.memory),
+            globals: Arc::downgrade(&self.globals),
+            imports: self.imports.as_ref().map(Arc::downgrade),
+        }
+    }
+}
+
+/// A context that doesn't keep its instance alive, so tables can hold one
+/// without instances that share them keeping each other around.
+#[derive(Clone, Debug)]
+pub(crate) struct WeakContext {
+    module: Weak<Module>,
+    memory: Weak<Mutex<Vec<u8>>>,
+    globals: Weak<Mutex<Vec<WasmValue>>>,
+    imports: Option<Weak<Imports>>,
+}
+
+impl WeakContext {
+    /// Whether this is the context of the instance running `module`.
+    pub fn is(&self, module: &Module) -> bool {
+        core::ptr::eq(self.module.as_ptr(), module)
+    }
+
+    pub fn upgrade(&self) -> Option<Context> {
+        Some(Context {
+            module: self.module.upgrade()?,
+            memory: self.memory.upgrade()?,
+            globals: self.globals.upgrade()?,
+            imports: match &self.imports {
+                Some(imports) => Some(imports.upgrade()?),
+                None => None,
+            },
+        })
+    }
+}
+
+/// The functions `call_indirect` picks from, which instances that import
+/// the table share.
+#[derive(Debug, Default)]
+pub(crate) struct Table {
+    pub elements: Vec<Option<Element>>,
+    pub max: Option<usize>,
+}
+
+impl Table {
+    pub fn empty(size: usize, max: Option<usize>) -> Self {
+        Table {
+            elements: vec![None; size],
+            max,
+        }
+    }
+}
+
+/// A function in a table, along with the instance it belongs to.
+#[derive(Clone, Debug)]
+pub(crate) struct Element {
+    pub function: usize,
+    pub instance: WeakContext,
+}
+
+// copies the mutable globals the module imports in from where they're kept,
+// before it runs
+pub(crate) fn load_globals(module: &Module, globals: &mut [WasmValue]) {
+    for (i, shared) in module.global_imports.iter().filter(|(_, g)| g.is_mutable) {
+        globals[*i] = shared.get();
+    }
+}
+
+// copies them back out once it's run
+pub(crate) fn store_globals(module: &Module, globals: &[WasmValue]) {
+    for (i, shared) in module.global_imports.iter().filter(|(_, g)| g.is_mutable) {
+        shared.globals.lock()[shared.index] = globals[*i];
+    }
+}
+
+/// A function exported by another instance, which an import can stand for.
+#[derive(Clone, Debug)]
+pub(crate) struct Link {
+    pub function: usize,
+    pub context: Context,
+}
+
+impl Link {
+    pub fn signature(&self) -> &FunctionType {
+        &self.context.module.functions[self.function].signature
+    }
+}
+
+/// A call that's waiting on one it made into another instance.
+pub(crate) struct Suspended {
+    context: Context,
+    call_stack: Vec<CallFrame>,
+    value_stack: Vec<WasmValue>,
+}
+
+impl Suspended {
+    pub(crate) fn depth(&self) -> usize {
+        self.call_stack.len()
+    }
+}
+
+impl<T> WasmExecution<T>
+where
+    T: InterpretableProgram,
+{
+    // switches over to another instance to call one of its exports
+    pub(crate) fn enter(&mut self, link: &Link, params: &[WasmValue]) -> Result<(), &'static str> {
+        let depth = self.call_stack.len() + self.outer.iter().map(|s| s.depth()).sum::<usize>();
+        if depth >= link.context.module.limits.calls {
+            return Err(EXHAUSTED);
+        }
+        let frame = entry(&link.context.module, link.function, params)?;
+        let link = link.context.clone();
+        let context = Context {
+            module: mem::replace(&mut self.module, link.module),
+            memory: mem::replace(&mut self.memory, link.memory),
+            globals: mem::replace(&mut self.globals, link.globals),
+            imports: mem::replace(&mut self.imports, link.imports),
+        };
+        self.outer.push(Suspended {
+            context,
+            call_stack: mem::replace(&mut self.call_stack, vec![frame]),
+            value_stack: mem::take(&mut self.value_stack),
+        });
+        Ok(())
+    }
+
+    // switches back to the instance that made the call, handing it the
+    // results
+    pub(crate) fn leave(&mut self, results: Vec<WasmValue>) {
+        if let Some(s) = self.outer.pop() {
+            self.module = s.context.module;
+            self.memory = s.context.memory;
+            self.globals = s.context.globals;
+            self.imports = s.context.imports;
+            self.call_stack = s.call_stack;
+            self.value_stack = s.value_stack;
+            self.value_stack.extend(results);
+        }
+    }
+}
+
+/// Instances that import from one another. Once an instance is registered
+/// under a name, modules instantiated afterwards can import its exports
+/// from that name: they call its functions and share its memory, tables
+/// and globals.
+pub struct Store<T>
+where
+    T: InterpretableProgram,
+{
+    instances: Vec<Instance<T>>,
+    names: BTreeMap<String, usize>,
+    // instances whose start function trapped, kept around since the
+    // functions they put in shared tables stay there
+    trapped: Vec<Instance<T>>,
+}
+
+impl<T> Default for Store<T>
+where
+    T: InterpretableProgram,
+{
+    fn default() -> Self {
+        Store {
+            instances: vec![],
+            names: BTreeMap::new(),
+            trapped: vec![],
+        }
+    }
+}
+
+impl<T> Store<T>
+where
+    T: InterpretableProgram,
+{
+    pub fn new() -> Self {
+        Store::default()
+    }
+
+    /// Instantiates `p`, taking what it imports from registered instances
+    /// and from `imports` for module names that aren't registered. Returns
+    /// the index of the new instance.
+    pub fn instantiate(
+        &mut self,
+        p: T,
+        config: &InterpreterConfig,
+        mut imports: Imports,
+    ) -> Result<usize, &'static str> {
+        let program = p.to_program();
+        let wanted = program
+            .sections
+            .iter()
+            .filter_map(|s| match s {
+                Section::Import(s) => Some(s.imports.iter()),
+                _ => None,
+            })
+            .flatten();
+        for import in wanted {
+            let (module_name, name) = match import {
+                WasmImport::Function(i) => (&i.module_name, &i.name),
+                WasmImport::Memory(i) => (&i.module_name, &i.name),
+                WasmImport::Global(i) => (&i.module_name, &i.name),
+                WasmImport::Table(i) => (&i.module_name, &i.name),
+            };
+            let provider = match self.names.get(module_name) {
+                Some(i) => &self.instances[*i],
+                None => continue,
+            };
+            match import {
+                WasmImport::Function(_) => {
+                    if let Some(link) = provider.link(name) {
+                        imports.link(module_name, name, link);
+                    }
+                }
+                WasmImport::Memory(_) => {
+                    if let Some(m) = provider.shared_memory(name) {
+                        imports.memory(module_name, name, m.memory, m.max_pages);
+                    }
+                }
+                WasmImport::Global(_) => {
+                    if let Some(global) = provider.shared_global(name) {
+                        imports.shared_global(module_name, name, global);
+                    }
+                }
+                WasmImport::Table(_) => {
+                    if let Some(table) = provider.table(name) {
+                        imports.table(module_name, name, table);
+                    }
+                }
+            }
+        }
+        let mut instance = Instance::unstarted(p, config, imports)?;
+        if let Err(e) = instance.start() {
+            self.trapped.push(instance);
+            return Err(e);
+        }
+        self.instances.push(instance);
+        Ok(self.instances.len() - 1)
+    }
+
+    /// Makes the exports of an instance importable from `name`, in place of
+    /// any registered there before.
+    pub fn register(&mut self, name: &str, index: usize) -> Result<(), &'static str> {
+        if index >= self.instances.len() {
+            return Err("invalid instance index");
+        }
+        self.names.insert(name.to_string(), index);
+        Ok(())
+    }
+
+    pub fn instance(&mut self, index: usize) -> Option<&mut Instance<T>> {
+        self.instances.get_mut(index)
+    }
+
+    /// The instance registered under `name`.
+    pub fn registered(&mut self, name: &str) -> Option<&mut Instance<T>> {
+        let index = *self.names.get(name)?;
+        self.instances.get_mut(index)
+    }
+}
@@ -0,0 +1,647 @@
+use super::bytecode::Op;
+use super::run;
+use super::{ExecutionUnit, ImportCall, InterpretableProgram, WasmExecution, WasmValue};
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt::{Debug, Write};
+use core::str;
+use serde::{Deserialize, Serialize};
+use webassembly::*;
+
+/// Bytes written to memory starting at `address`.
+#[derive(Clone, PartialEq, Debug, Deserialize, Serialize)]
+pub struct MemoryWrite {
+    pub address: u32,
+    pub bytes: Vec<u8>,
+}
+
+/// An op a traced call ran.
+#[derive(Clone, PartialEq, Debug, Deserialize, Serialize)]
+pub struct TraceStep {
+    pub function: u32,
+    /// The instruction the op was lowered from, counting the function's
+    /// instructions in the order they're written, nested ones included. The
+    /// count of them all stands for the end of the function.
+    pub instruction: u32,
+    /// How many calls deep the op ran, counting the one traced as 1.
+    pub depth: u32,
+    /// The fewest values taken off the operand stack and put back on it
+    /// that leave it as the op did, the top last.
+    pub popped: u32,
+    pub pushed: Vec<WasmValue>,
+    pub write: Option<MemoryWrite>,
+}
+
+/// An import a traced call made, with what came back from it and what it
+/// wrote to memory.
+#[derive(Clone, PartialEq, Debug, Deserialize, Serialize)]
+pub struct TraceImport {
+    pub module_name: String,
+    pub name: String,
+    pub params: Vec<WasmValue>,
+    pub results: Vec<WasmValue>,
+    pub writes: Vec<MemoryWrite>,
+}
+
+/// Something a traced call did. Events are recorded in the order they
+/// happen, with an import call recorded once its results are in.
+#[derive(Clone, PartialEq, Debug, Deserialize, Serialize)]
+pub enum TraceEvent {
+    Step(TraceStep),
+    Import(TraceImport),
+    /// A call into another instance, whose ops run until the matching
+    /// `Leave` are that instance's. Calls through a shared table to a
+    /// function another instance put there have empty names.
+    Enter {
+        module_name: String,
+        name: String,
+        params: Vec<WasmValue>,
+    },
+    Leave {
+        results: Vec<WasmValue>,
+    },
+}
+
+/// Where the events of a traced call go.
+pub trait Tracer: Send {
+    fn record(&mut self, event: &TraceEvent) -> Result<(), &'static str>;
+}
+
+impl<F> Tracer for F
+where
+    F: FnMut(&TraceEvent) -> Result<(), &'static str> + Send,
+{
+    fn record(&mut self, event: &TraceEvent) -> Result<(), &'static str> {
+        self(event)
+    }
+}
+
+const MAGIC: &[u8] = b"\0wtr\x01";
+
+/// Streams events to `write` in a compact binary format that `read_trace`
+/// reads back, starting with a header.
+pub struct BinaryTrace<W> {
+    write: W,
+    started: bool,
+}
+
+impl<W> BinaryTrace<W>
+where
+    W: FnMut(&[u8]) -> Result<(), &'static str> + Send,
+{
+    pub fn new(write: W) -> Self {
+        BinaryTrace {
+            write,
+            started: false,
+        }
+    }
+}
+
+impl<W> Tracer for BinaryTrace<W>
+where
+    W: FnMut(&[u8]) -> Result<(), &'static str> + Send,
+{
+    fn record(&mut self, event: &TraceEvent) -> Result<(), &'static str> {
+        let mut bytes = vec![];
+        if !self.started {
+            bytes.extend_from_slice(MAGIC);
+            self.started = true;
+        }
+        encode(event, &mut bytes);
+        (self.write)(&bytes)
+    }
+}
+
+/// Streams events to `write` as JSON, one to a line, shaped the way serde
+/// derives them, except that infinities and NaNs are strings of their bits
+/// in hex.
+pub struct JsonTrace<W> {
+    write: W,
+}
+
+impl<W> JsonTrace<W>
+where
+    W: FnMut(&[u8]) -> Result<(), &'static str> + Send,
+{
+    pub fn new(write: W) -> Self {
+        JsonTrace { write }
+    }
+}
+
+impl<W> Tracer for JsonTrace<W>
+where
+    W: FnMut(&[u8]) -> Result<(), &'static str> + Send,
+{
+    fn record(&mut self, event: &TraceEvent) -> Result<(), &'static str> {
+        let mut line = json(event);
+        line.push('\n');
+        (self.write)(line.as_bytes())
+    }
+}
+
+/// Reads the events of a trace written by `BinaryTrace`.
+pub fn read_trace(mut bytes: &[u8]) -> Result<Vec<TraceEvent>, &'static str> {
+    if !bytes.starts_with(MAGIC) {
+        return Err("not a trace");
+    }
+    bytes = &bytes[MAGIC.len()..];
+    let mut events = vec![];
+    while !bytes.is_empty() {
+        events.push(decode(&mut bytes)?);
+    }
+    Ok(events)
+}
+
+/// Where two traces first differ, such as runs of two builds of a module,
+/// if they do. A trace that stops short differs where it stops, and floats
+/// are compared by their bits, so runs computing the same NaN agree.
+pub fn first_difference(a: &[TraceEvent], b: &[TraceEvent]) -> Option<usize> {
+    match a.iter().zip(b.iter()).position(|(a, b)| !same(a, b)) {
+        Some(i) => Some(i),
+        None if a.len() != b.len() => Some(a.len().min(b.len())),
+        None => None,
+    }
+}
+
+// events are the same if they encode to the same bytes, which has floats
+// as their bits
+fn same(a: &TraceEvent, b: &TraceEvent) -> bool {
+    let (mut x, mut y) = (vec![], vec![]);
+    encode(a, &mut x);
+    encode(b, &mut y);
+    x == y
+}
+
+/// What's being recorded of a call and the import it's waiting on the
+/// results of, if any.
+pub(crate) struct Tracing {
+    tracer: Box<dyn Tracer>,
+    pending: Option<Pending>,
+}
+
+struct Pending {
+    call: ImportCall,
+    // how many values were on the stack when the call was made
+    height: usize,
+    memory: Vec<u8>,
+}
+
+impl<T> WasmExecution<T>
+where
+    T: InterpretableProgram,
+{
+    /// Has `tracer` record every op the call runs from here on, along with
+    /// the imports it calls and its calls into other instances. A traced call
+    /// runs an op at a time on the stack engine, and copies memory around
+    /// each import to find what it writes. A call on the register engine
+    /// goes on with the stack engine from here on.
+    pub fn trace(&mut self, tracer: impl Tracer + 'static) -> Result<(), &'static str> {
+        self.tracing = Some(Tracing {
+            tracer: Box::new(tracer),
+            pending: None,
+        });
+        Ok(())
+    }
+
+    /// Stops tracing the call, handing back the tracer.
+    pub fn untrace(&mut self) -> Option<Box<dyn Tracer>> {
+        self.tracing.take().map(|t| t.tracer)
+    }
+
+    // runs an op, recording what it did
+    pub(crate) fn traced_step(
+        &mut self,
+        memory: &mut Vec<u8>,
+        globals: &mut [WasmValue],
+    ) -> Result<Option<ExecutionUnit>, &'static str> {
+        if let Some(p) = self.tracing.as_mut().and_then(|t| t.pending.take()) {
+            let results = self.value_stack.get(p.height..).unwrap_or(&[]).to_vec();
+            self.import_traced(p.call, results, &p.memory, memory)?;
+        }
+        let frame = self.call_stack.last().ok_or("no function is running")?;
+        let function = &self.module.functions[frame.function];
+        let op = function.code.get(frame.pc).cloned();
+        let position = (
+            frame.function as u32,
+            function.instructions.get(frame.pc).copied().unwrap_or(0),
+        );
+        let base = frame.stack_base.min(self.value_stack.len());
+        let before = self.value_stack[base..].to_vec();
+        let depth = self.call_stack.len() + self.outer.iter().map(|s| s.depth()).sum::<usize>();
+        // where a store is about to write
+        let stored = match &op {
+            Some(Op::Store(opcode, offset)) => match self.value_stack.iter().rev().nth(1) {
+                Some(WasmValue::I32(a)) => {
+                    let start = *a as u32 as usize + *offset as usize;
+                    Some(start..start + stored(*opcode))
+                }
+                _ => None,
+            },
+            _ => None,
+        };
+        let unit = run::step(
+            &self.module,
+            memory,
+            globals,
+            &mut self.call_stack,
+            &mut self.value_stack,
+            &mut self.fuel,
+        )?;
+        // paying for what's about to run isn't something the call does
+        if matches!(op, Some(Op::Fuel(_))) {
+            return Ok(unit);
+        }
+        let after = self.value_stack.get(base..).unwrap_or(&[]);
+        let kept = before
+            .iter()
+            .zip(after.iter())
+            .take_while(|(a, b)| a == b)
+            .count();
+        let write = stored.and_then(|range| {
+            Some(MemoryWrite {
+                address: range.start as u32,
+                bytes: memory.get(range)?.to_vec(),
+            })
+        });
+        let step = TraceStep {
+            function: position.0,
+            instruction: position.1,
+            depth: depth as u32,
+            popped: (before.len() - kept) as u32,
+            pushed: after[kept..].to_vec(),
+            write,
+        };
+        self.traced(|| TraceEvent::Step(step))?;
+        Ok(unit)
+    }
+
+    // records an event if the call is being traced
+    pub(crate) fn traced(
+        &mut self,
+        event: impl FnOnce() -> TraceEvent,
+    ) -> Result<(), &'static str> {
+        match &mut self.tracing {
+            Some(t) => t.tracer.record(&event()),
+            None => Ok(()),
+        }
+    }
+
+    // records an import call once it's done, finding what it wrote by
+    // comparing memory from before it with memory after
+    pub(crate) fn import_traced(
+        &mut self,
+        call: ImportCall,
+        results: Vec<WasmValue>,
+        before: &[u8],
+        after: &[u8],
+    ) -> Result<(), &'static str> {
+        self.traced(|| {
+            TraceEvent::Import(TraceImport {
+                module_name: call.module_name,
+                name: call.name,
+                params: call.params,
+                results,
+                writes: writes(before, after),
+            })
+        })
+    }
+
+    // holds on to an import call left to the caller until its results are
+    // pushed and the call carries on
+    pub(crate) fn await_import(&mut self, call: &ImportCall, memory: &[u8]) {
+        let height = self.value_stack.len();
+        if let Some(t) = &mut self.tracing {
+            t.pending = Some(Pending {
+                call: call.clone(),
+                height,
+                memory: memory.to_vec(),
+            });
+        }
+    }
+}
+
+// how many bytes a store opcode writes
+fn stored(opcode: u8) -> usize {
+    match opcode {
+        I32_STORE8 | I64_STORE8 => 1,
+        I32_STORE16 | I64_STORE16 => 2,
+        I32_STORE | F32_STORE | I64_STORE32 => 4,
+        _ => 8,
+    }
+}
+
+// the runs of bytes that differ between two copies of memory, taking what
+// memory grew by to have started out as zeros
+fn writes(before: &[u8], after: &[u8]) -> Vec<MemoryWrite> {
+    let was = |i: usize| before.get(i).copied().unwrap_or(0);
+    let mut writes = vec![];
+    let mut i = 0;
+    while i < after.len() {
+        if after[i] == was(i) {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        while i < after.len() && after[i] != was(i) {
+            i += 1;
+        }
+        writes.push(MemoryWrite {
+            address: start as u32,
+            bytes: after[start..i].to_vec(),
+        });
+    }
+    writes
+}
+
+fn encode_values(values: &[WasmValue], bytes: &mut Vec<u8>) {
+    bytes.extend(values.len().to_wasm_bytes());
+    for v in values.iter() {
+        match v {
+            WasmValue::I32(v) => {
+                bytes.push(I32);
+                bytes.extend(v.to_wasm_bytes());
+            }
+            WasmValue::I64(v) => {
+                bytes.push(I64);
+                bytes.extend(v.to_wasm_bytes());
+            }
+            WasmValue::F32(v) => {
+                bytes.push(F32);
+                bytes.extend(v.to_wasm_bytes());
+            }
+            WasmValue::F64(v) => {
+                bytes.push(F64);
+                bytes.extend(v.to_wasm_bytes());
+            }
+        }
+    }
+}
+
+fn encode_write(write: &MemoryWrite, bytes: &mut Vec<u8>) {
+    bytes.extend(write.address.to_wasm_bytes());
+    bytes.extend(write.bytes.len().to_wasm_bytes());
+    bytes.extend_from_slice(&write.bytes);
+}
+
+fn encode_name(name: &str, bytes: &mut Vec<u8>) {
+    bytes.extend(name.len().to_wasm_bytes());
+    bytes.extend_from_slice(name.as_bytes());
+}
+
+// each event is a tag byte followed by its fields, with counts and
+// integers as LEB128 and floats as their little endian bytes
+fn encode(event: &TraceEvent, bytes: &mut Vec<u8>) {
+    match event {
+        TraceEvent::Step(s) => {
+            bytes.push(0);
+            bytes.extend(s.function.to_wasm_bytes());
+            bytes.extend(s.instruction.to_wasm_bytes());
+            bytes.extend(s.depth.to_wasm_bytes());
+            bytes.extend(s.popped.to_wasm_bytes());
+            encode_values(&s.pushed, bytes);
+            match &s.write {
+                Some(w) => {
+                    bytes.push(1);
+                    encode_write(w, bytes);
+                }
+                None => bytes.push(0),
+            }
+        }
+        TraceEvent::Import(i) => {
+            bytes.push(1);
+            encode_name(&i.module_name, bytes);
+            encode_name(&i.name, bytes);
+            encode_values(&i.params, bytes);
+            encode_values(&i.results, bytes);
+            bytes.extend(i.writes.len().to_wasm_bytes());
+            for w in i.writes.iter() {
+                encode_write(w, bytes);
+            }
+        }
+        TraceEvent::Enter {
+            module_name,
+            name,
+            params,
+        } => {
+            bytes.push(2);
+            encode_name(module_name, bytes);
+            encode_name(name, bytes);
+            encode_values(params, bytes);
+        }
+        TraceEvent::Leave { results } => {
+            bytes.push(3);
+            encode_values(results, bytes);
+        }
+    }
+}
+
+const CORRUPT: &str = "trace is corrupt";
+
+fn take<'a>(bytes: &mut &'a [u8], n: usize) -> Result<&'a [u8], &'static str> {
+    if n > bytes.len() {
+        return Err(CORRUPT);
+    }
+    let (taken, rest) = bytes.split_at(n);
+    *bytes = rest;
+    Ok(taken)
+}
+
+fn decode_u32(bytes: &mut &[u8]) -> Result<u32, &'static str> {
+    let (v, size) = bytes.try_extract_u32(0).map_err(|_| CORRUPT)?;
+    *bytes = &bytes[size..];
+    Ok(v)
+}
+
+fn decode_values(bytes: &mut &[u8]) -> Result<Vec<WasmValue>, &'static str> {
+    let count = decode_u32(bytes)?;
+    let mut values = vec![];
+    for _ in 0..count {
+        let t = take(bytes, 1)?[0];
+        let (v, size) = match t {
+            I32 => bytes
+                .try_extract_i32(0)
+                .map(|(v, s)| (WasmValue::I32(v), s)),
+            I64 => bytes
+                .try_extract_i64(0)
+                .map(|(v, s)| (WasmValue::I64(v), s)),
+            F32 => bytes
+                .try_extract_f32(0)
+                .map(|(v, s)| (WasmValue::F32(v), s)),
+            F64 => bytes
+                .try_extract_f64(0)
+                .map(|(v, s)| (WasmValue::F64(v), s)),
+            _ => Err(CORRUPT),
+        }
+        .map_err(|_| CORRUPT)?;
+        *bytes = &bytes[size..];
+        values.push(v);
+    }
+    Ok(values)
+}
+
+fn decode_write(bytes: &mut &[u8]) -> Result<MemoryWrite, &'static str> {
+    let address = decode_u32(bytes)?;
+    let len = decode_u32(bytes)? as usize;
+    Ok(MemoryWrite {
+        address,
+        bytes: take(bytes, len)?.to_vec(),
+    })
+}
+
+fn decode_name(bytes: &mut &[u8]) -> Result<String, &'static str> {
+    let len = decode_u32(bytes)? as usize;
+    let name = str::from_utf8(take(bytes, len)?).map_err(|_| CORRUPT)?;
+    Ok(name.to_string())
+}
+
+fn decode(bytes: &mut &[u8]) -> Result<TraceEvent, &'static str> {
+    Ok(match take(bytes, 1)?[0] {
+        0 => TraceEvent::Step(TraceStep {
+            function: decode_u32(bytes)?,
+            instruction: decode_u32(bytes)?,
+            depth: decode_u32(bytes)?,
+            popped: decode_u32(bytes)?,
+            pushed: decode_values(bytes)?,
+            write: match take(bytes, 1)?[0] {
+                0 => None,
+                _ => Some(decode_write(bytes)?),
+            },
+        }),
+        1 => TraceEvent::Import(TraceImport {
+            module_name: decode_name(bytes)?,
+            name: decode_name(bytes)?,
+            params: decode_values(bytes)?,
+            results: decode_values(bytes)?,
+            writes: {
+                let count = decode_u32(bytes)?;
+                (0..count)
+                    .map(|_| decode_write(bytes))
+                    .collect::<Result<_, _>>()?
+            },
+        }),
+        2 => TraceEvent::Enter {
+            module_name: decode_name(bytes)?,
+            name: decode_name(bytes)?,
+            params: decode_values(bytes)?,
+        },
+        3 => TraceEvent::Leave {
+            results: decode_values(bytes)?,
+        },
+        _ => return Err(CORRUPT),
+    })
+}
+
+fn json_string(s: &str, out: &mut String) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn json_values(values: &[WasmValue], out: &mut String) {
+    out.push('[');
+    for (i, v) in values.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        match v {
+            WasmValue::I32(v) => {
+                let _ = write!(out, "{{\"I32\":{}}}", v);
+            }
+            WasmValue::I64(v) => {
+                let _ = write!(out, "{{\"I64\":{}}}", v);
+            }
+            WasmValue::F32(v) if v.is_finite() => {
+                let _ = write!(out, "{{\"F32\":{:?}}}", v);
+            }
+            WasmValue::F64(v) if v.is_finite() => {
+                let _ = write!(out, "{{\"F64\":{:?}}}", v);
+            }
+            // JSON has no numbers for these, so they're kept as their bits
+            WasmValue::F32(v) => {
+                let _ = write!(out, "{{\"F32\":\"{:#010x}\"}}", v.to_bits());
+            }
+            WasmValue::F64(v) => {
+                let _ = write!(out, "{{\"F64\":\"{:#018x}\"}}", v.to_bits());
+            }
+        }
+    }
+    out.push(']');
+}
+
+fn json_write(write: &MemoryWrite, out: &mut String) {
+    let _ = write!(out, "{{\"address\":{},\"bytes\":[", write.address);
+    for (i, b) in write.bytes.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let _ = write!(out, "{}", b);
+    }
+    out.push_str("]}");
+}
+
+fn json(event: &TraceEvent) -> String {
+    let mut out = String::new();
+    match event {
+        TraceEvent::Step(s) => {
+            let _ = write!(
+                out,
+                "{{\"Step\":{{\"function\":{},\"instruction\":{},\"depth\":{},\"popped\":{},\"pushed\":",
+                s.function, s.instruction, s.depth, s.popped
+            );
+            json_values(&s.pushed, &mut out);
+            out.push_str(",\"write\":");
+            match &s.write {
+                Some(w) => json_write(w, &mut out),
+                None => out.push_str("null"),
+            }
+            out.push_str("}}");
+        }
+        TraceEvent::Import(i) => {
+            out.push_str("{\"Import\":{\"module_name\":");
+            json_string(&i.module_name, &mut out);
+            out.push_str(",\"name\":");
+            json_string(&i.name, &mut out);
+            out.push_str(",\"params\":");
+            json_values(&i.params, &mut out);
+            out.push_str(",\"results\":");
+            json_values(&i.results, &mut out);
+            out.push_str(",\"writes\":[");
+            for (n, w) in i.writes.iter().enumerate() {
+                if n > 0 {
+                    out.push(',');
+                }
+                json_write(w, &mut out);
+            }
+            out.push_str("]}}");
+        }
+        TraceEvent::Enter {
+            module_name,
+            name,
+            params,
+        } => {
+            out.push_str("{\"Enter\":{\"module_name\":");
+            json_string(module_name, &mut out);
+            out.push_str(",\"name\":");
+            json_string(name, &mut out);
+            out.push_str(",\"params\":");
+            json_values(params, &mut out);
+            out.push_str("}}");
+        }
+        TraceEvent::Leave { results } => {
+            out.push_str("{\"Leave\":{\"results\":");
+            json_values(results, &mut out);
+            out.push_str("}}");
+        }
+    }
+    out
+}
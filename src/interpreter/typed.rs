@@ -0,0 +1,115 @@
+use super::{InterpretableProgram, Interpreter, WasmValue};
+use crate::core::ValueType;
+use alloc::vec::Vec;
+
+/// A Rust type that stands for a WebAssembly value type.
+pub trait WasmType: Sized {
+    const VALUE_TYPE: ValueType;
+    fn from_value(value: WasmValue) -> Option<Self>;
+    fn into_value(self) -> WasmValue;
+}
+
+macro_rules! wasm_type {
+    ($t:ty, $variant:ident) => {
+        impl WasmType for $t {
+            const VALUE_TYPE: ValueType = ValueType::$variant;
+
+            fn from_value(value: WasmValue) -> Option<Self> {
+                match value {
+                    WasmValue::$variant(v) => Some(v),
+                    _ => None,
+                }
+            }
+
+            fn into_value(self) -> WasmValue {
+                WasmValue::$variant(self)
+            }
+        }
+    };
+}
+
+wasm_type!(i32, I32);
+wasm_type!(i64, I64);
+wasm_type!(f32, F32);
+wasm_type!(f64, F64);
+
+/// Rust types that stand for a function's parameters or results: nothing,
+/// one value or a tuple of them.
+pub trait WasmValues: Sized {
+    fn value_types() -> Vec<ValueType>;
+    fn into_values(self) -> Vec<WasmValue>;
+    /// Converts values back, or returns `None` if their number or types
+    /// don't match exactly.
+    fn from_values(values: &[WasmValue]) -> Option<Self>;
+}
+
+impl<T: WasmType> WasmValues for T {
+    fn value_types() -> Vec<ValueType> {
+        vec![T::VALUE_TYPE]
+    }
+
+    fn into_values(self) -> Vec<WasmValue> {
+        vec![self.into_value()]
+    }
+
+    fn from_values(values: &[WasmValue]) -> Option<Self> {
+        match values {
+            [v] => T::from_value(*v),
+            _ => None,
+        }
+    }
+}
+
+macro_rules! wasm_values {
+    ($($t:ident),*) => {
+        impl<$($t: WasmType),*> WasmValues for ($($t,)*) {
+            fn value_types() -> Vec<ValueType> {
+                vec![$($t::VALUE_TYPE),*]
+            }
+
+            #[allow(non_snake_case)]
+            fn into_values(self) -> Vec<WasmValue> {
+                let ($($t,)*) = self;
+                vec![$($t.into_value()),*]
+            }
+
+            #[allow(non_snake_case)]
+            fn from_values(values: &[WasmValue]) -> Option<Self> {
+                match values {
+                    [$($t),*] => Some(($($t::from_value(*$t)?,)*)),
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+wasm_values!();
+wasm_values!(A);
+wasm_values!(A, B);
+wasm_values!(A, B, C);
+wasm_values!(A, B, C, D);
+wasm_values!(A, B, C, D, E);
+wasm_values!(A, B, C, D, E, G);
+
+impl<T> Interpreter<T>
+where
+    T: InterpretableProgram,
+{
+    /// Calls an export with Rust values and runs it to completion, checking
+    /// the export's signature against `P` and `R` first. Imports can only be
+    /// called if the interpreter was given them with `with_imports`.
+    pub fn call_typed<P, R>(&mut self, name: &str, params: P) -> Result<R, &'static str>
+    where
+        P: WasmValues,
+        R: WasmValues,
+    {
+        let function = self.module.find_export(name)?;
+        let signature = &self.module.functions[function].signature;
+        if signature.inputs != P::value_types() || signature.outputs != R::value_types() {
+            return Err("export has a different signature");
+        }
+        let results = self.call(name, &params.into_values())?.finish()?;
+        R::from_values(&results).ok_or("call returned the wrong values")
+    }
+}
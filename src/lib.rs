@@ -2,26 +2,52 @@
 #[macro_use]
 extern crate alloc;
 extern crate serde;
+#[cfg(feature = "std")]
+extern crate std;
 extern crate webassembly;
 
 mod compiler;
 mod core;
 mod interpreter;
+mod linker;
 mod parser;
 mod util;
+#[cfg(feature = "std")]
+pub mod gdb;
+#[cfg(feature = "wasi")]
+pub mod wasi;
 
 pub use crate::core::common::*;
 pub use crate::core::view::*;
 use crate::core::wast::Wast;
+pub use crate::core::CostTable;
+pub use crate::core::DeadCode;
+pub use crate::core::Features;
+pub use crate::core::FunctionBuilder;
+pub use crate::core::GasMeter;
+pub use crate::core::IndexSpace;
 pub use crate::core::Instruction;
+pub use crate::core::Label;
 pub use crate::core::Program;
 pub use crate::core::ProgramView;
 pub use crate::interpreter::*;
+pub use crate::linker::*;
+pub use crate::parser::config::*;
 
 pub fn parse<'p>(input: &'p [u8]) -> Result<core::ProgramView<'p>, &'static str> {
     parser::wasm::wasm_module(input)
 }
 
+/// Parses a module, failing if it uses any feature not enabled in `config`.
+pub fn parse_with_config<'p>(
+    input: &'p [u8],
+    config: &ParserConfig,
+) -> Result<core::ProgramView<'p>, &'static str> {
+    let program = parser::wasm::wasm_module(input)?;
+    config.features.check(&program.used_features())?;
+    Ok(program)
+}
+
 pub fn parse_wast<'p>(input: &'p [u8]) -> Result<Wast, &'static str> {
     parser::wast::wast_file(input)
 }
@@ -0,0 +1,600 @@
+use crate::core::*;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+const FUNCTION: usize = 0;
+const TABLE: usize = 1;
+const MEMORY: usize = 2;
+const GLOBAL: usize = 3;
+
+const PAGE_SIZE: usize = 65536;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Target {
+    // a definition in one of the linked modules
+    Defined(usize, usize),
+    // an import nothing in the link satisfies
+    Import(usize),
+    // a position independent module's `env.__memory_base` or `env.__table_base`
+    Base(usize),
+}
+
+struct Module<'a> {
+    name: &'a str,
+    program: &'a Program,
+    types: Vec<&'a FunctionType>,
+    imports: [Vec<&'a WasmImport>; 4],
+    function_types: Vec<usize>,
+    tables: Vec<&'a Table>,
+    memories: Vec<&'a WasmMemory>,
+    globals: Vec<&'a Global>,
+}
+
+impl<'a> Module<'a> {
+    fn new(name: &'a str, program: &'a Program) -> Self {
+        let mut m = Module {
+            name,
+            program,
+            types: vec![],
+            imports: [vec![], vec![], vec![], vec![]],
+            function_types: vec![],
+            tables: vec![],
+            memories: vec![],
+            globals: vec![],
+        };
+        for s in program.sections.iter() {
+            match s {
+                Section::Type(s) => m.types.extend(s.types.iter()),
+                Section::Import(s) => {
+                    for i in s.imports.iter() {
+                        m.imports[kind_of_import(i)].push(i);
+                    }
+                }
+                Section::Function(s) => m.function_types.extend(s.function_types.iter()),
+                Section::Table(s) => m.tables.extend(s.tables.iter()),
+                Section::Memory(s) => m.memories.extend(s.memories.iter()),
+                Section::Global(s) => m.globals.extend(s.globals.iter()),
+                _ => {}
+            }
+        }
+        m
+    }
+
+    fn defined_count(&self, kind: usize) -> usize {
+        match kind {
+            FUNCTION => self.function_types.len(),
+            TABLE => self.tables.len(),
+            MEMORY => self.memories.len(),
+            _ => self.globals.len(),
+        }
+    }
+
+    fn find_export(&self, kind: usize, name: &str) -> Option<usize> {
+        for s in self.program.sections.iter() {
+            if let Section::Export(s) = s {
+                for e in s.exports.iter() {
+                    let (k, e) = match e {
+                        WasmExport::Function(e) => (FUNCTION, e),
+                        WasmExport::Table(e) => (TABLE, e),
+                        WasmExport::Memory(e) => (MEMORY, e),
+                        WasmExport::Global(e) => (GLOBAL, e),
+                    };
+                    if k == kind && e.name == name {
+                        return Some(e.index);
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+fn kind_of_import(i: &WasmImport) -> usize {
+    match i {
+        WasmImport::Function(_) => FUNCTION,
+        WasmImport::Table(_) => TABLE,
+        WasmImport::Memory(_) => MEMORY,
+        WasmImport::Global(_) => GLOBAL,
+    }
+}
+
+fn import_names(i: &WasmImport) -> (&str, &str) {
+    match i {
+        WasmImport::Function(i) => (&i.module_name, &i.name),
+        WasmImport::Table(i) => (&i.module_name, &i.name),
+        WasmImport::Memory(i) => (&i.module_name, &i.name),
+        WasmImport::Global(i) => (&i.module_name, &i.name),
+    }
+}
+
+fn is_base_import(i: &WasmImport) -> bool {
+    matches!(
+        import_names(i),
+        ("env", "__memory_base") | ("env", "__table_base")
+    )
+}
+
+fn align(n: usize) -> usize {
+    (n + 15) & !15
+}
+
+fn find_or_push_type(types: &mut Vec<FunctionType>, t: &FunctionType) -> usize {
+    match types.iter().position(|x| x == t) {
+        Some(i) => i,
+        None => {
+            types.push(t.clone());
+            types.len() - 1
+        }
+    }
+}
+
+// the end of the highest constant offset segment, where relocated data can go
+fn static_end(expression: &[Instruction], len: usize) -> usize {
+    match expression {
+        [Instruction::I32Const(offset)] => *offset as u32 as usize + len,
+        _ => 0,
+    }
+}
+
+// whether constant offset segments of different modules overlap, as each
+// module expects its own contents to be there
+fn overlap(segments: &[(&[Instruction], usize)], modules: &[usize]) -> bool {
+    let ranges: Vec<(usize, usize, usize)> = segments
+        .iter()
+        .zip(modules.iter())
+        .filter_map(|((expression, len), m)| match expression {
+            [Instruction::I32Const(offset)] if *len > 0 => {
+                let start = *offset as u32 as usize;
+                Some((*m, start, start + len))
+            }
+            _ => None,
+        })
+        .collect();
+    ranges.iter().enumerate().any(|(i, (m, start, end))| {
+        ranges[i + 1..]
+            .iter()
+            .any(|(n, s, e)| m != n && start < e && s < end)
+    })
+}
+
+struct Link<'a> {
+    modules: Vec<Module<'a>>,
+    types: Vec<FunctionType>,
+    imports: Vec<WasmImport>,
+    // (module, is table base) for every relocated base global handed out
+    bases: Vec<(usize, bool)>,
+}
+
+impl<'a> Link<'a> {
+    fn resolve(
+        &mut self,
+        m: usize,
+        kind: usize,
+        index: usize,
+        depth: usize,
+    ) -> Result<Target, &'static str> {
+        if depth > self.modules.len() {
+            return Err("import cycle between linked modules");
+        }
+        let import_count = self.modules[m].imports[kind].len();
+        if index >= import_count {
+            if index - import_count >= self.modules[m].defined_count(kind) {
+                return Err("index out of range in linked module");
+            }
+            return Ok(Target::Defined(m, index - import_count));
+        }
+        let import = self.modules[m].imports[kind][index];
+        let (module_name, name) = import_names(import);
+        let exporter = self.modules.iter().enumerate().find_map(|(j, x)| {
+            if x.name == module_name {
+                x.find_export(kind, name).map(|e| (j, e))
+            } else {
+                None
+            }
+        });
+        if let Some((j, e)) = exporter {
+            return self.resolve(j, kind, e, depth + 1);
+        }
+        if is_base_import(import) {
+            let slot = (m, name == "__table_base");
+            return Ok(Target::Base(
+                match self.bases.iter().position(|x| *x == slot) {
+                    Some(i) => i,
+                    None => {
+                        self.bases.push(slot);
+                        self.bases.len() - 1
+                    }
+                },
+            ));
+        }
+        // unresolved imports are kept once per distinct module and name
+        let mut import = import.clone();
+        if let WasmImport::Function(f) = &mut import {
+            let t = self.modules[m]
+                .types
+                .get(f.type_index)
+                .ok_or("invalid type index")?;
+            f.type_index = find_or_push_type(&mut self.types, t);
+        }
+        for (i, existing) in self.imports.iter().enumerate() {
+            if kind_of_import(existing) == kind && import_names(existing) == (module_name, name) {
+                if *existing != import {
+                    return Err("linked modules import the same name with different types");
+                }
+                return Ok(Target::Import(i));
+            }
+        }
+        self.imports.push(import);
+        Ok(Target::Import(self.imports.len() - 1))
+    }
+
+    fn function_type(&self, target: Target) -> Option<&FunctionType> {
+        match target {
+            Target::Defined(m, d) => {
+                let m = &self.modules[m];
+                m.types.get(m.function_types[d]).copied()
+            }
+            Target::Import(i) => match &self.imports[i] {
+                WasmImport::Function(f) => self.types.get(f.type_index),
+                _ => None,
+            },
+            Target::Base(_) => None,
+        }
+    }
+
+    fn global_type(&self, target: Target) -> Option<(ValueType, bool)> {
+        match target {
+            Target::Defined(m, d) => {
+                let g = self.modules[m].globals[d];
+                Some((g.value_type, g.is_mutable))
+            }
+            Target::Import(i) => match &self.imports[i] {
+                WasmImport::Global(g) => Some((g.value_type, g.is_mutable)),
+                _ => None,
+            },
+            Target::Base(_) => Some((ValueType::I32, false)),
+        }
+    }
+}
+
+/// Links several modules into one. Each module is given the name other
+/// modules import it by; any function, table, memory or global import that
+/// names a module in the link and one of its exports is resolved to that
+/// export, everything else stays an import of the linked module.
+///
+/// All index spaces are renumbered, identical function types are shared,
+/// every module's exports are kept and, if several modules have a start
+/// function, a new start function calls them in order. Position independent
+/// modules importing `env.__memory_base` or `env.__table_base` have their
+/// data and element segments relocated after everything placed at constant
+/// offsets, which can't be moved, so the link fails if those of different
+/// modules overlap. Custom sections are dropped since their contents can't be
+/// renumbered.
+pub fn link(modules: &[(&str, &Program)]) -> Result<Program, &'static str> {
+    let mut link = Link {
+        modules: modules.iter().map(|(n, p)| Module::new(n, p)).collect(),
+        types: vec![],
+        imports: vec![],
+        bases: vec![],
+    };
+
+    // resolve every import of every module
+    let mut targets: Vec<[Vec<Target>; 4]> = vec![];
+    for m in 0..link.modules.len() {
+        let mut module_targets = [vec![], vec![], vec![], vec![]];
+        for (kind, kind_targets) in module_targets.iter_mut().enumerate() {
+            for i in 0..link.modules[m].imports[kind].len() {
+                let target = link.resolve(m, kind, i, 0)?;
+                match link.modules[m].imports[kind][i] {
+                    WasmImport::Function(f) => {
+                        let expected = link.modules[m].types.get(f.type_index).copied();
+                        if expected != link.function_type(target) {
+                            return Err("function import resolved to export of a different type");
+                        }
+                    }
+                    WasmImport::Global(g)
+                        if Some((g.value_type, g.is_mutable)) != link.global_type(target) =>
+                    {
+                        return Err("global import resolved to export of a different type");
+                    }
+                    _ => {}
+                }
+                kind_targets.push(target);
+            }
+        }
+        targets.push(module_targets);
+    }
+
+    // lay out every index space as unresolved imports followed by each
+    // module's definitions
+    let mut import_counts = [0; 4];
+    for i in link.imports.iter() {
+        import_counts[kind_of_import(i)] += 1;
+    }
+    let mut import_positions = vec![];
+    let mut seen = [0; 4];
+    for i in link.imports.iter() {
+        let kind = kind_of_import(i);
+        import_positions.push(seen[kind]);
+        seen[kind] += 1;
+    }
+    let mut definition_offsets = vec![];
+    let mut totals = [0; 4];
+    for m in link.modules.iter() {
+        definition_offsets.push(totals);
+        for (kind, total) in totals.iter_mut().enumerate() {
+            *total += m.defined_count(kind);
+        }
+    }
+    if import_counts[MEMORY] + totals[MEMORY] > 1 {
+        return Err("linked modules would need more than one memory");
+    }
+    if import_counts[TABLE] + totals[TABLE] > 1 {
+        return Err("linked modules would need more than one table");
+    }
+    let output_index = |kind: usize, target: Target| match target {
+        Target::Defined(m, d) => import_counts[kind] + definition_offsets[m][kind] + d,
+        Target::Import(i) => import_positions[i],
+        Target::Base(b) => import_counts[GLOBAL] + totals[GLOBAL] + b,
+    };
+
+    // renumber each module into the linked layout
+    let mut element_offset = 0;
+    let mut data_offset = 0;
+    let mut programs = vec![];
+    for (m, module) in link.modules.iter().enumerate() {
+        let mut maps: [Vec<usize>; 4] = [vec![], vec![], vec![], vec![]];
+        for (kind, map) in maps.iter_mut().enumerate() {
+            for t in targets[m][kind].iter() {
+                map.push(output_index(kind, *t));
+            }
+            for d in 0..module.defined_count(kind) {
+                map.push(output_index(kind, Target::Defined(m, d)));
+            }
+        }
+        let mut type_map = vec![];
+        for t in module.types.iter() {
+            type_map.push(find_or_push_type(&mut link.types, t));
+        }
+        let mut p = module.program.clone();
+        let (elements, data) = (element_offset, data_offset);
+        let mut out_of_range = false;
+        p.map_indices(&mut |space, i| {
+            let map = match space {
+                IndexSpace::Type => &type_map,
+                IndexSpace::Function => &maps[FUNCTION],
+                IndexSpace::Table => &maps[TABLE],
+                IndexSpace::Memory => &maps[MEMORY],
+                IndexSpace::Global => &maps[GLOBAL],
+                IndexSpace::Element => return elements + i,
+                IndexSpace::Data => return data + i,
+            };
+            match map.get(i) {
+                Some(i) => *i,
+                None => {
+                    out_of_range = true;
+                    i
+                }
+            }
+        })?;
+        if out_of_range {
+            return Err("index out of range in linked module");
+        }
+        for s in p.sections.iter() {
+            match s {
+                Section::Element(s) => element_offset += s.elements.len(),
+                Section::Data(s) => data_offset += s.data_blocks.len(),
+                _ => {}
+            }
+        }
+        programs.push(p);
+    }
+
+    let mut function_types = vec![];
+    let mut code_blocks = vec![];
+    let mut tables = vec![];
+    let mut memories = vec![];
+    let mut globals = vec![];
+    let mut exports: Vec<WasmExport> = vec![];
+    let mut starts = vec![];
+    let mut elements = vec![];
+    let mut data_blocks = vec![];
+    let mut data_count = false;
+    // which module each element and data segment came from
+    let mut element_modules = vec![];
+    let mut data_modules = vec![];
+    for (m, p) in programs.into_iter().enumerate() {
+        for s in p.sections.into_iter() {
+            match s {
+                Section::Function(s) => function_types.extend(s.function_types),
+                Section::Code(s) => code_blocks.extend(s.code_blocks),
+                Section::Table(s) => tables.extend(s.tables),
+                Section::Memory(s) => memories.extend(s.memories),
+                Section::Global(s) => globals.extend(s.globals),
+                Section::Start(s) => starts.push(s.start_function),
+                Section::Element(s) => {
+                    element_modules.extend(s.elements.iter().map(|_| m));
+                    elements.extend(s.elements);
+                }
+                Section::Data(s) => {
+                    data_modules.extend(s.data_blocks.iter().map(|_| m));
+                    data_blocks.extend(s.data_blocks);
+                }
+                Section::DataCount(_) => data_count = true,
+                Section::Export(s) => {
+                    for e in s.exports.into_iter() {
+                        let name = |e: &WasmExport| -> String {
+                            match e {
+                                WasmExport::Function(x)
+                                | WasmExport::Table(x)
+                                | WasmExport::Memory(x)
+                                | WasmExport::Global(x) => x.name.clone(),
+                            }
+                        };
+                        match exports.iter().find(|x| name(x) == name(&e)) {
+                            Some(existing) if *existing == e => {}
+                            Some(_) => return Err("linked modules export the same name"),
+                            None => exports.push(e),
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let data_segments: Vec<_> = data_blocks
+        .iter()
+        .map(|d| (&d.offset_expression[..], d.data.len()))
+        .collect();
+    if overlap(&data_segments, &data_modules) {
+        return Err("linked modules place data at overlapping constant offsets");
+    }
+    let element_segments: Vec<_> = elements
+        .iter()
+        .map(|e| (&e.value_expression[..], e.functions.len()))
+        .collect();
+    if overlap(&element_segments, &element_modules) {
+        return Err("linked modules place elements at overlapping constant offsets");
+    }
+
+    // relocate position independent modules after all static data and elements
+    let base_globals = import_counts[GLOBAL] + totals[GLOBAL];
+    let mut memory_end = align(
+        data_blocks
+            .iter()
+            .map(|d| static_end(&d.offset_expression, d.data.len()))
+            .max()
+            .unwrap_or(0),
+    );
+    let mut table_end = elements
+        .iter()
+        .map(|e| static_end(&e.value_expression, e.functions.len()))
+        .max()
+        .unwrap_or(0);
+    for (b, (_, is_table)) in link.bases.iter().enumerate() {
+        let base_global = (base_globals + b) as u32;
+        let base = if *is_table { table_end } else { memory_end };
+        let mut size = 0;
+        if *is_table {
+            for e in elements.iter_mut() {
+                if e.value_expression == [Instruction::GlobalGet(base_global)] {
+                    e.value_expression = vec![Instruction::I32Const((base + size) as i32)];
+                    size += e.functions.len();
+                }
+            }
+            table_end += size;
+        } else {
+            for d in data_blocks.iter_mut() {
+                if d.offset_expression == [Instruction::GlobalGet(base_global)] {
+                    d.offset_expression = vec![Instruction::I32Const((base + size) as i32)];
+                    size += align(d.data.len());
+                }
+            }
+            memory_end += size;
+        }
+        globals.push(Global {
+            value_type: ValueType::I32,
+            is_mutable: false,
+            value_expression: vec![Instruction::I32Const(base as i32)],
+        });
+    }
+    if let Some(m) = memories.first_mut() {
+        let pages = memory_end.div_ceil(PAGE_SIZE);
+        if pages > m.min_pages {
+            if matches!(m.max_pages, Some(max) if max < pages) {
+                return Err("relocated data does not fit in memory");
+            }
+            m.min_pages = pages;
+        }
+    }
+    if let Some(t) = tables.first_mut() {
+        if table_end > t.min {
+            if matches!(t.max, Some(max) if max < table_end) {
+                return Err("relocated elements do not fit in table");
+            }
+            t.min = table_end;
+        }
+    }
+
+    // several start functions are called in turn by a new one
+    let start = match starts.len() {
+        0 => None,
+        1 => Some(starts[0]),
+        _ => {
+            let type_index = find_or_push_type(
+                &mut link.types,
+                &FunctionType {
+                    inputs: vec![],
+                    outputs: vec![],
+                },
+            );
+            function_types.push(type_index);
+            code_blocks.push(CodeBlock {
+                locals: vec![],
+                instructions: starts
+                    .iter()
+                    .map(|s| Instruction::Call(*s as u32))
+                    .collect(),
+            });
+            Some(import_counts[FUNCTION] + function_types.len() - 1)
+        }
+    };
+
+    // a data count is only written if an input had one or something needs
+    // it, so linking MVP modules makes an MVP module
+    data_count |= data_blocks.iter().any(|d| d.passive);
+    for c in code_blocks.iter_mut() {
+        map_instruction_indices(&mut c.instructions, &mut |space, i| {
+            data_count |= space == IndexSpace::Data;
+            i
+        })?;
+    }
+
+    let mut p = Program::new();
+    if !link.types.is_empty() {
+        p.sections
+            .push(Section::Type(TypeSection { types: link.types }));
+    }
+    if !link.imports.is_empty() {
+        p.sections.push(Section::Import(ImportSection {
+            imports: link.imports,
+        }));
+    }
+    if !function_types.is_empty() {
+        p.sections
+            .push(Section::Function(FunctionSection { function_types }));
+    }
+    if !tables.is_empty() {
+        p.sections.push(Section::Table(TableSection { tables }));
+    }
+    if !memories.is_empty() {
+        p.sections.push(Section::Memory(MemorySection { memories }));
+    }
+    if !globals.is_empty() {
+        p.sections.push(Section::Global(GlobalSection { globals }));
+    }
+    if !exports.is_empty() {
+        p.sections.push(Section::Export(ExportSection { exports }));
+    }
+    if let Some(start_function) = start {
+        p.sections
+            .push(Section::Start(StartSection { start_function }));
+    }
+    if !elements.is_empty() {
+        p.sections
+            .push(Section::Element(ElementSection { elements }));
+    }
+    if data_count {
+        p.sections.push(Section::DataCount(DataCountSection {
+            count: data_blocks.len(),
+        }));
+    }
+    if !code_blocks.is_empty() {
+        p.sections.push(Section::Code(CodeSection { code_blocks }));
+    }
+    if !data_blocks.is_empty() {
+        p.sections.push(Section::Data(DataSection { data_blocks }));
+    }
+    Ok(p)
+}
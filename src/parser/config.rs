@@ -0,0 +1,16 @@
+use crate::core::Features;
+
+/// Options controlling what `parse_with_config` accepts.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct ParserConfig {
+    pub features: Features,
+}
+
+impl ParserConfig {
+    /// Only accept modules that stay within the WebAssembly MVP.
+    pub fn mvp() -> Self {
+        ParserConfig {
+            features: Features::mvp(),
+        }
+    }
+}
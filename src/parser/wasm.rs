@@ -1,7 +1,7 @@
 use crate::core::*;
 use crate::util::*;
 use alloc::vec::Vec;
-use core::convert::TryInto;
+use core::convert::{TryFrom, TryInto};
 use webassembly::*;
 
 fn wasm_u32(input: &[u8]) -> Result<(&[u8], u32), &'static str> {
@@ -74,19 +74,74 @@ fn wasm_global_type(input: &[u8]) -> Result<(&[u8], ValueType, bool), &'static s
 }
 
 fn wasm_limit(input: &[u8]) -> Result<(&[u8], usize, Option<usize>), &'static str> {
+    let (input, limit) = wasm_memory_limit(input)?;
+    if limit.shared || limit.memory64 {
+        return Err("only memories can be shared or 64-bit");
+    }
+    Ok((input, limit.min, limit.max))
+}
+
+// limits that may also be marked shared, as memories' can with threads, or
+// 64-bit, as they can with memory64
+struct MemoryLimit {
+    min: usize,
+    max: Option<usize>,
+    shared: bool,
+    memory64: bool,
+}
+
+fn wasm_memory_limit(input: &[u8]) -> Result<(&[u8], MemoryLimit), &'static str> {
     let (input, mem_type) = take(1)(input)?;
-    match mem_type[0] {
+    let shared = mem_type[0] & LIMIT_SHARED != 0;
+    let memory64 = mem_type[0] & LIMIT_MEMORY64 != 0;
+    let size = if memory64 { wasm_u64 } else { wasm_u32_size };
+    let (input, min, max) = match mem_type[0] & !(LIMIT_SHARED | LIMIT_MEMORY64) {
         LIMIT_MIN_MAX => {
-            let (input, min) = wasm_u32(input)?;
-            let (input, max) = wasm_u32(input)?;
-            Ok((input, min as usize, Some(max as usize)))
+            let (input, min) = size(input)?;
+            let (input, max) = size(input)?;
+            (input, min, Some(max))
         }
+        LIMIT_MIN if shared => return Err("shared memory must have maximum"),
         LIMIT_MIN => {
-            let (input, min) = wasm_u32(input)?;
-            Ok((input, min as usize, None))
+            let (input, min) = size(input)?;
+            (input, min, None)
+        }
+        _ => return Err("unhandled memory type"),
+    };
+    Ok((
+        input,
+        MemoryLimit {
+            min,
+            max,
+            shared,
+            memory64,
+        },
+    ))
+}
+
+fn wasm_u32_size(input: &[u8]) -> Result<(&[u8], usize), &'static str> {
+    let (input, i) = wasm_u32(input)?;
+    Ok((input, i as usize))
+}
+
+// an unsigned LEB128 of up to 64 bits that has to fit in a usize
+fn wasm_u64(input: &[u8]) -> Result<(&[u8], usize), &'static str> {
+    let mut value: u64 = 0;
+    for (i, byte) in input.iter().enumerate().take(10) {
+        let bits = u64::from(byte & 0x7F);
+        // the tenth byte only has room for the top bit
+        if i == 9 && bits > 1 {
+            return Err("integer too large");
+        }
+        value |= bits << (i * 7);
+        if byte & 0x80 == 0 {
+            return match usize::try_from(value) {
+                Ok(v) => Ok((&input[i + 1..], v)),
+                Err(_) => Err("integer too large"),
+            };
         }
-        _ => Err("unhandled memory type"),
     }
+    Err("could not parse integer")
 }
 
 fn wasm_instruction(op: u8, input: &[u8]) -> Result<(&[u8], Instruction), &'static str> {
@@ -497,11 +552,112 @@ fn wasm_instruction(op: u8, input: &[u8]) -> Result<(&[u8], Instruction), &'stat
         I64_REINTERPRET_F64 => instruction = Instruction::I64ReinterpretF64,
         F32_REINTERPRET_I32 => instruction = Instruction::F32ReinterpretI32,
         F64_REINTERPRET_I64 => instruction = Instruction::F64ReinterpretI64,
-        _ => return Err("unknown expression"),
+        PREFIX_MISC | PREFIX_SIMD | PREFIX_THREADS => {
+            let (input, sub_op) = wasm_u32(input)?;
+            let layout = match immediate_layout(op, Some(sub_op)) {
+                Some(l) => l,
+                None => return Err("unknown expression"),
+            };
+            let (input, immediates) = wasm_immediates(input, layout)?;
+            instruction = Instruction::Prefixed(op, sub_op, immediates);
+            ip = input;
+        }
+        _ => {
+            let layout = match immediate_layout(op, None) {
+                Some(l) => l,
+                None => return Err("unknown expression"),
+            };
+            let (input, immediates) = wasm_immediates(input, layout)?;
+            instruction = Instruction::Unknown(op, immediates);
+            ip = input;
+        }
     };
     Ok((ip, instruction))
 }
 
+const SECTION_DATA_COUNT: u8 = 12;
+
+enum Immediate {
+    U32,
+    Byte,
+    MemArg,
+    Bytes(usize),
+    ValueTypes,
+}
+
+// Immediate layouts of opcodes from proposals watson doesn't model yet, so
+// they can be carried through a parse/compile untouched. Opcodes whose layout
+// can't be known (structured ones like `try`) are still rejected.
+fn immediate_layout(op: u8, sub_op: Option<u32>) -> Option<&'static [Immediate]> {
+    use Immediate::*;
+    let layout: &'static [Immediate] = match (op, sub_op) {
+        // tail calls
+        (0x12, None) => &[U32],
+        (0x13, None) => &[U32, U32],
+        // typed select
+        (0x1C, None) => &[ValueTypes],
+        // table.get, table.set
+        (0x25, None) | (0x26, None) => &[U32],
+        // sign extension
+        (0xC0..=0xC4, None) => &[],
+        // ref.null, ref.is_null, ref.func
+        (0xD0, None) => &[Byte],
+        (0xD1, None) => &[],
+        (0xD2, None) => &[U32],
+        // saturating truncation
+        (PREFIX_MISC, Some(0..=7)) => &[],
+        // memory.init, data.drop, memory.copy, memory.fill
+        (PREFIX_MISC, Some(8)) => &[U32, Byte],
+        (PREFIX_MISC, Some(9)) => &[U32],
+        (PREFIX_MISC, Some(10)) => &[Byte, Byte],
+        (PREFIX_MISC, Some(11)) => &[Byte],
+        // table.init, elem.drop, table.copy, table.grow, table.size, table.fill
+        (PREFIX_MISC, Some(12)) | (PREFIX_MISC, Some(14)) => &[U32, U32],
+        (PREFIX_MISC, Some(13)) | (PREFIX_MISC, Some(15..=17)) => &[U32],
+        // v128 loads and stores
+        (PREFIX_SIMD, Some(0..=11)) => &[MemArg],
+        // v128.const, i8x16.shuffle
+        (PREFIX_SIMD, Some(12)) | (PREFIX_SIMD, Some(13)) => &[Bytes(16)],
+        (PREFIX_SIMD, Some(14..=20)) => &[],
+        // lane extraction and replacement
+        (PREFIX_SIMD, Some(21..=34)) => &[Byte],
+        (PREFIX_SIMD, Some(35..=83)) => &[],
+        // lane loads and stores
+        (PREFIX_SIMD, Some(84..=91)) => &[MemArg, Byte],
+        (PREFIX_SIMD, Some(92..=93)) => &[MemArg],
+        (PREFIX_SIMD, Some(94..=255)) => &[],
+        // memory.atomic.notify, memory.atomic.wait32/64, atomic.fence
+        (PREFIX_THREADS, Some(0..=2)) => &[MemArg],
+        (PREFIX_THREADS, Some(3)) => &[Byte],
+        // atomic loads, stores and read-modify-writes
+        (PREFIX_THREADS, Some(0x10..=0x4E)) => &[MemArg],
+        _ => return None,
+    };
+    Some(layout)
+}
+
+fn wasm_immediates<'a>(
+    input: &'a [u8],
+    layout: &[Immediate],
+) -> Result<(&'a [u8], Vec<u8>), &'static str> {
+    let original_input = input;
+    let mut ip = input;
+    for immediate in layout.iter() {
+        ip = match immediate {
+            Immediate::U32 => wasm_u32(ip)?.0,
+            Immediate::Byte => take(1)(ip)?.0,
+            Immediate::MemArg => wasm_u32(wasm_u32(ip)?.0)?.0,
+            Immediate::Bytes(n) => take(*n)(ip)?.0,
+            Immediate::ValueTypes => {
+                let (input, num_types) = wasm_u32(ip)?;
+                take(num_types as usize)(input)?.0
+            }
+        };
+    }
+    let consumed = original_input.len() - ip.len();
+    Ok((ip, original_input[..consumed].to_vec()))
+}
+
 fn wasm_expression(input: &[u8]) -> Result<(&[u8], Vec<Instruction>), &'static str> {
     let mut instructions = vec![];
     let mut ip = input;
@@ -572,7 +728,7 @@ fn wasm_if_else(input: &[u8]) -> Result<(&[u8], Instructions, Option<Instruction
     }
 }
 
-fn section(input: &[u8]) -> Result<(&[u8], SectionView), &'static str> {
+pub(crate) fn section(input: &[u8]) -> Result<(&[u8], SectionView<'_>), &'static str> {
     let (input, id) = take(1)(input)?;
     let (input, section_length) = wasm_u32(input)?;
     match id[0] {
@@ -719,14 +875,16 @@ fn section(input: &[u8]) -> Result<(&[u8], SectionView), &'static str> {
                         ))
                     }
                     DESC_MEMORY => {
-                        let (input, min_pages, max_pages) = wasm_limit(input)?;
+                        let (input, limit) = wasm_memory_limit(input)?;
                         Ok((
                             input,
                             WasmImportView::Memory(MemoryImportView {
                                 module_name,
                                 name,
-                                min_pages,
-                                max_pages,
+                                min_pages: limit.min,
+                                max_pages: limit.max,
+                                shared: limit.shared,
+                                memory64: limit.memory64,
                             }),
                         ))
                     }
@@ -795,8 +953,11 @@ fn section(input: &[u8]) -> Result<(&[u8], SectionView), &'static str> {
                 Ok(b) => b,
                 Err(_) => return Err("could not parse utf8 string"),
             };
-            let (input, bytes) =
-                take((section_length as usize - name_bytes_length) as usize)(input)?;
+            let data_length = match (section_length as usize).checked_sub(name_bytes_length) {
+                Some(l) => l,
+                None => return Err("custom section name is longer than section"),
+            };
+            let (input, bytes) = take(data_length)(input)?;
             Ok((
                 input,
                 SectionView::Custom(CustomSectionView { name, data: bytes }),
@@ -826,8 +987,20 @@ fn section(input: &[u8]) -> Result<(&[u8], SectionView), &'static str> {
         SECTION_DATA => {
             let (input, num_items) = wasm_u32(input)?;
             let parse_items = many_n(num_items as usize, |input| {
-                let (input, mem_index) = wasm_u32(input)?;
-                let (input, offset_expression) = wasm_expression(input)?;
+                let (input, flags) = wasm_u32(input)?;
+                let (input, mem_index, offset_expression) = match flags {
+                    0 => {
+                        let (input, offset_expression) = wasm_expression(input)?;
+                        (input, 0, offset_expression)
+                    }
+                    1 => (input, 0, vec![]),
+                    2 => {
+                        let (input, mem_index) = wasm_u32(input)?;
+                        let (input, offset_expression) = wasm_expression(input)?;
+                        (input, mem_index, offset_expression)
+                    }
+                    _ => return Err("unknown data segment kind"),
+                };
                 let (input, data_len) = wasm_u32(input)?;
                 let (input, data) = take(data_len as usize)(input)?;
                 Ok((
@@ -836,6 +1009,7 @@ fn section(input: &[u8]) -> Result<(&[u8], SectionView), &'static str> {
                         memory: mem_index as usize,
                         offset_expression,
                         data,
+                        passive: flags == 1,
                     },
                 ))
             });
@@ -848,12 +1022,14 @@ fn section(input: &[u8]) -> Result<(&[u8], SectionView), &'static str> {
         SECTION_MEMORY => {
             let (input, num_items) = wasm_u32(input)?;
             let parse_items = many_n(num_items as usize, |input| {
-                let (input, min, max) = wasm_limit(input)?;
+                let (input, limit) = wasm_memory_limit(input)?;
                 Ok((
                     input,
                     WasmMemory {
-                        min_pages: min,
-                        max_pages: max,
+                        min_pages: limit.min,
+                        max_pages: limit.max,
+                        shared: limit.shared,
+                        memory64: limit.memory64,
                     },
                 ))
             });
@@ -889,6 +1065,15 @@ fn section(input: &[u8]) -> Result<(&[u8], SectionView), &'static str> {
                 SectionView::Element(ElementSection { elements: items }),
             ))
         }
+        SECTION_DATA_COUNT => {
+            let (input, count) = wasm_u32(input)?;
+            Ok((
+                input,
+                SectionView::DataCount(DataCountSection {
+                    count: count as usize,
+                }),
+            ))
+        }
         _ => Err("unknow section"),
     }
 }
@@ -897,12 +1082,14 @@ pub fn wasm_module(input: &[u8]) -> Result<ProgramView, &'static str> {
     let (input, _) = tag(MAGIC_NUMBER)(input)?;
     let (input, _) = tag(VERSION_1)(input)?;
     let mut sections = vec![];
+    let mut raw_sections = vec![];
     let mut ip = input;
-    let mut p = ProgramView { sections: vec![] };
+    let mut p = ProgramView::default();
     loop {
         match section(ip) {
             Ok((input, item)) => {
                 sections.push(item);
+                raw_sections.push(&ip[..ip.len() - input.len()]);
                 ip = input;
             }
             Err(e) => {
@@ -915,5 +1102,172 @@ pub fn wasm_module(input: &[u8]) -> Result<ProgramView, &'static str> {
         }
     }
     p.sections = sections;
+    p.raw_sections = raw_sections;
     Ok(p)
 }
+
+/// Where each instruction of every function body in a module starts, as an
+/// offset from the start of the module, followed by where the body's final
+/// `end` is. Nested instructions are in the order they're written, which is
+/// how the interpreter numbers them.
+pub(crate) fn instruction_offsets(module: &[u8]) -> Result<Vec<Vec<usize>>, &'static str> {
+    let (mut ip, _) = take(MAGIC_NUMBER.len() + VERSION_1.len())(module)?;
+    let offset = |ip: &[u8]| ip.as_ptr() as usize - module.as_ptr() as usize;
+    let mut functions = vec![];
+    while !ip.is_empty() {
+        let (input, id) = take(1)(ip)?;
+        let (input, length) = wasm_u32(input)?;
+        let (rest, section) = take(length as usize)(input)?;
+        ip = rest;
+        if id[0] != SECTION_CODE {
+            continue;
+        }
+        let (mut input, num_bodies) = wasm_u32(section)?;
+        for _ in 0..num_bodies {
+            let (rest, size) = wasm_u32(input)?;
+            let (rest, body) = take(size as usize)(rest)?;
+            input = rest;
+            let (mut body, num_local_vecs) = wasm_u32(body)?;
+            for _ in 0..num_local_vecs {
+                body = wasm_u32(body)?.0;
+                body = take(1)(body)?.0;
+            }
+            // blocks are walked through rather than parsed whole so that
+            // what's in them is found too
+            let mut offsets = vec![];
+            let end = (offset(body) + body.len()).saturating_sub(1);
+            while !body.is_empty() {
+                let start = offset(body);
+                let (rest, op) = take(1)(body)?;
+                body = match op[0] {
+                    END | ELSE => rest,
+                    BLOCK | LOOP | IF => {
+                        offsets.push(start);
+                        take(1)(rest)?.0
+                    }
+                    op => {
+                        offsets.push(start);
+                        wasm_instruction(op, rest)?.0
+                    }
+                };
+            }
+            offsets.push(end);
+            functions.push(offsets);
+        }
+    }
+    Ok(functions)
+}
+
+/// Names given to functions and their locals by index.
+pub(crate) type NameMap<'a> = Vec<(u32, &'a str)>;
+
+/// A name map for each of the things an index refers to, like the locals of
+/// each function.
+pub(crate) type IndirectNameMap<'a> = Vec<(u32, NameMap<'a>)>;
+
+/// The function and local names in the data of a custom section called
+/// `name`. Other kinds of names are skipped.
+pub(crate) fn name_section(
+    data: &[u8],
+) -> Result<(NameMap<'_>, IndirectNameMap<'_>), &'static str> {
+    let mut functions = vec![];
+    let mut locals = vec![];
+    let mut ip = data;
+    while !ip.is_empty() {
+        let (input, id) = take(1)(ip)?;
+        let (input, length) = wasm_u32(input)?;
+        let (rest, subsection) = take(length as usize)(input)?;
+        ip = rest;
+        match id[0] {
+            1 => functions = name_map(subsection)?.1,
+            2 => locals = indirect_name_map(subsection)?.1,
+            _ => {}
+        }
+    }
+    Ok((functions, locals))
+}
+
+fn name_map(input: &[u8]) -> Result<(&[u8], NameMap<'_>), &'static str> {
+    let (input, count) = wasm_u32(input)?;
+    many_n(count as usize, |input| {
+        let (input, index) = wasm_u32(input)?;
+        let (input, name) = wasm_string(input)?;
+        Ok((input, (index, name)))
+    })(input)
+}
+
+fn indirect_name_map(input: &[u8]) -> Result<(&[u8], IndirectNameMap<'_>), &'static str> {
+    let (input, count) = wasm_u32(input)?;
+    many_n(count as usize, |input| {
+        let (input, index) = wasm_u32(input)?;
+        let (input, names) = name_map(input)?;
+        Ok((input, (index, names)))
+    })(input)
+}
+
+/// The data of a custom section called `name` with every index in it
+/// rewritten by `f`, keeping each map in index order. Subsections naming
+/// things it doesn't know about are dropped.
+pub(crate) fn map_name_indices(
+    data: &[u8],
+    f: &mut impl FnMut(IndexSpace, usize) -> usize,
+) -> Result<Vec<u8>, &'static str> {
+    // writes a name map, putting it back in index order
+    fn extend_name_map(v: &mut Vec<u8>, mut names: Vec<(usize, &str)>) {
+        names.sort_by_key(|n| n.0);
+        v.extend(names.len().to_wasm_bytes());
+        for (index, name) in names {
+            v.extend(index.to_wasm_bytes());
+            v.extend(name.len().to_wasm_bytes());
+            v.extend(name.as_bytes());
+        }
+    }
+    let mut renamed = vec![];
+    let mut ip = data;
+    while !ip.is_empty() {
+        let (input, id) = take(1)(ip)?;
+        let (input, length) = wasm_u32(input)?;
+        let (rest, subsection) = take(length as usize)(input)?;
+        ip = rest;
+        let mut content = vec![];
+        match id[0] {
+            // the module's own name
+            0 => content.extend(subsection),
+            // locals and labels, by function
+            2 | 3 => {
+                let mut maps: Vec<(usize, NameMap)> = indirect_name_map(subsection)?
+                    .1
+                    .into_iter()
+                    .map(|(i, names)| (f(IndexSpace::Function, i as usize), names))
+                    .collect();
+                maps.sort_by_key(|m| m.0);
+                content.extend(maps.len().to_wasm_bytes());
+                for (index, names) in maps {
+                    content.extend(index.to_wasm_bytes());
+                    // neither locals nor labels move
+                    let names = names.into_iter().map(|(i, n)| (i as usize, n));
+                    extend_name_map(&mut content, names.collect());
+                }
+            }
+            1 | 4..=9 => {
+                let space = match id[0] {
+                    1 => IndexSpace::Function,
+                    4 => IndexSpace::Type,
+                    5 => IndexSpace::Table,
+                    6 => IndexSpace::Memory,
+                    7 => IndexSpace::Global,
+                    8 => IndexSpace::Element,
+                    _ => IndexSpace::Data,
+                };
+                let names = name_map(subsection)?.1.into_iter();
+                let names = names.map(|(i, n)| (f(space, i as usize), n));
+                extend_name_map(&mut content, names.collect());
+            }
+            _ => continue,
+        }
+        renamed.push(id[0]);
+        renamed.extend(content.len().to_wasm_bytes());
+        renamed.extend(content);
+    }
+    Ok(renamed)
+}
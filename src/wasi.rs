@@ -0,0 +1,328 @@
+//! The `wasi_snapshot_preview1` system interface, provided through
+//! [`Imports`] so that programs built for `wasm32-wasi` can run in the
+//! interpreter. Their files live in whatever [`FileSystem`]s they're given
+//! as preopened directories.
+
+use crate::interpreter::{Caller, Imports, Instance, InterpretableProgram, Trap};
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+mod filesystem;
+#[cfg(feature = "std")]
+mod host;
+mod state;
+
+pub use filesystem::*;
+#[cfg(feature = "std")]
+pub use host::HostDirectory;
+use state::{Clock, Descriptor, State};
+
+/// The module name the interface is imported from.
+pub const MODULE: &str = "wasi_snapshot_preview1";
+
+/// What a host function fails with once the program calls `proc_exit`.
+pub const EXITED: &str = "program exited";
+
+/// An error number as the interface defines them.
+pub type Errno = u16;
+
+/// The error numbers the interface defines.
+pub mod errno {
+    use super::Errno;
+
+    pub const SUCCESS: Errno = 0;
+    pub const TOOBIG: Errno = 1;
+    pub const ACCES: Errno = 2;
+    pub const BADF: Errno = 8;
+    pub const BUSY: Errno = 10;
+    pub const EXIST: Errno = 20;
+    pub const FAULT: Errno = 21;
+    pub const FBIG: Errno = 22;
+    pub const ILSEQ: Errno = 25;
+    pub const INVAL: Errno = 28;
+    pub const IO: Errno = 29;
+    pub const ISDIR: Errno = 31;
+    pub const LOOP: Errno = 32;
+    pub const NAMETOOLONG: Errno = 37;
+    pub const NOENT: Errno = 44;
+    pub const NOSPC: Errno = 51;
+    pub const NOSYS: Errno = 52;
+    pub const NOTDIR: Errno = 54;
+    pub const NOTEMPTY: Errno = 55;
+    pub const NOTSUP: Errno = 58;
+    pub const PERM: Errno = 63;
+    pub const SPIPE: Errno = 70;
+    pub const XDEV: Errno = 75;
+    pub const NOTCAPABLE: Errno = 76;
+}
+
+fn status(result: Result<(), Errno>) -> i32 {
+    match result {
+        Ok(()) => errno::SUCCESS as i32,
+        Err(e) => e as i32,
+    }
+}
+
+/// The system a program sees: its arguments and environment, its standard
+/// streams, the directories it's given, and where its time and randomness
+/// come from. Clones share all of it, so one can be kept to look at what a
+/// program wrote once another has been turned into its imports.
+///
+/// By default standard input is empty and what's written to standard output
+/// and error is kept, time starts at 0 and only passes when the program
+/// waits for it, and random numbers come from a fixed seed. That way a
+/// program does the same thing every time it's run.
+#[derive(Clone)]
+pub struct Wasi {
+    state: Arc<Mutex<State>>,
+}
+
+impl Default for Wasi {
+    fn default() -> Self {
+        Wasi {
+            state: Arc::new(Mutex::new(State {
+                args: vec![],
+                env: vec![],
+                filesystems: vec![],
+                fds: vec![
+                    Some(Descriptor::Stdin),
+                    Some(Descriptor::Stdout),
+                    Some(Descriptor::Stderr),
+                ],
+                stdin: vec![],
+                stdout: vec![],
+                stderr: vec![],
+                #[cfg(feature = "std")]
+                inherit_stdio: false,
+                clock: Clock::Virtual(0),
+                random: 0x853c_49e6_748f_ea9b,
+                exit_code: None,
+            })),
+        }
+    }
+}
+
+impl Wasi {
+    pub fn new() -> Self {
+        Wasi::default()
+    }
+
+    /// Adds an argument, the first being the program's name by convention.
+    pub fn arg(&mut self, arg: &str) -> &mut Self {
+        self.state.lock().args.push(arg.to_string());
+        self
+    }
+
+    pub fn env(&mut self, key: &str, value: &str) -> &mut Self {
+        self.state.lock().env.push(format!("{}={}", key, value));
+        self
+    }
+
+    /// Gives the program a directory it can reach as `name`, which is the
+    /// root of `fs`. Directories are given descriptors in the order they're
+    /// added, starting from 3.
+    pub fn preopen(&mut self, name: &str, fs: impl FileSystem + 'static) -> &mut Self {
+        {
+            let mut state = self.state.lock();
+            state.filesystems.push(Box::new(fs));
+            let fs = state.filesystems.len() - 1;
+            state.fds.push(Some(Descriptor::Directory {
+                fs,
+                path: String::new(),
+                preopen: Some(name.to_string()),
+            }));
+        }
+        self
+    }
+
+    /// Adds to what the program reads from standard input.
+    pub fn stdin(&mut self, input: &[u8]) -> &mut Self {
+        self.state.lock().stdin.extend_from_slice(input);
+        self
+    }
+
+    /// Takes the time from `now`, which is given the id of the clock asked
+    /// for and returns nanoseconds. Waiting doesn't take any time.
+    pub fn clock(&mut self, now: impl FnMut(u32) -> u64 + Send + 'static) -> &mut Self {
+        self.state.lock().clock = Clock::Host(Box::new(now));
+        self
+    }
+
+    pub fn seed(&mut self, seed: u64) -> &mut Self {
+        // xorshift never leaves 0
+        self.state.lock().random = seed.max(1);
+        self
+    }
+
+    /// Connects the program's standard streams to the host's.
+    #[cfg(feature = "std")]
+    pub fn inherit_stdio(&mut self) -> &mut Self {
+        self.state.lock().inherit_stdio = true;
+        self
+    }
+
+    /// Takes the time from the host, really waiting when the program waits.
+    #[cfg(feature = "std")]
+    pub fn system_clock(&mut self) -> &mut Self {
+        self.state.lock().clock = Clock::System(std::time::Instant::now());
+        self
+    }
+
+    /// What the program has written to standard output so far.
+    pub fn stdout(&self) -> Vec<u8> {
+        self.state.lock().stdout.clone()
+    }
+
+    /// What the program has written to standard error so far.
+    pub fn stderr(&self) -> Vec<u8> {
+        self.state.lock().stderr.clone()
+    }
+
+    /// The code the program exited with, if it's called `proc_exit`.
+    pub fn exit_code(&self) -> Option<u32> {
+        self.state.lock().exit_code
+    }
+
+    pub fn imports(&self) -> Imports {
+        let mut imports = Imports::new();
+        self.add_to(&mut imports);
+        imports
+    }
+
+    /// Registers every function of the interface in `imports`.
+    pub fn add_to(&self, imports: &mut Imports) {
+        macro_rules! call {
+            ($name:ident($($p:ident: $t:ty),*)) => {{
+                let state = self.state.clone();
+                imports.func(
+                    MODULE,
+                    stringify!($name),
+                    move |caller: &mut Caller, $($p: $t),*| {
+                        Ok(status(state.lock().$name(&mut caller.memory(), $($p as _),*)))
+                    },
+                );
+            }};
+        }
+        macro_rules! unsupported {
+            ($errno:ident, $name:ident($($t:ty),*)) => {
+                imports.func(MODULE, stringify!($name), |_: &mut Caller, $(_: $t),*| {
+                    Ok(errno::$errno as i32)
+                });
+            };
+        }
+
+        call!(args_get(argv: i32, buf: i32));
+        call!(args_sizes_get(argc: i32, size: i32));
+        call!(environ_get(environ: i32, buf: i32));
+        call!(environ_sizes_get(count: i32, size: i32));
+        call!(clock_res_get(id: i32, res: i32));
+        call!(clock_time_get(id: i32, precision: i64, time: i32));
+        call!(fd_advise(fd: i32, offset: i64, len: i64, advice: i32));
+        call!(fd_allocate(fd: i32, offset: i64, len: i64));
+        call!(fd_close(fd: i32));
+        call!(fd_datasync(fd: i32));
+        call!(fd_fdstat_get(fd: i32, stat: i32));
+        call!(fd_fdstat_set_flags(fd: i32, flags: i32));
+        call!(fd_fdstat_set_rights(fd: i32, base: i64, inheriting: i64));
+        call!(fd_filestat_get(fd: i32, buf: i32));
+        call!(fd_filestat_set_size(fd: i32, size: i64));
+        call!(fd_filestat_set_times(fd: i32, accessed: i64, modified: i64, flags: i32));
+        call!(fd_pread(fd: i32, iovs: i32, iovs_len: i32, offset: i64, nread: i32));
+        call!(fd_prestat_get(fd: i32, buf: i32));
+        call!(fd_prestat_dir_name(fd: i32, path: i32, len: i32));
+        call!(fd_pwrite(fd: i32, iovs: i32, iovs_len: i32, offset: i64, nwritten: i32));
+        call!(fd_read(fd: i32, iovs: i32, iovs_len: i32, nread: i32));
+        call!(fd_readdir(fd: i32, buf: i32, buf_len: i32, cookie: i64, bufused: i32));
+        call!(fd_renumber(fd: i32, to: i32));
+        call!(fd_seek(fd: i32, offset: i64, whence: i32, new_offset: i32));
+        call!(fd_sync(fd: i32));
+        call!(fd_tell(fd: i32, offset: i32));
+        call!(fd_write(fd: i32, iovs: i32, iovs_len: i32, nwritten: i32));
+        call!(path_create_directory(fd: i32, path: i32, len: i32));
+        call!(path_filestat_get(fd: i32, flags: i32, path: i32, len: i32, buf: i32));
+        call!(path_filestat_set_times(
+            fd: i32,
+            flags: i32,
+            path: i32,
+            len: i32,
+            accessed: i64,
+            modified: i64,
+            fst_flags: i32
+        ));
+        call!(path_link(
+            old_fd: i32,
+            flags: i32,
+            old_path: i32,
+            old_len: i32,
+            new_fd: i32,
+            new_path: i32,
+            new_len: i32
+        ));
+        call!(path_open(
+            fd: i32,
+            dirflags: i32,
+            path: i32,
+            len: i32,
+            oflags: i32,
+            base: i64,
+            inheriting: i64,
+            fdflags: i32,
+            opened: i32
+        ));
+        call!(path_readlink(
+            fd: i32,
+            path: i32,
+            len: i32,
+            buf: i32,
+            buf_len: i32,
+            bufused: i32
+        ));
+        call!(path_remove_directory(fd: i32, path: i32, len: i32));
+        call!(path_rename(
+            fd: i32,
+            old_path: i32,
+            old_len: i32,
+            new_fd: i32,
+            new_path: i32,
+            new_len: i32
+        ));
+        call!(path_symlink(
+            old_path: i32,
+            old_len: i32,
+            fd: i32,
+            new_path: i32,
+            new_len: i32
+        ));
+        call!(path_unlink_file(fd: i32, path: i32, len: i32));
+        call!(poll_oneoff(subscriptions: i32, events: i32, count: i32, nevents: i32));
+        call!(random_get(buf: i32, len: i32));
+
+        let state = self.state.clone();
+        imports.func(MODULE, "proc_exit", move |_: &mut Caller, code: i32| {
+            state.lock().exit_code = Some(code as u32);
+            Err::<(), _>(Trap(EXITED))
+        });
+        imports.func(MODULE, "sched_yield", |_: &mut Caller| Ok(0));
+        // there are no signals or sockets to speak of
+        unsupported!(NOSYS, proc_raise(i32));
+        unsupported!(NOTSUP, sock_accept(i32, i32, i32));
+        unsupported!(NOTSUP, sock_recv(i32, i32, i32, i32, i32, i32));
+        unsupported!(NOTSUP, sock_send(i32, i32, i32, i32, i32));
+        unsupported!(NOTSUP, sock_shutdown(i32, i32));
+    }
+
+    /// Runs a program's `_start` export, returning the code it exits with.
+    pub fn run<T>(&self, instance: &mut Instance<T>) -> Result<u32, &'static str>
+    where
+        T: InterpretableProgram,
+    {
+        match instance.call("_start", &[]) {
+            Ok(_) => Ok(0),
+            Err(EXITED) => Ok(self.exit_code().unwrap_or(0)),
+            Err(e) => Err(e),
+        }
+    }
+}
@@ -0,0 +1,416 @@
+use super::errno::*;
+use super::Errno;
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use spin::Mutex;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FileType {
+    Directory,
+    RegularFile,
+    SymbolicLink,
+}
+
+impl FileType {
+    pub(crate) fn to_wasi(self) -> u8 {
+        match self {
+            FileType::Directory => 3,
+            FileType::RegularFile => 4,
+            FileType::SymbolicLink => 7,
+        }
+    }
+}
+
+/// What's known about a file. Times are in nanoseconds since the epoch.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FileStat {
+    pub file_type: FileType,
+    pub inode: u64,
+    pub size: u64,
+    pub accessed: u64,
+    pub modified: u64,
+    pub changed: u64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DirEntry {
+    pub name: String,
+    pub file_type: FileType,
+    pub inode: u64,
+}
+
+/// Somewhere a program's files can live, which a directory it's given is
+/// the root of. Paths are relative to that root, separated by `/` and
+/// already free of `.` and `..`, with the root itself being `""`.
+pub trait FileSystem: Send {
+    /// Follows symbolic links if the file system has them.
+    fn stat(&mut self, path: &str) -> Result<FileStat, Errno>;
+    /// Reads from `offset` into `buf`, returning how much was read, which is
+    /// only less than `buf` at the end of the file.
+    fn read(&mut self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, Errno>;
+    /// Writes `data` at `offset`, filling any gap past the end with zeros.
+    fn write(&mut self, path: &str, offset: u64, data: &[u8]) -> Result<usize, Errno>;
+    /// Creates an empty file, failing if anything is already there.
+    fn create_file(&mut self, path: &str) -> Result<(), Errno>;
+    fn set_size(&mut self, path: &str, size: u64) -> Result<(), Errno>;
+    /// Everything in a directory apart from `.` and `..`.
+    fn read_dir(&mut self, path: &str) -> Result<Vec<DirEntry>, Errno>;
+    fn create_dir(&mut self, path: &str) -> Result<(), Errno>;
+    /// Removes a directory, which has to be empty.
+    fn remove_dir(&mut self, path: &str) -> Result<(), Errno>;
+    fn remove_file(&mut self, path: &str) -> Result<(), Errno>;
+    /// Moves a file or directory, replacing a file already at `to`.
+    fn rename(&mut self, from: &str, to: &str) -> Result<(), Errno>;
+
+    /// Sets the times that are given and leaves the others alone.
+    fn set_times(
+        &mut self,
+        _path: &str,
+        _accessed: Option<u64>,
+        _modified: Option<u64>,
+    ) -> Result<(), Errno> {
+        Err(NOTSUP)
+    }
+
+    fn hard_link(&mut self, _from: &str, _to: &str) -> Result<(), Errno> {
+        Err(NOTSUP)
+    }
+
+    /// Creates a link at `path` holding `target` exactly as it's given.
+    fn symlink(&mut self, _target: &str, _path: &str) -> Result<(), Errno> {
+        Err(NOTSUP)
+    }
+
+    fn read_link(&mut self, _path: &str) -> Result<String, Errno> {
+        Err(NOTSUP)
+    }
+}
+
+#[derive(Debug)]
+enum Contents {
+    File(Vec<u8>),
+    Directory,
+    Link(String),
+}
+
+#[derive(Debug)]
+struct Node {
+    contents: Contents,
+    inode: u64,
+    accessed: u64,
+    modified: u64,
+}
+
+#[derive(Debug)]
+struct Nodes {
+    nodes: BTreeMap<String, Node>,
+    next_inode: u64,
+}
+
+/// A file system that's kept in memory. Clones share their files, so one
+/// can be handed to a program while another is kept to look at what it
+/// did. Symbolic links can be made and read, but aren't followed, and
+/// times only change when they're set. Files can't grow past a maximum
+/// size, a gibibyte unless it's set, so a program can't make the host run
+/// out of memory.
+#[derive(Clone, Debug)]
+pub struct MemoryFileSystem {
+    nodes: Arc<Mutex<Nodes>>,
+    max_file_size: usize,
+}
+
+impl Default for MemoryFileSystem {
+    fn default() -> Self {
+        let mut nodes = BTreeMap::new();
+        nodes.insert(
+            String::new(),
+            Node {
+                contents: Contents::Directory,
+                inode: 1,
+                accessed: 0,
+                modified: 0,
+            },
+        );
+        MemoryFileSystem {
+            nodes: Arc::new(Mutex::new(Nodes {
+                nodes,
+                next_inode: 2,
+            })),
+            max_file_size: 1 << 30,
+        }
+    }
+}
+
+fn parent(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(i) => &path[..i],
+        None => "",
+    }
+}
+
+fn within(dir: &str, path: &str) -> bool {
+    dir.is_empty() && !path.is_empty()
+        || path.len() > dir.len() && path.starts_with(dir) && path.as_bytes()[dir.len()] == b'/'
+}
+
+impl Nodes {
+    fn get(&self, path: &str) -> Result<&Node, Errno> {
+        self.nodes.get(path).ok_or(NOENT)
+    }
+
+    fn file(&mut self, path: &str) -> Result<&mut Vec<u8>, Errno> {
+        match &mut self.nodes.get_mut(path).ok_or(NOENT)?.contents {
+            Contents::File(data) => Ok(data),
+            Contents::Directory => Err(ISDIR),
+            Contents::Link(_) => Err(INVAL),
+        }
+    }
+
+    // adds a node where there's nothing yet, inside a directory that exists
+    fn insert(&mut self, path: &str, contents: Contents) -> Result<(), Errno> {
+        if self.nodes.contains_key(path) {
+            return Err(EXIST);
+        }
+        match self.get(parent(path))?.contents {
+            Contents::Directory => {}
+            _ => return Err(NOTDIR),
+        }
+        let inode = self.next_inode;
+        self.next_inode += 1;
+        self.nodes.insert(
+            path.to_string(),
+            Node {
+                contents,
+                inode,
+                accessed: 0,
+                modified: 0,
+            },
+        );
+        Ok(())
+    }
+
+    fn is_empty_dir(&self, path: &str) -> bool {
+        !self.nodes.keys().any(|k| within(path, k))
+    }
+}
+
+impl MemoryFileSystem {
+    pub fn new() -> Self {
+        MemoryFileSystem::default()
+    }
+
+    /// Sets how big, in bytes, a program can make a file, with writes and
+    /// resizes past it failing with `FBIG`.
+    pub fn with_max_file_size(mut self, size: usize) -> Self {
+        self.max_file_size = size;
+        self
+    }
+
+    // a size as one files can be, if they're allowed to be that big
+    fn allowed(&self, size: u64) -> Result<usize, Errno> {
+        match usize::try_from(size) {
+            Ok(size) if size <= self.max_file_size => Ok(size),
+            _ => Err(FBIG),
+        }
+    }
+
+    /// Puts a file at `path` with the given contents, creating the
+    /// directories it's in as needed.
+    pub fn insert_file(&self, path: &str, contents: &[u8]) -> Result<(), Errno> {
+        let mut nodes = self.nodes.lock();
+        let mut dir = 0;
+        while let Some(i) = path[dir..].find('/') {
+            dir += i;
+            match nodes.insert(&path[..dir], Contents::Directory) {
+                Ok(()) | Err(EXIST) => {}
+                Err(e) => return Err(e),
+            }
+            dir += 1;
+        }
+        match nodes.insert(path, Contents::File(contents.to_vec())) {
+            Err(EXIST) => {
+                *nodes.file(path)? = contents.to_vec();
+                Ok(())
+            }
+            r => r,
+        }
+    }
+
+    /// The contents of the file at `path`.
+    pub fn contents(&self, path: &str) -> Option<Vec<u8>> {
+        match &self.nodes.lock().nodes.get(path)?.contents {
+            Contents::File(data) => Some(data.clone()),
+            _ => None,
+        }
+    }
+}
+
+impl FileSystem for MemoryFileSystem {
+    fn stat(&mut self, path: &str) -> Result<FileStat, Errno> {
+        let nodes = self.nodes.lock();
+        let node = nodes.get(path)?;
+        let (file_type, size) = match &node.contents {
+            Contents::File(data) => (FileType::RegularFile, data.len() as u64),
+            Contents::Directory => (FileType::Directory, 0),
+            Contents::Link(target) => (FileType::SymbolicLink, target.len() as u64),
+        };
+        Ok(FileStat {
+            file_type,
+            inode: node.inode,
+            size,
+            accessed: node.accessed,
+            modified: node.modified,
+            changed: node.modified,
+        })
+    }
+
+    fn read(&mut self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, Errno> {
+        let mut nodes = self.nodes.lock();
+        let data = nodes.file(path)?;
+        let start = usize::try_from(offset).map_or(data.len(), |o| o.min(data.len()));
+        let n = buf.len().min(data.len() - start);
+        buf[..n].copy_from_slice(&data[start..start + n]);
+        Ok(n)
+    }
+
+    fn write(&mut self, path: &str, offset: u64, data: &[u8]) -> Result<usize, Errno> {
+        let end = offset.checked_add(data.len() as u64).ok_or(FBIG)?;
+        let end = self.allowed(end)?;
+        let start = end - data.len();
+        let mut nodes = self.nodes.lock();
+        let file = nodes.file(path)?;
+        if file.len() < end {
+            file.resize(end, 0);
+        }
+        file[start..end].copy_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn create_file(&mut self, path: &str) -> Result<(), Errno> {
+        self.nodes.lock().insert(path, Contents::File(vec![]))
+    }
+
+    fn set_size(&mut self, path: &str, size: u64) -> Result<(), Errno> {
+        let size = self.allowed(size)?;
+        self.nodes.lock().file(path)?.resize(size, 0);
+        Ok(())
+    }
+
+    fn read_dir(&mut self, path: &str) -> Result<Vec<DirEntry>, Errno> {
+        let nodes = self.nodes.lock();
+        match nodes.get(path)?.contents {
+            Contents::Directory => {}
+            _ => return Err(NOTDIR),
+        }
+        Ok(nodes
+            .nodes
+            .iter()
+            .filter(|(k, _)| within(path, k) && parent(k) == path)
+            .map(|(k, node)| DirEntry {
+                name: k.rsplit('/').next().unwrap_or("").to_string(),
+                file_type: match node.contents {
+                    Contents::File(_) => FileType::RegularFile,
+                    Contents::Directory => FileType::Directory,
+                    Contents::Link(_) => FileType::SymbolicLink,
+                },
+                inode: node.inode,
+            })
+            .collect())
+    }
+
+    fn create_dir(&mut self, path: &str) -> Result<(), Errno> {
+        self.nodes.lock().insert(path, Contents::Directory)
+    }
+
+    fn remove_dir(&mut self, path: &str) -> Result<(), Errno> {
+        let mut nodes = self.nodes.lock();
+        match nodes.get(path)?.contents {
+            Contents::Directory => {}
+            _ => return Err(NOTDIR),
+        }
+        if path.is_empty() {
+            return Err(BUSY);
+        }
+        if !nodes.is_empty_dir(path) {
+            return Err(NOTEMPTY);
+        }
+        nodes.nodes.remove(path);
+        Ok(())
+    }
+
+    fn remove_file(&mut self, path: &str) -> Result<(), Errno> {
+        let mut nodes = self.nodes.lock();
+        if let Contents::Directory = nodes.get(path)?.contents {
+            return Err(ISDIR);
+        }
+        nodes.nodes.remove(path);
+        Ok(())
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> Result<(), Errno> {
+        let mut nodes = self.nodes.lock();
+        let moving_dir = matches!(nodes.get(from)?.contents, Contents::Directory);
+        if from == to {
+            return Ok(());
+        }
+        if from.is_empty() || within(from, to) {
+            return Err(INVAL);
+        }
+        match nodes.nodes.get(to).map(|n| &n.contents) {
+            Some(Contents::Directory) if !moving_dir => return Err(ISDIR),
+            Some(Contents::Directory) if !nodes.is_empty_dir(to) => return Err(NOTEMPTY),
+            Some(Contents::Directory) => {}
+            Some(_) if moving_dir => return Err(NOTDIR),
+            Some(_) => {}
+            None => match nodes.get(parent(to))?.contents {
+                Contents::Directory => {}
+                _ => return Err(NOTDIR),
+            },
+        }
+        let moved: Vec<String> = nodes
+            .nodes
+            .keys()
+            .filter(|k| *k == from || within(from, k))
+            .cloned()
+            .collect();
+        for old in moved {
+            let node = nodes.nodes.remove(&old).unwrap();
+            let new = format!("{}{}", to, &old[from.len()..]);
+            nodes.nodes.insert(new, node);
+        }
+        Ok(())
+    }
+
+    fn set_times(
+        &mut self,
+        path: &str,
+        accessed: Option<u64>,
+        modified: Option<u64>,
+    ) -> Result<(), Errno> {
+        let mut nodes = self.nodes.lock();
+        let node = nodes.nodes.get_mut(path).ok_or(NOENT)?;
+        if let Some(t) = accessed {
+            node.accessed = t;
+        }
+        if let Some(t) = modified {
+            node.modified = t;
+        }
+        Ok(())
+    }
+
+    fn symlink(&mut self, target: &str, path: &str) -> Result<(), Errno> {
+        self.nodes
+            .lock()
+            .insert(path, Contents::Link(target.to_string()))
+    }
+
+    fn read_link(&mut self, path: &str) -> Result<String, Errno> {
+        match &self.nodes.lock().get(path)?.contents {
+            Contents::Link(target) => Ok(target.clone()),
+            _ => Err(INVAL),
+        }
+    }
+}
@@ -0,0 +1,223 @@
+use super::errno::*;
+use super::{DirEntry, Errno, FileStat, FileSystem, FileType};
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use std::fs::{self, File, FileTimes, Metadata, OpenOptions};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A directory on the host. Paths can't lead out of it, whether with `..`
+/// or through symbolic links, which are only followed while they stay
+/// inside.
+#[derive(Clone, Debug)]
+pub struct HostDirectory {
+    root: PathBuf,
+}
+
+impl HostDirectory {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        HostDirectory { root: root.into() }
+    }
+
+    // where a path leads on the host, refusing any that goes outside the
+    // directory. the last component isn't followed if it's a link, since
+    // it may be the link that's being read, renamed or removed
+    fn path(&self, path: &str) -> Result<PathBuf, Errno> {
+        let root = self.root.canonicalize().map_err(errno)?;
+        let full = root.join(path);
+        match (full.parent(), full.file_name()) {
+            (Some(parent), Some(name)) if full != root => {
+                let parent = inside(&root, parent.canonicalize().map_err(errno)?)?;
+                Ok(parent.join(name))
+            }
+            _ => inside(&root, full.canonicalize().map_err(errno)?),
+        }
+    }
+
+    // like `path`, except a link at the end is followed too
+    fn followed(&self, path: &str) -> Result<PathBuf, Errno> {
+        let path = self.path(path)?;
+        match fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                let root = self.root.canonicalize().map_err(errno)?;
+                inside(&root, path.canonicalize().map_err(errno)?)
+            }
+            _ => Ok(path),
+        }
+    }
+}
+
+fn inside(root: &Path, path: PathBuf) -> Result<PathBuf, Errno> {
+    if path.starts_with(root) {
+        Ok(path)
+    } else {
+        Err(NOTCAPABLE)
+    }
+}
+
+fn errno(e: io::Error) -> Errno {
+    match e.kind() {
+        ErrorKind::NotFound => NOENT,
+        ErrorKind::PermissionDenied => ACCES,
+        ErrorKind::AlreadyExists => EXIST,
+        ErrorKind::InvalidInput => INVAL,
+        ErrorKind::Unsupported => NOTSUP,
+        ErrorKind::IsADirectory => ISDIR,
+        ErrorKind::NotADirectory => NOTDIR,
+        ErrorKind::DirectoryNotEmpty => NOTEMPTY,
+        ErrorKind::StorageFull => NOSPC,
+        ErrorKind::CrossesDevices => XDEV,
+        _ => IO,
+    }
+}
+
+fn nanoseconds(time: io::Result<SystemTime>) -> u64 {
+    time.ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+fn file_type(metadata: &Metadata) -> FileType {
+    if metadata.is_dir() {
+        FileType::Directory
+    } else if metadata.file_type().is_symlink() {
+        FileType::SymbolicLink
+    } else {
+        FileType::RegularFile
+    }
+}
+
+#[cfg(unix)]
+fn inode(metadata: &Metadata) -> u64 {
+    std::os::unix::fs::MetadataExt::ino(metadata)
+}
+
+#[cfg(not(unix))]
+fn inode(_: &Metadata) -> u64 {
+    0
+}
+
+impl FileSystem for HostDirectory {
+    fn stat(&mut self, path: &str) -> Result<FileStat, Errno> {
+        let metadata = fs::metadata(self.followed(path)?).map_err(errno)?;
+        Ok(FileStat {
+            file_type: file_type(&metadata),
+            inode: inode(&metadata),
+            size: metadata.len(),
+            accessed: nanoseconds(metadata.accessed()),
+            modified: nanoseconds(metadata.modified()),
+            changed: nanoseconds(metadata.modified()),
+        })
+    }
+
+    fn read(&mut self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, Errno> {
+        let mut file = File::open(self.followed(path)?).map_err(errno)?;
+        file.seek(SeekFrom::Start(offset)).map_err(errno)?;
+        let mut n = 0;
+        while n < buf.len() {
+            match file.read(&mut buf[n..]).map_err(errno)? {
+                0 => break,
+                read => n += read,
+            }
+        }
+        Ok(n)
+    }
+
+    fn write(&mut self, path: &str, offset: u64, data: &[u8]) -> Result<usize, Errno> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(self.followed(path)?)
+            .map_err(errno)?;
+        file.seek(SeekFrom::Start(offset)).map_err(errno)?;
+        file.write_all(data).map_err(errno)?;
+        Ok(data.len())
+    }
+
+    fn create_file(&mut self, path: &str) -> Result<(), Errno> {
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(self.followed(path)?)
+            .map(|_| ())
+            .map_err(errno)
+    }
+
+    fn set_size(&mut self, path: &str, size: u64) -> Result<(), Errno> {
+        let file = OpenOptions::new()
+            .write(true)
+            .open(self.followed(path)?)
+            .map_err(errno)?;
+        file.set_len(size).map_err(errno)
+    }
+
+    fn read_dir(&mut self, path: &str) -> Result<Vec<DirEntry>, Errno> {
+        let mut entries = vec![];
+        for entry in fs::read_dir(self.followed(path)?).map_err(errno)? {
+            let entry = entry.map_err(errno)?;
+            let metadata = entry.metadata().map_err(errno)?;
+            entries.push(DirEntry {
+                name: entry.file_name().to_string_lossy().to_string(),
+                file_type: file_type(&metadata),
+                inode: inode(&metadata),
+            });
+        }
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(entries)
+    }
+
+    fn create_dir(&mut self, path: &str) -> Result<(), Errno> {
+        fs::create_dir(self.path(path)?).map_err(errno)
+    }
+
+    fn remove_dir(&mut self, path: &str) -> Result<(), Errno> {
+        if path.is_empty() {
+            return Err(BUSY);
+        }
+        fs::remove_dir(self.path(path)?).map_err(errno)
+    }
+
+    fn remove_file(&mut self, path: &str) -> Result<(), Errno> {
+        let path = self.path(path)?;
+        if fs::symlink_metadata(&path).map_err(errno)?.is_dir() {
+            return Err(ISDIR);
+        }
+        fs::remove_file(path).map_err(errno)
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> Result<(), Errno> {
+        fs::rename(self.path(from)?, self.path(to)?).map_err(errno)
+    }
+
+    fn set_times(
+        &mut self,
+        path: &str,
+        accessed: Option<u64>,
+        modified: Option<u64>,
+    ) -> Result<(), Errno> {
+        let mut times = FileTimes::new();
+        if let Some(t) = accessed {
+            times = times.set_accessed(UNIX_EPOCH + Duration::from_nanos(t));
+        }
+        if let Some(t) = modified {
+            times = times.set_modified(UNIX_EPOCH + Duration::from_nanos(t));
+        }
+        let file = File::open(self.followed(path)?).map_err(errno)?;
+        file.set_times(times).map_err(errno)
+    }
+
+    fn hard_link(&mut self, from: &str, to: &str) -> Result<(), Errno> {
+        fs::hard_link(self.path(from)?, self.path(to)?).map_err(errno)
+    }
+
+    #[cfg(unix)]
+    fn symlink(&mut self, target: &str, path: &str) -> Result<(), Errno> {
+        std::os::unix::fs::symlink(target, self.path(path)?).map_err(errno)
+    }
+
+    fn read_link(&mut self, path: &str) -> Result<String, Errno> {
+        let target = fs::read_link(self.path(path)?).map_err(errno)?;
+        Ok(target.to_string_lossy().to_string())
+    }
+}
@@ -0,0 +1,1027 @@
+// the calls take what the interface passes them, however much that is
+#![allow(clippy::too_many_arguments)]
+
+use super::errno::*;
+use super::filesystem::{FileStat, FileSystem, FileType};
+use super::Errno;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use core::str;
+
+const RIGHTS_ALL: u64 = (1 << 30) - 1;
+const RIGHT_FD_SEEK: u64 = 1 << 2;
+const RIGHT_FD_TELL: u64 = 1 << 5;
+const CHARACTER_DEVICE: u8 = 2;
+const FDFLAGS_APPEND: u16 = 1;
+const OFLAGS_CREAT: u16 = 1;
+const OFLAGS_DIRECTORY: u16 = 2;
+const OFLAGS_EXCL: u16 = 4;
+const OFLAGS_TRUNC: u16 = 8;
+const FSTFLAGS_ATIM: u16 = 1;
+const FSTFLAGS_ATIM_NOW: u16 = 2;
+const FSTFLAGS_MTIM: u16 = 4;
+const FSTFLAGS_MTIM_NOW: u16 = 8;
+const SUBSCRIPTION_CLOCK_ABSTIME: u16 = 1;
+const EVENTTYPE_CLOCK: u8 = 0;
+const EVENTTYPE_FD_READ: u8 = 1;
+const EVENTTYPE_FD_WRITE: u8 = 2;
+
+pub(crate) enum Descriptor {
+    Stdin,
+    Stdout,
+    Stderr,
+    Directory {
+        fs: usize,
+        path: String,
+        preopen: Option<String>,
+    },
+    File {
+        fs: usize,
+        path: String,
+        offset: u64,
+        append: bool,
+    },
+}
+
+/// Where a program gets the time from.
+pub(crate) enum Clock {
+    /// Starts at 0 and only moves on when the program waits.
+    Virtual(u64),
+    Host(Box<dyn FnMut(u32) -> u64 + Send>),
+    #[cfg(feature = "std")]
+    System(std::time::Instant),
+}
+
+/// Everything a program sees of the system it's running on.
+pub(crate) struct State {
+    pub args: Vec<String>,
+    pub env: Vec<String>,
+    pub filesystems: Vec<Box<dyn FileSystem>>,
+    pub fds: Vec<Option<Descriptor>>,
+    pub stdin: Vec<u8>,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    #[cfg(feature = "std")]
+    pub inherit_stdio: bool,
+    pub clock: Clock,
+    pub random: u64,
+    pub exit_code: Option<u32>,
+}
+
+fn bytes(memory: &[u8], ptr: u32, len: u32) -> Result<&[u8], Errno> {
+    let start = ptr as usize;
+    let end = start.checked_add(len as usize).ok_or(FAULT)?;
+    memory.get(start..end).ok_or(FAULT)
+}
+
+fn bytes_mut(memory: &mut [u8], ptr: u32, len: u32) -> Result<&mut [u8], Errno> {
+    let start = ptr as usize;
+    let end = start.checked_add(len as usize).ok_or(FAULT)?;
+    memory.get_mut(start..end).ok_or(FAULT)
+}
+
+fn load_u16(memory: &[u8], ptr: u32) -> Result<u16, Errno> {
+    Ok(u16::from_le_bytes(
+        bytes(memory, ptr, 2)?.try_into().unwrap(),
+    ))
+}
+
+fn load_u32(memory: &[u8], ptr: u32) -> Result<u32, Errno> {
+    Ok(u32::from_le_bytes(
+        bytes(memory, ptr, 4)?.try_into().unwrap(),
+    ))
+}
+
+fn load_u64(memory: &[u8], ptr: u32) -> Result<u64, Errno> {
+    Ok(u64::from_le_bytes(
+        bytes(memory, ptr, 8)?.try_into().unwrap(),
+    ))
+}
+
+fn store(memory: &mut [u8], ptr: u32, data: &[u8]) -> Result<(), Errno> {
+    bytes_mut(memory, ptr, data.len() as u32)?.copy_from_slice(data);
+    Ok(())
+}
+
+fn store_u32(memory: &mut [u8], ptr: u32, value: u32) -> Result<(), Errno> {
+    store(memory, ptr, &value.to_le_bytes())
+}
+
+fn store_u64(memory: &mut [u8], ptr: u32, value: u64) -> Result<(), Errno> {
+    store(memory, ptr, &value.to_le_bytes())
+}
+
+fn string(memory: &[u8], ptr: u32, len: u32) -> Result<&str, Errno> {
+    str::from_utf8(bytes(memory, ptr, len)?).map_err(|_| ILSEQ)
+}
+
+// the buffers an iovec array points at, as pointers and lengths
+fn iovecs(memory: &[u8], ptr: u32, len: u32) -> Result<Vec<(u32, u32)>, Errno> {
+    (0..len)
+        .map(|i| {
+            let iovec = ptr
+                .checked_add(i.checked_mul(8).ok_or(FAULT)?)
+                .ok_or(FAULT)?;
+            Ok((load_u32(memory, iovec)?, load_u32(memory, iovec + 4)?))
+        })
+        .collect()
+}
+
+// writes a list of strings out the way argv and environ are laid out
+fn store_strings(
+    memory: &mut [u8],
+    strings: &[String],
+    pointers: u32,
+    mut buf: u32,
+) -> Result<(), Errno> {
+    for (i, s) in strings.iter().enumerate() {
+        store_u32(memory, pointers + 4 * i as u32, buf)?;
+        store(memory, buf, s.as_bytes())?;
+        store(memory, buf + s.len() as u32, &[0])?;
+        buf += s.len() as u32 + 1;
+    }
+    Ok(())
+}
+
+fn store_filestat(
+    memory: &mut [u8],
+    ptr: u32,
+    file_type: u8,
+    stat: Option<&FileStat>,
+) -> Result<(), Errno> {
+    let mut out = [0; 64];
+    out[16] = file_type;
+    if let Some(stat) = stat {
+        out[8..16].copy_from_slice(&stat.inode.to_le_bytes());
+        out[24..32].copy_from_slice(&1u64.to_le_bytes());
+        out[32..40].copy_from_slice(&stat.size.to_le_bytes());
+        out[40..48].copy_from_slice(&stat.accessed.to_le_bytes());
+        out[48..56].copy_from_slice(&stat.modified.to_le_bytes());
+        out[56..64].copy_from_slice(&stat.changed.to_le_bytes());
+    }
+    store(memory, ptr, &out)
+}
+
+fn descriptor(fds: &mut [Option<Descriptor>], fd: u32) -> Result<&mut Descriptor, Errno> {
+    fds.get_mut(fd as usize)
+        .and_then(|d| d.as_mut())
+        .ok_or(BADF)
+}
+
+/// Joins a path a program gave onto the directory it's relative to, refusing
+/// any that would lead outside the file system.
+pub(crate) fn join(base: &str, path: &str) -> Result<String, Errno> {
+    if path.starts_with('/') {
+        return Err(NOTCAPABLE);
+    }
+    let mut parts: Vec<&str> = base.split('/').filter(|p| !p.is_empty()).collect();
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop().ok_or(NOTCAPABLE)?;
+            }
+            p => parts.push(p),
+        }
+    }
+    Ok(parts.join("/"))
+}
+
+impl State {
+    pub fn now(&mut self, clock_id: u32) -> u64 {
+        match &mut self.clock {
+            Clock::Virtual(now) => *now,
+            Clock::Host(f) => f(clock_id),
+            #[cfg(feature = "std")]
+            Clock::System(start) => match clock_id {
+                0 => std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map(|d| d.as_nanos() as u64)
+                    .unwrap_or(0),
+                _ => start.elapsed().as_nanos() as u64,
+            },
+        }
+    }
+
+    // lets `nanoseconds` pass, as far as the program can tell
+    fn wait(&mut self, nanoseconds: u64) {
+        match &mut self.clock {
+            Clock::Virtual(now) => *now = now.saturating_add(nanoseconds),
+            Clock::Host(_) => {}
+            #[cfg(feature = "std")]
+            Clock::System(_) => std::thread::sleep(std::time::Duration::from_nanos(nanoseconds)),
+        }
+    }
+
+    fn next_random(&mut self) -> u64 {
+        // xorshift64*
+        let mut x = self.random;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.random = x;
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    // puts a descriptor in the lowest free slot
+    fn allocate(&mut self, d: Descriptor) -> u32 {
+        match self.fds.iter().position(|d| d.is_none()) {
+            Some(fd) => {
+                self.fds[fd] = Some(d);
+                fd as u32
+            }
+            None => {
+                self.fds.push(Some(d));
+                self.fds.len() as u32 - 1
+            }
+        }
+    }
+
+    // the file system and path of a path given relative to a directory
+    fn resolve(
+        &mut self,
+        memory: &[u8],
+        fd: u32,
+        path: u32,
+        len: u32,
+    ) -> Result<(usize, String), Errno> {
+        match descriptor(&mut self.fds, fd)? {
+            Descriptor::Directory { fs, path: base, .. } => {
+                Ok((*fs, join(base, string(memory, path, len)?)?))
+            }
+            _ => Err(NOTDIR),
+        }
+    }
+
+    // the file system and path of an open file or directory
+    fn opened(&mut self, fd: u32) -> Result<(usize, String), Errno> {
+        match descriptor(&mut self.fds, fd)? {
+            Descriptor::Directory { fs, path, .. } | Descriptor::File { fs, path, .. } => {
+                Ok((*fs, path.clone()))
+            }
+            _ => Err(BADF),
+        }
+    }
+
+    fn times(
+        &mut self,
+        accessed: u64,
+        modified: u64,
+        flags: u16,
+    ) -> Result<(Option<u64>, Option<u64>), Errno> {
+        if flags & FSTFLAGS_ATIM != 0 && flags & FSTFLAGS_ATIM_NOW != 0
+            || flags & FSTFLAGS_MTIM != 0 && flags & FSTFLAGS_MTIM_NOW != 0
+        {
+            return Err(INVAL);
+        }
+        let now = self.now(0);
+        let pick = |set, set_now, value| {
+            if flags & set != 0 {
+                Some(value)
+            } else if flags & set_now != 0 {
+                Some(now)
+            } else {
+                None
+            }
+        };
+        Ok((
+            pick(FSTFLAGS_ATIM, FSTFLAGS_ATIM_NOW, accessed),
+            pick(FSTFLAGS_MTIM, FSTFLAGS_MTIM_NOW, modified),
+        ))
+    }
+
+    // reads from a descriptor, at its own offset unless one's given
+    fn read_into(&mut self, fd: u32, out: &mut [u8], at: Option<u64>) -> Result<usize, Errno> {
+        match descriptor(&mut self.fds, fd)? {
+            Descriptor::Stdin if at.is_some() => Err(SPIPE),
+            Descriptor::Stdin => {
+                #[cfg(feature = "std")]
+                {
+                    if self.inherit_stdio {
+                        use std::io::Read;
+                        return std::io::stdin().read(out).map_err(|_| IO);
+                    }
+                }
+                let n = out.len().min(self.stdin.len());
+                out[..n].copy_from_slice(&self.stdin[..n]);
+                self.stdin.drain(..n);
+                Ok(n)
+            }
+            Descriptor::File {
+                fs, path, offset, ..
+            } => {
+                let n = self.filesystems[*fs].read(path, at.unwrap_or(*offset), out)?;
+                if at.is_none() {
+                    *offset += n as u64;
+                }
+                Ok(n)
+            }
+            Descriptor::Directory { .. } => Err(ISDIR),
+            _ => Err(BADF),
+        }
+    }
+
+    // writes to a descriptor, at its own offset unless one's given
+    fn write_from(&mut self, fd: u32, data: &[u8], at: Option<u64>) -> Result<usize, Errno> {
+        let d = descriptor(&mut self.fds, fd)?;
+        let stdout = matches!(d, Descriptor::Stdout);
+        match d {
+            Descriptor::Stdout | Descriptor::Stderr if at.is_some() => Err(SPIPE),
+            Descriptor::Stdout | Descriptor::Stderr => {
+                #[cfg(feature = "std")]
+                {
+                    if self.inherit_stdio {
+                        use std::io::Write;
+                        let written = if stdout {
+                            std::io::stdout().write_all(data)
+                        } else {
+                            std::io::stderr().write_all(data)
+                        };
+                        return written.map(|_| data.len()).map_err(|_| IO);
+                    }
+                }
+                if stdout {
+                    self.stdout.extend_from_slice(data);
+                } else {
+                    self.stderr.extend_from_slice(data);
+                }
+                Ok(data.len())
+            }
+            Descriptor::File {
+                fs,
+                path,
+                offset,
+                append,
+            } => {
+                let fs = &mut self.filesystems[*fs];
+                let position = match at {
+                    Some(at) => at,
+                    None if *append => fs.stat(path)?.size,
+                    None => *offset,
+                };
+                let n = fs.write(path, position, data)?;
+                if at.is_none() {
+                    *offset = position + n as u64;
+                }
+                Ok(n)
+            }
+            Descriptor::Directory { .. } => Err(ISDIR),
+            _ => Err(BADF),
+        }
+    }
+
+    fn read_vectored(
+        &mut self,
+        memory: &mut [u8],
+        fd: u32,
+        iovs: u32,
+        iovs_len: u32,
+        at: Option<u64>,
+    ) -> Result<u32, Errno> {
+        let mut total = 0;
+        for (buf, len) in iovecs(memory, iovs, iovs_len)? {
+            let out = bytes_mut(memory, buf, len)?;
+            let n = self.read_into(fd, out, at.map(|at| at + total as u64))?;
+            total += n as u32;
+            if n < len as usize {
+                break;
+            }
+        }
+        Ok(total)
+    }
+
+    fn write_vectored(
+        &mut self,
+        memory: &[u8],
+        fd: u32,
+        iovs: u32,
+        iovs_len: u32,
+        at: Option<u64>,
+    ) -> Result<u32, Errno> {
+        let mut total = 0;
+        for (buf, len) in iovecs(memory, iovs, iovs_len)? {
+            let data = bytes(memory, buf, len)?;
+            total += self.write_from(fd, data, at.map(|at| at + total as u64))? as u32;
+        }
+        Ok(total)
+    }
+
+    pub fn args_get(&mut self, memory: &mut [u8], argv: u32, buf: u32) -> Result<(), Errno> {
+        store_strings(memory, &self.args, argv, buf)
+    }
+
+    pub fn args_sizes_get(&mut self, memory: &mut [u8], argc: u32, size: u32) -> Result<(), Errno> {
+        store_u32(memory, argc, self.args.len() as u32)?;
+        let total: usize = self.args.iter().map(|a| a.len() + 1).sum();
+        store_u32(memory, size, total as u32)
+    }
+
+    pub fn environ_get(&mut self, memory: &mut [u8], environ: u32, buf: u32) -> Result<(), Errno> {
+        store_strings(memory, &self.env, environ, buf)
+    }
+
+    pub fn environ_sizes_get(
+        &mut self,
+        memory: &mut [u8],
+        count: u32,
+        size: u32,
+    ) -> Result<(), Errno> {
+        store_u32(memory, count, self.env.len() as u32)?;
+        let total: usize = self.env.iter().map(|e| e.len() + 1).sum();
+        store_u32(memory, size, total as u32)
+    }
+
+    pub fn clock_res_get(&mut self, memory: &mut [u8], id: u32, res: u32) -> Result<(), Errno> {
+        if id > 3 {
+            return Err(INVAL);
+        }
+        store_u64(memory, res, 1)
+    }
+
+    pub fn clock_time_get(
+        &mut self,
+        memory: &mut [u8],
+        id: u32,
+        _precision: u64,
+        time: u32,
+    ) -> Result<(), Errno> {
+        if id > 3 {
+            return Err(INVAL);
+        }
+        let now = self.now(id);
+        store_u64(memory, time, now)
+    }
+
+    pub fn fd_advise(
+        &mut self,
+        _memory: &mut [u8],
+        fd: u32,
+        _offset: u64,
+        _len: u64,
+        advice: u32,
+    ) -> Result<(), Errno> {
+        descriptor(&mut self.fds, fd)?;
+        if advice > 5 {
+            return Err(INVAL);
+        }
+        Ok(())
+    }
+
+    pub fn fd_allocate(
+        &mut self,
+        _memory: &mut [u8],
+        fd: u32,
+        offset: u64,
+        len: u64,
+    ) -> Result<(), Errno> {
+        match descriptor(&mut self.fds, fd)? {
+            Descriptor::File { fs, path, .. } => {
+                let fs = &mut self.filesystems[*fs];
+                let end = offset.checked_add(len).ok_or(FBIG)?;
+                if fs.stat(path)?.size < end {
+                    fs.set_size(path, end)?;
+                }
+                Ok(())
+            }
+            _ => Err(BADF),
+        }
+    }
+
+    pub fn fd_close(&mut self, _memory: &mut [u8], fd: u32) -> Result<(), Errno> {
+        self.fds
+            .get_mut(fd as usize)
+            .and_then(|d| d.take())
+            .map(|_| ())
+            .ok_or(BADF)
+    }
+
+    pub fn fd_datasync(&mut self, _memory: &mut [u8], fd: u32) -> Result<(), Errno> {
+        descriptor(&mut self.fds, fd).map(|_| ())
+    }
+
+    pub fn fd_fdstat_get(&mut self, memory: &mut [u8], fd: u32, stat: u32) -> Result<(), Errno> {
+        let (file_type, flags, base, inheriting) = match descriptor(&mut self.fds, fd)? {
+            Descriptor::Stdin | Descriptor::Stdout | Descriptor::Stderr => (
+                CHARACTER_DEVICE,
+                0,
+                RIGHTS_ALL & !(RIGHT_FD_SEEK | RIGHT_FD_TELL),
+                0,
+            ),
+            Descriptor::Directory { .. } => {
+                (FileType::Directory.to_wasi(), 0, RIGHTS_ALL, RIGHTS_ALL)
+            }
+            Descriptor::File { append, .. } => {
+                let flags = if *append { FDFLAGS_APPEND } else { 0 };
+                (FileType::RegularFile.to_wasi(), flags, RIGHTS_ALL, 0)
+            }
+        };
+        let mut out = [0; 24];
+        out[0] = file_type;
+        out[2..4].copy_from_slice(&flags.to_le_bytes());
+        out[8..16].copy_from_slice(&base.to_le_bytes());
+        out[16..24].copy_from_slice(&inheriting.to_le_bytes());
+        store(memory, stat, &out)
+    }
+
+    pub fn fd_fdstat_set_flags(
+        &mut self,
+        _memory: &mut [u8],
+        fd: u32,
+        flags: u32,
+    ) -> Result<(), Errno> {
+        if let Descriptor::File { append, .. } = descriptor(&mut self.fds, fd)? {
+            *append = flags as u16 & FDFLAGS_APPEND != 0;
+        }
+        Ok(())
+    }
+
+    pub fn fd_fdstat_set_rights(
+        &mut self,
+        _memory: &mut [u8],
+        fd: u32,
+        _base: u64,
+        _inheriting: u64,
+    ) -> Result<(), Errno> {
+        descriptor(&mut self.fds, fd).map(|_| ())
+    }
+
+    pub fn fd_filestat_get(&mut self, memory: &mut [u8], fd: u32, buf: u32) -> Result<(), Errno> {
+        let (fs, path) = match descriptor(&mut self.fds, fd)? {
+            Descriptor::Stdin | Descriptor::Stdout | Descriptor::Stderr => {
+                return store_filestat(memory, buf, CHARACTER_DEVICE, None)
+            }
+            _ => self.opened(fd)?,
+        };
+        let stat = self.filesystems[fs].stat(&path)?;
+        store_filestat(memory, buf, stat.file_type.to_wasi(), Some(&stat))
+    }
+
+    pub fn fd_filestat_set_size(
+        &mut self,
+        _memory: &mut [u8],
+        fd: u32,
+        size: u64,
+    ) -> Result<(), Errno> {
+        match descriptor(&mut self.fds, fd)? {
+            Descriptor::File { fs, path, .. } => self.filesystems[*fs].set_size(path, size),
+            Descriptor::Directory { .. } => Err(ISDIR),
+            _ => Err(BADF),
+        }
+    }
+
+    pub fn fd_filestat_set_times(
+        &mut self,
+        _memory: &mut [u8],
+        fd: u32,
+        accessed: u64,
+        modified: u64,
+        flags: u32,
+    ) -> Result<(), Errno> {
+        let (fs, path) = self.opened(fd)?;
+        let (accessed, modified) = self.times(accessed, modified, flags as u16)?;
+        self.filesystems[fs].set_times(&path, accessed, modified)
+    }
+
+    pub fn fd_pread(
+        &mut self,
+        memory: &mut [u8],
+        fd: u32,
+        iovs: u32,
+        iovs_len: u32,
+        offset: u64,
+        nread: u32,
+    ) -> Result<(), Errno> {
+        let n = self.read_vectored(memory, fd, iovs, iovs_len, Some(offset))?;
+        store_u32(memory, nread, n)
+    }
+
+    pub fn fd_prestat_get(&mut self, memory: &mut [u8], fd: u32, buf: u32) -> Result<(), Errno> {
+        match descriptor(&mut self.fds, fd)? {
+            Descriptor::Directory {
+                preopen: Some(name),
+                ..
+            } => {
+                let mut out = [0; 8];
+                out[4..8].copy_from_slice(&(name.len() as u32).to_le_bytes());
+                store(memory, buf, &out)
+            }
+            _ => Err(BADF),
+        }
+    }
+
+    pub fn fd_prestat_dir_name(
+        &mut self,
+        memory: &mut [u8],
+        fd: u32,
+        path: u32,
+        len: u32,
+    ) -> Result<(), Errno> {
+        match descriptor(&mut self.fds, fd)? {
+            Descriptor::Directory {
+                preopen: Some(name),
+                ..
+            } => {
+                if (len as usize) < name.len() {
+                    return Err(NAMETOOLONG);
+                }
+                store(memory, path, name.as_bytes())
+            }
+            _ => Err(BADF),
+        }
+    }
+
+    pub fn fd_pwrite(
+        &mut self,
+        memory: &mut [u8],
+        fd: u32,
+        iovs: u32,
+        iovs_len: u32,
+        offset: u64,
+        nwritten: u32,
+    ) -> Result<(), Errno> {
+        let n = self.write_vectored(memory, fd, iovs, iovs_len, Some(offset))?;
+        store_u32(memory, nwritten, n)
+    }
+
+    pub fn fd_read(
+        &mut self,
+        memory: &mut [u8],
+        fd: u32,
+        iovs: u32,
+        iovs_len: u32,
+        nread: u32,
+    ) -> Result<(), Errno> {
+        let n = self.read_vectored(memory, fd, iovs, iovs_len, None)?;
+        store_u32(memory, nread, n)
+    }
+
+    /// Lists a directory starting with `.` and `..`, where each entry's
+    /// cookie is its position in the list.
+    pub fn fd_readdir(
+        &mut self,
+        memory: &mut [u8],
+        fd: u32,
+        buf: u32,
+        buf_len: u32,
+        cookie: u64,
+        bufused: u32,
+    ) -> Result<(), Errno> {
+        let (fs, path) = match descriptor(&mut self.fds, fd)? {
+            Descriptor::Directory { fs, path, .. } => (*fs, path.clone()),
+            _ => return Err(NOTDIR),
+        };
+        let fs = &mut self.filesystems[fs];
+        let inode = fs.stat(&path)?.inode;
+        let mut entries = vec![(".".into(), FileType::Directory, inode)];
+        entries.push(("..".into(), FileType::Directory, inode));
+        for e in fs.read_dir(&path)? {
+            entries.push((e.name, e.file_type, e.inode));
+        }
+        let mut out = vec![];
+        for (i, (name, file_type, inode)) in entries.iter().enumerate().skip(cookie as usize) {
+            if out.len() >= buf_len as usize {
+                break;
+            }
+            out.extend_from_slice(&(i as u64 + 1).to_le_bytes());
+            out.extend_from_slice(&inode.to_le_bytes());
+            out.extend_from_slice(&(name.len() as u32).to_le_bytes());
+            out.extend_from_slice(&[file_type.to_wasi(), 0, 0, 0]);
+            out.extend_from_slice(name.as_bytes());
+        }
+        out.truncate(buf_len as usize);
+        store(memory, buf, &out)?;
+        store_u32(memory, bufused, out.len() as u32)
+    }
+
+    pub fn fd_renumber(&mut self, _memory: &mut [u8], fd: u32, to: u32) -> Result<(), Errno> {
+        descriptor(&mut self.fds, fd)?;
+        descriptor(&mut self.fds, to)?;
+        let moved = self.fds[fd as usize].take();
+        self.fds[to as usize] = moved;
+        Ok(())
+    }
+
+    pub fn fd_seek(
+        &mut self,
+        memory: &mut [u8],
+        fd: u32,
+        offset: i64,
+        whence: u32,
+        new_offset: u32,
+    ) -> Result<(), Errno> {
+        let position = match descriptor(&mut self.fds, fd)? {
+            Descriptor::File {
+                fs,
+                path,
+                offset: current,
+                ..
+            } => {
+                let base = match whence {
+                    0 => 0,
+                    1 => *current,
+                    2 => self.filesystems[*fs].stat(path)?.size,
+                    _ => return Err(INVAL),
+                };
+                let position = (base as i64).checked_add(offset).ok_or(INVAL)?;
+                if position < 0 {
+                    return Err(INVAL);
+                }
+                *current = position as u64;
+                position as u64
+            }
+            Descriptor::Directory { .. } => return Err(ISDIR),
+            _ => return Err(SPIPE),
+        };
+        store_u64(memory, new_offset, position)
+    }
+
+    pub fn fd_sync(&mut self, memory: &mut [u8], fd: u32) -> Result<(), Errno> {
+        self.fd_datasync(memory, fd)
+    }
+
+    pub fn fd_tell(&mut self, memory: &mut [u8], fd: u32, offset: u32) -> Result<(), Errno> {
+        self.fd_seek(memory, fd, 0, 1, offset)
+    }
+
+    pub fn fd_write(
+        &mut self,
+        memory: &mut [u8],
+        fd: u32,
+        iovs: u32,
+        iovs_len: u32,
+        nwritten: u32,
+    ) -> Result<(), Errno> {
+        let n = self.write_vectored(memory, fd, iovs, iovs_len, None)?;
+        store_u32(memory, nwritten, n)
+    }
+
+    pub fn path_create_directory(
+        &mut self,
+        memory: &mut [u8],
+        fd: u32,
+        path: u32,
+        len: u32,
+    ) -> Result<(), Errno> {
+        let (fs, path) = self.resolve(memory, fd, path, len)?;
+        self.filesystems[fs].create_dir(&path)
+    }
+
+    pub fn path_filestat_get(
+        &mut self,
+        memory: &mut [u8],
+        fd: u32,
+        _flags: u32,
+        path: u32,
+        len: u32,
+        buf: u32,
+    ) -> Result<(), Errno> {
+        let (fs, path) = self.resolve(memory, fd, path, len)?;
+        let stat = self.filesystems[fs].stat(&path)?;
+        store_filestat(memory, buf, stat.file_type.to_wasi(), Some(&stat))
+    }
+
+    pub fn path_filestat_set_times(
+        &mut self,
+        memory: &mut [u8],
+        fd: u32,
+        _flags: u32,
+        path: u32,
+        len: u32,
+        accessed: u64,
+        modified: u64,
+        fst_flags: u32,
+    ) -> Result<(), Errno> {
+        let (fs, path) = self.resolve(memory, fd, path, len)?;
+        let (accessed, modified) = self.times(accessed, modified, fst_flags as u16)?;
+        self.filesystems[fs].set_times(&path, accessed, modified)
+    }
+
+    pub fn path_link(
+        &mut self,
+        memory: &mut [u8],
+        old_fd: u32,
+        _flags: u32,
+        old_path: u32,
+        old_len: u32,
+        new_fd: u32,
+        new_path: u32,
+        new_len: u32,
+    ) -> Result<(), Errno> {
+        let (fs, from) = self.resolve(memory, old_fd, old_path, old_len)?;
+        let (to_fs, to) = self.resolve(memory, new_fd, new_path, new_len)?;
+        if fs != to_fs {
+            return Err(XDEV);
+        }
+        self.filesystems[fs].hard_link(&from, &to)
+    }
+
+    pub fn path_open(
+        &mut self,
+        memory: &mut [u8],
+        fd: u32,
+        _dirflags: u32,
+        path: u32,
+        len: u32,
+        oflags: u32,
+        _base: u64,
+        _inheriting: u64,
+        fdflags: u32,
+        opened: u32,
+    ) -> Result<(), Errno> {
+        let (fs_index, path) = self.resolve(memory, fd, path, len)?;
+        let (oflags, fdflags) = (oflags as u16, fdflags as u16);
+        let fs = &mut self.filesystems[fs_index];
+        if oflags & OFLAGS_CREAT != 0 {
+            match fs.create_file(&path) {
+                Err(EXIST) if oflags & OFLAGS_EXCL == 0 => {}
+                r => r?,
+            }
+        }
+        let d = match fs.stat(&path)?.file_type {
+            FileType::Directory if oflags & OFLAGS_TRUNC != 0 => return Err(ISDIR),
+            FileType::Directory => Descriptor::Directory {
+                fs: fs_index,
+                path,
+                preopen: None,
+            },
+            _ if oflags & OFLAGS_DIRECTORY != 0 => return Err(NOTDIR),
+            FileType::SymbolicLink => return Err(NOTSUP),
+            FileType::RegularFile => {
+                if oflags & OFLAGS_TRUNC != 0 {
+                    fs.set_size(&path, 0)?;
+                }
+                Descriptor::File {
+                    fs: fs_index,
+                    path,
+                    offset: 0,
+                    append: fdflags & FDFLAGS_APPEND != 0,
+                }
+            }
+        };
+        let fd = self.allocate(d);
+        store_u32(memory, opened, fd)
+    }
+
+    pub fn path_readlink(
+        &mut self,
+        memory: &mut [u8],
+        fd: u32,
+        path: u32,
+        len: u32,
+        buf: u32,
+        buf_len: u32,
+        bufused: u32,
+    ) -> Result<(), Errno> {
+        let (fs, path) = self.resolve(memory, fd, path, len)?;
+        let target = self.filesystems[fs].read_link(&path)?;
+        let n = target.len().min(buf_len as usize);
+        store(memory, buf, &target.as_bytes()[..n])?;
+        store_u32(memory, bufused, n as u32)
+    }
+
+    pub fn path_remove_directory(
+        &mut self,
+        memory: &mut [u8],
+        fd: u32,
+        path: u32,
+        len: u32,
+    ) -> Result<(), Errno> {
+        let (fs, path) = self.resolve(memory, fd, path, len)?;
+        self.filesystems[fs].remove_dir(&path)
+    }
+
+    pub fn path_rename(
+        &mut self,
+        memory: &mut [u8],
+        fd: u32,
+        old_path: u32,
+        old_len: u32,
+        new_fd: u32,
+        new_path: u32,
+        new_len: u32,
+    ) -> Result<(), Errno> {
+        let (fs, from) = self.resolve(memory, fd, old_path, old_len)?;
+        let (to_fs, to) = self.resolve(memory, new_fd, new_path, new_len)?;
+        if fs != to_fs {
+            return Err(XDEV);
+        }
+        self.filesystems[fs].rename(&from, &to)
+    }
+
+    pub fn path_symlink(
+        &mut self,
+        memory: &mut [u8],
+        old_path: u32,
+        old_len: u32,
+        fd: u32,
+        new_path: u32,
+        new_len: u32,
+    ) -> Result<(), Errno> {
+        let target = String::from(string(memory, old_path, old_len)?);
+        let (fs, path) = self.resolve(memory, fd, new_path, new_len)?;
+        // the link can't lead anywhere a path from where it is couldn't
+        let dir = path.rsplit_once('/').map_or("", |(dir, _)| dir);
+        join(dir, &target)?;
+        self.filesystems[fs].symlink(&target, &path)
+    }
+
+    pub fn path_unlink_file(
+        &mut self,
+        memory: &mut [u8],
+        fd: u32,
+        path: u32,
+        len: u32,
+    ) -> Result<(), Errno> {
+        let (fs, path) = self.resolve(memory, fd, path, len)?;
+        self.filesystems[fs].remove_file(&path)
+    }
+
+    /// Files are always ready, so subscriptions to them come back straight
+    /// away. Otherwise the program waits until the soonest clock it's
+    /// subscribed to goes off.
+    pub fn poll_oneoff(
+        &mut self,
+        memory: &mut [u8],
+        subscriptions: u32,
+        events: u32,
+        count: u32,
+        nevents: u32,
+    ) -> Result<(), Errno> {
+        if count == 0 {
+            return Err(INVAL);
+        }
+        let mut ready = vec![];
+        let mut clocks = vec![];
+        for i in 0..count {
+            let s = subscriptions
+                .checked_add(i.checked_mul(48).ok_or(FAULT)?)
+                .ok_or(FAULT)?;
+            let userdata = load_u64(memory, s)?;
+            match bytes(memory, s + 8, 1)?[0] {
+                EVENTTYPE_CLOCK => {
+                    let id = load_u32(memory, s + 16)?;
+                    let timeout = load_u64(memory, s + 24)?;
+                    let flags = load_u16(memory, s + 40)?;
+                    if id > 3 {
+                        ready.push((userdata, INVAL, EVENTTYPE_CLOCK, 0));
+                        continue;
+                    }
+                    let wait = if flags & SUBSCRIPTION_CLOCK_ABSTIME != 0 {
+                        timeout.saturating_sub(self.now(id))
+                    } else {
+                        timeout
+                    };
+                    clocks.push((userdata, wait));
+                }
+                t @ EVENTTYPE_FD_READ | t @ EVENTTYPE_FD_WRITE => {
+                    let fd = load_u32(memory, s + 16)?;
+                    let (error, available) = match descriptor(&mut self.fds, fd) {
+                        Ok(Descriptor::File {
+                            fs, path, offset, ..
+                        }) if t == EVENTTYPE_FD_READ => match self.filesystems[*fs].stat(path) {
+                            Ok(stat) => (0, stat.size.saturating_sub(*offset)),
+                            Err(e) => (e, 0),
+                        },
+                        Ok(Descriptor::Stdin) if t == EVENTTYPE_FD_READ => {
+                            (0, self.stdin.len() as u64)
+                        }
+                        Ok(_) => (0, 0),
+                        Err(e) => (e, 0),
+                    };
+                    ready.push((userdata, error, t, available));
+                }
+                _ => return Err(INVAL),
+            }
+        }
+        if ready.is_empty() {
+            if let Some(soonest) = clocks.iter().map(|(_, wait)| *wait).min() {
+                self.wait(soonest);
+                for (userdata, wait) in clocks {
+                    if wait <= soonest {
+                        ready.push((userdata, 0, EVENTTYPE_CLOCK, 0));
+                    }
+                }
+            }
+        }
+        for (i, (userdata, error, event_type, available)) in ready.iter().enumerate() {
+            let mut out = [0; 32];
+            out[0..8].copy_from_slice(&userdata.to_le_bytes());
+            out[8..10].copy_from_slice(&error.to_le_bytes());
+            out[10] = *event_type;
+            out[16..24].copy_from_slice(&available.to_le_bytes());
+            store(memory, events + 32 * i as u32, &out)?;
+        }
+        store_u32(memory, nevents, ready.len() as u32)
+    }
+
+    pub fn random_get(&mut self, memory: &mut [u8], buf: u32, len: u32) -> Result<(), Errno> {
+        bytes_mut(memory, buf, len)?;
+        for i in 0..len {
+            let byte = self.next_random() as u8;
+            memory[(buf + i) as usize] = byte;
+        }
+        Ok(())
+    }
+}
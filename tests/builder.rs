@@ -0,0 +1,162 @@
+use watson::*;
+
+#[test]
+fn adding_imports_renumbers_functions() {
+    let mut p = Program::new();
+    let (helper, helper_index) = p.create_function(&[], &[]).unwrap();
+    helper.instructions = vec![Instruction::Nop];
+    let (main, main_index) = p.create_export("main", &[], &[]).unwrap();
+    main.instructions = vec![Instruction::Call(helper_index as u32)];
+    assert_eq!((helper_index, main_index), (0, 1));
+
+    let log = p.create_import("log", &[ValueType::I32], &[]).unwrap();
+    assert_eq!(log, 0);
+    assert_eq!(p.find_exported_function("main").unwrap().index, 2);
+    assert_eq!(
+        p.find_code_block(1).unwrap().instructions,
+        vec![Instruction::Call(1)]
+    );
+
+    // imports of other kinds don't shift functions
+    let global = p
+        .add_import(WasmImport::Global(GlobalImport {
+            module_name: "env".to_string(),
+            name: "g".to_string(),
+            value_type: ValueType::I32,
+            is_mutable: false,
+        }))
+        .unwrap();
+    assert_eq!(global, 0);
+    assert_eq!(p.create_import("other", &[], &[]).unwrap(), 1);
+    assert_eq!(p.find_exported_function("main").unwrap().index, 3);
+}
+
+#[test]
+fn adding_imports_renumbers_names() {
+    let mut p = Program::new();
+    let (main, _) = p.create_export("main", &[], &[]).unwrap();
+    main.instructions = vec![Instruction::Nop];
+    // names for function 0 and its local 0, then the module's own name
+    let data = vec![
+        1, 7, 1, 0, 4, b'm', b'a', b'i', b'n', // functions
+        2, 6, 1, 0, 1, 0, 1, b'x', // locals
+        0, 2, 1, b'm', // module
+    ];
+    p.sections.push(Section::Custom(CustomSection {
+        name: "name".to_string(),
+        data,
+    }));
+    p.create_import("log", &[], &[]).unwrap();
+    let names = p
+        .sections
+        .iter()
+        .find_map(|s| match s {
+            Section::Custom(c) if c.name == "name" => Some(&c.data),
+            _ => None,
+        })
+        .unwrap();
+    assert_eq!(
+        names,
+        &vec![
+            1, 7, 1, 1, 4, b'm', b'a', b'i', b'n', // functions
+            2, 6, 1, 1, 1, 0, 1, b'x', // locals
+            0, 2, 1, b'm', // module
+        ]
+    );
+
+    // names that can't be read are dropped rather than left pointing at the
+    // wrong functions
+    for s in p.sections.iter_mut() {
+        if let Section::Custom(c) = s {
+            c.data = vec![1, 9];
+        }
+    }
+    p.create_import("other", &[], &[]).unwrap();
+    assert!(!p.sections.iter().any(|s| matches!(s, Section::Custom(_))));
+}
+
+#[test]
+fn refuses_to_move_the_table_call_indirect_uses() {
+    let mut p = Program::new();
+    p.create_table(1, None).unwrap();
+    let (main, _) = p.create_export("main", &[], &[]).unwrap();
+    main.instructions = vec![Instruction::I32Const(0), Instruction::CallIndirect(0)];
+    assert_eq!(
+        p.import_table("env", "table", 1, None),
+        Err("call_indirect can only use table 0")
+    );
+    // nothing was imported
+    assert!(!p.sections.iter().any(|s| matches!(s, Section::Import(_))));
+}
+
+#[test]
+fn removing_renumbers_and_refuses_referenced_items() {
+    let mut p = Program::new();
+    let unused = p.create_import("unused", &[], &[]).unwrap();
+    let (helper, helper_index) = p.create_function(&[], &[]).unwrap();
+    helper.instructions = vec![Instruction::Nop];
+    let (main, _) = p.create_export("main", &[], &[]).unwrap();
+    main.instructions = vec![Instruction::Call(helper_index as u32)];
+
+    assert!(p.remove_function(helper_index).is_err());
+    p.remove_function(unused).unwrap();
+    assert_eq!(p.find_exported_function("main").unwrap().index, 1);
+    assert_eq!(
+        p.find_code_block(1).unwrap().instructions,
+        vec![Instruction::Call(0)]
+    );
+    assert!(p.remove_function(5).is_err());
+}
+
+#[test]
+fn builds_every_kind_of_section() {
+    let mut p = Program::new();
+    let memory = p.import_memory("host", "memory", 1, None).unwrap();
+    let base = p
+        .import_global("host", "base", ValueType::I32, false)
+        .unwrap();
+    let print = p
+        .import_function("console", "print", &[ValueType::I32], &[])
+        .unwrap();
+    let (_, counter) = p
+        .create_global(ValueType::I32, true, &[Instruction::I32Const(0)])
+        .unwrap();
+    let (_, table) = p.create_table(1, Some(1)).unwrap();
+    let (start, start_index) = p.create_function(&[], &[]).unwrap();
+    start.instructions = vec![
+        Instruction::GlobalGet(counter as u32),
+        Instruction::Call(print as u32),
+    ];
+    p.add_element_segment(table, &[Instruction::I32Const(0)], &[start_index])
+        .unwrap();
+    p.add_data_segment(memory, &[Instruction::GlobalGet(base as u32)], b"hi")
+        .unwrap();
+    p.set_start(start_index).unwrap();
+    p.export_global("counter", counter).unwrap();
+    p.export_table("table", table).unwrap();
+    assert_eq!((memory, base, counter, table, start_index), (0, 0, 1, 0, 1));
+
+    assert!(p.export_global("missing", 2).is_err());
+    assert!(p.set_start(2).is_err());
+    assert!(p.add_element_segment(1, &[], &[]).is_err());
+
+    let compiled = p.compile();
+    assert_eq!(parse(&compiled).unwrap().to_owned().sections, p.sections);
+}
+
+#[test]
+fn numbers_defined_memories_after_imported_ones() {
+    let mut p = Program::new();
+    let imported = p.import_memory("host", "memory", 1, None).unwrap();
+    let (_, defined) = p.create_memory("scratch", 1, None).unwrap();
+    assert_eq!((imported, defined), (0, 1));
+    let exported = p
+        .sections
+        .iter()
+        .find_map(|s| match s {
+            Section::Export(s) => Some(&s.exports),
+            _ => None,
+        })
+        .unwrap();
+    assert!(matches!(&exported[0], WasmExport::Memory(e) if e.index == 1));
+}
@@ -0,0 +1,107 @@
+use watson::*;
+
+fn program() -> Program {
+    let mut p = Program::new();
+    let unused_import = p.create_import("unused", &[ValueType::F64], &[]).unwrap();
+    let log = p.create_import("log", &[ValueType::I32], &[]).unwrap();
+    let (unused, _) = p.create_function(&[ValueType::I64], &[]).unwrap();
+    unused.instructions = vec![
+        Instruction::I32Const(0),
+        Instruction::Call(unused_import as u32),
+    ];
+    let (helper, helper_index) = p.create_function(&[], &[ValueType::I32]).unwrap();
+    helper.instructions = vec![Instruction::I32Const(42)];
+    let (main, _) = p.create_export("main", &[], &[]).unwrap();
+    main.instructions = vec![
+        Instruction::Call(helper_index as u32),
+        Instruction::Call(log as u32),
+    ];
+    p
+}
+
+#[test]
+fn removes_unreachable_functions_and_types() {
+    let mut p = program();
+    let dead = p.eliminate_dead_code().unwrap();
+    assert_eq!(dead.functions, vec![0, 2]);
+    assert_eq!(dead.types.len(), 2);
+    assert!(dead.globals.is_empty());
+
+    let main = p.find_exported_function("main").unwrap();
+    assert_eq!(main.index, 2);
+    let code = p.find_code_block(1).unwrap();
+    assert_eq!(
+        code.instructions,
+        vec![Instruction::Call(1), Instruction::Call(0)]
+    );
+
+    // the result still compiles to a module that parses back the same
+    let compiled = p.compile();
+    assert_eq!(parse(&compiled).unwrap().to_owned().sections, p.sections);
+}
+
+#[test]
+fn nothing_left_to_remove_the_second_time() {
+    let mut p = program();
+    p.eliminate_dead_code().unwrap();
+    let before = p.clone();
+    assert!(p.eliminate_dead_code().unwrap().is_empty());
+    assert_eq!(p, before);
+}
+
+#[test]
+fn removes_unused_passive_data() {
+    let mut p = Program::new();
+    p.create_memory("memory", 1, None).unwrap();
+    let segment = |data: u8, passive| DataBlock {
+        memory: 0,
+        offset_expression: if passive {
+            vec![]
+        } else {
+            vec![Instruction::I32Const(0)]
+        },
+        data: vec![data],
+        passive,
+    };
+    p.sections
+        .push(Section::DataCount(DataCountSection { count: 3 }));
+    p.sections.push(Section::Data(DataSection {
+        data_blocks: vec![segment(1, true), segment(2, false), segment(3, true)],
+    }));
+    let (main, _) = p.create_export("main", &[], &[]).unwrap();
+    // data.drop 2
+    main.instructions = vec![Instruction::Prefixed(0xfc, 9, vec![2])];
+
+    let dead = p.eliminate_dead_code().unwrap();
+    assert_eq!(dead.data, vec![0]);
+    assert!(p
+        .sections
+        .iter()
+        .any(|s| matches!(s, Section::DataCount(c) if c.count == 2)));
+    let code = p.find_code_block(0).unwrap();
+    assert_eq!(
+        code.instructions,
+        vec![Instruction::Prefixed(0xfc, 9, vec![1])]
+    );
+    let compiled = p.compile();
+    assert_eq!(parse(&compiled).unwrap().to_owned().sections, p.sections);
+}
+
+#[test]
+fn keeps_tables_only_call_indirect_uses() {
+    let mut p = Program::new();
+    p.import_table("env", "table", 1, None).unwrap();
+    let (main, _) = p.create_export("main", &[], &[]).unwrap();
+    main.instructions = vec![Instruction::I32Const(0), Instruction::CallIndirect(0)];
+
+    let dead = p.eliminate_dead_code().unwrap();
+    assert!(dead.tables.is_empty());
+    assert!(p.sections.iter().any(|s| matches!(
+        s,
+        Section::Import(i) if matches!(i.imports[..], [WasmImport::Table(_)])
+    )));
+    let compiled = p.compile();
+    assert_eq!(parse(&compiled).unwrap().to_owned().sections, p.sections);
+    // nor can it be removed out from under it
+    assert!(p.remove_table(0).is_err());
+}
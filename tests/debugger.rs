@@ -0,0 +1,255 @@
+use wast::parser::{self, ParseBuffer};
+use watson::*;
+
+fn wasm(text: &str) -> Vec<u8> {
+    let buffer = ParseBuffer::new(text).unwrap();
+    let mut wat = parser::parse::<wast::Wat>(&buffer).unwrap();
+    wat.module.encode().unwrap()
+}
+
+// adds up its parameters, then stores and checks what main makes of them
+const MODULE: &str = r#"(module
+  (memory 1)
+  (global $total (mut i32) (i32.const 0))
+  (func $add (param $a i32) (param $b i32) (result i32)
+    (local $sum i32)
+    local.get $a
+    local.get $b
+    i32.add
+    local.set $sum
+    local.get $sum)
+  (func $main (export "main") (result i32)
+    i32.const 1
+    i32.const 2
+    call $add
+    i32.const 12345
+    i32.add
+    global.set $total
+    i32.const 8
+    i32.const 7
+    i32.store
+    global.get $total
+    if
+      nop
+      i32.const 5
+      drop
+    else
+      unreachable
+    end
+    global.get $total))"#;
+
+fn debugger(bytes: &[u8]) -> Debugger<Program> {
+    let p = parse(bytes).unwrap().to_owned();
+    let mut interpreter = Interpreter::new(p).unwrap();
+    Debugger::new(interpreter.call("main", &[]).unwrap()).unwrap()
+}
+
+fn path(d: &Debugger<Program>) -> Vec<usize> {
+    d.location().unwrap().path
+}
+
+#[test]
+fn stops_at_breakpoints_by_name() {
+    let mut d = debugger(&wasm(MODULE));
+    assert_eq!(d.function_name(0), Some("add"));
+    assert_eq!(d.local_name(0, 2), Some("sum"));
+    let id = d.break_at_name("add", &[2]).unwrap();
+    assert!(matches!(d.resume(), Ok(StopReason::Breakpoint(i)) if i == id));
+    let frames = d.frames().unwrap();
+    assert_eq!(frames.len(), 2);
+    assert_eq!(frames[0].name.as_deref(), Some("add"));
+    assert_eq!(frames[0].location.path, vec![2]);
+    assert_eq!(
+        frames[0].locals[..2],
+        [WasmValue::I32(1), WasmValue::I32(2)]
+    );
+    assert_eq!(frames[0].operands, [WasmValue::I32(1), WasmValue::I32(2)]);
+    // the caller waits on its call
+    assert_eq!(frames[1].name.as_deref(), Some("main"));
+    assert_eq!(frames[1].location.path, vec![2]);
+    assert!(frames[1].operands.is_empty());
+    assert!(matches!(d.resume(), Ok(StopReason::Complete(v)) if v == [WasmValue::I32(12348)]));
+    assert!(d.resume().is_err());
+}
+
+#[test]
+fn steps_into_over_and_out_of_calls() {
+    let mut d = debugger(&wasm(MODULE));
+    d.break_at(1, &[1]).unwrap();
+    assert!(matches!(d.resume(), Ok(StopReason::Breakpoint(_))));
+    assert!(matches!(d.step(), Ok(StopReason::Step)));
+    assert_eq!(path(&d), vec![2]);
+    assert!(matches!(d.step(), Ok(StopReason::Step)));
+    assert_eq!(d.location().unwrap().function, 0);
+    assert_eq!(path(&d), vec![0]);
+    assert!(matches!(d.step_out(), Ok(StopReason::Step)));
+    let frames = d.frames().unwrap();
+    assert_eq!(frames.len(), 1);
+    assert_eq!(frames[0].location.path, vec![3]);
+    assert_eq!(frames[0].operands, [WasmValue::I32(3)]);
+
+    let mut d = debugger(&wasm(MODULE));
+    d.break_at(1, &[2]).unwrap();
+    d.resume().unwrap();
+    assert!(matches!(d.step_over(), Ok(StopReason::Step)));
+    assert_eq!(d.location().unwrap().function, 1);
+    assert_eq!(path(&d), vec![3]);
+    // stepping out of the outermost call finishes it
+    assert!(matches!(d.step_out(), Ok(StopReason::Complete(_))));
+}
+
+#[test]
+fn breaks_inside_blocks_and_at_the_end() {
+    let mut d = debugger(&wasm(MODULE));
+    let inner = d.break_at(1, &[10, 1]).unwrap();
+    let end = d.break_at(1, &[12]).unwrap();
+    assert!(matches!(d.resume(), Ok(StopReason::Breakpoint(i)) if i == inner));
+    assert_eq!(path(&d), vec![10, 1]);
+    assert!(matches!(d.resume(), Ok(StopReason::Breakpoint(i)) if i == end));
+    assert_eq!(d.frames().unwrap()[0].operands, [WasmValue::I32(12348)]);
+
+    // nop doesn't run, so its breakpoint stops at what comes after it
+    let mut d = debugger(&wasm(MODULE));
+    d.break_at(1, &[10, 0]).unwrap();
+    d.resume().unwrap();
+    assert_eq!(path(&d), vec![10, 1]);
+    assert!(d.break_at(1, &[10, 4]).is_err());
+    assert!(d.break_at(1, &[13]).is_err());
+}
+
+#[test]
+fn breaks_at_byte_offsets() {
+    let bytes = wasm(MODULE);
+    // i32.const 12345
+    let offset = bytes
+        .windows(3)
+        .position(|w| w == [0x41, 0xb9, 0xe0])
+        .unwrap();
+    let mut d = debugger(&bytes);
+    d.break_at_offset(offset).unwrap();
+    assert!(matches!(d.resume(), Ok(StopReason::Breakpoint(_))));
+    let location = d.location().unwrap();
+    assert_eq!((location.function, location.path), (1, vec![3]));
+    assert_eq!(location.offset, Some(offset));
+    assert!(d.break_at_offset(offset + 1).is_err());
+}
+
+#[test]
+fn stops_when_watched_values_change() {
+    let mut d = debugger(&wasm(MODULE));
+    let total = d.watch_global(0).unwrap();
+    let memory = d.watch_memory(8..12).unwrap();
+    match d.resume() {
+        Ok(StopReason::Watchpoint { id, old, new }) => {
+            assert_eq!(id, total);
+            assert_eq!(old, Watched::Global(WasmValue::I32(0)));
+            assert_eq!(new, Watched::Global(WasmValue::I32(12348)));
+        }
+        r => panic!("{:?}", r),
+    }
+    // stops after the instruction that did it
+    assert_eq!(path(&d), vec![6]);
+    match d.resume() {
+        Ok(StopReason::Watchpoint { id, new, .. }) => {
+            assert_eq!(id, memory);
+            assert_eq!(new, Watched::Memory(vec![7, 0, 0, 0]));
+        }
+        r => panic!("{:?}", r),
+    }
+    assert_eq!(d.read_memory(8..12).unwrap(), [7, 0, 0, 0]);
+    assert_eq!(d.globals(), [WasmValue::I32(12348)]);
+    d.unwatch(memory);
+    assert!(matches!(d.resume(), Ok(StopReason::Complete(_))));
+    assert!(d.watch_memory(65535..65537).is_err());
+}
+
+#[test]
+fn stops_on_traps_and_imports() {
+    let bytes = wasm(
+        r#"(module
+          (import "env" "get" (func $get (result i32)))
+          (func (export "main") (result i32)
+            call $get
+            i32.const 0
+            i32.div_u))"#,
+    );
+    let mut d = debugger(&bytes);
+    let call = match d.resume() {
+        Ok(StopReason::CallImport(call)) => call,
+        r => panic!("{:?}", r),
+    };
+    assert_eq!(call.name, "get");
+    assert_eq!(path(&d), vec![0]);
+    d.execution()
+        .execute(ExecutionResponse::AddValues(vec![WasmValue::I32(1)]))
+        .unwrap();
+    assert!(matches!(d.resume(), Ok(StopReason::Trap(_))));
+    // the frame is left where it trapped
+    let frames = d.frames().unwrap();
+    assert_eq!(frames[0].location.path, vec![2]);
+    assert_eq!(frames[0].operands, []);
+}
+
+// runs main on the register engine until `stop` says to, then debugs the
+// rest of it
+fn debug_partway(
+    text: &str,
+    fuel: Option<u64>,
+    mut stop: impl FnMut(&mut WasmExecution<Program>, ExecutionUnit) -> bool,
+) -> Debugger<Program> {
+    let p = parse(&wasm(text)).unwrap().to_owned();
+    let config = InterpreterConfig {
+        engine: Engine::Registers,
+        fuel,
+        ..Default::default()
+    };
+    let mut interpreter = Interpreter::with_config(p, &config).unwrap();
+    let mut execution = interpreter.call("main", &[]).unwrap();
+    loop {
+        let unit = execution.next_unit().unwrap();
+        if stop(&mut execution, unit) {
+            return Debugger::new(execution).unwrap();
+        }
+    }
+}
+
+#[test]
+fn takes_over_calls_from_the_register_engine() {
+    let text = r#"(module
+      (import "env" "five" (func $five (result i32)))
+      (func $add (param i32) (result i32)
+        local.get 0
+        call $five
+        i32.add)
+      (func (export "main") (result i32)
+        i32.const 1
+        i32.const 2
+        call $add
+        i32.add))"#;
+    let mut d = debug_partway(text, None, |execution, unit| {
+        assert!(matches!(unit, ExecutionUnit::CallImport(_)));
+        let five = ExecutionResponse::AddValues(vec![WasmValue::I32(5)]);
+        execution.execute(five).unwrap();
+        true
+    });
+    let frames = d.frames().unwrap();
+    assert_eq!(frames[0].location.path, vec![2]);
+    assert_eq!(frames[0].operands, [WasmValue::I32(2), WasmValue::I32(5)]);
+    assert_eq!(frames[1].location.path, vec![2]);
+    assert_eq!(frames[1].operands, [WasmValue::I32(1)]);
+    assert!(matches!(d.step(), Ok(StopReason::Step)));
+    assert_eq!(d.frames().unwrap()[0].operands, [WasmValue::I32(7)]);
+    assert!(matches!(d.resume(), Ok(StopReason::Complete(v)) if v == [WasmValue::I32(8)]));
+
+    // and from wherever it ran out of fuel
+    for fuel in 0..16 {
+        let mut d = debug_partway(MODULE, Some(fuel), |_, unit| match unit {
+            ExecutionUnit::OutOfFuel => true,
+            ExecutionUnit::Complete(_) => panic!("ran to completion on {} fuel", fuel),
+            _ => false,
+        });
+        d.execution().add_fuel(100);
+        let done = d.resume();
+        assert!(matches!(done, Ok(StopReason::Complete(v)) if v == [WasmValue::I32(12348)]));
+    }
+}
@@ -0,0 +1,119 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+use watson::*;
+
+// polls a future until it's done, counting how often it wasn't
+fn block_on<F: Future>(future: F) -> (F::Output, usize) {
+    fn raw() -> RawWaker {
+        RawWaker::new(std::ptr::null(), &VTABLE)
+    }
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(|_| raw(), |_| {}, |_| {}, |_| {});
+    let waker = unsafe { Waker::from_raw(raw()) };
+    let mut context = Context::from_waker(&waker);
+    let mut future = Box::pin(future);
+    let mut pending = 0;
+    loop {
+        if let Poll::Ready(v) = future.as_mut().poll(&mut context) {
+            return (v, pending);
+        }
+        pending += 1;
+    }
+}
+
+// a stand-in for a sleep, which isn't ready the first time it's polled
+struct Nap(bool);
+
+impl Future for Nap {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        if self.0 {
+            return Poll::Ready(());
+        }
+        self.0 = true;
+        context.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+// doubles each of 1 to n with an import that has to be waited on, adding
+// up the results
+fn program() -> Program {
+    let mut p = Program::new();
+    let double = p
+        .import_function("env", "double", &[ValueType::I32], &[ValueType::I32])
+        .unwrap();
+    let mut f = FunctionBuilder::new(&p, &[ValueType::I32], &[ValueType::I32]);
+    f.name_param(0, "n");
+    f.local("sum", ValueType::I32);
+    let again = f.begin_loop(None);
+    f.local_get("sum")
+        .local_get("n")
+        .emit(Instruction::Call(double as u32))
+        .emit(Instruction::I32Add)
+        .local_set("sum");
+    f.local_get("n")
+        .emit(Instruction::I32Const(1))
+        .emit(Instruction::I32Sub)
+        .local_tee("n")
+        .br_if(again)
+        .end();
+    f.local_get("sum");
+    let sum = f.build(&mut p).unwrap();
+    let (export, _) = p
+        .create_export("sum", &[ValueType::I32], &[ValueType::I32])
+        .unwrap();
+    export.instructions = vec![Instruction::LocalGet(0), Instruction::Call(sum as u32)];
+    p
+}
+
+#[test]
+fn awaits_imports() {
+    let engines = [Engine::Stack, Engine::Registers];
+    for engine in engines.iter() {
+        let config = InterpreterConfig {
+            engine: *engine,
+            ..Default::default()
+        };
+        let mut interpreter = Interpreter::with_config(program(), &config).unwrap();
+        let mut executor = interpreter.call("sum", &[WasmValue::I32(10)]).unwrap();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let (result, pending) = block_on(executor.run_async(|call| {
+            let calls = calls.clone();
+            async move {
+                Nap(false).await;
+                calls.fetch_add(1, Ordering::SeqCst);
+                match call.name.as_str() {
+                    "double" => Ok(vec![WasmValue::I32(call.params[0].to_i32() * 2)]),
+                    _ => Err("unknown import"),
+                }
+            }
+        }));
+        assert_eq!(result, Ok(vec![WasmValue::I32(110)]));
+        assert_eq!(calls.load(Ordering::SeqCst), 10);
+        assert_eq!(pending, 10);
+    }
+}
+
+#[test]
+fn fails_with_the_resolver_or_a_trap() {
+    let mut interpreter = Interpreter::new(program()).unwrap();
+    let mut executor = interpreter.call("sum", &[WasmValue::I32(10)]).unwrap();
+    let (result, _) = block_on(executor.run_async(|_| async { Err("no imports here") }));
+    assert_eq!(result, Err("no imports here"));
+
+    let config = InterpreterConfig {
+        fuel: Some(10),
+        ..Default::default()
+    };
+    let mut interpreter = Interpreter::with_config(program(), &config).unwrap();
+    let mut executor = interpreter.call("sum", &[WasmValue::I32(10)]).unwrap();
+    let (result, _) =
+        block_on(executor.run_async(|call| async move {
+            Ok(vec![WasmValue::I32(call.params[0].to_i32() * 2)])
+        }));
+    assert_eq!(result, Err("ran out of fuel"));
+}
@@ -0,0 +1,84 @@
+use watson::*;
+
+// (func (result i32) i32.const -1 i32.extend8_s)
+const SIGN_EXTENSION: [u8; 28] = [
+    0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, // header
+    0x01, 0x05, 0x01, 0x60, 0x00, 0x01, 0x7f, // type section
+    0x03, 0x02, 0x01, 0x00, // function section
+    0x0a, 0x07, 0x01, 0x05, 0x00, 0x41, 0x7f, 0xc0, 0x0b, // code section
+];
+
+#[test]
+fn reports_used_features() {
+    let program = parse(&SIGN_EXTENSION).unwrap();
+    let used = program.used_features();
+    assert!(used.sign_extension);
+    assert_eq!(
+        used,
+        Features {
+            sign_extension: true,
+            ..Features::mvp()
+        }
+    );
+    assert_eq!(program.to_owned().used_features(), used);
+}
+
+#[test]
+fn rejects_disabled_features() {
+    assert_eq!(
+        parse_with_config(&SIGN_EXTENSION, &ParserConfig::mvp()).unwrap_err(),
+        "feature sign-extension not enabled"
+    );
+    let config = ParserConfig {
+        features: Features {
+            sign_extension: true,
+            ..Features::mvp()
+        },
+    };
+    assert!(parse_with_config(&SIGN_EXTENSION, &config).is_ok());
+    assert!(parse_with_config(&SIGN_EXTENSION, &ParserConfig::default()).is_ok());
+}
+
+#[test]
+fn shared_memories_need_threads() {
+    // (memory 1 2 shared)
+    let bytes = [
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, // header
+        0x05, 0x04, 0x01, 0x03, 0x01, 0x02, // memory section
+    ];
+    let program = parse(&bytes).unwrap();
+    assert!(program.used_features().threads);
+    let mut program = program.to_owned();
+    assert!(matches!(&program.sections[0], Section::Memory(s) if s.memories[0].shared));
+    assert_eq!(program.compile(), bytes.to_vec());
+    assert_eq!(
+        parse_with_config(&bytes, &ParserConfig::mvp()).unwrap_err(),
+        "feature threads not enabled"
+    );
+}
+
+#[test]
+fn memory64_is_parsed_when_enabled() {
+    // (memory i64 1 65536)
+    let bytes = [
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, // header
+        0x05, 0x06, 0x01, 0x05, 0x01, 0x80, 0x80, 0x04, // memory section
+    ];
+    let view = parse(&bytes).unwrap();
+    assert!(view.used_features().memory64);
+    let mut program = view.to_owned();
+    assert_eq!(program.used_features(), view.used_features());
+    assert!(matches!(
+        &program.sections[0],
+        Section::Memory(s) if s.memories[0].memory64 && s.memories[0].max_pages == Some(65536)
+    ));
+    assert_eq!(program.compile(), bytes.to_vec());
+    assert_eq!(
+        parse_with_config(&bytes, &ParserConfig::mvp()).unwrap_err(),
+        "feature memory64 not enabled"
+    );
+    assert_eq!(
+        Interpreter::new(program).err(),
+        Some("64-bit memories are not supported")
+    );
+}
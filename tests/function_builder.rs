@@ -0,0 +1,71 @@
+use watson::*;
+
+#[test]
+fn builds_structured_control_with_named_locals() {
+    let mut p = Program::new();
+    let mut f = FunctionBuilder::new(&p, &[ValueType::I32], &[ValueType::I32]);
+    f.name_param(0, "n");
+    f.local("sum", ValueType::I32);
+    let done = f.begin_block(None);
+    let again = f.begin_loop(None);
+    f.local_get("n").emit(Instruction::I32Eqz).br_if(done);
+    f.local_get("sum")
+        .local_get("n")
+        .emit(Instruction::I32Add)
+        .local_set("sum");
+    f.local_get("n")
+        .emit(Instruction::I32Const(1))
+        .emit(Instruction::I32Sub)
+        .local_set("n");
+    f.br(again).end().end();
+    f.local_get("sum");
+    let index = f.build(&mut p).unwrap();
+    assert_eq!(index, 0);
+
+    let code = p.find_code_block(0).unwrap();
+    assert_eq!(code.locals.len(), 1);
+    match &code.instructions[0] {
+        Instruction::Block(_, body) => match &body[0] {
+            Instruction::Loop(_, body) => {
+                assert_eq!(body[2], Instruction::BrIf(1));
+                assert_eq!(body[body.len() - 1], Instruction::Br(0));
+            }
+            i => panic!("expected a loop, found {:?}", i),
+        },
+        i => panic!("expected a block, found {:?}", i),
+    }
+    assert_eq!(code.instructions[1], Instruction::LocalGet(1));
+}
+
+#[test]
+fn reports_type_errors() {
+    let p = Program::new();
+    let mut f = FunctionBuilder::new(&p, &[], &[ValueType::I32]);
+    f.emit(Instruction::I64Const(1));
+    assert_eq!(f.build(&mut p.clone()), Err("operand has the wrong type"));
+
+    let mut f = FunctionBuilder::new(&p, &[], &[]);
+    f.emit(Instruction::I32Add);
+    assert_eq!(f.build(&mut p.clone()), Err("operand stack underflow"));
+
+    let mut f = FunctionBuilder::new(&p, &[], &[]);
+    f.begin_block(None);
+    assert_eq!(f.build(&mut p.clone()), Err("block was never ended"));
+
+    let mut f = FunctionBuilder::new(&p, &[], &[]);
+    let inner = f.begin_block(None);
+    f.end().br(inner);
+    assert_eq!(f.build(&mut p.clone()), Err("label is not in scope"));
+}
+
+#[test]
+fn code_after_a_branch_is_unconstrained() {
+    let mut p = Program::new();
+    let mut f = FunctionBuilder::new(&p, &[], &[ValueType::I32]);
+    let b = f.begin_block(Some(ValueType::I32));
+    f.emit(Instruction::I32Const(1))
+        .br(b)
+        .emit(Instruction::I32Add)
+        .end();
+    assert!(f.build(&mut p).is_ok());
+}
@@ -0,0 +1,95 @@
+use watson::*;
+
+// adds up 1 to n in a loop, behind an export so there's a call to pay for
+fn program() -> Program {
+    let mut p = Program::new();
+    let mut f = FunctionBuilder::new(&p, &[ValueType::I32], &[ValueType::I32]);
+    f.name_param(0, "n");
+    f.local("sum", ValueType::I32);
+    let again = f.begin_loop(None);
+    f.local_get("sum")
+        .local_get("n")
+        .emit(Instruction::I32Add)
+        .local_set("sum");
+    f.local_get("n")
+        .emit(Instruction::I32Const(1))
+        .emit(Instruction::I32Sub)
+        .local_tee("n")
+        .br_if(again)
+        .end();
+    f.local_get("sum");
+    let sum = f.build(&mut p).unwrap();
+    let (export, _) = p
+        .create_export("sum", &[ValueType::I32], &[ValueType::I32])
+        .unwrap();
+    export.instructions = vec![Instruction::LocalGet(0), Instruction::Call(sum as u32)];
+    p
+}
+
+fn run(
+    p: Program,
+    n: i32,
+    config: &InterpreterConfig,
+) -> (Result<Vec<WasmValue>, &'static str>, i64, u64) {
+    let mut interpreter = Interpreter::with_config(p, config).unwrap();
+    let mut executor = interpreter.call("sum", &[WasmValue::I32(n)]).unwrap();
+    let mut gas = 0;
+    let result = loop {
+        let response = match executor.next_unit() {
+            Ok(ExecutionUnit::CallImport(x)) if x.name == "gas" => {
+                gas += x.params[0].to_i64();
+                ExecutionResponse::DoNothing
+            }
+            Ok(ExecutionUnit::Complete(v)) => break Ok(v),
+            Ok(ExecutionUnit::Unreachable) => break Err("unreachable"),
+            Ok(mut x) => x.evaluate().unwrap(),
+            Err(e) => break Err(e),
+        };
+        executor.execute(response).unwrap();
+    };
+    (result, gas, executor.fuel_consumed())
+}
+
+#[test]
+fn charges_an_import_per_block() {
+    let costs = CostTable::default().with(webassembly::I32_ADD, 5);
+    let mut p = program();
+    let gas = p
+        .inject_gas(&GasMeter::Import("metering".to_string()), &costs)
+        .unwrap();
+    assert_eq!(gas, 0);
+    let (result, charged, _) = run(p, 100, &InterpreterConfig::default());
+    assert_eq!(result, Ok(vec![WasmValue::I32(5050)]));
+
+    // the same as the interpreter's own fuel for the uninstrumented program
+    let config = InterpreterConfig {
+        fuel: Some(u64::MAX),
+        costs,
+        ..Default::default()
+    };
+    let (_, _, fuel) = run(program(), 100, &config);
+    assert_eq!(charged as u64, fuel);
+    assert_eq!(charged, 2 + 1 + 100 * (9 + 4) + 1);
+}
+
+#[test]
+fn charges_a_global_and_traps_when_it_runs_out() {
+    let mut p = program();
+    let meter = GasMeter::Global("gas".to_string(), 1000);
+    let gas = p.inject_gas(&meter, &CostTable::default()).unwrap();
+    assert!(!p.compile().is_empty());
+
+    let mut interpreter = Interpreter::new(p.clone()).unwrap();
+    let mut executor = interpreter.call("sum", &[WasmValue::I32(10)]).unwrap();
+    assert!(matches!(
+        executor.next_unit(),
+        Ok(ExecutionUnit::Complete(_))
+    ));
+    assert_eq!(
+        interpreter.globals.lock()[gas],
+        WasmValue::I64(1000 - (2 + 1 + 10 * 9 + 1))
+    );
+
+    let (result, _, _) = run(p, 1000, &InterpreterConfig::default());
+    assert_eq!(result, Err("unreachable"));
+}
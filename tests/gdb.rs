@@ -0,0 +1,171 @@
+#![cfg(feature = "std")]
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread::{self, JoinHandle};
+use wast::parser::{self, ParseBuffer};
+use watson::gdb::*;
+use watson::*;
+
+fn wasm(text: &str) -> Vec<u8> {
+    let buffer = ParseBuffer::new(text).unwrap();
+    let mut wat = parser::parse::<wast::Wat>(&buffer).unwrap();
+    wat.module.encode().unwrap()
+}
+
+const MODULE: &str = r#"(module
+  (memory 1)
+  (global $total (mut i32) (i32.const 0))
+  (func $add (param $a i32) (param $b i32) (result i32)
+    local.get $a
+    local.get $b
+    i32.add)
+  (func $main (export "main") (result i32)
+    i32.const 1
+    i32.const 2
+    call $add
+    i32.const 12345
+    i32.add
+    global.set $total
+    i32.const 8
+    i32.const 7
+    i32.store
+    global.get $total))"#;
+
+// serves a call to main on another thread, returning how the stub ended
+fn serve(bytes: Vec<u8>) -> (Client, JoinHandle<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let p = parse(&bytes).unwrap().to_owned();
+        let mut interpreter = Interpreter::new(p).unwrap();
+        let debugger = Debugger::new(interpreter.call("main", &[]).unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_nodelay(true).unwrap();
+        let ended = GdbStub::new(debugger).serve(stream);
+        format!("{:?}", ended)
+    });
+    let stream = TcpStream::connect(address).unwrap();
+    stream.set_nodelay(true).unwrap();
+    let client = Client { stream, ack: true };
+    (client, server)
+}
+
+struct Client {
+    stream: TcpStream,
+    ack: bool,
+}
+
+impl Client {
+    fn byte(&mut self) -> u8 {
+        let mut b = [0];
+        self.stream.read_exact(&mut b).unwrap();
+        b[0]
+    }
+
+    fn request(&mut self, packet: &str) -> String {
+        let sum = packet.bytes().fold(0u8, |s, b| s.wrapping_add(b));
+        write!(self.stream, "${}#{:02x}", packet, sum).unwrap();
+        if self.ack {
+            assert_eq!(self.byte(), b'+');
+        }
+        assert_eq!(self.byte(), b'$');
+        let mut reply = vec![];
+        loop {
+            match self.byte() {
+                b'#' => break,
+                b'}' => {
+                    let b = self.byte();
+                    reply.push(b ^ 0x20)
+                }
+                b => reply.push(b),
+            }
+        }
+        let checksum = [self.byte(), self.byte()];
+        let sum = reply.iter().fold(0u8, |s, b| s.wrapping_add(*b));
+        assert_eq!(
+            std::str::from_utf8(&checksum).unwrap(),
+            format!("{:02x}", sum)
+        );
+        if self.ack {
+            self.stream.write_all(b"+").unwrap();
+        }
+        String::from_utf8(reply).unwrap()
+    }
+}
+
+fn offset(bytes: &[u8], code: &[u8]) -> u64 {
+    bytes.windows(code.len()).position(|w| w == code).unwrap() as u64
+}
+
+fn le(v: u64) -> String {
+    v.to_le_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[test]
+fn debugs_over_the_remote_protocol() {
+    let bytes = wasm(MODULE);
+    // local.get 1, i32.add in add and i32.const 12345 in main
+    let add = offset(&bytes, &[0x20, 0x01, 0x6a]) + 2;
+    let constant = offset(&bytes, &[0x41, 0xb9, 0xe0, 0x00]);
+    let (mut c, server) = serve(bytes.clone());
+
+    assert!(c
+        .request("qSupported:xmlRegisters=i386")
+        .contains("qXfer:libraries:read+"));
+    assert_eq!(c.request("?"), "T05thread:1;");
+    assert_eq!(c.request("qfThreadInfo"), "m1");
+    assert!(c.request("qRegisterInfo0").contains("generic:pc"));
+    assert_eq!(c.request("vCont?"), "vCont;c;C;s;S");
+    let libraries = c.request("qXfer:libraries:read::0,1000");
+    assert!(libraries.starts_with("l<library-list>"));
+    assert!(libraries.contains("address=\"0x4000000000000000\""));
+    assert_eq!(c.request("qXfer:libraries:read::0,5"), "m<libr");
+    assert_eq!(c.request("m4000000000000000,4"), "0061736d");
+    assert_eq!(c.request("Z0,4000000000000001,1"), "E01");
+    assert_eq!(c.request("unknown"), "");
+
+    assert_eq!(c.request(&format!("Z0,{:x},1", CODE | add)), "OK");
+    assert_eq!(c.request(&format!("Z0,{:x},1", CODE | constant)), "OK");
+    assert_eq!(c.request("c"), "T05thread:1;reason:breakpoint;");
+    assert_eq!(c.request("p0"), le(CODE | add));
+    let stack = c.request("qWasmCallStack:1");
+    assert_eq!(stack.len(), 32);
+    assert!(stack.starts_with(&le(CODE | add)));
+    assert_eq!(c.request("qWasmLocal:0;1"), "02000000");
+    assert_eq!(c.request("qWasmStackValue:0;0"), "01000000");
+    assert_eq!(c.request("qWasmLocal:0;5"), "E03");
+    assert_eq!(c.request(&format!("z0,{:x},1", CODE | add)), "OK");
+
+    assert_eq!(c.request("vCont;c:1"), "T05thread:1;reason:breakpoint;");
+    assert_eq!(c.request("g"), le(CODE | constant));
+    assert_eq!(c.request("qWasmStackValue:0;0"), "03000000");
+    assert_eq!(c.request("s"), "T05thread:1;reason:trace;");
+    assert_eq!(c.request("p0"), le(CODE | (constant + 4)));
+
+    assert_eq!(c.request("Z2,8,4"), "OK");
+    assert_eq!(c.request("c"), "T05watch:8;thread:1;reason:watchpoint;");
+    assert_eq!(c.request("m8,4"), "07000000");
+    assert_eq!(c.request("qWasmMem:0;8;2"), "0700");
+    assert_eq!(c.request("m10000,1"), "E01");
+    // 12348
+    assert_eq!(c.request("qWasmGlobal:0;0"), "3c300000");
+
+    assert_eq!(c.request("QStartNoAckMode"), "OK");
+    c.ack = false;
+    assert_eq!(c.request("c"), "W3c");
+    assert_eq!(server.join().unwrap(), "Ok(Some(Complete([I32(12348)])))");
+}
+
+#[test]
+fn stops_on_traps_until_detached() {
+    let bytes = wasm(r#"(module (func (export "main") (result i32) i32.const 1 unreachable))"#);
+    let (mut c, server) = serve(bytes);
+    let stop = c.request("c");
+    assert!(stop.starts_with("T04thread:1;reason:exception;"));
+    assert_eq!(c.request("qWasmStackValue:0;0"), "01000000");
+    assert_eq!(c.request("D"), "OK");
+    assert_eq!(server.join().unwrap(), "Ok(Some(Unreachable))");
+}
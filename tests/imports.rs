@@ -0,0 +1,134 @@
+use spin::Mutex;
+use std::sync::Arc;
+use watson::*;
+
+// combines two numbers with one import and writes the result to memory
+// with another
+fn program() -> Program {
+    let mut p = Program::new();
+    let combine = p
+        .import_function(
+            "env",
+            "combine",
+            &[ValueType::I32, ValueType::I32],
+            &[ValueType::I64],
+        )
+        .unwrap();
+    let poke = p
+        .import_function("env", "poke", &[ValueType::I64], &[])
+        .unwrap();
+    p.create_memory("memory", 1, None).unwrap();
+    let (main, _) = p
+        .create_export("main", &[ValueType::I32, ValueType::I32], &[ValueType::I64])
+        .unwrap();
+    main.instructions = vec![
+        Instruction::LocalGet(0),
+        Instruction::LocalGet(1),
+        Instruction::Call(combine as u32),
+        Instruction::Call(poke as u32),
+        Instruction::I32Const(0),
+        Instruction::I64Load(3, 0),
+    ];
+    p
+}
+
+fn imports() -> Imports {
+    let mut imports = Imports::new();
+    imports
+        .func("env", "combine", |_: &mut Caller, a: i32, b: i32| {
+            Ok(((a as i64) << 32) | b as i64)
+        })
+        .func("env", "poke", |caller: &mut Caller, v: i64| {
+            caller.memory()[..8].copy_from_slice(&v.to_le_bytes());
+            Ok(())
+        });
+    imports
+}
+
+#[test]
+fn calls_registered_closures() {
+    let engines = [Engine::Stack, Engine::Registers];
+    for engine in engines.iter() {
+        let config = InterpreterConfig {
+            engine: *engine,
+            ..Default::default()
+        };
+        let mut interpreter = Interpreter::with_imports(program(), &config, imports()).unwrap();
+        let mut executor = interpreter
+            .call("main", &[WasmValue::I32(3), WasmValue::I32(4)])
+            .unwrap();
+        match executor.next_unit() {
+            Ok(ExecutionUnit::Complete(v)) => assert_eq!(v, vec![WasmValue::I64((3 << 32) | 4)]),
+            x => panic!("expected the call to complete, got {:?}", x),
+        }
+    }
+}
+
+#[test]
+fn reports_missing_imports_up_front() {
+    let p = program();
+    assert_eq!(
+        Imports::new().unresolved(&p),
+        vec![("env", "combine"), ("env", "poke")]
+    );
+
+    let mut wrong = imports();
+    wrong.func("env", "combine", |_: &mut Caller, a: i32, b: i32| Ok(a + b));
+    assert_eq!(wrong.unresolved(&p), vec![("env", "combine")]);
+    assert!(Interpreter::with_imports(p.clone(), &Default::default(), wrong).is_err());
+
+    assert!(imports().unresolved(&p).is_empty());
+}
+
+#[test]
+fn passes_on_what_closures_fail_with() {
+    let mut imports = imports();
+    imports.func(
+        "env",
+        "poke",
+        |_: &mut Caller, _: i64| -> Result<(), Trap> { Err(Trap("poked")) },
+    );
+    let mut interpreter =
+        Interpreter::with_imports(program(), &Default::default(), imports).unwrap();
+    let mut executor = interpreter
+        .call("main", &[WasmValue::I32(3), WasmValue::I32(4)])
+        .unwrap();
+    assert_eq!(executor.next_unit().err(), Some("poked"));
+}
+
+#[test]
+fn lets_closures_lock_what_the_instance_imports() {
+    let mut p = Program::new();
+    let touch = p.import_function("env", "touch", &[], &[]).unwrap();
+    p.import_memory("env", "memory", 1, None).unwrap();
+    let counter = p
+        .import_global("env", "counter", ValueType::I32, true)
+        .unwrap();
+    let (main, _) = p.create_export("main", &[], &[ValueType::I32]).unwrap();
+    main.instructions = vec![
+        Instruction::Call(touch as u32),
+        Instruction::I32Const(0),
+        Instruction::I32Load8U(0, 0),
+        Instruction::GlobalGet(counter as u32),
+        Instruction::I32Add,
+    ];
+
+    let memory = Arc::new(Mutex::new(vec![0; 65536]));
+    let global = SharedGlobal::new(WasmValue::I32(0), true);
+    let mut imports = Imports::new();
+    imports
+        .memory("env", "memory", memory.clone(), None)
+        .shared_global("env", "counter", global.clone());
+    imports.func("env", "touch", move |caller: &mut Caller| {
+        memory.lock()[0] = 5;
+        global.set(WasmValue::I32(7))?;
+        // what the caller sees is kept in step with both
+        if caller.memory()[0] != 5 || caller.globals()[0] != WasmValue::I32(7) {
+            return Err(Trap("caller is out of step"));
+        }
+        Ok(())
+    });
+    let mut interpreter = Interpreter::with_imports(p, &Default::default(), imports).unwrap();
+    let mut executor = interpreter.call("main", &[]).unwrap();
+    assert_eq!(executor.finish(), Ok(vec![WasmValue::I32(12)]));
+}
@@ -0,0 +1,159 @@
+use spin::Mutex;
+use std::sync::Arc;
+use watson::*;
+
+// imports a memory and a base address, copies a greeting to the base and
+// starts a counter there too, which the start function then multiplies
+fn program() -> Program {
+    let mut p = Program::new();
+    let base = p
+        .import_global("env", "base", ValueType::I32, false)
+        .unwrap();
+    p.import_memory("env", "memory", 1, Some(2)).unwrap();
+    let (_, counter) = p
+        .create_global(ValueType::I32, true, &[Instruction::GlobalGet(base as u32)])
+        .unwrap();
+    p.export_global("counter", counter).unwrap();
+    p.export_global("base", base).unwrap();
+    p.add_data_segment(0, &[Instruction::GlobalGet(base as u32)], b"hi")
+        .unwrap();
+    let (bump, bump_index) = p.create_export("bump", &[], &[ValueType::I32]).unwrap();
+    bump.instructions = vec![
+        Instruction::GlobalGet(counter as u32),
+        Instruction::I32Const(1),
+        Instruction::I32Add,
+        Instruction::GlobalSet(counter as u32),
+        Instruction::GlobalGet(counter as u32),
+    ];
+    p.create_table(2, None).unwrap();
+    p.export_table("table", 0).unwrap();
+    p.add_element_segment(0, &[Instruction::I32Const(1)], &[bump_index])
+        .unwrap();
+    let (start, start_index) = p.create_function(&[], &[]).unwrap();
+    start.instructions = vec![
+        Instruction::GlobalGet(counter as u32),
+        Instruction::I32Const(10),
+        Instruction::I32Mul,
+        Instruction::GlobalSet(counter as u32),
+    ];
+    p.set_start(start_index).unwrap();
+    p
+}
+
+fn imports(memory: &Arc<Mutex<Vec<u8>>>) -> Imports {
+    let mut imports = Imports::new();
+    imports.global("env", "base", WasmValue::I32(4)).memory(
+        "env",
+        "memory",
+        memory.clone(),
+        Some(2),
+    );
+    imports
+}
+
+#[test]
+fn instantiates_in_order_and_runs_start() {
+    let engines = [Engine::Stack, Engine::Registers];
+    for engine in engines.iter() {
+        let config = InterpreterConfig {
+            engine: *engine,
+            ..Default::default()
+        };
+        let memory = Arc::new(Mutex::new(vec![0; 65536]));
+        let mut instance = Instance::new(program(), &config, imports(&memory)).unwrap();
+        assert_eq!(&memory.lock()[4..6], b"hi");
+        assert_eq!(instance.global("counter"), Some(WasmValue::I32(40)));
+        assert_eq!(instance.call("bump", &[]), Ok(vec![WasmValue::I32(41)]));
+        assert_eq!(instance.call_typed::<(), i32>("bump", ()), Ok(42));
+    }
+}
+
+#[test]
+fn exposes_exports_by_name() {
+    let memory = Arc::new(Mutex::new(vec![0; 65536]));
+    let mut instance = Instance::new(program(), &Default::default(), imports(&memory)).unwrap();
+    assert_eq!(instance.exports().len(), 4);
+    assert_eq!(
+        instance.function("bump"),
+        Some(&FunctionType {
+            inputs: vec![],
+            outputs: vec![ValueType::I32],
+        })
+    );
+    assert_eq!(instance.function("counter"), None);
+    let table = instance.table("table").unwrap();
+    assert_eq!(table.functions(), vec![None, Some(0)]);
+    // the memory is only imported
+    assert!(instance.memory("memory").is_none());
+
+    assert_eq!(instance.set_global("counter", WasmValue::I32(7)), Ok(()));
+    assert_eq!(instance.call_typed::<(), i32>("bump", ()), Ok(8));
+    assert_eq!(
+        instance.set_global("counter", WasmValue::I64(7)),
+        Err("global has a different type")
+    );
+    assert_eq!(
+        instance.set_global("base", WasmValue::I32(7)),
+        Err("global is immutable")
+    );
+}
+
+#[test]
+fn refuses_what_does_not_fit() {
+    let memory = Arc::new(Mutex::new(vec![0; 65536]));
+    let mut p = program();
+    p.add_data_segment(0, &[Instruction::I32Const(65535)], b"no")
+        .unwrap();
+    let refused = Instance::new(p, &Default::default(), imports(&memory));
+    assert_eq!(refused.err(), Some("data segment does not fit"));
+    // nothing's written unless every segment fits
+    assert_eq!(&memory.lock()[4..6], &[0, 0]);
+
+    let too_big = Arc::new(Mutex::new(vec![0; 3 * 65536]));
+    let refused = Instance::new(program(), &Default::default(), imports(&too_big));
+    assert!(refused.is_err());
+    let mut missing = Imports::new();
+    missing.memory("env", "memory", memory, Some(2));
+    assert_eq!(missing.unresolved(&program()), vec![("env", "base")]);
+    assert!(Instance::new(program(), &Default::default(), missing).is_err());
+}
+
+#[test]
+fn interpreters_make_up_what_they_are_not_given() {
+    // the imported memory and base start out zeroed, and the start function
+    // is left unrun
+    let mut interpreter = Interpreter::new(program()).unwrap();
+    assert_eq!(interpreter.memory.lock().len(), 65536);
+    assert_eq!(&interpreter.memory.lock()[..2], b"hi");
+    let mut execution = interpreter.call("bump", &[]).unwrap();
+    assert_eq!(execution.finish(), Ok(vec![WasmValue::I32(1)]));
+}
+
+#[test]
+fn writes_elements_into_imported_tables() {
+    let mut p = Program::new();
+    p.import_table("env", "table", 1, Some(3)).unwrap();
+    let (seven, seven_index) = p.create_function(&[], &[ValueType::I32]).unwrap();
+    seven.instructions = vec![Instruction::I32Const(7)];
+    p.add_element_segment(0, &[Instruction::I32Const(1)], &[seven_index])
+        .unwrap();
+
+    let table = SharedTable::new(2, Some(3));
+    let mut imports = Imports::new();
+    imports.table("env", "table", table.clone());
+    let instance = Instance::new(p.clone(), &Default::default(), imports).unwrap();
+    assert_eq!(table.functions(), vec![None, Some(0)]);
+    drop(instance);
+
+    // a table that could grow past the maximum the module allows
+    let mut imports = Imports::new();
+    imports.table("env", "table", SharedTable::new(2, None));
+    assert!(Instance::new(p.clone(), &Default::default(), imports).is_err());
+    // and one big enough for the module but not for its element
+    let mut imports = Imports::new();
+    imports.table("env", "table", SharedTable::new(1, Some(1)));
+    assert_eq!(
+        Instance::new(p, &Default::default(), imports).err(),
+        Some("elements segment does not fit")
+    );
+}
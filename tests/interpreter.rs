@@ -0,0 +1,480 @@
+use watson::*;
+
+fn run_with(
+    program: Program,
+    config: &InterpreterConfig,
+    name: &str,
+    params: &[WasmValue],
+) -> Result<Vec<WasmValue>, &'static str> {
+    let mut interpreter = Interpreter::with_config(program, config)?;
+    let mut executor = interpreter.call(name, params)?;
+    loop {
+        let response = match executor.next_unit()? {
+            ExecutionUnit::CallImport(x) if x.name == "add" => {
+                ExecutionResponse::AddValues(vec![WasmValue::I32(
+                    x.params[0].to_i32() * 10 + x.params[1].to_i32(),
+                )])
+            }
+            ExecutionUnit::Complete(v) => break Ok(v),
+            mut x => x.evaluate()?,
+        };
+        executor.execute(response)?;
+    }
+}
+
+// runs on every engine with otherwise the same config, which have to agree
+fn run_configured(
+    program: Program,
+    config: InterpreterConfig,
+    name: &str,
+    params: &[WasmValue],
+) -> Result<Vec<WasmValue>, &'static str> {
+    let on = |engine| InterpreterConfig { engine, ..config };
+    let stack = run_with(program.clone(), &on(Engine::Stack), name, params);
+    let registers = run_with(program.clone(), &on(Engine::Registers), name, params);
+    assert_eq!(stack, registers);
+    #[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+    assert_eq!(stack, run_with(program, &on(Engine::Jit), name, params));
+    stack
+}
+
+fn run(program: Program, name: &str, params: &[WasmValue]) -> Result<Vec<WasmValue>, &'static str> {
+    run_configured(program, InterpreterConfig::default(), name, params)
+}
+
+#[test]
+fn runs_loops_and_recursion() {
+    let mut p = Program::new();
+    let (fib, _) = p
+        .create_export("fib", &[ValueType::I32], &[ValueType::I32])
+        .unwrap();
+    fib.instructions = vec![
+        Instruction::LocalGet(0),
+        Instruction::I32Const(2),
+        Instruction::I32LtS,
+        Instruction::If(
+            ValueType::I32.into_wasm_byte(),
+            vec![Instruction::LocalGet(0)],
+            Some(vec![
+                Instruction::LocalGet(0),
+                Instruction::I32Const(1),
+                Instruction::I32Sub,
+                Instruction::Call(0),
+                Instruction::LocalGet(0),
+                Instruction::I32Const(2),
+                Instruction::I32Sub,
+                Instruction::Call(0),
+                Instruction::I32Add,
+            ]),
+        ),
+    ];
+
+    let mut f = FunctionBuilder::new(&p, &[ValueType::I32], &[ValueType::I32]);
+    f.name_param(0, "n");
+    f.local("sum", ValueType::I32);
+    let done = f.begin_block(None);
+    let again = f.begin_loop(None);
+    f.local_get("n").emit(Instruction::I32Eqz).br_if(done);
+    f.local_get("sum")
+        .local_get("n")
+        .emit(Instruction::I32Add)
+        .local_set("sum");
+    f.local_get("n")
+        .emit(Instruction::I32Const(1))
+        .emit(Instruction::I32Sub)
+        .local_set("n");
+    f.br(again).end().end();
+    f.local_get("sum");
+    let sum = f.build(&mut p).unwrap();
+    let (sum_export, _) = p
+        .create_export("sum", &[ValueType::I32], &[ValueType::I32])
+        .unwrap();
+    sum_export.instructions = vec![Instruction::LocalGet(0), Instruction::Call(sum as u32)];
+
+    assert_eq!(
+        run(p.clone(), "fib", &[WasmValue::I32(10)]),
+        Ok(vec![WasmValue::I32(55)])
+    );
+    assert_eq!(
+        run(p, "sum", &[WasmValue::I32(100)]),
+        Ok(vec![WasmValue::I32(5050)])
+    );
+}
+
+#[test]
+fn uses_memory_imports_and_tables() {
+    let mut p = Program::new();
+    let add = p
+        .create_import("add", &[ValueType::I32, ValueType::I32], &[ValueType::I32])
+        .unwrap();
+    p.create_memory("memory", 1, Some(2)).unwrap();
+    let (seven, seven_index) = p.create_function(&[], &[ValueType::I32]).unwrap();
+    seven.instructions = vec![Instruction::I32Const(7)];
+    p.create_table(1, None).unwrap();
+    p.add_element_segment(0, &[Instruction::I32Const(0)], &[seven_index])
+        .unwrap();
+    let (main, _) = p.create_export("main", &[], &[ValueType::I32]).unwrap();
+    main.instructions = vec![
+        // store 1 then 2 into memory and hand both to the import in order
+        Instruction::I32Const(8),
+        Instruction::I32Const(1),
+        Instruction::I32Store(2, 0),
+        Instruction::I32Const(8),
+        Instruction::I32Const(2),
+        Instruction::I32Store(2, 4),
+        Instruction::I32Const(8),
+        Instruction::I32Load(2, 0),
+        Instruction::I32Const(8),
+        Instruction::I32Load(2, 4),
+        Instruction::Call(add as u32),
+        // plus a call through the table
+        Instruction::I32Const(0),
+        Instruction::CallIndirect(1),
+        Instruction::I32Add,
+        // plus the page count before and after growing past the maximum
+        Instruction::I32Const(1),
+        Instruction::MemoryGrow,
+        Instruction::I32Add,
+        Instruction::I32Const(1),
+        Instruction::MemoryGrow,
+        Instruction::I32Add,
+        Instruction::MemorySize,
+        Instruction::I32Add,
+    ];
+    assert_eq!(
+        run(p, "main", &[]),
+        Ok(vec![WasmValue::I32(12 + 7 + 1 - 1 + 2)])
+    );
+}
+
+#[test]
+fn calls_imports_from_deep_in_compiled_code() {
+    let mut p = Program::new();
+    let add = p
+        .create_import("add", &[ValueType::I32, ValueType::I32], &[ValueType::I32])
+        .unwrap();
+    let (inner, inner_index) = p
+        .create_function(&[ValueType::I32, ValueType::I32], &[ValueType::I32])
+        .unwrap();
+    inner.instructions = vec![
+        Instruction::LocalGet(0),
+        Instruction::LocalGet(1),
+        Instruction::Call(add as u32),
+        Instruction::I32Const(1),
+        Instruction::I32Add,
+    ];
+    let (main, _) = p
+        .create_export("main", &[ValueType::I32], &[ValueType::I64])
+        .unwrap();
+    main.locals = vec![LocalCount {
+        count: 1,
+        value_type: ValueType::I32,
+    }];
+    // an i64 and an f64 wait on the stack while the import is called
+    main.instructions = vec![
+        Instruction::I64Const(100),
+        Instruction::F64Const(2.5),
+        Instruction::LocalGet(0),
+        Instruction::I32Const(3),
+        Instruction::Call(inner_index as u32),
+        Instruction::LocalSet(1),
+        Instruction::I64ReinterpretF64,
+        Instruction::I64Add,
+        Instruction::LocalGet(1),
+        Instruction::I64ExtendUI32,
+        Instruction::I64Add,
+    ];
+    assert_eq!(
+        run(p, "main", &[WasmValue::I32(4)]),
+        Ok(vec![WasmValue::I64(100 + 2.5f64.to_bits() as i64 + 44)])
+    );
+}
+
+#[test]
+fn traps() {
+    let mut p = Program::new();
+    let (div, _) = p
+        .create_export("div", &[ValueType::I32, ValueType::I32], &[ValueType::I32])
+        .unwrap();
+    div.instructions = vec![
+        Instruction::LocalGet(0),
+        Instruction::LocalGet(1),
+        Instruction::I32DivS,
+    ];
+    let (load, _) = p.create_export("load", &[], &[ValueType::I32]).unwrap();
+    load.instructions = vec![Instruction::I32Const(0), Instruction::I32Load(2, 0)];
+    let (trap, _) = p.create_export("trap", &[], &[]).unwrap();
+    trap.instructions = vec![Instruction::Unreachable];
+
+    let div = |a, b| run(p.clone(), "div", &[WasmValue::I32(a), WasmValue::I32(b)]);
+    assert_eq!(div(7, 2), Ok(vec![WasmValue::I32(3)]));
+    assert_eq!(div(1, 0), Err("integer divide by zero"));
+    assert_eq!(div(i32::MIN, -1), Err("integer overflow"));
+    assert_eq!(
+        run(p.clone(), "load", &[]),
+        Err("out of bounds memory access")
+    );
+    assert!(run(p.clone(), "trap", &[]).is_err());
+    assert_eq!(
+        run(p, "div", &[WasmValue::I64(1), WasmValue::I32(1)]).err(),
+        Some("parameter has the wrong type")
+    );
+}
+
+#[test]
+fn branches_carry_values() {
+    let mut p = Program::new();
+    let (classify, _) = p
+        .create_export("classify", &[ValueType::I32], &[ValueType::I32])
+        .unwrap();
+    // each way out of the outer block leaves junk under the value it carries
+    classify.instructions = vec![
+        Instruction::Block(
+            ValueType::I32.into_wasm_byte(),
+            vec![
+                Instruction::Block(
+                    webassembly::EMPTY,
+                    vec![
+                        Instruction::Block(
+                            webassembly::EMPTY,
+                            vec![Instruction::LocalGet(0), Instruction::BrTable(vec![0], 1)],
+                        ),
+                        Instruction::I32Const(9),
+                        Instruction::I32Const(100),
+                        Instruction::I32Const(1),
+                        Instruction::BrIf(1),
+                        Instruction::Drop,
+                        Instruction::Drop,
+                    ],
+                ),
+                Instruction::I32Const(7),
+                Instruction::LocalGet(0),
+                Instruction::I32Const(200),
+                Instruction::I32Add,
+                Instruction::LocalTee(0),
+                Instruction::Br(0),
+            ],
+        ),
+        Instruction::I32Const(1),
+        Instruction::I32Const(2),
+        Instruction::LocalGet(0),
+        Instruction::Select,
+        Instruction::I32Add,
+    ];
+    let classify = |x| run(p.clone(), "classify", &[WasmValue::I32(x)]);
+    assert_eq!(classify(0), Ok(vec![WasmValue::I32(102)]));
+    assert_eq!(classify(5), Ok(vec![WasmValue::I32(206)]));
+}
+
+#[test]
+fn rejects_unbalanced_stacks() {
+    let lowers = |instructions: Vec<Instruction>, params: &[ValueType]| {
+        let mut p = Program::new();
+        let (f, _) = p.create_export("f", params, &[ValueType::I32]).unwrap();
+        f.instructions = instructions;
+        Interpreter::new(p).map(|_| ())
+    };
+    let i32_block = ValueType::I32.into_wasm_byte();
+    assert_eq!(lowers(vec![], &[]), Err("type mismatch"));
+    assert_eq!(
+        lowers(
+            vec![Instruction::Block(i32_block, vec![Instruction::Br(0)])],
+            &[]
+        ),
+        Err("value stack underflow")
+    );
+    assert_eq!(
+        lowers(vec![Instruction::Return], &[]),
+        Err("value stack underflow")
+    );
+    assert_eq!(
+        lowers(vec![Instruction::Call(0)], &[ValueType::I32]),
+        Err("value stack underflow")
+    );
+    // a block can't take what's on the stack below it
+    assert_eq!(
+        lowers(
+            vec![
+                Instruction::I32Const(1),
+                Instruction::Block(i32_block, vec![Instruction::BrIf(0)]),
+            ],
+            &[]
+        ),
+        Err("value stack underflow")
+    );
+    assert_eq!(
+        lowers(
+            vec![
+                Instruction::I32Const(1),
+                Instruction::If(i32_block, vec![Instruction::I32Const(2)], None),
+            ],
+            &[]
+        ),
+        Err("type mismatch")
+    );
+    assert_eq!(lowers(vec![Instruction::I32Const(1)], &[]), Ok(()));
+}
+
+#[test]
+fn limits_how_many_locals_a_function_has() {
+    let lowers = |counts: &[u32]| {
+        let mut p = Program::new();
+        let (f, _) = p.create_export("f", &[ValueType::I32], &[]).unwrap();
+        f.locals = counts
+            .iter()
+            .map(|&count| LocalCount {
+                count,
+                value_type: ValueType::I64,
+            })
+            .collect();
+        Interpreter::new(p).map(|_| ())
+    };
+    assert_eq!(lowers(&[49_000, 999]), Ok(()));
+    // the parameter counts too
+    assert_eq!(
+        lowers(&[49_000, 1_000]),
+        Err("a function can't have more than 50,000 locals")
+    );
+    assert_eq!(
+        lowers(&[u32::MAX, u32::MAX]),
+        Err("a function can't have more than 50,000 locals")
+    );
+}
+
+// runs a metered call, topping it up with `fuel` each time it runs out
+fn run_metered(
+    program: Program,
+    engine: Engine,
+    costs: CostTable,
+    fuel: u64,
+    params: &[WasmValue],
+) -> (Vec<WasmValue>, u64, usize) {
+    let config = InterpreterConfig {
+        engine,
+        fuel: Some(fuel),
+        costs,
+        ..Default::default()
+    };
+    let mut interpreter = Interpreter::with_config(program, &config).unwrap();
+    let mut executor = interpreter.call("sum", params).unwrap();
+    let mut refills = 0;
+    loop {
+        match executor.next_unit().unwrap() {
+            ExecutionUnit::OutOfFuel => {
+                refills += 1;
+                executor.add_fuel(fuel);
+            }
+            ExecutionUnit::Complete(v) => break (v, executor.fuel_consumed(), refills),
+            _ => panic!("unexpected execution unit"),
+        }
+    }
+}
+
+#[test]
+fn meters_fuel() {
+    let mut p = Program::new();
+    let mut f = FunctionBuilder::new(&p, &[ValueType::I32], &[ValueType::I32]);
+    f.name_param(0, "n");
+    f.local("sum", ValueType::I32);
+    let again = f.begin_loop(None);
+    f.local_get("sum")
+        .local_get("n")
+        .emit(Instruction::I32Add)
+        .local_set("sum");
+    f.local_get("n")
+        .emit(Instruction::I32Const(1))
+        .emit(Instruction::I32Sub)
+        .local_tee("n")
+        .br_if(again)
+        .end();
+    f.local_get("sum");
+    let sum = f.build(&mut p).unwrap();
+    let (export, _) = p
+        .create_export("sum", &[ValueType::I32], &[ValueType::I32])
+        .unwrap();
+    export.instructions = vec![Instruction::LocalGet(0), Instruction::Call(sum as u32)];
+
+    // the call, the loop, 100 runs of its 9 instructions and the result
+    let costs = CostTable::default();
+    let (result, consumed, refills) = run_metered(
+        p.clone(),
+        Engine::Stack,
+        costs,
+        1_000_000,
+        &[WasmValue::I32(100)],
+    );
+    assert_eq!(result, vec![WasmValue::I32(5050)]);
+    assert_eq!(refills, 0);
+    assert_eq!(consumed, 2 + 1 + 100 * 9 + 1);
+
+    let engines = [Engine::Stack, Engine::Registers];
+    for engine in engines.iter() {
+        // running out along the way only pauses the call
+        let (result, refills_consumed, refills) =
+            run_metered(p.clone(), *engine, costs, 25, &[WasmValue::I32(100)]);
+        assert_eq!(result, vec![WasmValue::I32(5050)]);
+        assert_eq!(refills_consumed, consumed);
+        assert!(refills > 30);
+
+        let costs = costs.with(webassembly::I32_ADD, 11);
+        let (_, weighted, _) =
+            run_metered(p.clone(), *engine, costs, 1_000, &[WasmValue::I32(100)]);
+        assert_eq!(weighted, consumed + 100 * 10);
+    }
+}
+
+#[test]
+fn exhausts_the_call_stack() {
+    let mut p = Program::new();
+    let (depth, _) = p
+        .create_export("depth", &[ValueType::I32], &[ValueType::I32])
+        .unwrap();
+    depth.instructions = vec![
+        Instruction::LocalGet(0),
+        Instruction::If(
+            ValueType::I32.into_wasm_byte(),
+            vec![
+                Instruction::LocalGet(0),
+                Instruction::I32Const(1),
+                Instruction::I32Sub,
+                Instruction::Call(0),
+                Instruction::I32Const(1),
+                Instruction::I32Add,
+            ],
+            Some(vec![Instruction::I32Const(0)]),
+        ),
+    ];
+    let depth = |config, n| run_configured(p.clone(), config, "depth", &[WasmValue::I32(n)]);
+
+    let shallow = InterpreterConfig {
+        max_call_depth: 100,
+        ..Default::default()
+    };
+    assert_eq!(depth(shallow, 99), Ok(vec![WasmValue::I32(99)]));
+    assert_eq!(depth(shallow, 100), Err("call stack exhausted"));
+    assert_eq!(
+        depth(InterpreterConfig::default(), 1_000_000),
+        Err("call stack exhausted")
+    );
+
+    let cramped = InterpreterConfig {
+        max_stack_values: 200,
+        ..Default::default()
+    };
+    assert_eq!(depth(cramped, 10), Ok(vec![WasmValue::I32(10)]));
+    assert_eq!(depth(cramped, 200), Err("call stack exhausted"));
+
+    // compiled code only nests as deep as the native stack has room for
+    #[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+    {
+        let deep = InterpreterConfig {
+            engine: Engine::Jit,
+            max_call_depth: 20_000,
+            ..Default::default()
+        };
+        assert_eq!(
+            Interpreter::with_config(p.clone(), &deep).err(),
+            Some("the jit can't nest calls deeper than 10,000")
+        );
+    }
+}
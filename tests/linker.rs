@@ -0,0 +1,244 @@
+use watson::*;
+
+fn math() -> Program {
+    let mut p = Program::new();
+    let (add, _) = p
+        .create_export("add", &[ValueType::I32, ValueType::I32], &[ValueType::I32])
+        .unwrap();
+    add.instructions = vec![
+        Instruction::LocalGet(0),
+        Instruction::LocalGet(1),
+        Instruction::I32Add,
+    ];
+    p
+}
+
+fn app() -> Program {
+    let mut p = Program::new();
+    let add = p
+        .create_import("add", &[ValueType::I32, ValueType::I32], &[ValueType::I32])
+        .unwrap();
+    let log = p.create_import("log", &[ValueType::I32], &[]).unwrap();
+    if let Some(Section::Import(s)) = p
+        .sections
+        .iter_mut()
+        .find(|s| matches!(s, Section::Import(_)))
+    {
+        if let WasmImport::Function(f) = &mut s.imports[add] {
+            f.module_name = "math".to_string();
+        }
+    }
+    let (main, _) = p.create_export("main", &[], &[]).unwrap();
+    main.instructions = vec![
+        Instruction::I32Const(1),
+        Instruction::I32Const(2),
+        Instruction::Call(add as u32),
+        Instruction::Call(log as u32),
+    ];
+    p
+}
+
+#[test]
+fn resolves_imports_against_exports() {
+    let mut linked = link(&[("math", &math()), ("app", &app())]).unwrap();
+
+    // only the unresolved import is left, ahead of both modules' functions
+    let imports = linked
+        .sections
+        .iter()
+        .find_map(|s| match s {
+            Section::Import(s) => Some(&s.imports),
+            _ => None,
+        })
+        .unwrap();
+    assert_eq!(imports.len(), 1);
+    assert!(matches!(&imports[0], WasmImport::Function(f) if f.name == "log"));
+
+    let main = linked.find_exported_function("main").unwrap();
+    assert_eq!(main.index, 2);
+    let code = linked.find_code_block(1).unwrap();
+    assert_eq!(code.instructions[2], Instruction::Call(1));
+    assert_eq!(code.instructions[3], Instruction::Call(0));
+
+    // the linked module still parses
+    let compiled = linked.compile();
+    assert_eq!(
+        parse(&compiled).unwrap().to_owned().sections,
+        linked.sections
+    );
+}
+
+#[test]
+fn relocates_position_independent_data() {
+    let mut a = Program::new();
+    a.create_memory("memory", 1, None).unwrap();
+    a.sections.push(Section::Data(DataSection {
+        data_blocks: vec![DataBlock {
+            memory: 0,
+            offset_expression: vec![Instruction::I32Const(8)],
+            data: vec![1, 2, 3, 4],
+            passive: false,
+        }],
+    }));
+    let mut b = Program::new();
+    b.sections.push(Section::Import(ImportSection {
+        imports: vec![WasmImport::Global(GlobalImport {
+            module_name: "env".to_string(),
+            name: "__memory_base".to_string(),
+            value_type: ValueType::I32,
+            is_mutable: false,
+        })],
+    }));
+    b.import_memory("a", "memory", 1, None).unwrap();
+    b.sections.push(Section::Data(DataSection {
+        data_blocks: vec![DataBlock {
+            memory: 0,
+            offset_expression: vec![Instruction::GlobalGet(0)],
+            data: vec![5, 6],
+            passive: false,
+        }],
+    }));
+
+    let linked = link(&[("a", &a), ("b", &b)]).unwrap();
+    let data = linked
+        .sections
+        .iter()
+        .find_map(|s| match s {
+            Section::Data(s) => Some(&s.data_blocks),
+            _ => None,
+        })
+        .unwrap();
+    assert_eq!(data[1].offset_expression, vec![Instruction::I32Const(16)]);
+    assert!(!linked
+        .sections
+        .iter()
+        .any(|s| matches!(s, Section::Import(_))));
+}
+
+#[test]
+fn rejects_mismatched_signatures() {
+    let mut app = app();
+    if let Some(Section::Type(s)) = app
+        .sections
+        .iter_mut()
+        .find(|s| matches!(s, Section::Type(_)))
+    {
+        s.types[0].outputs.clear();
+    }
+    assert!(link(&[("math", &math()), ("app", &app)]).is_err());
+}
+
+// data for the memory of module "a"
+fn data_at(offset: i32, data: Vec<u8>) -> Program {
+    let mut p = Program::new();
+    p.import_memory("a", "memory", 1, None).unwrap();
+    p.sections.push(Section::Data(DataSection {
+        data_blocks: vec![DataBlock {
+            memory: 0,
+            offset_expression: vec![Instruction::I32Const(offset)],
+            data,
+            passive: false,
+        }],
+    }));
+    p
+}
+
+#[test]
+fn rejects_overlapping_constant_offsets() {
+    let mut a = Program::new();
+    a.create_memory("memory", 1, None).unwrap();
+    a.sections.push(Section::Data(DataSection {
+        data_blocks: vec![DataBlock {
+            memory: 0,
+            offset_expression: vec![Instruction::I32Const(8)],
+            data: vec![1, 2, 3, 4],
+            passive: false,
+        }],
+    }));
+    assert_eq!(
+        link(&[("a", &a), ("b", &data_at(10, vec![5]))]).unwrap_err(),
+        "linked modules place data at overlapping constant offsets"
+    );
+
+    let linked = link(&[("a", &a), ("b", &data_at(12, vec![5]))]).unwrap();
+    assert!(linked
+        .sections
+        .iter()
+        .any(|s| matches!(s, Section::Data(d) if d.data_blocks.len() == 2)));
+}
+
+#[test]
+fn rejects_indices_it_cannot_renumber() {
+    let mut app = app();
+    let (main, _) = app.create_export("broken", &[], &[]).unwrap();
+    main.instructions = vec![Instruction::Call(99)];
+    assert_eq!(
+        link(&[("math", &math()), ("app", &app)]).unwrap_err(),
+        "index out of range in linked module"
+    );
+
+    // a block type can only hold type indices below 0x40
+    let mut many = Program::new();
+    many.sections.push(Section::Type(TypeSection {
+        types: (0..0x40)
+            .map(|n| FunctionType {
+                inputs: vec![ValueType::I32; n],
+                outputs: vec![],
+            })
+            .collect(),
+    }));
+    let mut block = Program::new();
+    block.sections.push(Section::Type(TypeSection {
+        types: vec![FunctionType {
+            inputs: vec![],
+            outputs: vec![ValueType::I64, ValueType::I64],
+        }],
+    }));
+    block.sections.push(Section::Code(CodeSection {
+        code_blocks: vec![CodeBlock {
+            locals: vec![],
+            instructions: vec![Instruction::Block(0, vec![])],
+        }],
+    }));
+    assert_eq!(
+        link(&[("many", &many), ("block", &block)]).unwrap_err(),
+        "type index too large for a block type"
+    );
+}
+
+#[test]
+fn links_mvp_modules_into_an_mvp_module() {
+    let mut data = Program::new();
+    data.create_memory("memory", 1, None).unwrap();
+    let segment = |passive| DataBlock {
+        memory: 0,
+        offset_expression: if passive {
+            vec![]
+        } else {
+            vec![Instruction::I32Const(8)]
+        },
+        data: vec![1, 2, 3, 4],
+        passive,
+    };
+    data.sections.push(Section::Data(DataSection {
+        data_blocks: vec![segment(false)],
+    }));
+
+    let mut linked = link(&[("math", &math()), ("data", &data)]).unwrap();
+    assert!(!linked
+        .sections
+        .iter()
+        .any(|s| matches!(s, Section::DataCount(_))));
+    let compiled = linked.compile();
+    assert!(parse_with_config(&compiled, &ParserConfig::mvp()).is_ok());
+
+    // a passive segment still needs its count
+    data.sections.pop();
+    data.sections.push(Section::Data(DataSection {
+        data_blocks: vec![segment(true)],
+    }));
+    let mut linked = link(&[("math", &math()), ("data", &data)]).unwrap();
+    let compiled = linked.compile();
+    assert!(parse(&compiled).is_ok());
+    assert!(parse_with_config(&compiled, &ParserConfig::mvp()).is_err());
+}
@@ -0,0 +1,159 @@
+use std::fs;
+use watson::*;
+
+// pulls every `(module ... binary "..." ...)` out of a .wast script
+fn binary_modules(script: &str) -> Vec<Vec<u8>> {
+    let mut modules = vec![];
+    let mut rest = script;
+    while let Some(start) = rest.find("(module") {
+        rest = &rest[start + "(module".len()..];
+        let header = rest.trim_start();
+        let header = if header.starts_with('$') {
+            header
+                .trim_start_matches(|c: char| !c.is_whitespace())
+                .trim_start()
+        } else {
+            header
+        };
+        if !header.starts_with("binary") {
+            continue;
+        }
+        let mut body = header["binary".len()..].trim_start();
+        let mut bytes = vec![];
+        while body.starts_with('"') {
+            let (literal, remaining) = string_literal(&body[1..]);
+            bytes.extend(literal);
+            body = remaining.trim_start();
+        }
+        modules.push(bytes);
+        rest = body;
+    }
+    modules
+}
+
+fn string_literal(input: &str) -> (Vec<u8>, &str) {
+    let bytes = input.as_bytes();
+    let mut out = vec![];
+    let mut i = 0;
+    while bytes[i] != b'"' {
+        if bytes[i] == b'\\' {
+            match bytes[i + 1] {
+                b'n' => out.push(b'\n'),
+                b't' => out.push(b'\t'),
+                b'r' => out.push(b'\r'),
+                b'\\' => out.push(b'\\'),
+                b'\'' => out.push(b'\''),
+                b'"' => out.push(b'"'),
+                b'u' => {
+                    let end = i + input[i..].find('}').unwrap();
+                    let c = u32::from_str_radix(&input[i + 3..end], 16).unwrap();
+                    let mut buf = [0; 4];
+                    out.extend(
+                        std::char::from_u32(c)
+                            .unwrap()
+                            .encode_utf8(&mut buf)
+                            .as_bytes(),
+                    );
+                    i = end + 1;
+                    continue;
+                }
+                _ => {
+                    out.push(u8::from_str_radix(&input[i + 1..i + 3], 16).unwrap());
+                    i += 3;
+                    continue;
+                }
+            }
+            i += 2;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    (out, &input[i + 1..])
+}
+
+#[test]
+fn unmodified_modules_round_trip_byte_for_byte() {
+    let mut checked = 0;
+    for entry in fs::read_dir("tests/core").unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().and_then(|e| e.to_str()) != Some("wast") {
+            continue;
+        }
+        let script = fs::read_to_string(&path).unwrap();
+        for bytes in binary_modules(&script) {
+            // malformed modules the parser rejects have nothing to round trip
+            let program = match parse(&bytes) {
+                Ok(p) => p,
+                Err(_) => continue,
+            };
+            assert_eq!(
+                program.to_owned().compile_as_parsed(&program),
+                bytes,
+                "round trip changed a module in {}",
+                path.display()
+            );
+            checked += 1;
+        }
+    }
+    assert!(checked > 0);
+}
+
+#[test]
+fn custom_sections_keep_their_place() {
+    let bytes = [
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, // header
+        0x01, 0x04, 0x01, 0x60, 0x00, 0x00, // type section
+        0x03, 0x02, 0x01, 0x00, // function section
+        0x0a, 0x04, 0x01, 0x02, 0x00, 0x0b, // code section
+        0x00, 0x05, 0x04, 0x6e, 0x61, 0x6d, 0x65, // "name" custom section
+    ];
+    let mut program = parse(&bytes).unwrap().to_owned();
+    // the type section is put back in its place, the custom section stays
+    // behind the code section
+    let types = program.sections.remove(0);
+    program.sections.push(types);
+    assert_eq!(program.compile(), bytes.to_vec());
+    assert!(matches!(program.sections.last(), Some(Section::Custom(_))));
+}
+
+#[test]
+fn recoded_sections_are_written_back_as_parsed() {
+    let bytes = [
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, // header
+        0x01, 0x85, 0x80, 0x80, 0x00, 0x01, 0x60, 0x00, 0x00, // padded type section
+        0x03, 0x02, 0x01, 0x00, // function section
+        0x0a, 0x04, 0x01, 0x02, 0x00, 0x0b, // code section
+    ];
+    let view = parse(&bytes).unwrap();
+    assert_eq!(view.raw_sections()[0], &bytes[8..17]);
+    let mut program = view.to_owned();
+    assert_eq!(program.compile_as_parsed(&view), bytes.to_vec());
+    let compiled = program.compile();
+    assert_eq!(&compiled[8..14], &[0x01, 0x04, 0x01, 0x60, 0x00, 0x00]);
+    assert_eq!(&compiled[14..], &bytes[17..]);
+    // how a section was encoded makes no difference to the program
+    assert_eq!(parse(&compiled).unwrap().to_owned(), program);
+}
+
+#[test]
+fn unknown_opcodes_pass_through() {
+    let bytes = [
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, // header
+        0x01, 0x04, 0x01, 0x60, 0x00, 0x00, // type section
+        0x03, 0x02, 0x01, 0x00, // function section
+        0x05, 0x03, 0x01, 0x00, 0x01, // memory section
+        0x0a, 0x11, 0x01, 0x0f, 0x00, // code section
+        0x41, 0x00, 0x41, 0x00, 0x41, 0x00, 0xfc, 0x0b, 0x00, // memory.fill
+        0x41, 0x7f, 0xc0, 0x1a, // i32.extend8_s, drop
+        0x0b,
+    ];
+    let mut program = parse(&bytes).unwrap().to_owned();
+    let code = program.find_code_block(0).unwrap();
+    assert_eq!(
+        code.instructions[3],
+        Instruction::Prefixed(0xfc, 11, vec![0x00])
+    );
+    assert_eq!(code.instructions[5], Instruction::Unknown(0xc0, vec![]));
+    assert_eq!(program.compile(), bytes.to_vec());
+}
@@ -0,0 +1,224 @@
+use watson::*;
+
+// counts n down, storing each count in memory, adding it to a global and
+// telling the host about it
+fn program() -> Program {
+    let mut p = Program::new();
+    let tick = p
+        .import_function("env", "tick", &[ValueType::I32], &[])
+        .unwrap();
+    p.create_memory("memory", 1, None).unwrap();
+    let (_, total) = p
+        .create_global(ValueType::I32, true, &[Instruction::I32Const(0)])
+        .unwrap();
+    let mut f = FunctionBuilder::new(&p, &[ValueType::I32], &[ValueType::I32]);
+    f.name_param(0, "n");
+    let again = f.begin_loop(None);
+    f.local_get("n")
+        .emit(Instruction::I32Const(1))
+        .emit(Instruction::I32Sub)
+        .local_set("n");
+    f.local_get("n")
+        .emit(Instruction::I32Const(4))
+        .emit(Instruction::I32Mul)
+        .local_get("n")
+        .emit(Instruction::I32Const(1))
+        .emit(Instruction::I32Add)
+        .emit(Instruction::I32Store(2, 0));
+    f.emit(Instruction::GlobalGet(total as u32))
+        .local_get("n")
+        .emit(Instruction::I32Add)
+        .emit(Instruction::GlobalSet(total as u32));
+    f.local_get("n")
+        .emit(Instruction::Call(tick as u32))
+        .local_get("n")
+        .br_if(again)
+        .end();
+    f.emit(Instruction::GlobalGet(total as u32));
+    let fill = f.build(&mut p).unwrap();
+    let (export, _) = p
+        .create_export("fill", &[ValueType::I32], &[ValueType::I32])
+        .unwrap();
+    export.instructions = vec![Instruction::LocalGet(0), Instruction::Call(fill as u32)];
+    p
+}
+
+// runs until the call completes or `pause` says to stop at a tick
+fn run(
+    executor: &mut WasmExecution<Program>,
+    pause: impl Fn(i32) -> bool,
+) -> Option<Vec<WasmValue>> {
+    loop {
+        let response = match executor.next_unit().unwrap() {
+            ExecutionUnit::CallImport(x) if pause(x.params[0].to_i32()) => return None,
+            ExecutionUnit::CallImport(_) => ExecutionResponse::DoNothing,
+            ExecutionUnit::OutOfFuel => return None,
+            ExecutionUnit::Complete(v) => return Some(v),
+            mut x => x.evaluate().unwrap(),
+        };
+        executor.execute(response).unwrap();
+    }
+}
+
+#[test]
+fn restores_a_paused_call_on_another_interpreter() {
+    let engines = [Engine::Stack, Engine::Registers];
+    for engine in engines.iter() {
+        let config = InterpreterConfig {
+            engine: *engine,
+            ..Default::default()
+        };
+        let mut whole = Interpreter::with_config(program(), &config).unwrap();
+        let mut executor = whole.call("fill", &[WasmValue::I32(100)]).unwrap();
+        let expected = run(&mut executor, |_| false);
+        assert_eq!(expected, Some(vec![WasmValue::I32(4950)]));
+
+        for compress in [false, true].iter() {
+            let mut first = Interpreter::with_config(program(), &config).unwrap();
+            let mut executor = first.call("fill", &[WasmValue::I32(100)]).unwrap();
+            assert_eq!(run(&mut executor, |n| n == 40), None);
+            let snapshot = executor.snapshot(*compress).unwrap();
+            drop(executor);
+            drop(first);
+
+            let mut second = Interpreter::with_config(program(), &config).unwrap();
+            let mut executor = second.restore(&snapshot).unwrap();
+            // the host answers the import the call was paused at
+            executor.execute(ExecutionResponse::DoNothing).unwrap();
+            assert_eq!(run(&mut executor, |_| false), expected);
+            assert_eq!(*second.memory.lock(), *whole.memory.lock());
+            assert_eq!(*second.globals.lock(), *whole.globals.lock());
+        }
+    }
+}
+
+#[test]
+fn restores_fuel_and_where_it_ran_out() {
+    let config = InterpreterConfig {
+        fuel: Some(500),
+        ..Default::default()
+    };
+    let mut first = Interpreter::with_config(program(), &config).unwrap();
+    let mut executor = first.call("fill", &[WasmValue::I32(100)]).unwrap();
+    assert_eq!(run(&mut executor, |_| false), None);
+    let snapshot = executor.snapshot(true).unwrap();
+    assert_eq!(snapshot.fuel_consumed, executor.fuel_consumed());
+
+    let mut second = Interpreter::with_config(program(), &config).unwrap();
+    let mut executor = second.restore(&snapshot).unwrap();
+    assert_eq!(executor.fuel(), snapshot.fuel);
+    executor.add_fuel(u64::MAX / 2);
+    assert_eq!(
+        run(&mut executor, |_| false),
+        Some(vec![WasmValue::I32(4950)])
+    );
+}
+
+#[test]
+fn refuses_snapshots_of_other_modules() {
+    let mut first = Interpreter::new(program()).unwrap();
+    let mut executor = first.call("fill", &[WasmValue::I32(10)]).unwrap();
+    run(&mut executor, |n| n == 5);
+    let snapshot = executor.snapshot(false).unwrap();
+
+    let registers = InterpreterConfig {
+        engine: Engine::Registers,
+        ..Default::default()
+    };
+    let mut other = Interpreter::with_config(program(), &registers).unwrap();
+    assert_ne!(other.module_hash(), first.module_hash());
+    assert_eq!(
+        other.restore(&snapshot).err(),
+        Some("snapshot was taken of a different module")
+    );
+
+    let mut changed = program();
+    changed
+        .create_global(ValueType::I64, false, &[Instruction::I64Const(0)])
+        .unwrap();
+    assert!(Interpreter::new(changed)
+        .unwrap()
+        .restore(&snapshot)
+        .is_err());
+}
+
+#[test]
+fn round_trips_through_serde() {
+    for engine in [Engine::Stack, Engine::Registers].iter() {
+        let config = InterpreterConfig {
+            engine: *engine,
+            ..Default::default()
+        };
+        let mut first = Interpreter::with_config(program(), &config).unwrap();
+        let mut executor = first.call("fill", &[WasmValue::I32(10)]).unwrap();
+        assert_eq!(run(&mut executor, |n| n == 5), None);
+        let snapshot = executor.snapshot(true).unwrap();
+        let json = serde_json::to_string(&snapshot).unwrap();
+        let restored: Snapshot = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored, snapshot);
+
+        let mut second = Interpreter::with_config(program(), &config).unwrap();
+        let mut executor = second.restore(&restored).unwrap();
+        executor.execute(ExecutionResponse::DoNothing).unwrap();
+        assert_eq!(
+            run(&mut executor, |_| false),
+            Some(vec![WasmValue::I32(45)])
+        );
+    }
+}
+
+#[test]
+fn refuses_frames_the_module_could_not_have() {
+    for engine in [Engine::Stack, Engine::Registers].iter() {
+        let config = InterpreterConfig {
+            engine: *engine,
+            ..Default::default()
+        };
+        let mut first = Interpreter::with_config(program(), &config).unwrap();
+        let mut executor = first.call("fill", &[WasmValue::I32(10)]).unwrap();
+        run(&mut executor, |n| n == 5);
+        let snapshot = executor.snapshot(false).unwrap();
+        let mut interpreter = Interpreter::with_config(program(), &config).unwrap();
+        let mut restore = |change: &dyn Fn(&mut Snapshot)| {
+            let mut corrupt = snapshot.clone();
+            change(&mut corrupt);
+            interpreter.restore(&corrupt).err()
+        };
+        let corrupt = Some("snapshot does not fit the module");
+        assert_eq!(restore(&|_| {}), None);
+        assert_eq!(
+            restore(&|s| s.value_stack.extend(vec![WasmValue::I32(1); 3])),
+            corrupt
+        );
+        assert_eq!(restore(&|s| s.call_stack[1].stack_base += 1), corrupt);
+        assert_eq!(
+            restore(&|s| s.call_stack[1].locals[0] = WasmValue::F64(0.0)),
+            corrupt
+        );
+        assert_eq!(restore(&|s| s.call_stack[0].pc = 0), corrupt);
+    }
+}
+
+#[test]
+fn compresses_memory_without_losing_any() {
+    let mut memory = vec![0; 65536];
+    memory[10..20].copy_from_slice(&[1; 10]);
+    memory[30000] = 7;
+    memory[65535] = 9;
+    let compressed = MemoryImage::new(&memory, true);
+    match &compressed {
+        MemoryImage::Compressed(len, bytes) => {
+            assert_eq!(*len, memory.len());
+            assert!(bytes.len() < 64);
+        }
+        MemoryImage::Raw(_) => panic!("memory wasn't compressed"),
+    }
+    assert_eq!(compressed.to_bytes().unwrap(), memory);
+    assert_eq!(
+        MemoryImage::new(&[], true).to_bytes().unwrap(),
+        Vec::<u8>::new()
+    );
+    assert!(MemoryImage::Compressed(4, vec![2, 3, 1, 1, 1])
+        .to_bytes()
+        .is_err());
+}
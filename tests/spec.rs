@@ -0,0 +1,384 @@
+use spin::Mutex;
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Arc;
+use wast::parser::{self, ParseBuffer};
+use wast::{AssertExpression, NanPattern, Wast, WastDirective, WastExecute, WastInvoke};
+use watson::*;
+
+// scripts every engine has to get right, assertion for assertion
+const SCRIPTS: &[&str] = &[
+    "address",
+    "align",
+    "block",
+    "br",
+    "br_if",
+    "br_table",
+    "call",
+    "call_indirect",
+    "const",
+    "data",
+    "elem",
+    "endianness",
+    "f32",
+    "f32_bitwise",
+    "f32_cmp",
+    "f64",
+    "f64_bitwise",
+    "f64_cmp",
+    "fac",
+    "float_exprs",
+    "float_literals",
+    "float_memory",
+    "float_misc",
+    "forward",
+    "func",
+    "func_ptrs",
+    "global",
+    "if",
+    "imports",
+    "int_exprs",
+    "int_literals",
+    "labels",
+    "left-to-right",
+    "linking",
+    "load",
+    "local_get",
+    "local_set",
+    "local_tee",
+    "loop",
+    "memory",
+    "memory_grow",
+    "memory_redundancy",
+    "memory_size",
+    "memory_trap",
+    "names",
+    "nop",
+    "return",
+    "select",
+    "skip-stack-guard-page",
+    "stack",
+    "start",
+    "store",
+    "switch",
+    "traps",
+    "unreachable",
+    "unwind",
+];
+
+// every engine, and each of them metered with more fuel than any script needs
+fn configs() -> Vec<InterpreterConfig> {
+    let engines = [
+        Engine::Stack,
+        Engine::Registers,
+        #[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+        Engine::Jit,
+    ];
+    let unmetered = engines.iter().map(|engine| InterpreterConfig {
+        engine: *engine,
+        ..Default::default()
+    });
+    let metered = engines.iter().map(|engine| InterpreterConfig {
+        engine: *engine,
+        fuel: Some(u64::MAX),
+        ..Default::default()
+    });
+    unmetered.chain(metered).collect()
+}
+
+// what an invocation came to, with floats as bits so NaNs compare equal
+type Outcome = Result<Vec<(ValueType, u64)>, &'static str>;
+
+fn bits(v: &WasmValue) -> (ValueType, u64) {
+    match *v {
+        WasmValue::I32(v) => (ValueType::I32, v as u32 as u64),
+        WasmValue::I64(v) => (ValueType::I64, v as u64),
+        WasmValue::F32(v) => (ValueType::F32, v.to_bits() as u64),
+        WasmValue::F64(v) => (ValueType::F64, v.to_bits()),
+    }
+}
+
+fn call(instance: &mut Instance<Program>, name: &str, params: &[WasmValue]) -> Outcome {
+    Ok(instance.call(name, params)?.iter().map(bits).collect())
+}
+
+// what the spec's harness gives every module to import, with the functions
+// that print doing nothing
+fn spectest() -> Imports {
+    let mut imports = Imports::new();
+    imports
+        .func("spectest", "print", |_: &mut Caller| Ok(()))
+        .func("spectest", "print_i32", |_: &mut Caller, _: i32| Ok(()))
+        .func("spectest", "print_i64", |_: &mut Caller, _: i64| Ok(()))
+        .func("spectest", "print_f32", |_: &mut Caller, _: f32| Ok(()))
+        .func("spectest", "print_f64", |_: &mut Caller, _: f64| Ok(()))
+        .func(
+            "spectest",
+            "print_i32_f32",
+            |_: &mut Caller, _: i32, _: f32| Ok(()),
+        )
+        .func(
+            "spectest",
+            "print_f64_f64",
+            |_: &mut Caller, _: f64, _: f64| Ok(()),
+        )
+        .global("spectest", "global_i32", WasmValue::I32(666))
+        .global("spectest", "global_i64", WasmValue::I64(666))
+        .global("spectest", "global_f32", WasmValue::F32(666.6))
+        .global("spectest", "global_f64", WasmValue::F64(666.6))
+        .table("spectest", "table", SharedTable::new(10, Some(20)))
+        .memory(
+            "spectest",
+            "memory",
+            Arc::new(Mutex::new(vec![0; 65536])),
+            Some(2),
+        );
+    imports
+}
+
+fn program(module: &mut wast::Module) -> Program {
+    let bytes = module.encode().unwrap();
+    parse(&bytes).unwrap().to_owned()
+}
+
+fn params(invoke: &WastInvoke) -> Option<Vec<WasmValue>> {
+    invoke
+        .args
+        .iter()
+        .map(|e| match &e.instrs[..] {
+            [wast::Instruction::I32Const(v)] => Some(WasmValue::I32(*v)),
+            [wast::Instruction::I64Const(v)] => Some(WasmValue::I64(*v)),
+            [wast::Instruction::F32Const(v)] => Some(WasmValue::F32(f32::from_bits(v.bits))),
+            [wast::Instruction::F64Const(v)] => Some(WasmValue::F64(f64::from_bits(v.bits))),
+            _ => None,
+        })
+        .collect()
+}
+
+fn matches(expected: &AssertExpression, (t, v): (ValueType, u64)) -> bool {
+    match expected {
+        AssertExpression::I32(e) => t == ValueType::I32 && v == *e as u32 as u64,
+        AssertExpression::I64(e) => t == ValueType::I64 && v == *e as u64,
+        AssertExpression::F32(NanPattern::Value(e)) => t == ValueType::F32 && v == e.bits as u64,
+        AssertExpression::F64(NanPattern::Value(e)) => t == ValueType::F64 && v == e.bits,
+        AssertExpression::F32(_) => t == ValueType::F32 && f32::from_bits(v as u32).is_nan(),
+        AssertExpression::F64(_) => t == ValueType::F64 && f64::from_bits(v).is_nan(),
+        _ => false,
+    }
+}
+
+// a store for each configuration, along with its instance of the current
+// module and of each named one
+struct Runner {
+    config: InterpreterConfig,
+    store: Store<Program>,
+    current: Option<usize>,
+    named: HashMap<String, usize>,
+}
+
+impl Runner {
+    fn instance(&self, id: Option<wast::Id>) -> Option<usize> {
+        match id {
+            Some(id) => self.named.get(id.name()).copied(),
+            None => self.current,
+        }
+    }
+}
+
+struct Script {
+    name: &'static str,
+    runners: Vec<Runner>,
+    checked: usize,
+}
+
+impl Script {
+    // instantiates a module on every configuration, which all have to be
+    // able to
+    fn instantiate(&mut self, module: &mut wast::Module, line: usize) {
+        let program = program(module);
+        let name = self.name;
+        for e in self.runners.iter_mut() {
+            let index = e
+                .store
+                .instantiate(program.clone(), &e.config, spectest())
+                .unwrap_or_else(|err| {
+                    panic!(
+                        "{}.wast:{} {:?} with fuel {:?} couldn't instantiate the module: {}",
+                        name, line, e.config.engine, e.config.fuel, err
+                    )
+                });
+            e.current = Some(index);
+            if let Some(id) = module.id {
+                e.named.insert(id.name().to_string(), index);
+            }
+        }
+    }
+
+    // does the same to an instance on every configuration, which have to
+    // agree on what it came to
+    fn agree(
+        &mut self,
+        module: Option<wast::Id>,
+        what: &str,
+        line: usize,
+        f: impl Fn(&mut Instance<Program>) -> Outcome,
+    ) -> Option<Outcome> {
+        let mut outcomes = self.runners.iter_mut().filter_map(|e| {
+            let index = e.instance(module)?;
+            let instance = e.store.instance(index)?;
+            Some((&e.config, f(instance)))
+        });
+        let (_, first) = outcomes.next()?;
+        for (config, outcome) in outcomes {
+            assert_eq!(
+                first, outcome,
+                "{}.wast:{} {:?} with fuel {:?} disagrees on {}",
+                self.name, line, config.engine, config.fuel, what
+            );
+        }
+        self.checked += 1;
+        Some(first)
+    }
+
+    fn run(&mut self, invoke: &WastInvoke, line: usize) -> Option<Outcome> {
+        let params = params(invoke)?;
+        self.agree(invoke.module, invoke.name, line, |instance| {
+            call(instance, invoke.name, &params)
+        })
+    }
+
+    fn get(&mut self, module: Option<wast::Id>, global: &str, line: usize) -> Option<Outcome> {
+        self.agree(module, global, line, |instance| {
+            let value = instance
+                .global(global)
+                .ok_or("could not find exported global")?;
+            Ok(vec![bits(&value)])
+        })
+    }
+
+    // checks a module can't be instantiated with any configuration
+    fn refuse(&mut self, module: &mut wast::Module, line: usize) {
+        for e in self.runners.iter_mut() {
+            let refused = e.store.instantiate(program(module), &e.config, spectest());
+            assert!(
+                refused.is_err(),
+                "{}.wast:{} {:?} with fuel {:?} instantiated a module it shouldn't have",
+                self.name,
+                line,
+                e.config.engine,
+                e.config.fuel
+            );
+        }
+        self.checked += 1;
+    }
+
+    // checks what an invocation or global came to is what's expected
+    fn returned(&self, outcome: Outcome, expected: &[AssertExpression], what: &str, line: usize) {
+        let values = outcome
+            .unwrap_or_else(|e| panic!("{}.wast:{} {} trapped: {}", self.name, line, what, e));
+        assert!(
+            values.len() == expected.len()
+                && expected.iter().zip(values).all(|(e, v)| matches(e, v)),
+            "{}.wast:{} {} returned the wrong values",
+            self.name,
+            line,
+            what
+        );
+    }
+}
+
+fn run_script(name: &'static str) -> usize {
+    let text = fs::read_to_string(format!("tests/core/{}.wast", name)).unwrap();
+    let buffer = ParseBuffer::new(&text).unwrap();
+    let wast: Wast = parser::parse(&buffer).unwrap();
+    let mut script = Script {
+        name,
+        runners: configs()
+            .into_iter()
+            .map(|config| Runner {
+                config,
+                store: Store::new(),
+                current: None,
+                named: HashMap::new(),
+            })
+            .collect(),
+        checked: 0,
+    };
+    for directive in wast.directives {
+        let line = directive.span().linecol_in(&text).0 + 1;
+        match directive {
+            WastDirective::Module(mut module) => script.instantiate(&mut module, line),
+            WastDirective::Register { name, module, .. } => {
+                for e in script.runners.iter_mut() {
+                    let index = e.instance(module).unwrap();
+                    e.store.register(name, index).unwrap();
+                }
+            }
+            WastDirective::AssertUnlinkable { mut module, .. }
+            | WastDirective::AssertTrap {
+                exec: WastExecute::Module(mut module),
+                ..
+            } => script.refuse(&mut module, line),
+            WastDirective::Invoke(invoke) => {
+                script.run(&invoke, line);
+            }
+            WastDirective::AssertReturn {
+                exec: WastExecute::Invoke(invoke),
+                results,
+                ..
+            } => {
+                if let Some(outcome) = script.run(&invoke, line) {
+                    script.returned(outcome, &results, invoke.name, line);
+                }
+            }
+            WastDirective::AssertReturn {
+                exec: WastExecute::Get { module, global },
+                results,
+                ..
+            } => {
+                if let Some(outcome) = script.get(module, global, line) {
+                    script.returned(outcome, &results, global, line);
+                }
+            }
+            WastDirective::AssertTrap {
+                exec: WastExecute::Invoke(invoke),
+                ..
+            } => {
+                if let Some(outcome) = script.run(&invoke, line) {
+                    assert!(
+                        outcome.is_err(),
+                        "{}.wast:{} {} didn't trap",
+                        name,
+                        line,
+                        invoke.name
+                    );
+                }
+            }
+            WastDirective::AssertExhaustion {
+                call: invoke,
+                message,
+                ..
+            } => {
+                if let Some(outcome) = script.run(&invoke, line) {
+                    assert_eq!(
+                        outcome,
+                        Err(message),
+                        "{}.wast:{} {} didn't exhaust the stack",
+                        name,
+                        line,
+                        invoke.name
+                    );
+                }
+            }
+            _ => {}
+        }
+    }
+    script.checked
+}
+
+#[test]
+fn spec_scripts_pass_on_every_engine() {
+    for name in SCRIPTS {
+        assert!(run_script(name) > 0, "nothing was checked in {}.wast", name);
+    }
+}
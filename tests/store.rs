@@ -0,0 +1,296 @@
+use watson::*;
+
+// stores a value in its memory, tells the host about it with an import and
+// hands back double the value
+fn library() -> Program {
+    let mut p = Program::new();
+    let log = p
+        .import_function("env", "log", &[ValueType::I32], &[])
+        .unwrap();
+    p.create_memory("memory", 1, None).unwrap();
+    let (_, seven) = p
+        .create_global(ValueType::I32, false, &[Instruction::I32Const(7)])
+        .unwrap();
+    p.export_global("seven", seven).unwrap();
+    let (_, counter) = p
+        .create_global(ValueType::I32, true, &[Instruction::I32Const(0)])
+        .unwrap();
+    p.export_global("counter", counter).unwrap();
+    let (put, _) = p
+        .create_export("put", &[ValueType::I32, ValueType::I32], &[ValueType::I32])
+        .unwrap();
+    put.instructions = vec![
+        Instruction::LocalGet(0),
+        Instruction::LocalGet(1),
+        Instruction::I32Store(2, 0),
+        Instruction::LocalGet(1),
+        Instruction::Call(log as u32),
+        Instruction::LocalGet(1),
+        Instruction::I32Const(2),
+        Instruction::I32Mul,
+    ];
+    p
+}
+
+// puts the library's constant through the library, then reads it back out
+// of the memory they share
+fn app() -> Program {
+    let mut p = Program::new();
+    let put = p
+        .import_function(
+            "lib",
+            "put",
+            &[ValueType::I32, ValueType::I32],
+            &[ValueType::I32],
+        )
+        .unwrap();
+    p.import_memory("lib", "memory", 1, None).unwrap();
+    let seven = p
+        .import_global("lib", "seven", ValueType::I32, false)
+        .unwrap();
+    let (main, _) = p
+        .create_export("main", &[ValueType::I32], &[ValueType::I32])
+        .unwrap();
+    main.instructions = vec![
+        Instruction::LocalGet(0),
+        Instruction::GlobalGet(seven as u32),
+        Instruction::Call(put as u32),
+        Instruction::LocalGet(0),
+        Instruction::I32Load(2, 0),
+        Instruction::I32Add,
+    ];
+    p
+}
+
+fn logging() -> Imports {
+    let mut imports = Imports::new();
+    imports.func("env", "log", |_: &mut Caller, _: i32| Ok(()));
+    imports
+}
+
+#[test]
+fn calls_into_registered_instances() {
+    let engines = [Engine::Stack, Engine::Registers];
+    for library_engine in engines.iter() {
+        for app_engine in engines.iter() {
+            let mut store = Store::new();
+            let config = InterpreterConfig {
+                engine: *library_engine,
+                ..Default::default()
+            };
+            let lib = store.instantiate(library(), &config, logging()).unwrap();
+            store.register("lib", lib).unwrap();
+            let config = InterpreterConfig {
+                engine: *app_engine,
+                ..Default::default()
+            };
+            let app = store.instantiate(app(), &config, Imports::new()).unwrap();
+
+            let instance = store.instance(app).unwrap();
+            assert_eq!(instance.call_typed::<i32, i32>("main", 8), Ok(21));
+            let memory = store.registered("lib").unwrap().memory("memory").unwrap();
+            assert_eq!(memory.lock()[8], 7);
+        }
+    }
+}
+
+#[test]
+fn pauses_and_resumes_inside_another_instance() {
+    let mut store = Store::new();
+    let config = InterpreterConfig {
+        fuel: Some(3),
+        ..Default::default()
+    };
+    let lib = store.instantiate(library(), &config, logging()).unwrap();
+    store.register("lib", lib).unwrap();
+    let app = store.instantiate(app(), &config, Imports::new()).unwrap();
+    let interpreter = store.instance(app).unwrap().interpreter();
+    let mut executor = interpreter.call("main", &[WasmValue::I32(0)]).unwrap();
+    let mut pauses = 0;
+    loop {
+        match executor.next_unit() {
+            Ok(ExecutionUnit::OutOfFuel) => {
+                pauses += 1;
+                executor.add_fuel(3);
+            }
+            Ok(ExecutionUnit::Complete(v)) => {
+                assert_eq!(v, vec![WasmValue::I32(21)]);
+                break;
+            }
+            x => panic!(
+                "expected the call to run out of fuel or complete, got {:?}",
+                x
+            ),
+        }
+    }
+    assert!(pauses > 1);
+}
+
+#[test]
+fn refuses_what_cannot_be_linked() {
+    let mut store = Store::new();
+    let lib = store
+        .instantiate(library(), &Default::default(), logging())
+        .unwrap();
+    assert_eq!(store.register("lib", 1), Err("invalid instance index"));
+    store.register("lib", lib).unwrap();
+
+    let mut wrong = app();
+    wrong.import_function("lib", "missing", &[], &[]).unwrap();
+    assert!(store
+        .instantiate(wrong, &Default::default(), Imports::new())
+        .is_err());
+
+    // the counter is mutable, so it can't be imported as a constant
+    let mut constant = Program::new();
+    constant
+        .import_global("lib", "counter", ValueType::I32, false)
+        .unwrap();
+    assert!(store
+        .instantiate(constant, &Default::default(), Imports::new())
+        .is_err());
+    assert!(store.instance(1).is_none());
+}
+
+// exports a table holding a function that returns 7, and a function that
+// calls whatever's at an index of it
+fn table_library() -> Program {
+    let mut p = Program::new();
+    let (seven, seven_index) = p.create_function(&[], &[ValueType::I32]).unwrap();
+    seven.instructions = vec![Instruction::I32Const(7)];
+    let (call, _) = p
+        .create_export("call", &[ValueType::I32], &[ValueType::I32])
+        .unwrap();
+    call.instructions = vec![Instruction::LocalGet(0), Instruction::CallIndirect(0)];
+    p.create_table(2, Some(4)).unwrap();
+    p.export_table("table", 0).unwrap();
+    p.add_element_segment(0, &[Instruction::I32Const(0)], &[seven_index])
+        .unwrap();
+    p
+}
+
+// puts a function that returns 11 at `offset` in the library's table, and
+// calls through the table the same way the library does
+fn table_app(min: usize, offset: i32) -> Program {
+    let mut p = Program::new();
+    p.import_table("lib", "table", min, None).unwrap();
+    let (eleven, eleven_index) = p.create_function(&[], &[ValueType::I32]).unwrap();
+    eleven.instructions = vec![Instruction::I32Const(11)];
+    let (call, _) = p
+        .create_export("call", &[ValueType::I32], &[ValueType::I32])
+        .unwrap();
+    call.instructions = vec![Instruction::LocalGet(0), Instruction::CallIndirect(0)];
+    p.add_element_segment(0, &[Instruction::I32Const(offset)], &[eleven_index])
+        .unwrap();
+    p
+}
+
+#[test]
+fn calls_functions_through_shared_tables() {
+    let engines = [Engine::Stack, Engine::Registers];
+    for library_engine in engines.iter() {
+        for app_engine in engines.iter() {
+            let mut store = Store::new();
+            let config = InterpreterConfig {
+                engine: *library_engine,
+                ..Default::default()
+            };
+            let lib = store
+                .instantiate(table_library(), &config, Imports::new())
+                .unwrap();
+            store.register("lib", lib).unwrap();
+            let config = InterpreterConfig {
+                engine: *app_engine,
+                ..Default::default()
+            };
+            let app = store
+                .instantiate(table_app(2, 1), &config, Imports::new())
+                .unwrap();
+
+            let app = store.instance(app).unwrap();
+            assert_eq!(app.call_typed::<i32, i32>("call", 0), Ok(7));
+            assert_eq!(app.call_typed::<i32, i32>("call", 1), Ok(11));
+            let lib = store.registered("lib").unwrap();
+            assert_eq!(lib.call_typed::<i32, i32>("call", 0), Ok(7));
+            assert_eq!(lib.call_typed::<i32, i32>("call", 1), Ok(11));
+            let table = lib.table("table").unwrap();
+            assert_eq!(table.functions(), vec![Some(0), Some(0)]);
+        }
+    }
+}
+
+#[test]
+fn checks_elements_fit_imported_tables() {
+    let mut store = Store::new();
+    let lib = store
+        .instantiate(table_library(), &Default::default(), Imports::new())
+        .unwrap();
+    store.register("lib", lib).unwrap();
+
+    // the table is smaller than the app says it has to be
+    assert!(store
+        .instantiate(table_app(3, 0), &Default::default(), Imports::new())
+        .is_err());
+    // the element lands past the end of the table, which is left alone
+    assert_eq!(
+        store.instantiate(table_app(2, 2), &Default::default(), Imports::new()),
+        Err("elements segment does not fit")
+    );
+    let lib = store.registered("lib").unwrap();
+    assert_eq!(lib.table("table").unwrap().functions(), vec![Some(0), None]);
+    assert_eq!(
+        lib.call_typed::<i32, i32>("call", 1),
+        Err("uninitialized element")
+    );
+}
+
+// bumps the library's counter, which it imports and exports again
+fn counting_app() -> Program {
+    let mut p = Program::new();
+    let counter = p
+        .import_global("lib", "counter", ValueType::I32, true)
+        .unwrap();
+    p.export_global("counter", counter).unwrap();
+    let (bump, _) = p.create_export("bump", &[], &[ValueType::I32]).unwrap();
+    bump.instructions = vec![
+        Instruction::GlobalGet(counter as u32),
+        Instruction::I32Const(1),
+        Instruction::I32Add,
+        Instruction::GlobalSet(counter as u32),
+        Instruction::GlobalGet(counter as u32),
+    ];
+    p
+}
+
+#[test]
+fn shares_mutable_globals() {
+    let engines = [Engine::Stack, Engine::Registers];
+    for engine in engines.iter() {
+        let mut store = Store::new();
+        let config = InterpreterConfig {
+            engine: *engine,
+            ..Default::default()
+        };
+        let lib = store.instantiate(library(), &config, logging()).unwrap();
+        store.register("lib", lib).unwrap();
+        let apps = [
+            store
+                .instantiate(counting_app(), &config, Imports::new())
+                .unwrap(),
+            store
+                .instantiate(counting_app(), &config, Imports::new())
+                .unwrap(),
+        ];
+
+        let first = store.instance(apps[0]).unwrap();
+        assert_eq!(first.call_typed::<(), i32>("bump", ()), Ok(1));
+        let second = store.instance(apps[1]).unwrap();
+        assert_eq!(second.call_typed::<(), i32>("bump", ()), Ok(2));
+        second.set_global("counter", WasmValue::I32(10)).unwrap();
+        let lib = store.registered("lib").unwrap();
+        assert_eq!(lib.global("counter"), Some(WasmValue::I32(10)));
+        let first = store.instance(apps[0]).unwrap();
+        assert_eq!(first.call_typed::<(), i32>("bump", ()), Ok(11));
+        assert_eq!(first.global("counter"), Some(WasmValue::I32(11)));
+    }
+}
@@ -0,0 +1,334 @@
+use std::sync::{Arc, Mutex};
+use wast::parser::{self, ParseBuffer};
+use watson::*;
+
+fn wasm(text: &str) -> Vec<u8> {
+    let buffer = ParseBuffer::new(text).unwrap();
+    let mut wat = parser::parse::<wast::Wat>(&buffer).unwrap();
+    wat.module.encode().unwrap()
+}
+
+const MODULE: &str = r#"(module
+  (memory 1)
+  (func $add (param $a i32) (param $b i32) (result i32)
+    local.get $a
+    local.get $b
+    i32.add)
+  (func $main (export "main") (result i32)
+    i32.const 8
+    i32.const 7
+    i32.store
+    i32.const 1
+    i32.const 2
+    call $add))"#;
+
+// runs main to completion, collecting what it traced
+fn trace(text: &str) -> Vec<TraceEvent> {
+    let p = parse(&wasm(text)).unwrap().to_owned();
+    let mut interpreter = Interpreter::new(p).unwrap();
+    let mut execution = interpreter.call("main", &[]).unwrap();
+    let events = Arc::new(Mutex::new(vec![]));
+    let recorded = events.clone();
+    execution
+        .trace(move |e: &TraceEvent| {
+            recorded.lock().unwrap().push(e.clone());
+            Ok(())
+        })
+        .unwrap();
+    execution.finish().unwrap();
+    let events = events.lock().unwrap().clone();
+    events
+}
+
+fn step(event: &TraceEvent) -> &TraceStep {
+    match event {
+        TraceEvent::Step(s) => s,
+        e => panic!("expected a step, got {:?}", e),
+    }
+}
+
+#[test]
+fn records_every_op() {
+    let events = trace(MODULE);
+    let positions: Vec<(u32, u32, u32)> = events
+        .iter()
+        .map(step)
+        .map(|s| (s.function, s.instruction, s.depth))
+        .collect();
+    let main: Vec<_> = (0..6).map(|i| (1, i, 1)).collect();
+    let add: Vec<_> = (0..4).map(|i| (0, i, 2)).collect();
+    assert_eq!(positions, [&main[..], &add[..], &[(1, 6, 1)]].concat());
+
+    let store = step(&events[2]);
+    assert_eq!(store.popped, 2);
+    assert_eq!(
+        store.write,
+        Some(MemoryWrite {
+            address: 8,
+            bytes: vec![7, 0, 0, 0]
+        })
+    );
+    // the call takes its parameters, the add both operands
+    assert_eq!(step(&events[5]).popped, 2);
+    let add = step(&events[8]);
+    assert_eq!((add.popped, &add.pushed[..]), (2, &[WasmValue::I32(3)][..]));
+    let end = step(&events[10]);
+    assert_eq!((end.popped, end.pushed.len()), (1, 0));
+}
+
+// runs main to completion under `tracer`
+fn run(tracer: impl Tracer + 'static) {
+    let p = parse(&wasm(MODULE)).unwrap().to_owned();
+    let mut interpreter = Interpreter::new(p).unwrap();
+    let mut execution = interpreter.call("main", &[]).unwrap();
+    execution.trace(tracer).unwrap();
+    execution.finish().unwrap();
+}
+
+type Buffer = Arc<Mutex<Vec<u8>>>;
+
+// somewhere for a trace to be written to, and what writes to it
+fn buffer() -> (Buffer, impl FnMut(&[u8]) -> Result<(), &'static str> + Send) {
+    let buffer = Arc::new(Mutex::new(vec![]));
+    let written = buffer.clone();
+    let write = move |b: &[u8]| {
+        written.lock().unwrap().extend_from_slice(b);
+        Ok(())
+    };
+    (buffer, write)
+}
+
+#[test]
+fn streams_binary_and_json() {
+    let (binary, write) = buffer();
+    run(BinaryTrace::new(write));
+    let (json, write) = buffer();
+    run(JsonTrace::new(write));
+
+    let events = read_trace(&binary.lock().unwrap()).unwrap();
+    assert_eq!(events, trace(MODULE));
+    assert!(read_trace(b"nope").is_err());
+    let truncated = binary.lock().unwrap().len() - 1;
+    assert!(read_trace(&binary.lock().unwrap()[..truncated]).is_err());
+
+    let json = String::from_utf8(json.lock().unwrap().clone()).unwrap();
+    let lines: Vec<&str> = json.lines().collect();
+    assert_eq!(lines.len(), events.len());
+    assert_eq!(
+        lines[2],
+        r#"{"Step":{"function":1,"instruction":2,"depth":1,"popped":2,"pushed":[],"write":{"address":8,"bytes":[7,0,0,0]}}}"#
+    );
+    assert_eq!(
+        lines[8],
+        r#"{"Step":{"function":0,"instruction":2,"depth":2,"popped":2,"pushed":[{"I32":3}],"write":null}}"#
+    );
+}
+
+#[test]
+fn records_imports_with_what_they_wrote() {
+    let p = parse(&wasm(
+        r#"(module
+          (import "env" "get" (func $get (result i32)))
+          (memory 1)
+          (func (export "main") (result i32)
+            call $get
+            i32.const 0
+            i32.load8_u
+            i32.add))"#,
+    ))
+    .unwrap()
+    .to_owned();
+    let mut interpreter = Interpreter::new(p).unwrap();
+    let memory = interpreter.memory.clone();
+    let mut execution = interpreter.call("main", &[]).unwrap();
+    let events = Arc::new(Mutex::new(vec![]));
+    let recorded = events.clone();
+    execution
+        .trace(move |e: &TraceEvent| {
+            recorded.lock().unwrap().push(e.clone());
+            Ok(())
+        })
+        .unwrap();
+    // left to the caller, who answers it
+    assert!(matches!(
+        execution.next_unit(),
+        Ok(ExecutionUnit::CallImport(_))
+    ));
+    memory.lock()[0] = 9;
+    execution
+        .execute(ExecutionResponse::AddValues(vec![WasmValue::I32(5)]))
+        .unwrap();
+    assert_eq!(execution.finish(), Ok(vec![WasmValue::I32(14)]));
+
+    let events = events.lock().unwrap();
+    assert_eq!(
+        events[1],
+        TraceEvent::Import(TraceImport {
+            module_name: "env".to_string(),
+            name: "get".to_string(),
+            params: vec![],
+            results: vec![WasmValue::I32(5)],
+            writes: vec![MemoryWrite {
+                address: 0,
+                bytes: vec![9]
+            }],
+        })
+    );
+    assert_eq!(step(&events[2]).pushed, vec![WasmValue::I32(0)]);
+
+    // handled by the interpreter itself
+    let p = parse(&wasm(
+        r#"(module
+          (import "env" "poke" (func $poke (param i32)))
+          (memory 1)
+          (func (export "main")
+            i32.const 3
+            call $poke))"#,
+    ))
+    .unwrap()
+    .to_owned();
+    let mut imports = Imports::new();
+    imports.func("env", "poke", |caller: &mut Caller, v: i32| {
+        caller.memory()[4] = v as u8;
+        Ok(())
+    });
+    let mut interpreter = Interpreter::with_imports(p, &Default::default(), imports).unwrap();
+    let mut execution = interpreter.call("main", &[]).unwrap();
+    let events = Arc::new(Mutex::new(vec![]));
+    let recorded = events.clone();
+    execution
+        .trace(move |e: &TraceEvent| {
+            recorded.lock().unwrap().push(e.clone());
+            Ok(())
+        })
+        .unwrap();
+    execution.finish().unwrap();
+    let events = events.lock().unwrap();
+    match &events[2] {
+        TraceEvent::Import(i) => {
+            assert_eq!(i.params, vec![WasmValue::I32(3)]);
+            assert_eq!(
+                i.writes,
+                vec![MemoryWrite {
+                    address: 4,
+                    bytes: vec![3]
+                }]
+            );
+        }
+        e => panic!("expected an import, got {:?}", e),
+    }
+}
+
+#[test]
+fn brackets_calls_into_other_instances() {
+    let mut store = Store::new();
+    let lib = wasm(
+        r#"(module
+          (func (export "double") (param i32) (result i32)
+            local.get 0
+            i32.const 2
+            i32.mul))"#,
+    );
+    let lib = parse(&lib).unwrap().to_owned();
+    let lib = store
+        .instantiate(lib, &Default::default(), Imports::new())
+        .unwrap();
+    store.register("lib", lib).unwrap();
+    let app = wasm(
+        r#"(module
+          (import "lib" "double" (func $double (param i32) (result i32)))
+          (func (export "main") (result i32)
+            i32.const 4
+            call $double))"#,
+    );
+    let app = parse(&app).unwrap().to_owned();
+    let app = store
+        .instantiate(app, &Default::default(), Imports::new())
+        .unwrap();
+    let interpreter = store.instance(app).unwrap().interpreter();
+    let mut execution = interpreter.call("main", &[]).unwrap();
+    let events = Arc::new(Mutex::new(vec![]));
+    let recorded = events.clone();
+    execution
+        .trace(move |e: &TraceEvent| {
+            recorded.lock().unwrap().push(e.clone());
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!(execution.finish(), Ok(vec![WasmValue::I32(8)]));
+
+    let events = events.lock().unwrap();
+    assert_eq!(
+        events[2],
+        TraceEvent::Enter {
+            module_name: "lib".to_string(),
+            name: "double".to_string(),
+            params: vec![WasmValue::I32(4)],
+        }
+    );
+    let inside: Vec<_> = events[3..7].iter().map(|e| step(e).depth).collect();
+    assert_eq!(inside, vec![2; 4]);
+    assert_eq!(
+        events[7],
+        TraceEvent::Leave {
+            results: vec![WasmValue::I32(8)]
+        }
+    );
+    assert_eq!(step(&events[8]).depth, 1);
+}
+
+#[test]
+fn finds_where_builds_diverge() {
+    let a = trace(MODULE);
+    let b = trace(&MODULE.replace("i32.const 2", "i32.const 3"));
+    assert_eq!(first_difference(&a, &a), None);
+    assert_eq!(first_difference(&a, &b), Some(4));
+    assert_eq!(first_difference(&a, &a[..3]), Some(3));
+}
+
+#[test]
+fn keeps_floats_json_has_no_numbers_for() {
+    let a = trace(
+        r#"(module
+  (func (export "main") (result f32)
+    f64.const inf
+    drop
+    f32.const nan))"#,
+    );
+    // NaNs don't equal themselves, but the same NaN is no divergence
+    assert_eq!(first_difference(&a, &a.clone()), None);
+
+    let (json, write) = buffer();
+    let mut tracer = JsonTrace::new(write);
+    for event in a.iter() {
+        tracer.record(event).unwrap();
+    }
+    let json = String::from_utf8(json.lock().unwrap().clone()).unwrap();
+    let lines: Vec<&str> = json.lines().collect();
+    assert!(lines[0].contains(r#""pushed":[{"F64":"0x7ff0000000000000"}]"#));
+    assert!(lines[2].contains(r#""pushed":[{"F32":"0x7fc00000"}]"#));
+}
+
+#[test]
+fn traces_calls_on_the_register_engine() {
+    let p = parse(&wasm(MODULE)).unwrap().to_owned();
+    let config = InterpreterConfig {
+        engine: Engine::Registers,
+        ..Default::default()
+    };
+    let mut interpreter = Interpreter::with_config(p, &config).unwrap();
+    let mut execution = interpreter.call("main", &[]).unwrap();
+    let events = Arc::new(Mutex::new(vec![]));
+    let recorded = events.clone();
+    execution
+        .trace(move |e: &TraceEvent| {
+            recorded.lock().unwrap().push(e.clone());
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!(execution.finish(), Ok(vec![WasmValue::I32(3)]));
+    assert_eq!(
+        first_difference(&events.lock().unwrap(), &trace(MODULE)),
+        None
+    );
+}
@@ -0,0 +1,73 @@
+use watson::*;
+
+// adds an i32 to an i64 as an f32, and splits an i64 into its halves
+fn program() -> Program {
+    let mut p = Program::new();
+    let (mix, _) = p
+        .create_export("mix", &[ValueType::I32, ValueType::I64], &[ValueType::F32])
+        .unwrap();
+    mix.instructions = vec![
+        Instruction::LocalGet(0),
+        Instruction::I64ExtendSI32,
+        Instruction::LocalGet(1),
+        Instruction::I64Add,
+        Instruction::F32ConvertSI64,
+    ];
+    let (split, _) = p
+        .create_export(
+            "split",
+            &[ValueType::I64],
+            &[ValueType::I32, ValueType::I32],
+        )
+        .unwrap();
+    split.instructions = vec![
+        Instruction::LocalGet(0),
+        Instruction::I64Const(32),
+        Instruction::I64ShrU,
+        Instruction::I32wrapF64,
+        Instruction::LocalGet(0),
+        Instruction::I32wrapF64,
+    ];
+    p
+}
+
+#[test]
+fn calls_exports_with_rust_values() {
+    let mut interpreter = Interpreter::new(program()).unwrap();
+    let mixed: f32 = interpreter.call_typed("mix", (-3, 10i64)).unwrap();
+    assert_eq!(mixed, 7.0);
+    let halves = interpreter.call_typed::<i64, (i32, i32)>("split", 0x1_0000_0002);
+    assert_eq!(halves, Ok((1, 2)));
+}
+
+#[test]
+fn checks_signatures_before_calling() {
+    let mut interpreter = Interpreter::new(program()).unwrap();
+    let wrong = "export has a different signature";
+    assert_eq!(
+        interpreter.call_typed::<(i32, i32), f32>("mix", (1, 2)),
+        Err(wrong)
+    );
+    assert_eq!(
+        interpreter.call_typed::<(i32, i64), f64>("mix", (1, 2)),
+        Err(wrong)
+    );
+    assert_eq!(interpreter.call_typed::<i64, i32>("split", 1), Err(wrong));
+    assert!(interpreter.call_typed::<(), ()>("missing", ()).is_err());
+}
+
+#[test]
+fn converts_values_exactly() {
+    assert_eq!(i32::from_values(&[WasmValue::I32(5)]), Some(5));
+    assert_eq!(i32::from_values(&[WasmValue::I64(5)]), None);
+    assert_eq!(<(i32, f64)>::from_values(&[WasmValue::I32(1)]), None);
+    assert_eq!(
+        <(i32, f64)>::from_values(&[WasmValue::I32(1), WasmValue::F64(0.5)]),
+        Some((1, 0.5))
+    );
+    assert_eq!(<()>::value_types(), vec![]);
+    assert_eq!(
+        (1i64, 2f32).into_values(),
+        vec![WasmValue::I64(1), WasmValue::F32(2.0)]
+    );
+}
@@ -0,0 +1,422 @@
+#![cfg(feature = "wasi")]
+use std::convert::TryInto;
+use wast::parser::{self, ParseBuffer};
+use watson::wasi::*;
+use watson::*;
+
+fn program(text: &str) -> Program {
+    let buffer = ParseBuffer::new(text).unwrap();
+    let mut wat = parser::parse::<wast::Wat>(&buffer).unwrap();
+    let bytes = wat.module.encode().unwrap();
+    parse(&bytes).unwrap().to_owned()
+}
+
+// traps on any error number that isn't 0
+const CHECK: &str = r#"
+  (func $check (param i32)
+    (if (local.get 0) (then unreachable)))
+"#;
+
+fn with_check(imports: &str, rest: &str) -> Program {
+    program(&format!(
+        "(module {} (memory (export \"memory\") 1) {} {})",
+        imports, CHECK, rest
+    ))
+}
+
+#[test]
+fn writes_to_stdout_and_exits() {
+    let p = program(
+        r#"(module
+          (import "wasi_snapshot_preview1" "fd_write"
+            (func $fd_write (param i32 i32 i32 i32) (result i32)))
+          (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 16) "hello\n")
+          (func (export "_start")
+            (i32.store (i32.const 0) (i32.const 16))
+            (i32.store (i32.const 4) (i32.const 6))
+            (drop (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8)))
+            (call $proc_exit (i32.const 3))))"#,
+    );
+    let wasi = Wasi::new();
+    let mut instance = Instance::new(p, &Default::default(), wasi.imports()).unwrap();
+    assert_eq!(wasi.run(&mut instance), Ok(3));
+    assert_eq!(wasi.stdout(), b"hello\n");
+    assert_eq!(wasi.exit_code(), Some(3));
+}
+
+// makes a directory, then copies what it reads from in.txt into a file in it
+fn copy() -> Program {
+    with_check(
+        r#"
+        (import "wasi_snapshot_preview1" "path_open"
+          (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+        (import "wasi_snapshot_preview1" "path_create_directory"
+          (func $mkdir (param i32 i32 i32) (result i32)))
+        (import "wasi_snapshot_preview1" "fd_read"
+          (func $fd_read (param i32 i32 i32 i32) (result i32)))
+        (import "wasi_snapshot_preview1" "fd_write"
+          (func $fd_write (param i32 i32 i32 i32) (result i32)))
+        (import "wasi_snapshot_preview1" "fd_close" (func $fd_close (param i32) (result i32)))
+        "#,
+        r#"
+        (data (i32.const 300) "in.txt")
+        (data (i32.const 310) "out/./copy.txt")
+        (data (i32.const 330) "out")
+        (func (export "_start")
+          (call $check (call $mkdir (i32.const 3) (i32.const 330) (i32.const 3)))
+          (call $check (call $path_open (i32.const 3) (i32.const 0) (i32.const 300)
+            (i32.const 6) (i32.const 0) (i64.const -1) (i64.const -1) (i32.const 0)
+            (i32.const 100)))
+          (i32.store (i32.const 0) (i32.const 200))
+          (i32.store (i32.const 4) (i32.const 64))
+          (call $check (call $fd_read (i32.load (i32.const 100)) (i32.const 0) (i32.const 1)
+            (i32.const 104)))
+          ;; creating and truncating
+          (call $check (call $path_open (i32.const 3) (i32.const 0) (i32.const 310)
+            (i32.const 14) (i32.const 9) (i64.const -1) (i64.const -1) (i32.const 0)
+            (i32.const 108)))
+          (i32.store (i32.const 4) (i32.load (i32.const 104)))
+          (call $check (call $fd_write (i32.load (i32.const 108)) (i32.const 0) (i32.const 1)
+            (i32.const 112)))
+          (call $check (call $fd_close (i32.load (i32.const 100))))
+          (call $check (call $fd_close (i32.load (i32.const 108)))))
+        "#,
+    )
+}
+
+#[test]
+fn reads_and_writes_files() {
+    let fs = MemoryFileSystem::new();
+    fs.insert_file("in.txt", b"copied").unwrap();
+    let mut wasi = Wasi::new();
+    wasi.preopen(".", fs.clone());
+    let mut instance = Instance::new(copy(), &Default::default(), wasi.imports()).unwrap();
+    assert_eq!(wasi.run(&mut instance), Ok(0));
+    assert_eq!(fs.contents("out/copy.txt"), Some(b"copied".to_vec()));
+}
+
+#[test]
+fn passes_args_environment_and_time() {
+    let p = with_check(
+        r#"
+        (import "wasi_snapshot_preview1" "args_sizes_get"
+          (func $args_sizes_get (param i32 i32) (result i32)))
+        (import "wasi_snapshot_preview1" "args_get" (func $args_get (param i32 i32) (result i32)))
+        (import "wasi_snapshot_preview1" "environ_get"
+          (func $environ_get (param i32 i32) (result i32)))
+        (import "wasi_snapshot_preview1" "clock_time_get"
+          (func $clock_time_get (param i32 i64 i32) (result i32)))
+        (import "wasi_snapshot_preview1" "poll_oneoff"
+          (func $poll_oneoff (param i32 i32 i32 i32) (result i32)))
+        "#,
+        r#"
+        (func (export "sizes") (result i32 i32)
+          (call $check (call $args_sizes_get (i32.const 0) (i32.const 4)))
+          (i32.load (i32.const 0))
+          (i32.load (i32.const 4)))
+        (func (export "args")
+          (call $check (call $args_get (i32.const 0) (i32.const 64))))
+        (func (export "environ")
+          (call $check (call $environ_get (i32.const 0) (i32.const 64))))
+        ;; waits 5ms on the monotonic clock, returning when it woke up
+        (func (export "sleep") (result i64)
+          (i64.store (i32.const 0) (i64.const 7))
+          (i32.store8 (i32.const 8) (i32.const 0))
+          (i32.store (i32.const 16) (i32.const 1))
+          (i64.store (i32.const 24) (i64.const 5000000))
+          (call $check (call $poll_oneoff (i32.const 0) (i32.const 100) (i32.const 1)
+            (i32.const 200)))
+          (call $check (call $clock_time_get (i32.const 1) (i64.const 1) (i32.const 208)))
+          (i64.load (i32.const 208)))
+        "#,
+    );
+    let mut wasi = Wasi::new();
+    wasi.arg("prog").arg("--flag").env("HOME", "/");
+    let mut instance = Instance::new(p, &Default::default(), wasi.imports()).unwrap();
+    let memory = instance.memory("memory").unwrap();
+
+    assert_eq!(
+        instance.call_typed::<(), (i32, i32)>("sizes", ()),
+        Ok((2, 12))
+    );
+    instance.call_typed::<(), ()>("args", ()).unwrap();
+    {
+        let memory = memory.lock();
+        assert_eq!(&memory[0..8], &[64, 0, 0, 0, 69, 0, 0, 0]);
+        assert_eq!(&memory[64..76], b"prog\0--flag\0");
+    }
+    instance.call_typed::<(), ()>("environ", ()).unwrap();
+    assert_eq!(&memory.lock()[64..71], b"HOME=/\0");
+
+    assert_eq!(instance.call_typed::<(), i64>("sleep", ()), Ok(5_000_000));
+    // the event says which subscription went off
+    let memory = memory.lock();
+    assert_eq!(memory[200], 1);
+    assert_eq!(memory[100], 7);
+}
+
+#[test]
+fn lists_directories() {
+    let p = with_check(
+        r#"
+        (import "wasi_snapshot_preview1" "fd_readdir"
+          (func $fd_readdir (param i32 i32 i32 i64 i32) (result i32)))
+        "#,
+        r#"
+        (func (export "list") (param i64 i32) (result i32)
+          (call $check (call $fd_readdir (i32.const 3) (i32.const 16) (local.get 1)
+            (local.get 0) (i32.const 0)))
+          (i32.load (i32.const 0)))
+        "#,
+    );
+    let fs = MemoryFileSystem::new();
+    fs.insert_file("b/nested", b"").unwrap();
+    fs.insert_file("a", b"").unwrap();
+    let mut wasi = Wasi::new();
+    wasi.preopen("/", fs);
+    let mut instance = Instance::new(p, &Default::default(), wasi.imports()).unwrap();
+    let memory = instance.memory("memory").unwrap();
+
+    let used = instance
+        .call_typed::<(i64, i32), i32>("list", (0, 1024))
+        .unwrap();
+    let mut entries = vec![];
+    let mut at = 16;
+    let end = 16 + used as usize;
+    {
+        let memory = memory.lock();
+        while at < end {
+            let next = u64::from_le_bytes(memory[at..at + 8].try_into().unwrap());
+            let len = u32::from_le_bytes(memory[at + 16..at + 20].try_into().unwrap()) as usize;
+            let name = String::from_utf8(memory[at + 24..at + 24 + len].to_vec()).unwrap();
+            entries.push((next, memory[at + 20], name));
+            at += 24 + len;
+        }
+    }
+    assert_eq!(
+        entries,
+        vec![
+            (1, 3, ".".to_string()),
+            (2, 3, "..".to_string()),
+            (3, 4, "a".to_string()),
+            (4, 3, "b".to_string()),
+        ]
+    );
+    // a full buffer means there's more to come from the last cookie
+    assert_eq!(
+        instance.call_typed::<(i64, i32), i32>("list", (3, 10)),
+        Ok(10)
+    );
+    assert_eq!(&memory.lock()[16..24], &4u64.to_le_bytes());
+}
+
+#[test]
+fn keeps_programs_inside_their_directories() {
+    let p = with_check(
+        r#"
+        (import "wasi_snapshot_preview1" "path_open"
+          (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+        (import "wasi_snapshot_preview1" "fd_prestat_dir_name"
+          (func $dir_name (param i32 i32 i32) (result i32)))
+        "#,
+        r#"
+        (data (i32.const 100) "a/../../secret")
+        (func (export "open") (result i32)
+          (call $path_open (i32.const 3) (i32.const 0) (i32.const 100) (i32.const 14)
+            (i32.const 0) (i64.const -1) (i64.const -1) (i32.const 0) (i32.const 0)))
+        (func (export "name") (param i32) (result i32)
+          (call $dir_name (local.get 0) (i32.const 0) (i32.const 16)))
+        "#,
+    );
+    let mut wasi = Wasi::new();
+    wasi.preopen("/sandbox", MemoryFileSystem::new());
+    let mut instance = Instance::new(p, &Default::default(), wasi.imports()).unwrap();
+    let open = instance.call_typed::<(), i32>("open", ());
+    assert_eq!(open, Ok(errno::NOTCAPABLE as i32));
+    assert_eq!(instance.call_typed::<i32, i32>("name", 3), Ok(0));
+    assert_eq!(&instance.memory("memory").unwrap().lock()[..8], b"/sandbox");
+    // standard streams aren't directories
+    let name = instance.call_typed::<i32, i32>("name", 1);
+    assert_eq!(name, Ok(errno::BADF as i32));
+}
+
+#[test]
+fn keeps_files_in_memory() {
+    let mut fs = MemoryFileSystem::new();
+    fs.insert_file("dir/file", b"abc").unwrap();
+    assert_eq!(fs.create_file("dir/file"), Err(errno::EXIST));
+    assert_eq!(fs.create_file("missing/file"), Err(errno::NOENT));
+    assert_eq!(fs.remove_dir("dir"), Err(errno::NOTEMPTY));
+    assert_eq!(fs.write("dir/file", 5, b"!"), Ok(1));
+    assert_eq!(fs.contents("dir/file"), Some(b"abc\0\0!".to_vec()));
+
+    fs.rename("dir", "moved").unwrap();
+    assert_eq!(fs.stat("dir/file").err(), Some(errno::NOENT));
+    let mut buf = [0; 8];
+    assert_eq!(fs.read("moved/file", 1, &mut buf), Ok(5));
+    assert_eq!(&buf[..5], b"bc\0\0!");
+    assert_eq!(fs.stat("moved").unwrap().file_type, FileType::Directory);
+    assert_eq!(
+        fs.read_dir("")
+            .unwrap()
+            .iter()
+            .map(|e| &e.name[..])
+            .collect::<Vec<_>>(),
+        vec!["moved"]
+    );
+}
+
+#[test]
+fn refuses_to_grow_files_past_the_maximum_size() {
+    let p = with_check(
+        r#"
+        (import "wasi_snapshot_preview1" "path_open"
+          (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+        (import "wasi_snapshot_preview1" "fd_filestat_set_size"
+          (func $set_size (param i32 i64) (result i32)))
+        (import "wasi_snapshot_preview1" "fd_allocate"
+          (func $allocate (param i32 i64 i64) (result i32)))
+        (import "wasi_snapshot_preview1" "fd_pwrite"
+          (func $pwrite (param i32 i32 i32 i64 i32) (result i32)))
+        "#,
+        r#"
+        (data (i32.const 100) "file")
+        (func (export "open") (result i32)
+          (call $check (call $path_open (i32.const 3) (i32.const 0) (i32.const 100)
+            (i32.const 4) (i32.const 1) (i64.const -1) (i64.const -1) (i32.const 0)
+            (i32.const 0)))
+          (i32.load (i32.const 0)))
+        (func (export "set_size") (param i32 i64) (result i32)
+          (call $set_size (local.get 0) (local.get 1)))
+        (func (export "allocate") (param i32 i64) (result i32)
+          (call $allocate (local.get 0) (i64.const 0) (local.get 1)))
+        (func (export "pwrite") (param i32 i64) (result i32)
+          (i32.store (i32.const 8) (i32.const 100))
+          (i32.store (i32.const 12) (i32.const 4))
+          (call $pwrite (local.get 0) (i32.const 8) (i32.const 1) (local.get 1)
+            (i32.const 16)))
+        "#,
+    );
+    let fs = MemoryFileSystem::new().with_max_file_size(16);
+    let mut wasi = Wasi::new();
+    wasi.preopen(".", fs.clone());
+    let mut instance = Instance::new(p, &Default::default(), wasi.imports()).unwrap();
+    let fd = instance.call_typed::<(), i32>("open", ()).unwrap();
+
+    let fbig = Ok(errno::FBIG as i32);
+    for call in ["set_size", "allocate", "pwrite"].iter() {
+        assert_eq!(instance.call_typed::<(i32, i64), i32>(call, (fd, -1)), fbig);
+        assert_eq!(instance.call_typed::<(i32, i64), i32>(call, (fd, 17)), fbig);
+    }
+    assert_eq!(fs.contents("file"), Some(vec![]));
+    assert_eq!(
+        instance.call_typed::<(i32, i64), i32>("pwrite", (fd, 12)),
+        Ok(0)
+    );
+    assert_eq!(fs.contents("file").unwrap().len(), 16);
+}
+
+#[test]
+fn runs_wasm32_wasi_binaries() {
+    // parsing a module this big takes more stack than tests get in debug
+    // builds
+    let compile = std::thread::Builder::new()
+        .stack_size(64 * 1024 * 1024)
+        .spawn(|| {
+            let bf = parse(include_bytes!("../examples/bf/bf.wasm"))
+                .unwrap()
+                .to_owned();
+            let fs = MemoryFileSystem::new();
+            fs.insert_file("hello.bf", include_bytes!("../examples/bf/helloworld.bf"))
+                .unwrap();
+            let mut wasi = Wasi::new();
+            wasi.arg("bf")
+                .arg("hello.bf")
+                .arg("hello.wasm")
+                .preopen(".", fs.clone());
+            let mut instance = Instance::new(bf, &Default::default(), wasi.imports()).unwrap();
+            assert_eq!(wasi.run(&mut instance), Ok(0));
+            fs.contents("hello.wasm")
+        })
+        .unwrap();
+    let compiled = compile.join().unwrap();
+    assert_eq!(
+        compiled.as_deref(),
+        Some(&include_bytes!("../examples/bf/helloworld.wasm")[..])
+    );
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn opens_host_directories() {
+    let dir = std::env::temp_dir().join(format!("watson-wasi-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("in.txt"), b"from the host").unwrap();
+    let mut wasi = Wasi::new();
+    wasi.preopen(".", HostDirectory::new(&dir));
+    let mut instance = Instance::new(copy(), &Default::default(), wasi.imports()).unwrap();
+    assert_eq!(wasi.run(&mut instance), Ok(0));
+    let copied = std::fs::read(dir.join("out").join("copy.txt"));
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(copied.unwrap(), b"from the host");
+}
+
+#[cfg(all(feature = "std", unix))]
+#[test]
+fn keeps_symbolic_links_inside_host_directories() {
+    let p = with_check(
+        r#"
+        (import "wasi_snapshot_preview1" "path_open"
+          (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+        (import "wasi_snapshot_preview1" "path_symlink"
+          (func $path_symlink (param i32 i32 i32 i32 i32) (result i32)))
+        "#,
+        r#"
+        (data (i32.const 100) "../secret")
+        (data (i32.const 120) "/secret")
+        (data (i32.const 140) "in.txt")
+        (data (i32.const 150) "escape")
+        (data (i32.const 160) "link")
+        (data (i32.const 170) "planted")
+        (data (i32.const 180) "outside/secret")
+        (func (export "symlink") (param i32 i32 i32 i32) (result i32)
+          (call $path_symlink (local.get 0) (local.get 1) (i32.const 3) (local.get 2)
+            (local.get 3)))
+        (func (export "open") (param i32 i32) (result i32)
+          (call $path_open (i32.const 3) (i32.const 0) (local.get 0) (local.get 1)
+            (i32.const 0) (i64.const -1) (i64.const -1) (i32.const 0) (i32.const 0)))
+        "#,
+    );
+    let dir = std::env::temp_dir().join(format!("watson-wasi-links-{}", std::process::id()));
+    let root = dir.join("root");
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(dir.join("secret"), b"not for the guest").unwrap();
+    std::fs::write(root.join("in.txt"), b"for the guest").unwrap();
+    // links the host left lying around that lead out
+    std::os::unix::fs::symlink(dir.join("secret"), root.join("planted")).unwrap();
+    std::os::unix::fs::symlink(&dir, root.join("outside")).unwrap();
+    let mut wasi = Wasi::new();
+    wasi.preopen(".", HostDirectory::new(&root));
+    let mut instance = Instance::new(p, &Default::default(), wasi.imports()).unwrap();
+
+    let mut symlink = |target: (i32, i32), path: (i32, i32)| {
+        instance.call_typed::<(i32, i32, i32, i32), i32>(
+            "symlink",
+            (target.0, target.1, path.0, path.1),
+        )
+    };
+    let notcapable = Ok(errno::NOTCAPABLE as i32);
+    assert_eq!(symlink((100, 9), (150, 6)), notcapable);
+    assert_eq!(symlink((120, 7), (150, 6)), notcapable);
+    assert_eq!(symlink((140, 6), (160, 4)), Ok(0));
+    let escaped = std::fs::symlink_metadata(root.join("escape")).is_ok();
+
+    let mut open = |path: (i32, i32)| instance.call_typed::<(i32, i32), i32>("open", path);
+    assert_eq!(open((160, 4)), Ok(0));
+    assert_eq!(open((170, 7)), notcapable);
+    assert_eq!(open((180, 14)), notcapable);
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(!escaped);
+}